@@ -0,0 +1,645 @@
+//! Agent backend: `atune agent <root>` runs on the host of dst and writes the files it receives
+//! over stdin into root, so a change is synced over a connection kept open between the syncs
+//! instead of a new rsync and ssh process per change, see [crate::config::SyncBackend::Agent].
+//!
+//! The protocol is line based, paths are relative to root and escaped: `\\` for a backslash, `\n`
+//! and `\r` for line breaks. Every request is answered with `ok`, or `error <message>`:
+//!
+//! - `list`: replied with `ok <n>` followed by `<f|d> <size> <mtime> <path>` lines of the `n`
+//!   files and directories in root, mtime in seconds since the epoch
+//! - `put <size> <mtime> <mode> <path>` followed by the `size` bytes of the file: replace the
+//!   file, creating its parent directories
+//! - `mkdir <path>`: create the directory and its parents
+//! - `remove <path>`: remove the file or directory, missing paths are fine
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Component, Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{Mutex, OnceLock},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use tracing::{debug, info, warn};
+
+use crate::{
+    backend::{SyncBackend, TransferContext},
+    config,
+    sync::{RsyncStats, SyncOutput},
+};
+
+/// Serve the requests read from `input` into `root`, until the input ends
+pub fn serve(root: &Path, input: impl Read, mut output: impl Write) -> anyhow::Result<()> {
+    std::fs::create_dir_all(root)
+        .with_context(|| format!("Failed to create {}", root.display()))?;
+    let mut input = BufReader::new(input);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let request = line.trim_end_matches('\n');
+        if request.is_empty() {
+            continue;
+        }
+        match handle(root, request, &mut input) {
+            Err(err) if err.is::<OutOfStep>() => return Err(err),
+            Ok(Some(listing)) => {
+                writeln!(output, "ok {}", listing.len())?;
+                for l in listing {
+                    writeln!(output, "{l}")?;
+                }
+            }
+            Ok(None) => writeln!(output, "ok")?,
+            Err(err) => {
+                debug!(request, "Request failed: {err:#}");
+                writeln!(output, "error {}", format!("{err:#}").replace('\n', " "))?;
+            }
+        }
+        output.flush()?;
+    }
+}
+
+/// The rest of the input can't be told apart from the requests, e.g. after a `put` without a
+/// valid size
+#[derive(Debug)]
+struct OutOfStep;
+
+impl std::fmt::Display for OutOfStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The input is out of step with the requests")
+    }
+}
+
+impl std::error::Error for OutOfStep {}
+
+/// `path` with the backslashes and line breaks escaped, to fit on a line of the protocol
+fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The path of an [escape]d one
+fn unescape(escaped: &str) -> anyhow::Result<String> {
+    let mut path = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            path.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => path.push('\\'),
+            Some('n') => path.push('\n'),
+            Some('r') => path.push('\r'),
+            _ => anyhow::bail!("Invalid escape in {escaped:?}"),
+        }
+    }
+    Ok(path)
+}
+
+/// Handle one request, returning the lines of a listing
+fn handle(
+    root: &Path,
+    request: &str,
+    input: &mut impl BufRead,
+) -> anyhow::Result<Option<Vec<String>>> {
+    let (command, args) = request.split_once(' ').unwrap_or((request, ""));
+    match command {
+        "list" => {
+            let mut listing = Vec::new();
+            list(root, root, &mut listing)?;
+            Ok(Some(listing))
+        }
+        "put" => {
+            let mut parts = args.splitn(4, ' ');
+            let size = parts
+                .next()
+                .and_then(|n| n.parse::<u64>().ok())
+                .ok_or(OutOfStep)?;
+            let mut content = input.take(size);
+            let res = (|| {
+                let mut number = || {
+                    parts
+                        .next()
+                        .and_then(|n| n.parse::<u64>().ok())
+                        .context("Invalid put request")
+                };
+                let (mtime, mode) = (number()?, number()?);
+                let path = resolve(root, parts.next().unwrap_or_default())?;
+                put(&path, &mut content, size, mtime, mode)
+            })();
+            // the rest of the content is read even if writing fails, to stay in sync with the
+            // client
+            io::copy(&mut content, &mut io::sink())?;
+            anyhow::ensure!(content.limit() == 0, OutOfStep);
+            res?;
+            Ok(None)
+        }
+        "mkdir" => {
+            let path = resolve(root, args)?;
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            Ok(None)
+        }
+        "remove" => {
+            let path = resolve(root, args)?;
+            let res = match std::fs::symlink_metadata(&path) {
+                Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(&path),
+                Ok(_) => std::fs::remove_file(&path),
+                Err(_) => Ok(()),
+            };
+            res.with_context(|| format!("Failed to remove {}", path.display()))?;
+            Ok(None)
+        }
+        _ => anyhow::bail!("Unknown request {command}"),
+    }
+}
+
+/// The [escape]d `path` in root, refusing paths leaving it
+fn resolve(root: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    let path = unescape(path)?;
+    let rel = Path::new(&path);
+    anyhow::ensure!(
+        !path.is_empty() && rel.components().all(|c| matches!(c, Component::Normal(_))),
+        "Invalid path {path:?}"
+    );
+    Ok(root.join(rel))
+}
+
+fn list(root: &Path, dir: &Path, listing: &mut Vec<String>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Ok(meta) = std::fs::metadata(&path) else {
+            continue;
+        };
+        let rel = escape(&path.strip_prefix(root)?.to_string_lossy());
+        if meta.is_dir() {
+            listing.push(format!("d 0 0 {rel}"));
+            list(root, &path, listing)?;
+        } else {
+            listing.push(format!("f {} {} {rel}", meta.len(), mtime_secs(&meta)));
+        }
+    }
+    Ok(())
+}
+
+/// Replace `path` with the `size` bytes of `content`
+fn put(
+    path: &Path,
+    content: &mut impl Read,
+    size: u64,
+    mtime: u64,
+    mode: u64,
+) -> anyhow::Result<()> {
+    let parent = path.parent().context("Invalid path")?;
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("Failed to create {}", parent.display()))?;
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.is_dir()) {
+        std::fs::remove_dir_all(path)?;
+    }
+    // readers never see a half written file
+    let tmp = parent.join(format!(
+        ".{}.atune-agent",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let mut file = std::fs::File::create(&tmp)
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    let written = io::copy(content, &mut file)?;
+    if written != size {
+        drop(file);
+        let _ = std::fs::remove_file(&tmp);
+        anyhow::bail!("The content of {} ended early", path.display());
+    }
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        file.set_permissions(std::fs::Permissions::from_mode(mode as u32 & 0o7777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    drop(file);
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+fn mtime_secs(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(unix)]
+fn file_mode(meta: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt as _;
+    meta.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_meta: &std::fs::Metadata) -> u32 {
+    0o644
+}
+
+/// Connection to an agent
+struct Connection {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Connection {
+    /// Start the agent serving `dst`: over ssh for `host:path`, or as a local process
+    fn open(s: &crate::sync::ParsedSync, dst: &Path) -> anyhow::Result<Self> {
+        let agent = s.agent.as_deref().unwrap_or("atune");
+        let agent = shell_words::split(agent).context("Failed to split the agent command")?;
+        let (program, agent_args) = agent.split_first().context("The agent command is empty")?;
+        let mut cmd = match config::remote_dst(dst) {
+            Some((host, path)) => {
+                let mut cmd = Command::new("ssh");
+                let ssh = s.ssh_args_of(dst);
+                cmd.args(ssh);
+                if s.ssh_multiplexing {
+                    cmd.args(crate::ssh::multiplex_args(host, ssh));
+                }
+                // ssh passes the command to the remote shell
+                let remote = agent
+                    .iter()
+                    .map(String::as_str)
+                    .chain(["agent", path])
+                    .map(|a| shell_words::quote(a).into_owned())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                cmd.args(["-T", "-e", "none", host, &remote]);
+                cmd
+            }
+            None => {
+                let mut cmd = Command::new(program);
+                cmd.args(agent_args).arg("agent").arg(dst);
+                cmd
+            }
+        };
+        info!(?dst, "Starting the agent");
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .context("Failed to start the agent")?;
+        let stdin = child.stdin.take().context("The agent has no stdin")?;
+        let stdout = BufReader::new(child.stdout.take().context("The agent has no stdout")?);
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    /// Send a request and wait for its reply, returning the lines of a listing
+    fn request(&mut self, request: &str) -> anyhow::Result<Vec<String>> {
+        writeln!(self.stdin, "{request}")?;
+        self.stdin.flush()?;
+        self.reply()
+    }
+
+    /// Send the file `path` to `rel` in dst, returning its size. None if it doesn't exist anymore
+    fn put(&mut self, path: &Path, rel: &str) -> anyhow::Result<Option<u64>> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        let meta = file.metadata()?;
+        let size = meta.len();
+        writeln!(
+            self.stdin,
+            "put {size} {} {} {}",
+            mtime_secs(&meta),
+            file_mode(&meta),
+            escape(rel)
+        )?;
+        let sent = io::copy(&mut file.take(size), &mut self.stdin)?;
+        // the agent expects `size` bytes, it's sent again once the change of the file is synced
+        io::copy(&mut io::repeat(0).take(size - sent), &mut self.stdin)?;
+        self.stdin.flush()?;
+        self.reply()?;
+        anyhow::ensure!(
+            sent == size,
+            "{} was truncated while sending it",
+            path.display()
+        );
+        Ok(Some(size))
+    }
+
+    /// Wait for the reply of a request, returning the lines of a listing
+    fn reply(&mut self) -> anyhow::Result<Vec<String>> {
+        let mut reply = String::new();
+        anyhow::ensure!(self.stdout.read_line(&mut reply)? > 0, "The agent exited");
+        let reply = reply.trim_end();
+        if let Some(err) = reply.strip_prefix("error ") {
+            anyhow::bail!("The agent failed: {err}");
+        }
+        let count = match reply.strip_prefix("ok") {
+            Some("") => 0,
+            Some(n) => n
+                .trim()
+                .parse::<usize>()
+                .context("Invalid reply of the agent")?,
+            None => anyhow::bail!("Invalid reply of the agent: {reply}"),
+        };
+        let mut lines = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut line = String::new();
+            self.stdout.read_line(&mut line)?;
+            lines.push(line.trim_end_matches('\n').to_owned());
+        }
+        Ok(lines)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Idle connections by the agent command and dst, reused by the following syncs of the process
+type Connections = Mutex<HashMap<(Option<String>, PathBuf), Vec<Connection>>>;
+
+fn connections() -> &'static Connections {
+    static CONNECTIONS: OnceLock<Connections> = OnceLock::new();
+    CONNECTIONS.get_or_init(Default::default)
+}
+
+/// What the agent is sent for the transfer
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Plan {
+    dirs: Vec<String>,
+    /// local path and the path in dst
+    files: Vec<(PathBuf, String)>,
+    removed: Vec<String>,
+}
+
+/// Path of `path` in dst, relative to the transfer root of the sync
+fn dst_path(root: &Path, path: &Path) -> Option<String> {
+    let rel = path.strip_prefix(root).ok()?;
+    (!rel.as_os_str().is_empty()).then(|| rel.to_string_lossy().into_owned())
+}
+
+/// Add `path` and everything below it to the plan
+fn plan_local(root: &Path, path: &Path, plan: &mut Plan) {
+    let Ok(meta) = std::fs::metadata(path) else {
+        if let Some(rel) = dst_path(root, path) {
+            plan.removed.push(rel);
+        }
+        return;
+    };
+    let Some(rel) = dst_path(root, path) else {
+        return;
+    };
+    if meta.is_dir() {
+        plan.dirs.push(rel);
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for e in entries.flatten() {
+                plan_local(root, &e.path(), plan);
+            }
+        }
+    } else {
+        plan.files.push((path.to_owned(), rel));
+    }
+}
+
+/// Compare src to the listing of dst: send the files whose size or modification time differ and
+/// remove what's missing from src, like rsync's quick check
+fn plan_full(root: &Path, src: &Path, listing: &[String]) -> Plan {
+    let mut local = Plan::default();
+    plan_local(root, src, &mut local);
+    let remote = listing
+        .iter()
+        .filter_map(|l| {
+            let mut parts = l.splitn(4, ' ');
+            let kind = parts.next()?;
+            let size = parts.next()?.parse::<u64>().ok()?;
+            let mtime = parts.next()?.parse::<u64>().ok()?;
+            let path = unescape(parts.next()?).ok()?;
+            Some((path, (kind == "d", size, mtime)))
+        })
+        .collect::<BTreeMap<_, _>>();
+    let prefix = dst_path(root, src);
+    let mut plan = Plan::default();
+    for dir in local.dirs.iter() {
+        if remote.get(dir).is_none_or(|(is_dir, ..)| !is_dir) {
+            plan.dirs.push(dir.clone());
+        }
+    }
+    for (path, rel) in local.files.iter() {
+        let unchanged = std::fs::metadata(path)
+            .is_ok_and(|m| remote.get(rel) == Some(&(false, m.len(), mtime_secs(&m))));
+        if !unchanged {
+            plan.files.push((path.clone(), rel.clone()));
+        }
+    }
+    let kept = local
+        .dirs
+        .iter()
+        .chain(local.files.iter().map(|(_, r)| r))
+        .collect::<std::collections::HashSet<_>>();
+    for rel in remote.keys() {
+        let in_src = prefix
+            .as_ref()
+            .is_none_or(|p| rel == p || rel.starts_with(&format!("{p}/")));
+        // removing a directory removes its content
+        let parent_removed = plan
+            .removed
+            .last()
+            .is_some_and(|r| rel.starts_with(&format!("{r}/")));
+        if in_src && !kept.contains(rel) && !parent_removed {
+            plan.removed.push(rel.clone());
+        }
+    }
+    plan
+}
+
+/// The agent backend, see [crate::config::SyncBackend::Agent]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AgentBackend;
+
+impl SyncBackend for AgentBackend {
+    fn transfer(
+        &self,
+        ctx: &TransferContext<'_>,
+        output: Option<&mut SyncOutput>,
+    ) -> anyhow::Result<()> {
+        let s = ctx.sync;
+        let root = crate::sync::transfer_root(&s.src);
+        let key = (s.agent.clone(), ctx.dst.to_owned());
+        // taken while in use, so the syncs to other destinations don't wait for this one
+        let idle = connections()
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .and_then(Vec::pop);
+        let mut conn = match idle {
+            Some(conn) => conn,
+            None => Connection::open(s, ctx.dst)?,
+        };
+        let known = !ctx.changes.changed.is_empty() || !ctx.changes.deleted.is_empty();
+        let res = (|| {
+            let plan = if known {
+                let mut plan = Plan::default();
+                for path in ctx.changes.deleted.iter() {
+                    if let Some(rel) = dst_path(root, path) {
+                        plan.removed.push(rel);
+                    }
+                }
+                for path in ctx.changes.changed.iter() {
+                    plan_local(root, path, &mut plan);
+                }
+                plan
+            } else {
+                let listing = conn.request("list")?;
+                plan_full(root, &s.src, &listing)
+            };
+            debug!(?plan, "Agent transfer");
+            let mut stats = RsyncStats::default();
+            for rel in plan.removed.iter() {
+                conn.request(&format!("remove {}", escape(rel)))?;
+                stats.files_deleted += 1;
+            }
+            for rel in plan.dirs.iter() {
+                conn.request(&format!("mkdir {}", escape(rel)))?;
+            }
+            for (path, rel) in plan.files.iter() {
+                if let Some(runner) = ctx.runner {
+                    runner.check()?;
+                }
+                let Some(size) = conn.put(path, rel)? else {
+                    // deleted since, its deletion is synced next
+                    warn!(?path, "Skipping a file that vanished");
+                    continue;
+                };
+                stats.files_transferred += 1;
+                stats.transferred_size += size;
+                stats.bytes_sent += size;
+            }
+            anyhow::Ok(stats)
+        })();
+        // on errors the connection is dropped, it may be out of step
+        let stats = res?;
+        connections()
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .push(conn);
+        if let Some(output) = output {
+            output.stats = Some(stats);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serve() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        // the rejected contents are skipped, they aren't read as requests
+        let input = b"mkdir a/b\nput 5 1000 420 a/c.txt\nhello\nlist\nremove a/b\nput 5 0 420 ../x\nlist\nput 5 0 x a/d.txt\nlist\nput 4 0 420 a/new\\nline\nlinelist\n";
+        let mut output = Vec::new();
+        serve(&root, &input[..], &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let replies = output.lines().collect::<Vec<_>>();
+        assert_eq!(replies[..2], ["ok", "ok"]);
+        assert_eq!(replies[2], "ok 3");
+        let mut listing = replies[3..6].to_vec();
+        listing.sort();
+        assert_eq!(listing, ["d 0 0 a", "d 0 0 a/b", "f 5 1000 a/c.txt"]);
+        assert_eq!(replies[6], "ok");
+        assert!(
+            replies[7].starts_with("error Invalid path"),
+            "{}",
+            replies[7]
+        );
+        assert!(
+            replies[8].starts_with("error Invalid put request"),
+            "{}",
+            replies[8]
+        );
+        assert_eq!(replies[9], "ok");
+        assert_eq!(replies[10], "ok 3");
+        let mut listing = replies[11..].to_vec();
+        listing.sort();
+        assert_eq!(
+            listing,
+            ["d 0 0 a", "f 4 0 a/new\\nline", "f 5 1000 a/c.txt"]
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("a/c.txt")).unwrap(),
+            "hello"
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("a/new\nline")).unwrap(),
+            "line"
+        );
+        assert!(!root.join("a/b").exists());
+        assert!(!root.join("a/d.txt").exists());
+
+        // the size of the content is unknown
+        let mut output = Vec::new();
+        let err = serve(&root, &b"put x 0 420 a/e.txt\nlist\n"[..], &mut output).unwrap_err();
+        assert!(err.is::<OutOfStep>(), "{err}");
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_escape() {
+        for path in ["a/b.txt", "new\nline", "back\\slash\\n", "\r\n"] {
+            let escaped = escape(path);
+            assert!(!escaped.contains(['\n', '\r']), "{escaped:?}");
+            assert_eq!(unescape(&escaped).unwrap(), path);
+        }
+        assert!(unescape("a\\").is_err());
+        assert!(unescape("a\\t").is_err());
+    }
+
+    #[test]
+    fn test_plan_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("same.txt"), "same").unwrap();
+        std::fs::write(src.join("sub/new.txt"), "new").unwrap();
+        let mtime = mtime_secs(&std::fs::metadata(src.join("same.txt")).unwrap());
+        let listing = [
+            "d 0 0 src".to_owned(),
+            format!("f 4 {mtime} src/same.txt"),
+            "d 0 0 src/old".to_owned(),
+            "f 3 0 src/old/gone.txt".to_owned(),
+            "f 3 0 other/kept.txt".to_owned(),
+        ];
+        let plan = plan_full(dir.path(), &src, &listing);
+        assert_eq!(plan.dirs, ["src/sub"]);
+        assert_eq!(
+            plan.files,
+            [(src.join("sub/new.txt"), "src/sub/new.txt".to_owned())]
+        );
+        assert_eq!(plan.removed, ["src/old"]);
+    }
+}
@@ -0,0 +1,630 @@
+//! HTTP API of a running watch, see [crate::config::Config::api_addr]
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::Write as _,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use crossbeam::channel;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{debug, info};
+
+use crate::{rusage::ResourceUsage, SyncError, WatchControl, WatchEvent};
+
+/// Threads answering the requests, the `/events` streams get threads of their own
+const WORKERS: usize = 4;
+/// Clients of `/events` at a time, more are refused
+const MAX_EVENT_STREAMS: usize = 32;
+/// Interval of the comments sent to `/events` subscribers, so proxies don't close idle streams
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Default)]
+struct ProjectStatus {
+    paused: bool,
+    running: usize,
+    watcher_error: Option<String>,
+    /// success of the last finished sync
+    last_result: Option<bool>,
+    last_error: Option<String>,
+    /// resources used by the finished syncs of each entry, keyed by src
+    usage: BTreeMap<PathBuf, EntryUsage>,
+}
+
+#[derive(Debug, Default)]
+struct EntryUsage {
+    syncs: u64,
+    /// CPU times summed over the syncs, the largest memory of one
+    total: ResourceUsage,
+}
+
+impl ProjectStatus {
+    fn status(&self) -> &'static str {
+        if self.watcher_error.is_some() {
+            "watcher_degraded"
+        } else if self.paused {
+            "paused"
+        } else if self.running > 0 {
+            "syncing"
+        } else {
+            "idle"
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ApiState {
+    ready: bool,
+    projects: BTreeMap<String, ProjectStatus>,
+    /// clients of `/events`, receiving the JSON of the events
+    subscribers: Vec<channel::Sender<String>>,
+}
+
+impl ApiState {
+    fn apply(&mut self, event: &WatchEvent) {
+        match event {
+            WatchEvent::Ready => self.ready = true,
+            WatchEvent::SyncStarted { project, .. } => self.project(project).running += 1,
+            WatchEvent::SyncFinished {
+                project,
+                src,
+                result,
+                usage,
+                ..
+            } => {
+                let p = self.project(project);
+                p.running = p.running.saturating_sub(1);
+                p.last_result = Some(result.is_ok());
+                let entry = p.usage.entry(src.clone()).or_default();
+                entry.syncs += 1;
+                entry.total.add(usage);
+                if let Err(err) = result {
+                    p.last_error = Some(err.kind().to_owned());
+                }
+            }
+            WatchEvent::SyncCancelled { project, .. } => {
+                let p = self.project(project);
+                p.running = p.running.saturating_sub(1);
+            }
+            WatchEvent::WatcherDegraded { project, error } => {
+                self.project(project).watcher_error = Some(error.clone())
+            }
+            WatchEvent::WatcherRecovered { project } => self.project(project).watcher_error = None,
+            WatchEvent::Paused { project } => self.project(project).paused = true,
+            WatchEvent::Resumed { project } => self.project(project).paused = false,
+            WatchEvent::SyncProgress { .. }
+            | WatchEvent::HookFailed { .. }
+            | WatchEvent::DstDrift { .. } => {}
+        }
+    }
+
+    fn project(&mut self, name: &str) -> &mut ProjectStatus {
+        self.projects.entry(name.to_owned()).or_default()
+    }
+
+    fn status_json(&self) -> String {
+        let status = StatusJson {
+            ready: self.ready,
+            projects: self
+                .projects
+                .iter()
+                .map(|(name, p)| ProjectJson {
+                    name,
+                    status: p.status(),
+                    running: p.running,
+                    last_result: p.last_result.map(|ok| if ok { "ok" } else { "failed" }),
+                    last_error: p.last_error.as_deref(),
+                    watcher_error: p.watcher_error.as_deref(),
+                    syncs: p
+                        .usage
+                        .iter()
+                        .map(|(src, u)| EntryUsageJson {
+                            src: src.to_string_lossy(),
+                            syncs: u.syncs,
+                            usage: u.total,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+        serde_json::to_string(&status).expect("Strings always serialize")
+    }
+}
+
+/// The body of `/status`
+#[derive(serde_derive::Serialize)]
+struct StatusJson<'a> {
+    ready: bool,
+    projects: Vec<ProjectJson<'a>>,
+}
+
+#[derive(serde_derive::Serialize)]
+struct ProjectJson<'a> {
+    name: &'a str,
+    status: &'static str,
+    running: usize,
+    last_result: Option<&'static str>,
+    last_error: Option<&'a str>,
+    watcher_error: Option<&'a str>,
+    syncs: Vec<EntryUsageJson<'a>>,
+}
+
+#[derive(serde_derive::Serialize)]
+struct EntryUsageJson<'a> {
+    src: Cow<'a, str>,
+    syncs: u64,
+    #[serde(flatten)]
+    usage: ResourceUsage,
+}
+
+/// A [WatchEvent] as JSON, the paths lossily converted to strings
+#[derive(serde_derive::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum EventJson<'a> {
+    Ready,
+    SyncStarted {
+        project: &'a str,
+        src: Cow<'a, str>,
+        initialize: bool,
+    },
+    SyncFinished {
+        project: &'a str,
+        src: Cow<'a, str>,
+        result: &'static str,
+        exit_code: Option<i32>,
+        duration_ms: u128,
+        #[serde(flatten)]
+        usage: ResourceUsage,
+    },
+    SyncProgress {
+        project: &'a str,
+        src: Cow<'a, str>,
+        percent: u8,
+        transferred: &'a str,
+        rate: &'a str,
+        eta: &'a str,
+    },
+    SyncCancelled {
+        project: &'a str,
+        src: Cow<'a, str>,
+    },
+    HookFailed {
+        project: &'a str,
+        src: Cow<'a, str>,
+    },
+    WatcherDegraded {
+        project: &'a str,
+        error: &'a str,
+    },
+    WatcherRecovered {
+        project: &'a str,
+    },
+    Paused {
+        project: &'a str,
+    },
+    Resumed {
+        project: &'a str,
+    },
+    DstDrift {
+        project: &'a str,
+        src: Cow<'a, str>,
+        paths: Vec<Cow<'a, str>>,
+    },
+}
+
+/// The event as a JSON object, with its kind in the `event` field
+pub fn event_json(event: &WatchEvent) -> String {
+    let event = match event {
+        WatchEvent::Ready => EventJson::Ready,
+        WatchEvent::SyncStarted {
+            project,
+            src,
+            initialize,
+        } => EventJson::SyncStarted {
+            project,
+            src: src.to_string_lossy(),
+            initialize: *initialize,
+        },
+        WatchEvent::SyncFinished {
+            project,
+            src,
+            result,
+            duration,
+            usage,
+        } => EventJson::SyncFinished {
+            project,
+            src: src.to_string_lossy(),
+            result: result.as_ref().err().map_or("ok", SyncError::kind),
+            exit_code: match result {
+                Err(SyncError::Failed { exit_code }) => *exit_code,
+                _ => None,
+            },
+            duration_ms: duration.as_millis(),
+            usage: *usage,
+        },
+        WatchEvent::SyncProgress {
+            project,
+            src,
+            progress,
+        } => EventJson::SyncProgress {
+            project,
+            src: src.to_string_lossy(),
+            percent: progress.percent,
+            transferred: &progress.transferred,
+            rate: &progress.rate,
+            eta: &progress.eta,
+        },
+        WatchEvent::SyncCancelled { project, src } => EventJson::SyncCancelled {
+            project,
+            src: src.to_string_lossy(),
+        },
+        WatchEvent::HookFailed { project, src } => EventJson::HookFailed {
+            project,
+            src: src.to_string_lossy(),
+        },
+        WatchEvent::WatcherDegraded { project, error } => {
+            EventJson::WatcherDegraded { project, error }
+        }
+        WatchEvent::WatcherRecovered { project } => EventJson::WatcherRecovered { project },
+        WatchEvent::Paused { project } => EventJson::Paused { project },
+        WatchEvent::Resumed { project } => EventJson::Resumed { project },
+        WatchEvent::DstDrift {
+            project,
+            src,
+            paths,
+        } => EventJson::DstDrift {
+            project,
+            src: src.to_string_lossy(),
+            paths: paths.iter().map(|p| p.to_string_lossy()).collect(),
+        },
+    };
+    serde_json::to_string(&event).expect("Strings always serialize")
+}
+
+/// The HTTP server, stops listening when dropped
+pub struct ApiServer {
+    server: Arc<Server>,
+    addr: SocketAddr,
+    state: Arc<Mutex<ApiState>>,
+}
+
+impl std::fmt::Debug for ApiServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiServer")
+            .field("addr", &self.addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ApiServer {
+    /// Listen on `addr` for the API of the watch of `projects`, sending the commands of the
+    /// clients to `control`. If `token` is set, then the requests must pass it as
+    /// `Authorization: Bearer <token>`
+    pub fn start(
+        addr: &str,
+        projects: impl IntoIterator<Item = String>,
+        token: Option<String>,
+        control: channel::Sender<WatchControl>,
+    ) -> anyhow::Result<Self> {
+        let server = Server::http(addr)
+            .map_err(|err| anyhow::anyhow!(err))
+            .with_context(|| format!("Failed to bind the API to {addr}"))?;
+        let addr = server
+            .server_addr()
+            .to_ip()
+            .context("The API must listen on an IP address")?;
+        info!(%addr, "Serving the HTTP API");
+        let server = Arc::new(server);
+        let state = Arc::new(Mutex::new(ApiState {
+            projects: projects
+                .into_iter()
+                .map(|p| (p, ProjectStatus::default()))
+                .collect(),
+            ..Default::default()
+        }));
+        let token = Arc::new(token);
+        for _ in 0..WORKERS {
+            let server = server.clone();
+            let state = state.clone();
+            let control = control.clone();
+            let token = token.clone();
+            // until the server is unblocked when dropped
+            std::thread::spawn(move || {
+                while let Ok(request) = server.recv() {
+                    let peer = request.remote_addr().copied();
+                    if let Err(err) = handle(request, &state, &control, token.as_deref()) {
+                        debug!(?err, ?peer, "API request failed");
+                    }
+                }
+            });
+        }
+        Ok(Self {
+            server,
+            addr,
+            state,
+        })
+    }
+
+    /// Address the server listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Update the status with the event and stream it to the subscribers of `/events`
+    pub fn publish(&self, event: &WatchEvent) {
+        let mut state = self.state.lock().unwrap();
+        state.apply(event);
+        let json = event_json(event);
+        // subscribers that disconnected dropped their receivers
+        state.subscribers.retain(|s| s.send(json.clone()).is_ok());
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        for _ in 0..WORKERS {
+            self.server.unblock();
+        }
+        // ends the `/events` streams
+        self.state.lock().unwrap().subscribers.clear();
+    }
+}
+
+fn handle(
+    request: Request,
+    state: &Arc<Mutex<ApiState>>,
+    control: &channel::Sender<WatchControl>,
+    token: Option<&str>,
+) -> anyhow::Result<()> {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str().to_owned())
+    };
+    // browsers send the Origin of cross-site requests, which could otherwise drive a daemon on
+    // localhost from any web page. The API has no clients in browsers
+    if header("Origin").is_some() {
+        return respond(
+            request,
+            403,
+            r#"{"error":"cross-origin requests are refused"}"#,
+        );
+    }
+    if let Some(token) = token {
+        if header("Authorization").as_deref() != Some(format!("Bearer {token}").as_str()) {
+            return respond(request, 401, r#"{"error":"unauthorized"}"#);
+        }
+    }
+    let url = request.url().to_owned();
+    let (route, query) = url.split_once('?').unwrap_or((&url, ""));
+    let project = query
+        .split('&')
+        .find_map(|kv| kv.strip_prefix("project="))
+        .map(|p| {
+            percent_encoding::percent_decode_str(&p.replace('+', " "))
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .filter(|p| !p.is_empty());
+
+    match (request.method(), route) {
+        (Method::Get, "/status") => {
+            let body = state.lock().unwrap().status_json();
+            respond(request, 200, &body)
+        }
+        (Method::Get, "/events") => {
+            if state.lock().unwrap().subscribers.len() >= MAX_EVENT_STREAMS {
+                return respond(request, 503, r#"{"error":"too many event streams"}"#);
+            }
+            let state = state.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = stream_events(request, &state) {
+                    debug!(?err, "Event stream ended");
+                }
+            });
+            Ok(())
+        }
+        (Method::Post, "/trigger" | "/pause" | "/resume") => {
+            let projects = match project {
+                Some(p) if !state.lock().unwrap().projects.contains_key(&p) => {
+                    let body =
+                        serde_json::json!({ "error": format!("unknown project {p}") }).to_string();
+                    return respond(request, 404, &body);
+                }
+                Some(p) => vec![p],
+                None => state.lock().unwrap().projects.keys().cloned().collect(),
+            };
+            for project in projects.iter().cloned() {
+                let _ = control.send(match route {
+                    "/trigger" => WatchControl::Trigger { project },
+                    "/pause" => WatchControl::Pause { project },
+                    _ => WatchControl::Resume { project },
+                });
+            }
+            let body = serde_json::json!({ "projects": projects }).to_string();
+            respond(request, 202, &body)
+        }
+        (_, "/status" | "/events" | "/trigger" | "/pause" | "/resume") => {
+            respond(request, 405, r#"{"error":"method not allowed"}"#)
+        }
+        _ => respond(request, 404, r#"{"error":"not found"}"#),
+    }
+}
+
+fn respond(request: Request, status: u16, body: &str) -> anyhow::Result<()> {
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+    request.respond(response)?;
+    Ok(())
+}
+
+/// Send the events as server-sent events until the client disconnects or the server stops
+fn stream_events(request: Request, state: &Mutex<ApiState>) -> anyhow::Result<()> {
+    let (tx, rx) = channel::unbounded();
+    state.lock().unwrap().subscribers.push(tx);
+    // written directly, tiny_http buffers the chunks of streamed responses
+    let mut stream = request.into_writer();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+    stream.flush()?;
+    loop {
+        match rx.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(json) => write!(stream, "data: {json}\n\n")?,
+            Err(channel::RecvTimeoutError::Timeout) => write!(stream, ": keepalive\n\n")?,
+            Err(channel::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        stream.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read as _, net::TcpStream};
+
+    use super::*;
+
+    fn request(addr: SocketAddr, request: &str) -> String {
+        request_with(addr, request, "")
+    }
+
+    fn request_with(addr: SocketAddr, request: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{request}\r\nHost: localhost\r\nConnection: close\r\n{headers}\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_api() {
+        let (tx, rx) = channel::unbounded();
+        let api = ApiServer::start("127.0.0.1:0", ["web".to_owned()], None, tx).unwrap();
+        api.publish(&WatchEvent::SyncStarted {
+            project: "web".to_owned(),
+            src: "/src/web".into(),
+            initialize: true,
+        });
+
+        let response = request(api.local_addr(), "GET /status HTTP/1.1");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(
+            response.ends_with(
+                r#"{"ready":false,"projects":[{"name":"web","status":"syncing","running":1,"last_result":null,"last_error":null,"watcher_error":null,"syncs":[]}]}"#
+            ),
+            "{response}"
+        );
+
+        api.publish(&WatchEvent::SyncFinished {
+            project: "web".to_owned(),
+            src: "/src/web".into(),
+            result: Ok(()),
+            duration: Duration::from_secs(1),
+            usage: ResourceUsage {
+                user: Duration::from_millis(120),
+                system: Duration::from_millis(30),
+                max_rss: 4096,
+            },
+        });
+        let response = request(api.local_addr(), "GET /status HTTP/1.1");
+        assert!(
+            response.ends_with(
+                r#""syncs":[{"src":"/src/web","syncs":1,"cpu_user_ms":120,"cpu_system_ms":30,"max_rss_bytes":4096}]}]}"#
+            ),
+            "{response}"
+        );
+
+        let response = request(api.local_addr(), "POST /pause?project=web HTTP/1.1");
+        assert!(response.starts_with("HTTP/1.1 202"), "{response}");
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            WatchControl::Pause {
+                project: "web".to_owned()
+            }
+        );
+        let response = request(api.local_addr(), "POST /trigger?project=api HTTP/1.1");
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        let response = request(api.local_addr(), "GET /trigger HTTP/1.1");
+        assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+
+        // a cross-site request of a web page
+        let response = request_with(
+            api.local_addr(),
+            "POST /trigger HTTP/1.1",
+            "Origin: https://example.com\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+        assert!(rx.try_recv().is_err());
+
+        let mut events = TcpStream::connect(api.local_addr()).unwrap();
+        write!(events, "GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut events = std::io::BufReader::new(events);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            std::io::BufRead::read_line(&mut events, &mut line).unwrap();
+        }
+        api.publish(&WatchEvent::Ready);
+        line.clear();
+        std::io::BufRead::read_line(&mut events, &mut line).unwrap();
+        assert_eq!(line, "data: {\"event\":\"ready\"}\n");
+    }
+
+    #[test]
+    fn test_api_token() {
+        let (tx, rx) = channel::unbounded();
+        let api = ApiServer::start(
+            "127.0.0.1:0",
+            ["my web".to_owned()],
+            Some("secret".to_owned()),
+            tx,
+        )
+        .unwrap();
+        let response = request(api.local_addr(), "POST /trigger?project=my%20web HTTP/1.1");
+        assert!(response.starts_with("HTTP/1.1 401"), "{response}");
+        let response = request_with(
+            api.local_addr(),
+            "POST /trigger?project=my%20web HTTP/1.1",
+            "Authorization: Bearer secret\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 202"), "{response}");
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            WatchControl::Trigger {
+                project: "my web".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_event_json() {
+        assert_eq!(
+            event_json(&WatchEvent::SyncFinished {
+                project: "web".to_owned(),
+                src: "/src/web".into(),
+                result: Err(SyncError::Failed {
+                    exit_code: Some(23)
+                }),
+                duration: Duration::from_millis(1500),
+                usage: ResourceUsage {
+                    user: Duration::from_millis(900),
+                    system: Duration::from_millis(100),
+                    max_rss: 8_000_000,
+                },
+            }),
+            r#"{"event":"sync_finished","project":"web","src":"/src/web","result":"failed","exit_code":23,"duration_ms":1500,"cpu_user_ms":900,"cpu_system_ms":100,"max_rss_bytes":8000000}"#
+        );
+    }
+}
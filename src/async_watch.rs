@@ -0,0 +1,1034 @@
+//! Async engine of a watch on tokio, for GUI tools and servers that embed atune in their own
+//! runtime. The projects are tasks instead of threads, the syncs are tokio processes and the
+//! events and commands go through tokio channels.
+//!
+//! It covers the filesystem watch, the debounce, the initial syncs and their order by
+//! `depends_on`, `restart`, the [WatchControl] commands and the hooks of the projects and entries.
+//! Configs using settings that need the thread engine of [crate::Watcher] are refused: the HTTP
+//! API, in-process execution, `run` commands, healthchecks, the Shallow watcher, schedules, lock
+//! groups, priorities, `min_interval` and `watch_dst`. [WatchEvent::SyncFinished] reports no
+//! resource usage, tokio doesn't measure its children
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use notify::Watcher as _;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncRead},
+    sync::{mpsc, oneshot, watch},
+    task::JoinSet,
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::{self, CommandConfig, Config},
+    output::{OutputTail, Progress},
+    sync::{
+        self, ChangeKind, Debounce, ParsedProject, ParsedSync, SyncChanges, SyncError,
+        WatchControl, WatchEvent, WatchOptions,
+    },
+};
+
+/// Handle of a watch running on the tokio runtime it was started in
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// let config: atune::config::Config = serde_yaml::from_str("projects: {}")?;
+/// let mut watcher = atune::async_watch("atune.yaml".into(), config, Default::default());
+/// if let Some(event) = watcher.events().recv().await {
+///     println!("{event:?}");
+/// }
+/// watcher.stop().await
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct AsyncWatcher {
+    cancel: watch::Sender<bool>,
+    events: mpsc::UnboundedReceiver<WatchEvent>,
+    control: mpsc::UnboundedSender<WatchControl>,
+    task: tokio::task::JoinHandle<anyhow::Result<()>>,
+}
+
+/// Start watching the projects of the config on the current tokio runtime, which needs its IO and
+/// time drivers enabled.
+///
+/// `options.events` and `options.control` are replaced by the channels returned by
+/// [AsyncWatcher::events] and [AsyncWatcher::control]
+///
+/// # Panics
+///
+/// If called outside of a tokio runtime
+pub fn async_watch(config_path: PathBuf, config: Config, options: WatchOptions) -> AsyncWatcher {
+    let (cancel_tx, cancel_rx) = watch::channel(false);
+    let (events_tx, events_rx) = mpsc::unbounded_channel();
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    let task = tokio::spawn(run_watch(
+        config_path,
+        config,
+        options,
+        cancel_rx,
+        control_rx,
+        events_tx,
+    ));
+    AsyncWatcher {
+        cancel: cancel_tx,
+        events: events_rx,
+        control: control_tx,
+        task,
+    }
+}
+
+impl AsyncWatcher {
+    /// Events of the watch. The channel is closed once the watch stops
+    pub fn events(&mut self) -> &mut mpsc::UnboundedReceiver<WatchEvent> {
+        &mut self.events
+    }
+
+    /// Pause, resume or trigger the syncs of projects
+    pub fn control(&self) -> &mpsc::UnboundedSender<WatchControl> {
+        &self.control
+    }
+
+    /// Stop the watch and wait for it to exit
+    pub async fn stop(self) -> anyhow::Result<()> {
+        // the watch may have exited on its own
+        let _ = self.cancel.send(true);
+        self.task
+            .await
+            .context("Watch task panicked")?
+            .context("Watch error")
+    }
+}
+
+/// Refuse the settings the async engine doesn't implement, instead of ignoring them
+fn check_supported(config: &Config) -> anyhow::Result<()> {
+    if config.api_addr.is_some() {
+        anyhow::bail!("api_addr is not supported by the async engine");
+    }
+    if config.execution == config::Execution::InProcess {
+        anyhow::bail!("execution: InProcess is not supported by the async engine");
+    }
+    for (name, p) in config.projects.iter() {
+        let setting = if !p.run.is_empty() {
+            Some("run")
+        } else if !p.healthcheck.is_empty() {
+            Some("healthcheck")
+        } else if p.watcher == config::WatcherKind::Shallow {
+            Some("watcher: Shallow")
+        } else {
+            p.sync.iter().filter(|s| s.enabled).find_map(|s| {
+                if s.schedule.is_some() {
+                    Some("schedule")
+                } else if s.lock_group.is_some() {
+                    Some("lock_group")
+                } else if s.priority != 0 {
+                    Some("priority")
+                } else if s.min_interval.is_some() {
+                    Some("min_interval")
+                } else if s.watch_dst.is_some() {
+                    Some("watch_dst")
+                } else {
+                    None
+                }
+            })
+        };
+        if let Some(setting) = setting {
+            anyhow::bail!("{setting} of project {name} is not supported by the async engine");
+        }
+    }
+    Ok(())
+}
+
+/// Settings shared by the project tasks of a watch
+#[derive(Debug)]
+struct Shared {
+    config_path: PathBuf,
+    executable: OsString,
+    rsync: Option<PathBuf>,
+    progress: bool,
+    events: mpsc::UnboundedSender<WatchEvent>,
+    /// results of the initial syncs of the projects, None until they finished
+    initial_syncs: HashMap<String, watch::Sender<Option<bool>>>,
+}
+
+impl Shared {
+    fn emit(&self, event: WatchEvent) {
+        // the receiver may be dropped if the caller isn't interested in the events anymore
+        let _ = self.events.send(event);
+    }
+
+    /// Record the result of the initial syncs of `project`, the first one counts
+    fn finish(&self, project: &str, success: bool) {
+        if let Some(tx) = self.initial_syncs.get(project) {
+            tx.send_if_modified(|s| s.is_none() && s.replace(success).is_none());
+        }
+    }
+
+    /// Wait for the initial syncs of `projects`. Returns true if all of them succeeded
+    async fn initial_syncs_finished(&self, projects: &[String]) -> bool {
+        let mut success = true;
+        for project in projects {
+            let Some(tx) = self.initial_syncs.get(project) else {
+                continue;
+            };
+            let mut rx = tx.subscribe();
+            success &= rx
+                .wait_for(Option::is_some)
+                .await
+                .is_ok_and(|s| *s == Some(true));
+        }
+        success
+    }
+
+    /// [sync::run_hooks] on the blocking pool
+    async fn run_hooks(
+        &self,
+        project: &str,
+        name: &'static str,
+        cmds: &[CommandConfig],
+        env: &[(&'static str, &str)],
+    ) -> bool {
+        if cmds.is_empty() {
+            return true;
+        }
+        let config_path = self.config_path.clone();
+        let project = project.to_owned();
+        let cmds = cmds.to_vec();
+        let env = env
+            .iter()
+            .map(|(k, v)| (*k, (*v).to_owned()))
+            .collect::<Vec<_>>();
+        tokio::task::spawn_blocking(move || {
+            let env = env
+                .iter()
+                .map(|(k, v)| (*k, v.as_str()))
+                .collect::<Vec<_>>();
+            sync::run_hooks(&config_path, &project, name, &cmds, &env)
+        })
+        .await
+        .unwrap_or_else(|err| {
+            error!(?err, name, "Hook task panicked");
+            false
+        })
+    }
+}
+
+async fn run_watch(
+    config_path: PathBuf,
+    config: Config,
+    options: WatchOptions,
+    mut cancel: watch::Receiver<bool>,
+    mut control: mpsc::UnboundedReceiver<WatchControl>,
+    events: mpsc::UnboundedSender<WatchEvent>,
+) -> anyhow::Result<()> {
+    check_supported(&config)?;
+    // syncs of a watch that crashed
+    crate::process_group::reap_orphans();
+    let shared = Arc::new(Shared {
+        config_path,
+        executable: options
+            .executable
+            .map(|p| p.into_os_string())
+            .unwrap_or_else(sync::current_executable),
+        rsync: options.rsync,
+        progress: options.progress,
+        events,
+        initial_syncs: config
+            .projects
+            .keys()
+            .map(|name| (name.clone(), watch::channel(None).0))
+            .collect(),
+    });
+    let names = config.projects.keys().cloned().collect::<Vec<_>>();
+    let debounce = Debounce {
+        quiet_period: config.debounce,
+        max_wait: config.max_wait,
+    };
+    let mut projects = JoinSet::new();
+    let mut project_control = HashMap::with_capacity(config.projects.len());
+    for (name, project) in config.projects {
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        project_control.insert(name.clone(), control_tx);
+        let debounce = debounce.for_project(&project);
+        let shared = shared.clone();
+        let cancel = cancel.clone();
+        projects.spawn(async move {
+            let res =
+                watch_project(name.clone(), project, debounce, cancel, control_rx, &shared).await;
+            if let Err(err) = res.as_ref() {
+                error!(?err, project = name, "Failed to watch project");
+                // don't block the dependent projects
+                shared.finish(&name, false);
+            }
+        });
+    }
+
+    let dispatch = |ctl: WatchControl| {
+        let (WatchControl::Pause { project }
+        | WatchControl::Resume { project }
+        | WatchControl::Trigger { project }) = &ctl
+        else {
+            // the projects ignore the paths outside of their entries
+            for tx in project_control.values() {
+                let _ = tx.send(ctl.clone());
+            }
+            return;
+        };
+        match project_control.get(project) {
+            Some(tx) => {
+                let _ = tx.send(ctl);
+            }
+            None => warn!(project, "Control command for an unknown project"),
+        }
+    };
+    let mut ready = std::pin::pin!(shared.initial_syncs_finished(&names));
+    let mut started = false;
+    let mut control_open = true;
+    loop {
+        tokio::select! {
+            biased;
+            _ = &mut ready, if !started => {
+                started = true;
+                shared.run_hooks("", "on_start", &config.on_start, &[]).await;
+                shared.emit(WatchEvent::Ready);
+            }
+            _ = cancelled(&mut cancel) => {
+                info!("Stopping watchers");
+                break;
+            }
+            ctl = control.recv(), if control_open => match ctl {
+                Some(ctl) => dispatch(ctl),
+                None => control_open = false,
+            },
+            res = projects.join_next() => match res {
+                Some(Ok(())) => {}
+                Some(Err(err)) => error!(?err, "Failed to join watch task"),
+                None => break,
+            },
+        }
+    }
+    // the projects see the cancel too, and stop their syncs
+    while let Some(res) = projects.join_next().await {
+        if let Err(err) = res {
+            error!(?err, "Failed to join watch task");
+        }
+    }
+    if let Err(err) = tokio::task::spawn_blocking(crate::state::flush).await {
+        error!(?err, "Failed to flush the sync state");
+    }
+    shared.run_hooks("", "on_stop", &config.on_stop, &[]).await;
+
+    Ok(())
+}
+
+/// Resolves once the watch is stopped, or its handle was dropped
+async fn cancelled(cancel: &mut watch::Receiver<bool>) {
+    let _ = cancel.wait_for(|c| *c).await;
+}
+
+type WatcherTx = mpsc::UnboundedSender<notify::Result<notify::Event>>;
+
+/// The filesystem watcher of the entries, like the one of the thread engine
+fn create_watcher(
+    kind: config::WatcherKind,
+    poll_interval: std::time::Duration,
+    sync: &[ParsedSync],
+    tx: &WatcherTx,
+) -> anyhow::Result<Box<dyn notify::Watcher + Send>> {
+    if let Some(missing) = sync.iter().find(|s| !s.src.exists()) {
+        anyhow::bail!("Source {} does not exist", missing.src.display());
+    }
+    let follow_symlinks = sync.iter().any(|s| s.filter.follow_symlinks);
+    let handler = {
+        let tx = tx.clone();
+        move |ev| {
+            let _ = tx.send(ev);
+        }
+    };
+    let poll = || -> anyhow::Result<Box<dyn notify::Watcher + Send>> {
+        let config = notify::Config::default()
+            .with_poll_interval(poll_interval)
+            .with_follow_symlinks(follow_symlinks);
+        let mut watcher = notify::PollWatcher::new(handler.clone(), config)
+            .context("Failed to initialize poll watcher")?;
+        sync::register_paths(&mut watcher, sync)?;
+        Ok(Box::new(watcher))
+    };
+    let native = || -> anyhow::Result<Box<dyn notify::Watcher + Send>> {
+        let config = notify::Config::default().with_follow_symlinks(follow_symlinks);
+        let mut watcher = notify::RecommendedWatcher::new(handler.clone(), config)
+            .context("Failed to initialize watcher")?;
+        sync::register_paths(&mut watcher, sync)?;
+        Ok(Box::new(watcher))
+    };
+    match kind {
+        config::WatcherKind::Native => native(),
+        config::WatcherKind::Poll => poll(),
+        config::WatcherKind::Manual => Ok(Box::new(notify::NullWatcher)),
+        config::WatcherKind::Shallow => anyhow::bail!("The Shallow watcher is not supported"),
+        config::WatcherKind::Auto => native().or_else(|err| {
+            warn!(?err, "Native watcher failed, falling back to polling");
+            poll()
+        }),
+    }
+}
+
+/// A sync started by [start_sync]
+#[derive(Debug)]
+struct RunningSync {
+    /// None once the sync was cancelled
+    cancel: Option<oneshot::Sender<()>>,
+    task: tokio::task::Id,
+    /// changes the sync was started with
+    changes: SyncChanges,
+    started: Instant,
+    /// first change of the changes
+    since: Option<Instant>,
+}
+
+/// Outcome of a sync task: None if it was cancelled, otherwise the result and the last lines of
+/// its output
+type SyncOutcome = (PathBuf, Option<(Result<(), SyncError>, OutputTail)>);
+
+/// Where the progress of a sync is reported, see [WatchOptions::progress]
+#[derive(Debug, Clone)]
+struct ProgressSink {
+    events: mpsc::UnboundedSender<WatchEvent>,
+    project: String,
+    src: PathBuf,
+}
+
+/// Start `sync-project` for the entry `a` as a task of `syncs`
+#[allow(clippy::too_many_arguments)]
+fn start_sync(
+    shared: &Shared,
+    project: &str,
+    rsync: Option<&Path>,
+    a: &Path,
+    s: &ParsedSync,
+    initialize: bool,
+    changes: SyncChanges,
+    since: Option<Instant>,
+    syncs: &mut JoinSet<SyncOutcome>,
+) -> RunningSync {
+    let mut cmd = sync::sync_project_cmd(&shared.executable, project, &shared.config_path, rsync);
+    if initialize {
+        cmd.arg("--initialize");
+    }
+    cmd.arg("--src").arg(a.as_os_str());
+    for p in changes.changed.iter() {
+        cmd.arg("--changed").arg(p);
+    }
+    for p in changes.deleted.iter() {
+        cmd.arg("--deleted").arg(p);
+    }
+    for (from, to) in changes.renamed.iter() {
+        cmd.arg("--renamed").arg(from).arg(to);
+    }
+    if shared.progress {
+        cmd.arg("--progress");
+    }
+    if let Some(id) = changes.id {
+        cmd.env(sync::CHANGE_ID_ENV, id.to_string());
+    }
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut cmd = tokio::process::Command::from(cmd);
+    cmd.kill_on_drop(true);
+
+    let label = sync::sync_label(project, &s.src);
+    let progress = shared.progress.then(|| ProgressSink {
+        events: shared.events.clone(),
+        project: project.to_owned(),
+        src: s.src.clone(),
+    });
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    let key = a.to_owned();
+    let task = syncs
+        .spawn(async move { (key, run_sync(cmd, label, progress, cancel_rx).await) })
+        .id();
+    RunningSync {
+        cancel: Some(cancel_tx),
+        task,
+        changes,
+        started: Instant::now(),
+        since,
+    }
+}
+
+/// Run a sync process until it exits, or stop it and everything it started once `cancel` fires.
+/// Returns None if it was cancelled
+async fn run_sync(
+    mut cmd: tokio::process::Command,
+    label: String,
+    progress: Option<ProgressSink>,
+    cancel: oneshot::Receiver<()>,
+) -> Option<(Result<(), SyncError>, OutputTail)> {
+    let tail = OutputTail::default();
+    let (mut child, pgid) = match crate::process_group::spawn_async(&mut cmd) {
+        Ok(child) => child,
+        Err(err) => {
+            error!(?err, "Failed to spawn sync command");
+            return Some((Err(SyncError::Failed { exit_code: None }), tail));
+        }
+    };
+    // not awaited, the output may be held open by e.g. an ssh master the sync started
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(forward(stdout, label.clone(), tail.clone(), progress));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(forward(stderr, label, tail.clone(), None));
+    }
+    tokio::select! {
+        status = child.wait() => {
+            crate::process_group::forget_async(pgid);
+            let result = match status {
+                Ok(status) => sync::sync_result(status),
+                Err(err) => {
+                    error!(?err, "Failed to wait for sync command");
+                    Err(SyncError::Failed { exit_code: None })
+                }
+            };
+            Some((result, tail))
+        }
+        _ = cancel => {
+            debug!("Killing in-progress sync");
+            crate::process_group::kill_async(&mut child, pgid, crate::process_group::KILL_GRACE)
+                .await;
+            None
+        }
+    }
+}
+
+/// Log the output of a sync and keep its last lines in `tail`. The progress lines are reported to
+/// `progress` instead, if given
+async fn forward(
+    output: impl AsyncRead + Unpin,
+    label: String,
+    tail: OutputTail,
+    progress: Option<ProgressSink>,
+) {
+    let mut reader = tokio::io::BufReader::new(output);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let chunk = String::from_utf8_lossy(&buf);
+        // progress updates are separated by carriage returns
+        for line in chunk.trim_end_matches(['\r', '\n']).split('\r') {
+            if let Some(sink) = progress.as_ref() {
+                if let Some(progress) = Progress::parse(line) {
+                    let _ = sink.events.send(WatchEvent::SyncProgress {
+                        project: sink.project.clone(),
+                        src: sink.src.clone(),
+                        progress,
+                    });
+                    continue;
+                }
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            debug!(sync = label, "{line}");
+            tail.push(line.to_owned());
+        }
+    }
+}
+
+#[tracing::instrument(skip(project, debounce, cancel, control, shared))]
+async fn watch_project(
+    name: String,
+    project: config::Project,
+    debounce: Debounce,
+    mut cancel: watch::Receiver<bool>,
+    mut control: mpsc::UnboundedReceiver<WatchControl>,
+    shared: &Shared,
+) -> anyhow::Result<()> {
+    let ParsedProject {
+        name,
+        sync: mut entries,
+        restart,
+        rsync,
+        on_sync,
+        on_init,
+        on_delete,
+        depends_on,
+        watcher: watcher_kind,
+        poll_interval,
+        ..
+    } = (name, project)
+        .try_into()
+        .context("Failed to parse config")?;
+    entries.retain(|s| s.enabled);
+    let project = name.as_str();
+    let rsync = rsync.or_else(|| shared.rsync.clone());
+
+    // event filters of the entries, keyed by their src paths
+    let filters = entries
+        .iter()
+        .flat_map(|s| {
+            [
+                Some(s.src.clone()),
+                crate::platform::canonicalize(&s.src).ok(),
+            ]
+            .into_iter()
+            .flatten()
+            .map(move |src| (src, &s.filter))
+        })
+        .collect::<Vec<_>>();
+    let passes = |p: &Path, kind| {
+        filters.iter().any(|(src, filter)| {
+            p.strip_prefix(src)
+                .is_ok_and(|rel| filter.matches(src, rel, kind))
+        })
+    };
+    let files = entries
+        .iter()
+        .map(|s| {
+            // the src may not exist yet, the watcher picks it up once it is created
+            let follow = s.filter.follow_symlinks;
+            let src = crate::platform::resolve(s.src.as_path(), follow).unwrap_or_else(|err| {
+                warn!(?err, src = ?s.src, "Failed to resolve sync source");
+                s.src.clone()
+            });
+            (src, s)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let (fs_tx, mut fs_rx) = mpsc::unbounded_channel();
+    let mut watcher = None;
+    let mut degraded = false;
+    let mut backoff = sync::WATCHER_RETRY_MIN;
+    let mut retry_at = Instant::now();
+
+    let mut dependencies = std::pin::pin!(shared.initial_syncs_finished(&depends_on));
+    let mut waiting_for_dependencies = true;
+    // entries whose initial sync is still in progress, and whether it runs their init commands
+    let mut initializing = HashMap::new();
+    let mut initial_success = true;
+    // an initial sync ran the init commands of its entry, so the project's follow
+    let mut project_init = false;
+
+    // changes of the current burst, queued once it's over
+    let mut burst = HashMap::<PathBuf, SyncChanges>::new();
+    // end of the quiet period of the burst, and the latest its changes wait for
+    let mut burst_until: Option<(Instant, Instant)> = None;
+    // entries waiting for a sync, coalesced so that each entry is synced at most once more
+    let mut to_sync = HashMap::<PathBuf, SyncChanges>::new();
+    let mut first_change = HashMap::<PathBuf, Instant>::new();
+    // changes of failed syncs, retried with the next change of the entry
+    let mut refused = HashMap::<PathBuf, SyncChanges>::new();
+    let mut syncs = JoinSet::new();
+    let mut running = HashMap::<PathBuf, RunningSync>::new();
+    // a sync succeeded since the project's hooks last ran
+    let mut synced = false;
+    // deleted by the syncs of the current batch, for the on_delete commands of the project
+    let mut batch_deleted = BTreeSet::new();
+    let mut paused = false;
+    let mut control_open = true;
+    loop {
+        if !waiting_for_dependencies && !paused {
+            if restart {
+                for (a, changes) in to_sync.iter_mut() {
+                    let Some(r) = running.get_mut(a) else {
+                        continue;
+                    };
+                    if let Some(cancel) = r.cancel.take() {
+                        let _ = cancel.send(());
+                        changes.merge_older(std::mem::take(&mut r.changes));
+                        if let Some(since) = r.since {
+                            first_change.insert(a.clone(), since);
+                        }
+                        shared.emit(WatchEvent::SyncCancelled {
+                            project: project.to_owned(),
+                            src: files[a].src.clone(),
+                        });
+                    }
+                }
+            }
+            // a running entry is synced again once its sync was reaped
+            let pending = to_sync
+                .keys()
+                .filter(|a| !running.contains_key(*a))
+                .cloned()
+                .collect::<Vec<_>>();
+            for a in pending {
+                let s = files[&a];
+                let mut changes = to_sync.remove(&a).unwrap_or_default();
+                if let Some(older) = refused.remove(&a) {
+                    changes.merge_older(older);
+                }
+                changes.id.get_or_insert_with(sync::next_change_id);
+                let since = first_change.remove(&a);
+                info!(src=?s.src, dst=?s.dst, change_id=changes.id, waited=?since.map(|t| t.elapsed()), "syncing");
+                let r = start_sync(
+                    shared,
+                    project,
+                    rsync.as_deref(),
+                    &a,
+                    s,
+                    false,
+                    changes,
+                    since,
+                    &mut syncs,
+                );
+                running.insert(a, r);
+                shared.emit(WatchEvent::SyncStarted {
+                    project: project.to_owned(),
+                    src: s.src.clone(),
+                    initialize: false,
+                });
+            }
+        }
+        if synced && running.is_empty() {
+            let deleted = sync::join_paths(&std::mem::take(&mut batch_deleted));
+            let env = [
+                ("ATUNE_PROJECT", project),
+                ("ATUNE_DELETED_PATHS", deleted.as_str()),
+            ];
+            if deleted.is_empty()
+                || shared
+                    .run_hooks(project, "on_delete", &on_delete, &env)
+                    .await
+            {
+                shared.run_hooks(project, "on_sync", &on_sync, &env).await;
+            }
+            synced = false;
+        }
+
+        let burst_end = burst_until.map(|(quiet, max_wait)| quiet.min(max_wait));
+        tokio::select! {
+            success = &mut dependencies, if waiting_for_dependencies => {
+                waiting_for_dependencies = false;
+                if !success {
+                    error!(
+                        ?depends_on,
+                        "Dependencies failed their initial sync, skipping the initial sync"
+                    );
+                    shared.finish(project, false);
+                    continue;
+                }
+                // fingerprinting src walks it
+                let initial = {
+                    let config_path = shared.config_path.clone();
+                    let project = project.to_owned();
+                    let files = files
+                        .iter()
+                        .map(|(a, s)| (a.clone(), (*s).clone()))
+                        .collect::<Vec<_>>();
+                    tokio::task::spawn_blocking(move || {
+                        let state = files
+                            .iter()
+                            .any(|(_, f)| f.initial_sync == config::InitialSync::IfNeeded)
+                            .then(crate::state::SyncState::load);
+                        files
+                            .into_iter()
+                            .filter_map(|(a, f)| {
+                                let initialize =
+                                    sync::initial_sync(state.as_ref(), &config_path, &project, &f)?;
+                                Some((a, initialize))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .await
+                    .context("Failed to check the initial syncs")?
+                };
+                for (a, initialize) in initial {
+                    let s = files[&a];
+                    let r = start_sync(
+                        shared,
+                        project,
+                        rsync.as_deref(),
+                        &a,
+                        s,
+                        initialize,
+                        SyncChanges::default(),
+                        None,
+                        &mut syncs,
+                    );
+                    running.insert(a.clone(), r);
+                    initializing.insert(a, initialize);
+                    project_init |= initialize;
+                    shared.emit(WatchEvent::SyncStarted {
+                        project: project.to_owned(),
+                        src: s.src.clone(),
+                        initialize,
+                    });
+                }
+                if initializing.is_empty() {
+                    shared.finish(project, true);
+                }
+            }
+            _ = cancelled(&mut cancel) => break,
+            _ = tokio::time::sleep_until(retry_at), if watcher.is_none() => {
+                match create_watcher(watcher_kind, poll_interval, &entries, &fs_tx) {
+                    Ok(w) => {
+                        info!("Watching {} paths", entries.len());
+                        watcher = Some(w);
+                        if degraded {
+                            degraded = false;
+                            info!("Watcher recovered, syncing all paths");
+                            shared.emit(WatchEvent::WatcherRecovered {
+                                project: project.to_owned(),
+                            });
+                            // changes may have been missed while the watcher was down
+                            for a in files.keys() {
+                                to_sync
+                                    .entry(a.clone())
+                                    .or_default()
+                                    .add(a.clone(), ChangeKind::Changed);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        report_watcher_error(project, &err, backoff, shared);
+                        degraded = true;
+                        retry_at = Instant::now() + backoff;
+                        backoff = (backoff * 2).min(sync::WATCHER_RETRY_MAX);
+                    }
+                }
+            }
+            Some(ev) = fs_rx.recv() => {
+                let ev = match ev {
+                    Ok(ev) => ev,
+                    Err(err) => {
+                        if watcher.take().is_some() {
+                            report_watcher_error(project, &err.into(), backoff, shared);
+                            degraded = true;
+                            retry_at = Instant::now() + backoff;
+                            backoff = (backoff * 2).min(sync::WATCHER_RETRY_MAX);
+                        }
+                        continue;
+                    }
+                };
+                let id = sync::next_change_id();
+                let mut queue = |path: PathBuf, kind, renamed_from: Option<PathBuf>| {
+                    let Some(a) = path.ancestors().find(|a| files.contains_key(*a)) else {
+                        return;
+                    };
+                    let changes = burst.entry(a.to_owned()).or_default();
+                    changes.id.get_or_insert(id);
+                    first_change.entry(a.to_owned()).or_insert_with(Instant::now);
+                    match renamed_from {
+                        Some(from) if from.starts_with(a) => changes.rename(from, path),
+                        _ => changes.add(path, kind),
+                    }
+                };
+                if let notify::EventKind::Modify(notify::event::ModifyKind::Name(
+                    notify::event::RenameMode::Both,
+                )) = ev.kind
+                {
+                    // pairs the From and To events of the rename, which were handled already
+                    if let [from, to] = &ev.paths[..] {
+                        if passes(from, ChangeKind::Removed) && passes(to, ChangeKind::Changed) {
+                            queue(to.clone(), ChangeKind::Changed, Some(from.clone()));
+                        }
+                    }
+                } else {
+                    let kind = match ev.kind {
+                        notify::EventKind::Remove(_)
+                        | notify::EventKind::Modify(notify::event::ModifyKind::Name(
+                            notify::event::RenameMode::From,
+                        )) => ChangeKind::Removed,
+                        notify::EventKind::Create(_) | notify::EventKind::Modify(_) => {
+                            ChangeKind::Changed
+                        }
+                        _ => continue,
+                    };
+                    for p in ev.paths.into_iter().filter(|p| passes(p, kind)) {
+                        queue(p, kind, None);
+                    }
+                }
+                if !burst.is_empty() {
+                    debug!(change_id = id, "received file updates");
+                    let now = Instant::now();
+                    let max_wait = burst_until.map_or(now + debounce.max_wait, |(_, m)| m);
+                    burst_until = Some((now + debounce.quiet_period, max_wait));
+                }
+            }
+            _ = tokio::time::sleep_until(burst_end.unwrap_or_else(Instant::now)), if burst_end.is_some() => {
+                burst_until = None;
+                for (a, mut changes) in burst.drain() {
+                    if let Some(older) = to_sync.remove(&a) {
+                        changes.merge_older(older);
+                    }
+                    to_sync.insert(a, changes);
+                }
+            }
+            ctl = control.recv(), if control_open => match ctl {
+                Some(WatchControl::Pause { .. }) if !paused => {
+                    paused = true;
+                    info!("Paused");
+                    shared.emit(WatchEvent::Paused {
+                        project: project.to_owned(),
+                    });
+                }
+                Some(WatchControl::Resume { .. }) if paused => {
+                    paused = false;
+                    info!("Resumed");
+                    shared.emit(WatchEvent::Resumed {
+                        project: project.to_owned(),
+                    });
+                }
+                Some(WatchControl::Trigger { .. }) => {
+                    for a in files.keys() {
+                        to_sync
+                            .entry(a.clone())
+                            .or_default()
+                            .add(a.clone(), ChangeKind::Changed);
+                    }
+                }
+                Some(WatchControl::Changed { path }) => {
+                    let kind = if path.exists() {
+                        ChangeKind::Changed
+                    } else {
+                        ChangeKind::Removed
+                    };
+                    let entry = path.ancestors().find_map(|a| files.get_key_value(a));
+                    if let Some((a, s)) = entry {
+                        let rel = path.strip_prefix(a).unwrap_or(&path);
+                        if s.filter.matches(a, rel, kind) {
+                            to_sync.entry(a.clone()).or_default().add(path, kind);
+                        }
+                    }
+                }
+                Some(WatchControl::Pause { .. } | WatchControl::Resume { .. }) => {}
+                None => control_open = false,
+            },
+            Some(done) = syncs.join_next() => {
+                let (a, outcome) = match done {
+                    Ok(done) => done,
+                    Err(err) => {
+                        error!(?err, "Sync task failed");
+                        let Some(a) = running
+                            .iter()
+                            .find(|(_, r)| r.task == err.id())
+                            .map(|(a, _)| a.clone())
+                        else {
+                            continue;
+                        };
+                        let failed = Err(SyncError::Failed { exit_code: None });
+                        (a, Some((failed, OutputTail::default())))
+                    }
+                };
+                let Some(r) = running.remove(&a) else {
+                    continue;
+                };
+                // a cancelled sync was reported by SyncCancelled
+                let (Some(_), Some((result, output))) = (r.cancel, outcome) else {
+                    continue;
+                };
+                let duration = r.started.elapsed();
+                let changes = r.changes;
+                let s = files[&a];
+                let latency = r.since.map(|t| t.elapsed());
+                let bytes =
+                    crate::stats::transferred_bytes(output.lines().iter().map(String::as_str));
+                crate::stats::record(
+                    &shared.config_path,
+                    project,
+                    &s.src,
+                    result.is_ok(),
+                    bytes,
+                    latency,
+                );
+                let initialized = initializing.remove(&a);
+                if initialized.is_some() {
+                    initial_success &= result.is_ok();
+                }
+                info!(src = ?s.src, change_id = changes.id, ?duration, ?latency, "Sync finished");
+                if let Err(err) = result.as_ref() {
+                    error!(
+                        src = ?s.src,
+                        change_id = changes.id,
+                        "Sync failed: {err:?}. Last output:\n{}",
+                        output.lines().join("\n")
+                    );
+                }
+                if result == Err(SyncError::HookFailed) {
+                    shared.emit(WatchEvent::HookFailed {
+                        project: project.to_owned(),
+                        src: s.src.clone(),
+                    });
+                }
+                if let Err(err) = result.as_ref() {
+                    let sync_src = s.src.display().to_string();
+                    let env = [
+                        ("ATUNE_PROJECT", project),
+                        ("ATUNE_SYNC_SRC", sync_src.as_str()),
+                        ("ATUNE_SYNC_ERROR", err.kind()),
+                    ];
+                    shared.run_hooks(project, "on_failure", &s.on_failure, &env).await;
+                }
+                if result.is_ok() {
+                    crate::state::record_success_in_background(
+                        &shared.config_path,
+                        project,
+                        s,
+                        initialized == Some(true),
+                    );
+                    batch_deleted.extend(changes.deleted);
+                } else if matches!(
+                    result,
+                    Err(SyncError::DstConflict
+                        | SyncError::DeleteRefused
+                        | SyncError::DeleteLimit
+                        | SyncError::Unreachable)
+                ) {
+                    refused.insert(a.clone(), changes);
+                }
+                synced |= result.is_ok();
+                shared.emit(WatchEvent::SyncFinished {
+                    project: project.to_owned(),
+                    src: s.src.clone(),
+                    result,
+                    duration,
+                    usage: Default::default(),
+                });
+                // after the event, so Ready follows the last initial SyncFinished
+                if initialized.is_some() && initializing.is_empty() {
+                    shared.finish(project, initial_success);
+                    if initial_success && project_init {
+                        shared
+                            .run_hooks(project, "init", &on_init, &[("ATUNE_PROJECT", project)])
+                            .await;
+                    }
+                }
+            }
+        }
+    }
+    // stop the syncs before the watch exits
+    for r in running.values_mut() {
+        if let Some(cancel) = r.cancel.take() {
+            let _ = cancel.send(());
+        }
+    }
+    while syncs.join_next().await.is_some() {}
+    drop(watcher);
+    info!("Stopped watching");
+    Ok(())
+}
+
+fn report_watcher_error(
+    project: &str,
+    err: &anyhow::Error,
+    retry: std::time::Duration,
+    shared: &Shared,
+) {
+    match sync::watcher_error_hint(err) {
+        Some(hint) => error!(?err, ?retry, "Watcher failed. {hint}"),
+        None => error!(?err, ?retry, "Watcher failed, retrying"),
+    }
+    shared.emit(WatchEvent::WatcherDegraded {
+        project: project.to_owned(),
+        error: format!("{err:#}"),
+    });
+}
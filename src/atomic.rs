@@ -0,0 +1,185 @@
+//! Atomic syncs: the copy of src is synced into a staging directory next to it, which is then
+//! swapped into its place, see [crate::config::FileSync::atomic]
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tracing::debug;
+
+use crate::{backup::remote_quote, config};
+
+/// The copy of src in dst and its staging and retired siblings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Staging {
+    pub copy: PathBuf,
+    pub staging: PathBuf,
+    old: PathBuf,
+    /// arguments of ssh connecting to the host of a remote dst
+    ssh: Vec<String>,
+}
+
+impl Staging {
+    /// The paths for syncing `src` into `dst`, a local path or `host:path` reached with the ssh
+    /// arguments `ssh`
+    pub fn new(src: &Path, dst: &Path, ssh: &[String]) -> anyhow::Result<Self> {
+        let rel = src
+            .strip_prefix(crate::sync::transfer_root(src))
+            .unwrap_or(Path::new(""));
+        let copy = dst.join(rel);
+        let name = copy
+            .file_name()
+            .with_context(|| format!("{} has no parent to stage in", copy.display()))?
+            .to_string_lossy()
+            .into_owned();
+        Ok(Self {
+            staging: copy.with_file_name(format!(".{name}.atune-staging")),
+            old: copy.with_file_name(format!(".{name}.atune-old")),
+            copy,
+            ssh: ssh.to_vec(),
+        })
+    }
+
+    /// The `--link-dest` flag hard linking the unchanged files of the current copy, relative
+    /// to the staging directory
+    pub fn link_dest(&self) -> String {
+        let name = self.copy.file_name().unwrap_or_default().to_string_lossy();
+        format!("--link-dest=../{name}")
+    }
+
+    /// Remove the leftovers of an interrupted sync and create the parent of the staging directory
+    pub fn prepare(&self) -> anyhow::Result<()> {
+        match config::remote_dst(&self.staging) {
+            Some((host, path)) => {
+                let (_, old) = config::remote_dst(&self.old).unwrap_or_default();
+                let parent = Path::new(path).parent().unwrap_or(Path::new("."));
+                let script = format!(
+                    "rm -rf -- {staging} {old} && mkdir -p -- {parent}",
+                    staging = remote_quote(path),
+                    old = remote_quote(old),
+                    parent = remote_quote(&parent.to_string_lossy()),
+                );
+                run_ssh(host, &self.ssh, &script)
+                    .with_context(|| format!("Failed to prepare {}", self.staging.display()))
+            }
+            None => {
+                for dir in [&self.staging, &self.old] {
+                    remove_dir(dir)?;
+                }
+                if let Some(parent) = self.staging.parent() {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("Failed to create {}", parent.display()))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Move the complete staging directory into the place of the copy, and remove the old copy
+    pub fn swap(&self) -> anyhow::Result<()> {
+        match config::remote_dst(&self.staging) {
+            Some((host, staging)) => {
+                let (_, copy) = config::remote_dst(&self.copy).unwrap_or_default();
+                let (_, old) = config::remote_dst(&self.old).unwrap_or_default();
+                let (staging, copy, old) =
+                    (remote_quote(staging), remote_quote(copy), remote_quote(old));
+                let script = format!(
+                    "{{ [ ! -e {copy} ] || mv -T -- {copy} {old}; }} && mv -T -- {staging} {copy} && rm -rf -- {old}"
+                );
+                run_ssh(host, &self.ssh, &script)
+                    .with_context(|| format!("Failed to swap in {}", self.copy.display()))
+            }
+            None => {
+                if exchange(&self.staging, &self.copy)? {
+                    // the staging directory now holds the old copy
+                    return remove_dir(&self.staging);
+                }
+                if self.copy.exists() {
+                    std::fs::rename(&self.copy, &self.old)
+                        .with_context(|| format!("Failed to move {}", self.copy.display()))?;
+                }
+                std::fs::rename(&self.staging, &self.copy)
+                    .with_context(|| format!("Failed to swap in {}", self.copy.display()))?;
+                remove_dir(&self.old)
+            }
+        }
+    }
+}
+
+fn run_ssh(host: &str, ssh: &[String], script: &str) -> anyhow::Result<()> {
+    let sh = xshell::Shell::new()?;
+    xshell::cmd!(sh, "ssh -o BatchMode=yes {ssh...} {host} {script}")
+        .quiet()
+        .run()?;
+    Ok(())
+}
+
+fn remove_dir(dir: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_dir_all(dir) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Failed to remove {}", dir.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Swap `a` and `b` in a single step. Returns false if that isn't supported here, or `b`
+/// doesn't exist
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> anyhow::Result<bool> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let cstr = |p: &Path| std::ffi::CString::new(p.as_os_str().as_bytes());
+    let (a, b) = (cstr(a)?, cstr(b)?);
+    let res = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if res == 0 {
+        return Ok(true);
+    }
+    let err = std::io::Error::last_os_error();
+    debug!(?err, "Failed to exchange atomically, renaming");
+    Ok(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(_a: &Path, _b: &Path) -> anyhow::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staging_paths() {
+        let s = Staging::new(Path::new("/src/site"), Path::new("host:/var/www"), &[]).unwrap();
+        assert_eq!(s.copy, Path::new("host:/var/www/site"));
+        assert_eq!(s.staging, Path::new("host:/var/www/.site.atune-staging"));
+        assert_eq!(s.link_dest(), "--link-dest=../site");
+
+        // the contents of src are synced into dst itself
+        let s = Staging::new(Path::new("/src/site/"), Path::new("/var/www"), &[]).unwrap();
+        assert_eq!(s.copy, Path::new("/var/www"));
+        assert_eq!(s.staging, Path::new("/var/.www.atune-staging"));
+    }
+
+    #[test]
+    fn test_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = Staging::new(Path::new("/src/site"), dir.path(), &[]).unwrap();
+        for version in ["1", "2"] {
+            s.prepare().unwrap();
+            std::fs::create_dir(&s.staging).unwrap();
+            std::fs::write(s.staging.join("v"), version).unwrap();
+            s.swap().unwrap();
+            assert_eq!(std::fs::read_to_string(s.copy.join("v")).unwrap(), version);
+        }
+        assert!(!s.staging.exists());
+        assert!(!s.old.exists());
+    }
+}
@@ -0,0 +1,100 @@
+//! Transfer mechanisms of the syncs, see [crate::config::SyncBackend].
+//!
+//! rsync, the built-in copy and the agent are implemented as [SyncBackend]s, and library users can
+//! [register] their own under a name, used as `backend: <name>` in the config
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::Path,
+    sync::{Arc, OnceLock, RwLock},
+    time::SystemTime,
+};
+
+use crate::{
+    config,
+    in_process::ProcessRunner,
+    sync::{ParsedSync, SyncChanges, SyncOutput},
+};
+
+/// What a [SyncBackend] transfers, for one destination of the sync
+#[derive(Debug, Clone, Copy)]
+pub struct TransferContext<'a> {
+    pub sync: &'a ParsedSync,
+    /// The destination, one of [ParsedSync::destinations]
+    pub dst: &'a Path,
+    /// rsync binary of the project, None for `rsync` on the PATH
+    pub rsync: Option<&'a OsStr>,
+    /// This is the initial sync of the entry
+    pub initialize: bool,
+    /// Changes since the last sync, empty if unknown, e.g. on the initial sync
+    pub changes: &'a SyncChanges,
+    /// Runs the processes of in-process syncs, so they can be cancelled. Long running backends
+    /// should call [ProcessRunner::check] between their steps
+    pub runner: Option<&'a ProcessRunner>,
+    /// Start of the sync, e.g. naming its backups
+    pub started: SystemTime,
+}
+
+/// Transfers src to a destination. [prepare](SyncBackend::prepare),
+/// [transfer](SyncBackend::transfer) and [finalize](SyncBackend::finalize) run one after the
+/// other for every destination of a sync, stopping at the first error
+pub trait SyncBackend: Send + Sync {
+    /// Prepare the transfer, e.g. check that dst is reachable
+    fn prepare(&self, ctx: &TransferContext<'_>) -> anyhow::Result<()> {
+        let _ = ctx;
+        Ok(())
+    }
+
+    /// Transfer the changed paths of src, or all of src if the changes are empty. If `output`
+    /// is given, then the stats of the transfer can be recorded in it
+    fn transfer(
+        &self,
+        ctx: &TransferContext<'_>,
+        output: Option<&mut SyncOutput>,
+    ) -> anyhow::Result<()>;
+
+    /// Finish the transfer, e.g. verify dst
+    fn finalize(&self, ctx: &TransferContext<'_>) -> anyhow::Result<()> {
+        let _ = ctx;
+        Ok(())
+    }
+}
+
+type Registry = RwLock<HashMap<String, Arc<dyn SyncBackend>>>;
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Make `backend` available as `backend: <name>` to the syncs of this process, replacing a
+/// backend registered under the same name before.
+///
+/// The config is validated against the registered backends, so register them before loading it.
+/// `sync-project` processes don't know them, the projects need `execution: InProcess`
+pub fn register(name: impl Into<String>, backend: impl SyncBackend + 'static) {
+    registry()
+        .write()
+        .unwrap()
+        .insert(name.into(), Arc::new(backend));
+}
+
+/// Whether a backend was registered under `name`
+pub fn is_registered(name: &str) -> bool {
+    registry().read().unwrap().contains_key(name)
+}
+
+/// The implementation of `backend`
+pub fn get(backend: &config::SyncBackend) -> anyhow::Result<Arc<dyn SyncBackend>> {
+    match backend {
+        config::SyncBackend::Rsync => Ok(Arc::new(crate::sync::RsyncBackend)),
+        config::SyncBackend::Copy => Ok(Arc::new(crate::copy::CopyBackend)),
+        config::SyncBackend::Agent => Ok(Arc::new(crate::agent::AgentBackend)),
+        config::SyncBackend::Custom(name) => registry()
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unknown backend {name}")),
+    }
+}
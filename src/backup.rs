@@ -0,0 +1,273 @@
+//! Backups of the files of dst that syncs delete or overwrite, see [config::Backup]
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use tracing::{debug, info};
+
+use crate::{
+    config::{self, Backup},
+    template::{civil_from_days, days_from_civil},
+};
+
+/// Name of the backup of a sync started at `t`, e.g. `20240131T120000Z`
+pub fn timestamp(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Time of a backup by its name, None if it's not a backup
+pub fn parse_timestamp(name: &str) -> Option<SystemTime> {
+    let (date, time) = name.strip_suffix('Z')?.split_once('T')?;
+    if date.len() != 8
+        || time.len() != 6
+        || !(date.chars().chain(time.chars())).all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    let num = |s: &str| s.parse::<i64>().ok();
+    let days = days_from_civil(num(&date[..4])?, num(&date[4..6])?, num(&date[6..])?);
+    let secs = days * 86400 + num(&time[..2])? * 3600 + num(&time[2..4])? * 60 + num(&time[4..])?;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// rsync flags backing up into a new backup of `dst` named after `now`
+pub fn rsync_args(backup: &Backup, now: SystemTime) -> Vec<OsString> {
+    let dir = backup.dir.join(timestamp(now));
+    let mut arg = OsString::from("--backup-dir=");
+    arg.push(&dir);
+    let mut args = vec!["--backup".into(), arg];
+    if backup.dir.is_relative() {
+        // the backups live in dst, keep `--delete` away from them
+        let mut filter = OsString::from("--filter=P /");
+        filter.push(&backup.dir);
+        filter.push("/");
+        args.push(filter);
+    }
+    args
+}
+
+/// Directory of the backups of `dst`, in rsync's `host:path` syntax for remote destinations
+pub fn backup_root(dst: &Path, backup: &Backup) -> PathBuf {
+    match config::remote_dst(dst) {
+        Some((host, _)) if backup.dir.is_absolute() => {
+            format!("{host}:{}", backup.dir.display()).into()
+        }
+        _ => dst.join(&backup.dir),
+    }
+}
+
+/// Names of the backups of `dst`, oldest first. `ssh` are the arguments of ssh connecting to
+/// the host of a remote dst
+pub fn list(dst: &Path, ssh: &[String], backup: &Backup) -> anyhow::Result<Vec<String>> {
+    anyhow::ensure!(
+        config::rsync_daemon_dst(dst).is_none(),
+        "Listing backups is not supported with rsync daemon destinations"
+    );
+    list_dir(&backup_root(dst, backup), ssh)
+}
+
+/// Names of the timestamped directories in `root`, a local path or `host:path` reached with the
+/// ssh arguments `ssh`, oldest first. A missing `root` has none
+pub(crate) fn list_dir(root: &Path, ssh: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut names = match config::remote_dst(root) {
+        Some((host, path)) => {
+            let sh = xshell::Shell::new()?;
+            let ls = format!("ls -1 -- {} 2>/dev/null || true", remote_quote(path));
+            xshell::cmd!(sh, "ssh -o BatchMode=yes {ssh...} {host} {ls}")
+                .quiet()
+                .read()
+                .with_context(|| format!("Failed to list {}", root.display()))?
+                .lines()
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        }
+        None => match std::fs::read_dir(root) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to list {}", root.display()))
+            }
+        },
+    };
+    names.retain(|n| parse_timestamp(n).is_some());
+    names.sort();
+    Ok(names)
+}
+
+/// Remove the directories `names` of `root`, a local path or `host:path` reached with the ssh
+/// arguments `ssh`
+pub(crate) fn remove_dirs(root: &Path, ssh: &[String], names: &[&String]) -> anyhow::Result<()> {
+    match config::remote_dst(root) {
+        Some((host, path)) => {
+            let sh = xshell::Shell::new()?;
+            let dirs = names
+                .iter()
+                .map(|n| remote_quote(&format!("{path}/{n}")))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let rm = format!("rm -rf -- {dirs}");
+            xshell::cmd!(sh, "ssh -o BatchMode=yes {ssh...} {host} {rm}")
+                .quiet()
+                .run()
+                .with_context(|| format!("Failed to remove old directories on {host}"))?;
+        }
+        None => {
+            for name in names.iter() {
+                let dir = root.join(name);
+                std::fs::remove_dir_all(&dir)
+                    .with_context(|| format!("Failed to remove {}", dir.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Backups to remove according to the retention rules, `names` are sorted oldest first
+fn expired<'a>(names: &'a [String], backup: &Backup, now: SystemTime) -> Vec<&'a String> {
+    let keep_from = backup
+        .keep
+        .map_or(0, |keep| names.len().saturating_sub(keep));
+    names
+        .iter()
+        .enumerate()
+        .filter(|(i, name)| {
+            let too_old = backup.max_age.is_some_and(|max_age| {
+                parse_timestamp(name)
+                    .and_then(|t| now.duration_since(t).ok())
+                    .is_some_and(|age| age > max_age)
+            });
+            *i < keep_from || too_old
+        })
+        .map(|(_, name)| name)
+        .collect()
+}
+
+/// Remove the backups of `dst` exceeding the retention rules
+pub fn prune(dst: &Path, ssh: &[String], backup: &Backup, now: SystemTime) -> anyhow::Result<()> {
+    if backup.keep.is_none() && backup.max_age.is_none() {
+        return Ok(());
+    }
+    let names = list(dst, ssh, backup)?;
+    let expired = expired(&names, backup, now);
+    if expired.is_empty() {
+        return Ok(());
+    }
+    debug!(?expired, "Removing old backups");
+    remove_dirs(&backup_root(dst, backup), ssh, &expired)?;
+    info!(count = expired.len(), "Removed old backups");
+    Ok(())
+}
+
+/// Copy the files of the backup `name` of `dst` into `target`, which has the layout of dst.
+///
+/// Existing files are kept, unless `overwrite` is set
+pub fn restore(
+    rsync: &std::ffi::OsStr,
+    dst: &Path,
+    ssh: &[String],
+    backup: &Backup,
+    name: &str,
+    target: &Path,
+    overwrite: bool,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        parse_timestamp(name).is_some(),
+        "{name:?} is not a backup, expected a name like 20240131T120000Z"
+    );
+    let mut from = backup_root(dst, backup).join(name).into_os_string();
+    from.push("/");
+    let mut to = target.as_os_str().to_owned();
+    to.push("/");
+    let ignore_existing = (!overwrite).then_some("--ignore-existing");
+    let sh = xshell::Shell::new()?;
+    let mut cmd = xshell::cmd!(
+        sh,
+        "{rsync} -a --itemize-changes {ignore_existing...} {from} {to}"
+    );
+    // an explicit RSYNC_RSH takes precedence
+    if let Some((host, _)) = config::remote_dst(dst) {
+        if !ssh.is_empty() && std::env::var_os("RSYNC_RSH").is_none() {
+            cmd = cmd.env("RSYNC_RSH", crate::ssh::rsync_rsh(host, ssh, false));
+        }
+    }
+    cmd.run()
+        .with_context(|| format!("Failed to restore the backup {name}"))?;
+    Ok(())
+}
+
+/// Quote a path for the remote shell, keeping a leading `~/` expandable
+pub(crate) fn remote_quote(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("~/{}", shell_words::quote(rest)),
+        None => shell_words::quote(path).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_roundtrip() {
+        let t = UNIX_EPOCH + Duration::from_secs(1706702400);
+        assert_eq!(timestamp(t), "20240131T120000Z");
+        assert_eq!(parse_timestamp("20240131T120000Z"), Some(t));
+        assert_eq!(parse_timestamp("latest"), None);
+        assert_eq!(parse_timestamp("20240131T1200Z"), None);
+    }
+
+    #[test]
+    fn test_expired() {
+        let now = parse_timestamp("20240131T120000Z").unwrap();
+        let names = ["20240101T000000Z", "20240130T000000Z", "20240131T000000Z"]
+            .map(str::to_owned)
+            .to_vec();
+        let backup = |keep, max_age| Backup {
+            dir: ".atune-backup".into(),
+            keep,
+            max_age,
+        };
+        assert_eq!(
+            expired(&names, &backup(Some(2), None), now),
+            vec!["20240101T000000Z"]
+        );
+        assert_eq!(
+            expired(&names, &backup(None, Some(Duration::from_secs(86400))), now),
+            vec!["20240101T000000Z", "20240130T000000Z"]
+        );
+        assert!(expired(&names, &backup(None, None), now).is_empty());
+    }
+
+    #[test]
+    fn test_prune_local() {
+        let dst = tempfile::tempdir().unwrap();
+        let backup = Backup {
+            dir: ".atune-backup".into(),
+            keep: Some(1),
+            max_age: None,
+        };
+        for name in ["20240101T000000Z", "20240102T000000Z", "notes"] {
+            std::fs::create_dir_all(dst.path().join(".atune-backup").join(name)).unwrap();
+        }
+        prune(dst.path(), &[], &backup, SystemTime::now()).unwrap();
+        assert_eq!(
+            list(dst.path(), &[], &backup).unwrap(),
+            vec!["20240102T000000Z"]
+        );
+        assert!(dst.path().join(".atune-backup/notes").exists());
+    }
+}
@@ -0,0 +1,189 @@
+//! Compression of rsync transfers, see [crate::config::FileSync::compress]
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use tracing::debug;
+
+use crate::{config, state::Fnv};
+
+/// How long the detected zstd support of a remote rsync is trusted
+const CACHE_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// The rsync flags compressing the transfer to `dst`, reached with the ssh arguments `ssh` if
+/// it's remote.
+///
+/// zstd is chosen if both ends support it, otherwise rsync negotiates the algorithm itself
+pub fn rsync_flags(
+    compress: config::Compress,
+    rsync: &OsStr,
+    dst: &Path,
+    ssh: &[String],
+    ssh_multiplexing: bool,
+) -> Vec<String> {
+    let host = config::remote_dst(dst).map(|(host, _)| host);
+    let daemon = config::rsync_daemon_dst(dst).is_some();
+    let on = match compress {
+        config::Compress::On => true,
+        config::Compress::Off => false,
+        // local copies aren't worth compressing
+        config::Compress::Auto => host.is_some() || daemon,
+    };
+    if !on {
+        return vec!["--no-compress".to_owned()];
+    }
+    let mut flags = vec!["--compress".to_owned()];
+    // the version of a daemon isn't known before connecting
+    let zstd = !daemon
+        && local_zstd(rsync)
+        && host.is_none_or(|host| remote_zstd(host, ssh, ssh_multiplexing));
+    if zstd {
+        flags.push("--compress-choice=zstd".to_owned());
+    }
+    flags
+}
+
+/// Whether the `Compress list` of the output of `rsync --version` contains zstd
+pub fn supports_zstd(version: &str) -> bool {
+    // rsync before 3.2.3 doesn't print the list, zstd support is assumed missing then
+    let mut lines = version.lines();
+    lines.find(|l| l.trim_start().starts_with("Compress list:"));
+    lines
+        .next()
+        .is_some_and(|l| l.split_whitespace().any(|a| a == "zstd"))
+}
+
+fn local_zstd(rsync: &OsStr) -> bool {
+    let out = Command::new(rsync)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    match out {
+        Ok(out) if out.status.success() => supports_zstd(&String::from_utf8_lossy(&out.stdout)),
+        _ => false,
+    }
+}
+
+/// Asks the rsync of `host` over ssh, caching the answer in the state dir, as every sync of the
+/// subprocess execution would ask again
+fn remote_zstd(host: &str, ssh: &[String], ssh_multiplexing: bool) -> bool {
+    let cache = cache_path(host, ssh);
+    if let Some(cache) = cache.as_deref() {
+        let fresh = std::fs::metadata(cache)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age < CACHE_TTL);
+        if fresh {
+            if let Ok(cached) = std::fs::read_to_string(cache) {
+                return cached.trim() == "zstd";
+            }
+        }
+    }
+    let mut cmd = Command::new("ssh");
+    cmd.args(["-o", "BatchMode=yes", "-o", "ConnectTimeout=5"]);
+    cmd.args(ssh);
+    if ssh_multiplexing {
+        cmd.args(crate::ssh::multiplex_args(host, ssh));
+    }
+    let out = cmd
+        .args([host, "rsync", "--version"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    let zstd = match out {
+        Ok(out) if out.status.success() => supports_zstd(&String::from_utf8_lossy(&out.stdout)),
+        // not cached, so the next sync asks again
+        _ => {
+            debug!(
+                host,
+                "Failed to detect the compression support of the remote rsync"
+            );
+            return false;
+        }
+    };
+    debug!(
+        host,
+        zstd, "Detected the compression support of the remote rsync"
+    );
+    if let Some(cache) = cache {
+        let _ = std::fs::create_dir_all(cache.parent().unwrap_or(Path::new(".")));
+        let _ = std::fs::write(&cache, if zstd { "zstd" } else { "none" });
+    }
+    zstd
+}
+
+fn cache_path(host: &str, ssh: &[String]) -> Option<PathBuf> {
+    let state = crate::state::state_path()?;
+    let mut hash = Fnv::default();
+    hash.write(host.as_bytes());
+    // e.g. another port may reach another server
+    for arg in ssh {
+        hash.write(&[0]);
+        hash.write(arg.as_bytes());
+    }
+    Some(
+        state
+            .parent()?
+            .join("compress")
+            .join(format!("{:016x}", hash.0)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supports_zstd() {
+        let new = "rsync  version 3.2.7  protocol version 31\n\
+            Capabilities:\n    64-bit files, 64-bit inums\n\
+            Compress list:\n    zstd lz4 zlibx zlib none\n";
+        assert!(supports_zstd(new));
+        let without = "rsync  version 3.2.3  protocol version 31\n\
+            Compress list:\n    zlibx zlib none\n";
+        assert!(!supports_zstd(without));
+        let old = "rsync  version 3.1.3  protocol version 31\n\
+            Capabilities:\n    64-bit files, 64-bit inums\n";
+        assert!(!supports_zstd(old));
+    }
+
+    #[test]
+    fn test_rsync_flags() {
+        let rsync = OsStr::new("rsync");
+        assert_eq!(
+            rsync_flags(
+                config::Compress::Off,
+                rsync,
+                Path::new("host:/x"),
+                &[],
+                false
+            ),
+            ["--no-compress"]
+        );
+        assert_eq!(
+            rsync_flags(
+                config::Compress::Auto,
+                rsync,
+                Path::new("/tmp/x"),
+                &[],
+                false
+            ),
+            ["--no-compress"]
+        );
+        assert_eq!(
+            rsync_flags(
+                config::Compress::Auto,
+                rsync,
+                Path::new("host::mod"),
+                &[],
+                false
+            ),
+            ["--compress"]
+        );
+    }
+}
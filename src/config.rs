@@ -1,190 +1,3188 @@
+use anyhow::Context as _;
 use serde::de::{self, MapAccess, Visitor};
 use serde::{Deserialize, Deserializer};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::Infallible;
 use std::fmt;
 use std::str::FromStr;
-use std::{path::PathBuf, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 pub type ProjectName = String;
 
-#[derive(Debug, Clone, serde_derive::Deserialize)]
-pub struct Config {
-    pub projects: HashMap<ProjectName, Project>,
-    #[serde(default = "default_debounce")]
-    #[serde(deserialize_with = "duration_str::deserialize_duration")]
-    pub debounce: Duration,
-}
+/// File names searched for when no config path is given, in order of preference
+pub static CONFIG_FILE_NAMES: &[&str] = &["atune.yaml", "atune.toml", "atune.json"];
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            projects: Default::default(),
-            debounce: default_debounce(),
-        }
-    }
+/// Whether `path` has the extension of a config file
+pub fn is_config_file(path: &std::path::Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml" | "yml" | "toml" | "json")
+    )
 }
 
-#[derive(Default, Debug, Clone, serde_derive::Deserialize)]
-pub struct Project {
-    pub sync: Vec<FileSync>,
-    /// cancel in-progress on_sync commands if a new change happens while they're running
-    #[serde(default = "default_true")]
-    pub restart: bool,
+/// The config files of a directory of configs, sorted by name
+pub fn config_files(dir: &std::path::Path) -> anyhow::Result<Vec<std::path::PathBuf>> {
+    let mut files = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read the config directory {}", dir.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && is_config_file(p))
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
 }
 
-fn default_debounce() -> Duration {
-    Duration::from_millis(100)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
 }
 
-#[derive(Default, Debug, Clone, serde_derive::Deserialize)]
-pub struct FileSync {
-    /// wether this sync is enabled. if disabled, then this sync is ignored
-    /// default=true
-    #[serde(default = "default_true")]
-    pub enabled: bool,
-    pub src: PathBuf,
-    /// Watch src recursively. If src is a file then this flag is ignored
-    /// default=true
-    #[serde(default = "default_true")]
-    pub recursive: bool,
-    /// If omitted, then no sync is performed, only the commands are run
-    pub dst: Option<PathBuf>,
-    pub rsync_flags: Option<String>,
-    /// commands to run after sync
-    #[serde(default)]
-    #[serde(deserialize_with = "deser_command_list")]
-    pub on_sync: Vec<CommandConfig>,
+impl ConfigFormat {
+    /// Guess the format from the file extension. Defaults to yaml
+    pub fn from_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
 }
 
-#[derive(Default, Debug, Clone, Deserialize)]
-pub struct CommandConfig {
-    pub command: String,
-    #[serde(default)]
-    pub on: CommandOn,
-    #[serde(default)]
-    pub continue_on_failure: bool,
+/// Settings of the command line applied on top of the config file
+#[derive(Debug, Default, Clone)]
+pub struct ConfigOverrides {
+    /// name of the profile to apply
+    pub profile: Option<String>,
+    /// overrides the `variables` of the config and the profile
+    pub vars: HashMap<String, String>,
+    /// overrides [FileSync::confirm_delete] of the syncs it applies to, `--confirm` and `--yes`
+    pub confirm_delete: Option<bool>,
 }
 
-#[derive(Default, Debug, Clone, Deserialize)]
-pub enum CommandOn {
-    #[default]
-    Change,
-    /// Only run the given command at initialization
-    Init,
+/// Parse a TOML document into a [serde_yaml::Value], so it's deserialized like the YAML configs.
+/// Dates and times become strings in their RFC 3339 form
+fn parse_toml(content: &str) -> anyhow::Result<serde_yaml::Value> {
+    fn convert(value: toml::Value) -> serde_yaml::Value {
+        match value {
+            toml::Value::String(s) => s.into(),
+            toml::Value::Integer(i) => i.into(),
+            toml::Value::Float(f) => f.into(),
+            toml::Value::Boolean(b) => b.into(),
+            toml::Value::Datetime(d) => d.to_string().into(),
+            toml::Value::Array(a) => a.into_iter().map(convert).collect(),
+            toml::Value::Table(t) => serde_yaml::Value::Mapping(
+                t.into_iter().map(|(k, v)| (k.into(), convert(v))).collect(),
+            ),
+        }
+    }
+    let table: toml::Table = toml::from_str(content)?;
+    Ok(convert(toml::Value::Table(table)))
 }
 
-impl FromStr for CommandConfig {
-    type Err = Infallible;
+impl Config {
+    pub fn parse(content: &str, format: ConfigFormat) -> anyhow::Result<Self> {
+        Self::parse_with(content, format, &ConfigOverrides::default())
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(CommandConfig {
-            command: s.to_owned(),
-            ..Default::default()
-        })
+    /// Parse the config, applying the selected profile and variables
+    pub fn parse_with(
+        content: &str,
+        format: ConfigFormat,
+        overrides: &ConfigOverrides,
+    ) -> anyhow::Result<Self> {
+        let mut config: Self = match format {
+            // JSON is a subset of YAML
+            ConfigFormat::Yaml | ConfigFormat::Json => serde_yaml::from_str(content)?,
+            ConfigFormat::Toml => serde_yaml::from_value(parse_toml(content)?)?,
+        };
+        if let Some(profile) = overrides.profile.as_deref() {
+            config
+                .apply_profile(profile)
+                .with_context(|| format!("Failed to apply profile {profile}"))?;
+        }
+        config.apply_defaults();
+        config.resolve_host_groups()?;
+        config.expand_variables(&overrides.vars)?;
+        config.resolve_host_aliases();
+        config.validate()?;
+        if let Some(confirm) = overrides.confirm_delete {
+            for s in config.projects.values_mut().flat_map(|p| p.sync.iter_mut()) {
+                s.confirm_delete = confirm
+                    && s.dst.is_some()
+                    && s.backend == SyncBackend::Rsync
+                    && s.mode == SyncMode::Mirror;
+            }
+        }
+        Ok(config)
     }
-}
 
-fn default_true() -> bool {
-    true
-}
+    /// Read and parse the config file, resolving the src paths and the inherited shells.
+    /// The format is guessed from the file extension if not given
+    pub fn load(
+        path: &std::path::Path,
+        format: Option<ConfigFormat>,
+        overrides: &ConfigOverrides,
+    ) -> anyhow::Result<Self> {
+        let format = format.unwrap_or_else(|| ConfigFormat::from_path(path));
+        let content = std::fs::read_to_string(path).context("Failed to open config file")?;
+        let mut config = Self::parse_with(&content, format, overrides)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        config.expand_src_globs()?;
 
-struct CommandConfigDe(pub CommandConfig);
+        for s in config.projects.values_mut().flat_map(|p| p.sync.iter_mut()) {
+            let src = std::mem::take(&mut s.src);
+            let trailing_slash = src.as_os_str().to_string_lossy().ends_with('/');
+            s.src = crate::platform::resolve(&src, s.follows_symlinks()).unwrap_or(src);
+            s.src = normalize_trailing_slash(std::mem::take(&mut s.src), s.copy_contents);
+            match s.copy_contents {
+                Some(false) if trailing_slash => tracing::warn!(
+                    "The trailing slash of src {} conflicts with copy_contents: false, the directory is copied into dst",
+                    s.src.display()
+                ),
+                None if trailing_slash => tracing::warn!(
+                    "The trailing slash of src {0}/ is ignored, the directory is copied into dst. Set copy_contents: true to copy its contents",
+                    s.src.display()
+                ),
+                _ => {}
+            }
+        }
+        config.resolve_shares()?;
+        for c in config.on_start.iter_mut().chain(config.on_stop.iter_mut()) {
+            if c.shell.is_none() {
+                c.shell = config.shell.clone();
+            }
+        }
+        for p in config.projects.values_mut() {
+            let shell = p.shell.as_ref().or(config.shell.as_ref());
+            for c in p
+                .sync
+                .iter_mut()
+                .flat_map(|s| s.on_sync.iter_mut())
+                .chain(p.run.iter_mut())
+                .chain(p.on_sync.iter_mut())
+                .chain(p.healthcheck.iter_mut())
+            {
+                if c.shell.is_none() {
+                    c.shell = shell.cloned();
+                }
+            }
+        }
+        Ok(config)
+    }
 
-impl<'de> Deserialize<'de> for CommandConfigDe {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        struct CommandVisitor;
-        impl<'de> Visitor<'de> for CommandVisitor {
-            type Value = CommandConfigDe;
+    /// Fill [FileSync::shared_copies] from the entries of the same share group
+    fn resolve_shares(&mut self) -> anyhow::Result<()> {
+        let mut copies = HashMap::<String, Vec<PathBuf>>::new();
+        for s in self.projects.values().flat_map(|p| p.sync.iter()) {
+            let Some(share) = s.share.as_ref() else {
+                continue;
+            };
+            let rel = s
+                .src
+                .strip_prefix(crate::sync::transfer_root(&s.src))
+                .unwrap_or(Path::new(""));
+            for dst in s.destinations()? {
+                let dst = match remote_dst(&dst) {
+                    Some(_) => dst,
+                    None => std::path::absolute(&dst)?,
+                };
+                copies.entry(share.clone()).or_default().push(dst.join(rel));
+            }
+        }
+        for s in self.projects.values_mut().flat_map(|p| p.sync.iter_mut()) {
+            if let Some(share) = s.share.as_ref() {
+                s.shared_copies = copies.get(share).cloned().unwrap_or_default();
+            }
+        }
+        Ok(())
+    }
 
-            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("string or map")
+    fn apply_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        let profile = self.profiles.remove(name).with_context(|| {
+            let mut known = self.profiles.keys().map(String::as_str).collect::<Vec<_>>();
+            known.sort();
+            format!("Unknown profile, expected one of {known:?}")
+        })?;
+        self.variables.extend(profile.variables);
+        for (selector, o) in profile.overrides {
+            let selector = SyncSelector::from_str(&selector).unwrap_or_else(|e| match e {});
+            let project = self
+                .projects
+                .get_mut(&selector.project)
+                .with_context(|| format!("Unknown project {} in override", selector.project))?;
+            let mut matched = false;
+            for (i, s) in project.sync.iter_mut().enumerate() {
+                if !selector.matches(i, s) {
+                    continue;
+                }
+                matched = true;
+                if let Some(dst) = o.dst.as_ref() {
+                    s.dst = Some(dst.clone());
+                }
+                if let Some(flags) = o.rsync_flags.as_ref() {
+                    s.rsync_flags = Some(flags.clone());
+                }
+                if let Some(on_sync) = o.on_sync.as_ref() {
+                    s.on_sync = on_sync.clone();
+                }
             }
+            anyhow::ensure!(matched, "Override {selector} matches no sync");
+        }
+        Ok(())
+    }
 
-            fn visit_str<E>(self, value: &str) -> Result<CommandConfigDe, E>
-            where
-                E: de::Error,
-            {
-                Ok(CommandConfigDe(FromStr::from_str(value).unwrap()))
+    /// Fill the settings of the projects and syncs from [Config::defaults]
+    fn apply_defaults(&mut self) {
+        let defaults = &self.defaults;
+        let inherit_env = |c: &mut CommandConfig| {
+            for (k, v) in defaults.env.iter() {
+                c.env.entry(k.clone()).or_insert_with(|| v.clone());
+            }
+        };
+        self.on_start.iter_mut().for_each(inherit_env);
+        self.on_stop.iter_mut().for_each(inherit_env);
+        for p in self.projects.values_mut() {
+            p.restart = p.restart.or(defaults.restart);
+            p.run.iter_mut().for_each(inherit_env);
+            p.healthcheck.iter_mut().for_each(inherit_env);
+            p.on_sync.iter_mut().for_each(inherit_env);
+            for s in p.sync.iter_mut() {
+                if s.rsync_flags.is_none() {
+                    s.rsync_flags = defaults.rsync_flags.clone();
+                }
+                if s.extra_rsync_flags.is_none() {
+                    s.extra_rsync_flags = defaults.extra_rsync_flags.clone();
+                }
+                s.ignore_patterns
+                    .extend(defaults.ignore_patterns.iter().cloned());
+                s.nice = s.nice.or(defaults.nice);
+                s.ionice = s.ionice.or(defaults.ionice);
+                if s.cpus.is_none() {
+                    s.cpus = defaults.cpus.clone();
+                }
+                s.on_sync.iter_mut().for_each(inherit_env);
             }
+        }
+    }
 
-            fn visit_map<M>(self, map: M) -> Result<CommandConfigDe, M::Error>
-            where
-                M: MapAccess<'de>,
-            {
-                Deserialize::deserialize(de::value::MapAccessDeserializer::new(map))
-                    .map(CommandConfigDe)
+    /// Replace the host group names of the syncs by their hosts
+    fn resolve_host_groups(&mut self) -> anyhow::Result<()> {
+        for (name, p) in self.projects.iter_mut() {
+            for s in p.sync.iter_mut() {
+                let Some(Hosts::Group(group)) = s.hosts.as_ref() else {
+                    continue;
+                };
+                let groups = || {
+                    self.hosts.iter().filter_map(|(name, h)| match h {
+                        HostEntry::Group(hosts) => Some((name, hosts)),
+                        HostEntry::Alias(_) => None,
+                    })
+                };
+                let hosts = groups().find(|(n, _)| *n == group).map(|(_, h)| h).with_context(|| {
+                    let mut known = groups().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+                    known.sort();
+                    format!(
+                        "Unknown host group {group} of sync {} in project {name}, expected one of {known:?}",
+                        s.src.display()
+                    )
+                })?;
+                s.hosts = Some(Hosts::List(hosts.clone()));
             }
         }
+        Ok(())
+    }
 
-        deserializer.deserialize_any(CommandVisitor)
+    /// Replace the host aliases of the dsts by the hosts they stand for, see [HostAlias], and
+    /// resolve the ssh arguments of the hosts of every sync, see [FileSync::ssh_args]
+    fn resolve_host_aliases(&mut self) {
+        let aliases = self
+            .hosts
+            .values()
+            .filter_map(|h| match h {
+                HostEntry::Alias(alias) => Some((alias.host(), alias.ssh_args())),
+                HostEntry::Group(_) => None,
+            })
+            .collect::<HashMap<_, _>>();
+        for p in self.projects.values_mut() {
+            let opts = p
+                .ssh_opts
+                .as_ref()
+                .map(SshOptions::args)
+                .unwrap_or_default();
+            for s in p.sync.iter_mut() {
+                if let Some((name, path)) = s.dst.as_deref().and_then(remote_dst) {
+                    if let Some(HostEntry::Alias(alias)) = self.hosts.get(name) {
+                        s.dst = Some(alias.dst(path));
+                    }
+                }
+                s.ssh_args = s
+                    .destinations()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|dst| remote_dst(dst).map(|(host, _)| host.to_owned()))
+                    .map(|host| {
+                        let mut args = aliases.get(&host).cloned().unwrap_or_default();
+                        args.extend(opts.iter().cloned());
+                        (host, args)
+                    })
+                    .filter(|(_, args)| !args.is_empty())
+                    .collect();
+            }
+        }
     }
-}
 
-fn deser_command_list<'de, D>(deserializer: D) -> Result<Vec<CommandConfig>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    struct V;
-    impl<'de> Visitor<'de> for V {
-        type Value = Vec<CommandConfig>;
+    /// Replace every sync whose src contains wildcards with a sync per matching path, filling in
+    /// the `{{ match.N }}` placeholders of its dst, rsync flags and commands
+    fn expand_src_globs(&mut self) -> anyhow::Result<()> {
+        for (name, p) in self.projects.iter_mut() {
+            let mut expanded = Vec::with_capacity(p.sync.len());
+            for (i, s) in std::mem::take(&mut p.sync).into_iter().enumerate() {
+                let matches = if crate::glob::is_pattern(&s.src.to_string_lossy()) {
+                    let matches = crate::glob::expand(&s.src);
+                    if matches.is_empty() {
+                        tracing::warn!(
+                            "The src {} in project {name} matches no paths",
+                            s.src.display()
+                        );
+                    }
+                    matches
+                } else {
+                    vec![(s.src.clone(), Vec::new())]
+                };
+                for (src, captures) in matches {
+                    let src_str = src.to_string_lossy().into_owned();
+                    let lookup = |var: &str| {
+                        let n: usize = var.strip_prefix("match.")?.parse().ok()?;
+                        match n {
+                            0 => Some(src_str.clone()),
+                            n => captures.get(n - 1).cloned(),
+                        }
+                    };
+                    let lookup = keep_host(&s, lookup);
+                    let mut s = s.clone();
+                    s.src = src.clone();
+                    expand_matches(&mut s, lookup).with_context(|| {
+                        format!("Invalid sync {i} ({}) in project {name}", src.display())
+                    })?;
+                    expanded.push(s);
+                }
+            }
+            p.sync = expanded;
+        }
+        Ok(())
+    }
 
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("List of CommandConfigs")
+    /// Replace the `{{ name }}` placeholders of the paths, rsync flags and commands
+    fn expand_variables(&mut self, overrides: &HashMap<String, String>) -> anyhow::Result<()> {
+        let vars = std::mem::take(&mut self.variables);
+        let lookup = |project: Option<&str>| {
+            let vars = &vars;
+            let project = project.map(str::to_owned);
+            move |name: &str| {
+                overrides
+                    .get(name)
+                    .or_else(|| vars.get(name))
+                    .cloned()
+                    .or_else(|| match name {
+                        "project" => project.clone(),
+                        "hostname" => Some(crate::template::hostname()),
+                        "date" => Some(crate::template::date()),
+                        // filled in once the src globs are expanded
+                        _ if name.starts_with("match.") => Some(format!("{{{{ {name} }}}}")),
+                        _ => None,
+                    })
+            }
+        };
+        for c in self.on_start.iter_mut().chain(self.on_stop.iter_mut()) {
+            expand_command(c, lookup(None))?;
+        }
+        for (name, p) in self.projects.iter_mut() {
+            let lookup = lookup(Some(name));
+            for c in p.run.iter_mut() {
+                expand_command(c, &lookup)?;
+            }
+            for s in p.sync.iter_mut() {
+                let lookup = keep_host(s, &lookup);
+                expand_path(&mut s.src, &lookup)?;
+                if let Some(dst) = s.dst.as_mut() {
+                    expand_path(dst, &lookup)?;
+                }
+                if let Some(f) = s.password_file.as_mut() {
+                    expand_path(f, &lookup)?;
+                }
+                for flags in s
+                    .rsync_flags
+                    .iter_mut()
+                    .chain(s.extra_rsync_flags.iter_mut())
+                {
+                    *flags = crate::template::expand(flags, &lookup)?;
+                }
+                for c in s.on_sync.iter_mut() {
+                    expand_command(c, &lookup)?;
+                }
+            }
         }
+        self.variables = vars;
+        Ok(())
+    }
 
-        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
-        where
-            A: de::SeqAccess<'de>,
+    /// Check the references between projects and every sync, reporting all of the errors at once
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        let mut names: Vec<&String> = self.projects.keys().collect();
+        names.sort();
+        if self
+            .on_start
+            .iter()
+            .chain(&self.on_stop)
+            .any(CommandConfig::is_remote)
         {
-            let mut v = Vec::with_capacity(seq.size_hint().unwrap_or(1));
-            while let Some(CommandConfigDe(c)) = seq.next_element()? {
-                v.push(c);
+            errors.push("on_start and on_stop commands can't be remote".to_owned());
+        }
+        for name in names.iter().copied() {
+            let p = &self.projects[name];
+            if p.run.iter().chain(&p.on_sync).any(CommandConfig::is_remote) {
+                errors.push(format!(
+                    "Project {name} has remote commands, only the commands of sync entries run on the host of their dst"
+                ));
             }
-            Ok(v)
+            for dep in p.depends_on.iter() {
+                if !self.projects.contains_key(dep) {
+                    errors.push(format!("Project {name} depends on unknown project {dep}"));
+                }
+            }
+            for (i, s) in p.sync.iter().enumerate() {
+                if let Err(err) = s.validate() {
+                    errors.push(format!(
+                        "Invalid sync {i} ({}) in project {name}: {err:#}",
+                        s.src.display()
+                    ));
+                }
+                // registered in this process only
+                if matches!(s.backend, SyncBackend::Custom(_))
+                    && self.execution != Execution::InProcess
+                {
+                    errors.push(format!(
+                        "Invalid sync {i} ({}) in project {name}: backends registered by the library need execution: InProcess",
+                        s.src.display()
+                    ));
+                }
+            }
+        }
+        // depth first search for cycles
+        fn visit<'a>(
+            config: &'a Config,
+            name: &'a str,
+            path: &mut Vec<&'a str>,
+            done: &mut HashSet<&'a str>,
+        ) -> anyhow::Result<()> {
+            if done.contains(name) {
+                return Ok(());
+            }
+            if let Some(i) = path.iter().position(|p| *p == name) {
+                anyhow::bail!(
+                    "Project dependency cycle: {} -> {name}",
+                    path[i..].join(" -> ")
+                );
+            }
+            path.push(name);
+            for dep in config.projects[name].depends_on.iter() {
+                if config.projects.contains_key(dep) {
+                    visit(config, dep, path, done)?;
+                }
+            }
+            path.pop();
+            done.insert(name);
+            Ok(())
+        }
+        let mut done = HashSet::new();
+        for name in names {
+            if let Err(err) = visit(self, name, &mut Vec::new(), &mut done) {
+                errors.push(err.to_string());
+                break;
+            }
+        }
+        match errors.len() {
+            0 => Ok(()),
+            1 => anyhow::bail!("{}", errors[0]),
+            n => anyhow::bail!("{n} errors in the config:\n  {}", errors.join("\n  ")),
         }
     }
 
-    deserializer.deserialize_any(V)
+    /// Restrict the config to the syncs selected by `only` and not selected by `skip`.
+    ///
+    /// Syncs that aren't selected are disabled, so their indices don't change. Projects without
+    /// any selected sync are removed. If `only` is empty, then everything is selected.
+    pub fn select(&mut self, only: &[SyncSelector], skip: &[SyncSelector]) -> anyhow::Result<()> {
+        for s in only.iter().chain(skip) {
+            anyhow::ensure!(
+                self.projects.contains_key(&s.project),
+                "Unknown project {} in selector {s}",
+                s.project
+            );
+        }
+        self.projects.retain(|name, project| {
+            let project_only = only
+                .iter()
+                .filter(|s| &s.project == name)
+                .collect::<Vec<_>>();
+            let project_skip = skip
+                .iter()
+                .filter(|s| &s.project == name)
+                .collect::<Vec<_>>();
+            if project_skip.iter().any(|s| s.sync.is_none())
+                || (!only.is_empty() && project_only.is_empty())
+            {
+                return false;
+            }
+            let whole_project = only.is_empty() || project_only.iter().any(|s| s.sync.is_none());
+            for (i, sync) in project.sync.iter_mut().enumerate() {
+                let selected = whole_project || project_only.iter().any(|s| s.matches(i, sync));
+                if !selected || project_skip.iter().any(|s| s.matches(i, sync)) {
+                    sync.enabled = false;
+                }
+            }
+            true
+        });
+        Ok(())
+    }
+
+    /// Keep the syncs having any of the tags, their own or of their project, disabling the
+    /// others. Projects without such a sync are removed. No tags select everything. Returns
+    /// false if no enabled sync has the tags
+    pub fn select_tags(&mut self, tags: &[String]) -> bool {
+        if tags.is_empty() {
+            return true;
+        }
+        let tagged = |t: &Vec<String>| t.iter().any(|t| tags.contains(t));
+        self.projects.retain(|_, project| {
+            let project_tagged = tagged(&project.tags);
+            for sync in project.sync.iter_mut() {
+                if !project_tagged && !tagged(&sync.tags) {
+                    sync.enabled = false;
+                }
+            }
+            project.sync.iter().any(|s| s.enabled)
+        });
+        !self.projects.is_empty()
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::os::unix::ffi::OsStrExt as _;
+impl Project {
+    /// `[user@]host` of the remote destinations of the enabled syncs
+    pub fn remote_hosts(&self) -> BTreeSet<String> {
+        self.sync
+            .iter()
+            .filter(|s| s.enabled)
+            .filter_map(|s| s.destinations().ok())
+            .flatten()
+            .filter_map(|dst| remote_dst(&dst).map(|(host, _)| host.to_owned()))
+            .collect()
+    }
+}
 
-    use super::*;
+impl FileSync {
+    /// Whether links are followed, which is the default when no policy is set
+    pub fn follows_symlinks(&self) -> bool {
+        matches!(self.symlinks, None | Some(SymlinkPolicy::Follow))
+    }
 
-    #[test]
-    fn test_yaml_deser() {
-        let yaml = r#"
-debounce: 1s 30ms
-projects:
-    asd:
-      sync:
-          - src: asd
-            dst: remote:~/asd
-            on_sync:
-                - echo done
-                - command: echo hi
-                - command: echo hi
-                  on: Init
-"#;
+    /// Arguments of ssh connecting to the host of `dst`, see [FileSync::ssh_args]
+    pub fn ssh_args_of(&self, dst: &Path) -> &[String] {
+        ssh_args_of(&self.ssh_args, dst)
+    }
 
-        let config: Config = serde_yaml::from_str(yaml).unwrap();
+    /// dst, or a dst per host if the sync fans out to [FileSync::hosts]
+    pub fn destinations(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let Some(dst) = self.dst.as_deref() else {
+            return Ok(Vec::new());
+        };
+        let hosts = match self.hosts.as_ref() {
+            None => return Ok(vec![dst.to_owned()]),
+            Some(Hosts::Group(name)) => anyhow::bail!("Unknown host group {name}"),
+            Some(Hosts::List(hosts)) => hosts,
+        };
+        let dst = dst.to_string_lossy();
+        hosts
+            .iter()
+            .map(|host| {
+                let dst =
+                    crate::template::expand(&dst, |name| (name == "host").then(|| host.clone()))?;
+                Ok(PathBuf::from(dst))
+            })
+            .collect()
+    }
 
-        assert_eq!(config.projects.len(), 1);
+    /// Check the commands running on the host of dst, see [CommandConfig::location]
+    fn validate_remote_commands(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self
+                .on_drift
+                .iter()
+                .chain(&self.on_failure)
+                .any(CommandConfig::is_remote),
+            "on_drift and on_failure commands can't be remote"
+        );
+        if let Some(cmd) = self
+            .on_sync
+            .iter()
+            .find(|c| c.remote && c.location == Some(CommandLocation::Local))
+        {
+            anyhow::bail!("{} has both remote: true and where: Local", cmd.command);
+        }
+        let remote = self
+            .on_sync
+            .iter()
+            .filter(|c| c.is_remote())
+            .collect::<Vec<_>>();
+        if remote.is_empty() {
+            return Ok(());
+        }
+        anyhow::ensure!(
+            self.hosts.is_none() && self.dst.as_deref().and_then(remote_dst).is_some(),
+            "remote commands need a single [user@]host:path dst"
+        );
+        for cmd in remote {
+            anyhow::ensure!(
+                crate::plugin::parse(&cmd.command).is_none()
+                    && crate::script::parse(&cmd.command).is_none(),
+                "{} can't run remotely, only shell commands can",
+                cmd.command
+            );
+        }
+        Ok(())
+    }
 
-        assert_eq!(config.projects["asd"].sync[0].src.as_os_str(), "asd");
-        assert_eq!(
-            config.projects["asd"].sync[0]
-                .dst
-                .as_ref()
-                .map(|x| x.as_os_str()),
-            Some(std::ffi::OsStr::from_bytes(b"remote:~/asd"))
+    fn validate(&self) -> anyhow::Result<()> {
+        if let SyncBackend::Custom(name) = &self.backend {
+            anyhow::ensure!(
+                crate::backend::is_registered(name),
+                "Unknown backend {name}, expected Rsync, Copy, Agent or a backend registered by the library"
+            );
+        }
+        anyhow::ensure!(
+            self.agent.is_none() || self.backend == SyncBackend::Agent,
+            "agent needs the Agent backend"
         );
-        assert_eq!(config.debounce, Duration::from_millis(1030));
+        if self.backend == SyncBackend::Agent {
+            anyhow::ensure!(self.dst.is_some(), "The Agent backend needs a dst");
+            anyhow::ensure!(
+                !self.verify && self.backup.is_none(),
+                "verify and backup are not supported by the Agent backend"
+            );
+            anyhow::ensure!(
+                self.include.is_empty() && self.exclude.is_empty(),
+                "include and exclude are not supported by the Agent backend"
+            );
+        }
+        self.validate_remote_commands()?;
+        let daemon = self.dst.as_deref().and_then(rsync_daemon_dst);
+        anyhow::ensure!(
+            !self.create_dst || (self.dst.is_some() && daemon.is_none()),
+            "create_dst needs a local or ssh destination"
+        );
+        anyhow::ensure!(
+            (self.dst_owner.is_none() && self.dst_mode.is_none())
+                || (self.dst.is_some() && daemon.is_none() && self.mode == SyncMode::Mirror),
+            "dst_owner and dst_mode need a local or ssh destination and Mirror mode"
+        );
+        if let Some((host, module)) = daemon {
+            anyhow::ensure!(
+                !module.is_empty(),
+                "The rsync daemon destination on {host} has no module"
+            );
+            anyhow::ensure!(
+                self.backend == SyncBackend::Rsync,
+                "rsync daemon destinations need the Rsync backend"
+            );
+            anyhow::ensure!(
+                self.backup
+                    .as_ref()
+                    .is_none_or(|b| b.keep.is_none() && b.max_age.is_none()),
+                "Backup retention is not supported with rsync daemon destinations"
+            );
+        } else {
+            anyhow::ensure!(
+                self.bwlimit.is_none() || self.backend == SyncBackend::Rsync,
+                "bwlimit needs the Rsync backend"
+            );
+            anyhow::ensure!(
+                !self.protect_dst || self.backend == SyncBackend::Rsync,
+                "protect_dst needs the Rsync backend"
+            );
+            anyhow::ensure!(
+                self.max_file_size.is_none() || self.backend == SyncBackend::Rsync,
+                "max_file_size needs the Rsync backend"
+            );
+            anyhow::ensure!(
+                !self.confirm_delete || self.backend == SyncBackend::Rsync,
+                "confirm_delete needs the Rsync backend"
+            );
+            anyhow::ensure!(
+                self.max_delete.is_none() || self.backend == SyncBackend::Rsync,
+                "max_delete needs the Rsync backend"
+            );
+            anyhow::ensure!(
+                self.password.is_none()
+                    && self.password_file.is_none()
+                    && self.password_env.is_none(),
+                "password, password_file and password_env are only used with rsync daemon destinations"
+            );
+        }
+        if let Some(Hosts::List(hosts)) = self.hosts.as_ref() {
+            anyhow::ensure!(!hosts.is_empty(), "hosts is empty");
+            anyhow::ensure!(self.dst.is_some(), "hosts needs a dst");
+            let destinations = self.destinations()?;
+            anyhow::ensure!(
+                destinations.iter().collect::<HashSet<_>>().len() == destinations.len(),
+                "dst must contain the {{{{ host }}}} placeholder to sync to different hosts"
+            );
+        }
+        anyhow::ensure!(
+            !self.verify || self.mode == SyncMode::Mirror,
+            "verify doesn't apply to Snapshot mode, whose releases are new copies of src"
+        );
+        if self.dst.is_none() {
+            anyhow::ensure!(
+                self.mode == SyncMode::Mirror,
+                "Snapshot mode needs a dst, entries without one only run their commands"
+            );
+            anyhow::ensure!(
+                !self.partial
+                    && !self.manifest
+                    && !self.protect_dst
+                    && !self.confirm_delete
+                    && self.max_delete.is_none()
+                    && self.max_file_size.is_none()
+                    && !self.verify
+                    && self.bwlimit.is_none()
+                    && self.backup.is_none(),
+                "partial, manifest, protect_dst, confirm_delete, max_delete, max_file_size, verify, bwlimit and backup need a dst, entries without one only run their commands"
+            );
+        }
+        let passwords = [
+            self.password.is_some(),
+            self.password_file.is_some(),
+            self.password_env.is_some(),
+        ];
+        anyhow::ensure!(
+            passwords.iter().filter(|p| **p).count() <= 1,
+            "Only one of password, password_file and password_env can be set"
+        );
+        if self.mode == SyncMode::Snapshot {
+            anyhow::ensure!(
+                self.backend == SyncBackend::Rsync && daemon.is_none(),
+                "Snapshot mode needs the Rsync backend and a local or ssh destination"
+            );
+            anyhow::ensure!(
+                !self.partial && !self.manifest,
+                "Snapshot mode syncs the whole src into every release, partial and manifest don't apply"
+            );
+            anyhow::ensure!(
+                self.backup.is_none()
+                    && !self.protect_dst
+                    && !self.confirm_delete
+                    && self.max_delete.is_none(),
+                "Snapshot mode keeps the old releases, backup, protect_dst, confirm_delete and max_delete don't apply"
+            );
+            anyhow::ensure!(self.keep >= 1, "keep must be at least 1");
+            anyhow::ensure!(
+                !self.atomic,
+                "Snapshot mode switches releases atomically already"
+            );
+        }
+        if self.on_init_when == OnInitWhen::DstMissing {
+            anyhow::ensure!(
+                self.dst.is_some() && daemon.is_none(),
+                "on_init_when: DstMissing needs a local or ssh destination to probe"
+            );
+        }
+        if self.share.is_some() {
+            anyhow::ensure!(
+                self.dst.is_some() && self.backend == SyncBackend::Rsync && daemon.is_none(),
+                "share needs a dst, the Rsync backend and a local or ssh destination"
+            );
+            // rsync can't mix --copy-dest with their --link-dest
+            anyhow::ensure!(
+                self.mode == SyncMode::Mirror && !self.atomic,
+                "share doesn't apply to Snapshot mode and atomic syncs"
+            );
+        }
+        if self.share_relay {
+            anyhow::ensure!(self.share.is_some(), "share_relay needs a share group");
+        }
+        if self.atomic {
+            anyhow::ensure!(
+                self.dst.is_some() && self.backend == SyncBackend::Rsync && daemon.is_none(),
+                "atomic needs a dst, the Rsync backend and a local or ssh destination"
+            );
+            anyhow::ensure!(
+                !self.partial && !self.manifest,
+                "atomic syncs the whole src into the staging directory, partial and manifest don't apply"
+            );
+            anyhow::ensure!(
+                self.backup.is_none(),
+                "atomic replaces the copy in dst as a whole, backup doesn't apply"
+            );
+        }
+        for c in self.on_sync.iter() {
+            anyhow::ensure!(
+                !matches!(c.on, CommandOn::Init) || c.only_on != OnlyOn::Change,
+                "The command {:?} runs on Init, so only_on: Change never runs it",
+                c.command
+            );
+        }
+        if let Some(flags) = self.rsync_flags.as_deref() {
+            shell_words::split(flags).context("Failed to split rsync flags")?;
+        }
+        if let Some(flags) = self.extra_rsync_flags.as_deref() {
+            shell_words::split(flags).context("Failed to split extra rsync flags")?;
+        }
+        Ok(())
+    }
+}
+
+/// Selects a project, or a single sync of it, on the command line: `project[:sync]`.
+///
+/// The sync is matched by its index in the project, the file name of its src or its src path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncSelector {
+    pub project: String,
+    pub sync: Option<String>,
+}
+
+impl SyncSelector {
+    fn matches(&self, index: usize, sync: &FileSync) -> bool {
+        let Some(s) = self.sync.as_deref() else {
+            return true;
+        };
+        s == index.to_string()
+            || sync.src.file_name().is_some_and(|f| f == s)
+            || sync.src == Path::new(s)
+            || crate::platform::canonicalize(s).is_ok_and(|p| p == sync.src)
+    }
+}
+
+impl FromStr for SyncSelector {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.split_once(':') {
+            Some((project, sync)) => SyncSelector {
+                project: project.to_owned(),
+                sync: Some(sync.to_owned()),
+            },
+            None => SyncSelector {
+                project: s.to_owned(),
+                sync: None,
+            },
+        })
+    }
+}
+
+impl fmt::Display for SyncSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.sync.as_deref() {
+            Some(sync) => write!(f, "{}:{sync}", self.project),
+            None => f.write_str(&self.project),
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde_derive::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub projects: HashMap<ProjectName, Project>,
+    /// quiet period: changes are synced once no further changes arrived for this long
+    /// default=100ms
+    #[serde(default = "default_debounce", alias = "quiet_period")]
+    #[serde(deserialize_with = "duration_str::deserialize_duration")]
+    pub debounce: Duration,
+    /// upper bound of the wait since the first change of a burst, so that a constant stream of
+    /// changes doesn't delay the sync indefinitely
+    /// default=1s
+    #[serde(default = "default_max_wait")]
+    #[serde(deserialize_with = "duration_str::deserialize_duration")]
+    pub max_wait: Duration,
+    /// shell used to run hook commands, the command is passed as the last argument.
+    /// Can be overridden per project and per command
+    /// default=["sh", "-c"]
+    pub shell: Option<Vec<String>>,
+    /// write the output of each sync of `watch` to `<log_dir>/<project>-<sync>.log`, where sync
+    /// is the file name of its src. By default the output is printed, prefixed with
+    /// `[project:sync]`
+    pub log_dir: Option<PathBuf>,
+    /// copy the logs of atune to a file, rotated by size and age. The logging of the first
+    /// config applies, read when atune starts
+    pub logging: Option<Logging>,
+    /// address of the HTTP API of `watch`, e.g. `127.0.0.1:7700`: `GET /status`,
+    /// `GET /events` (server-sent events), `POST /trigger`, `POST /pause` and `POST /resume`,
+    /// optionally with `?project=name`. Requests with an `Origin` header, sent by web pages,
+    /// are refused. By default there is no API
+    pub api_addr: Option<String>,
+    /// token the clients of the API must pass as `Authorization: Bearer <token>`. Without it
+    /// the API has no authentication, bind it to localhost then
+    pub api_token: Option<Secret>,
+    /// webhooks to notify about sync events
+    #[serde(default)]
+    pub notifications: Vec<NotificationConfig>,
+    /// commands to run once `watch` started and the initial syncs finished
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_command_list")]
+    pub on_start: Vec<CommandConfig>,
+    /// commands to run when `watch` stops
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_command_list")]
+    pub on_stop: Vec<CommandConfig>,
+    /// share one ssh connection per remote host between the syncs, instead of a new handshake per
+    /// sync. `watch` keeps the master connections open while it runs
+    /// default=false
+    #[serde(default)]
+    pub ssh_multiplexing: bool,
+    /// how `watch` runs the syncs
+    /// default=Subprocess
+    #[serde(default)]
+    pub execution: Execution,
+    /// values of the `{{ name }}` placeholders in src, dst, rsync_flags, password_file and
+    /// commands. `project`, `hostname` and `date` (UTC, `YYYY-MM-DD`) are predefined.
+    /// Overridden by `--var name=value` on the command line
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// named sets of overrides, e.g. per environment, selected with `--profile` or `ATUNE_PROFILE`
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+    /// settings inherited by every project and sync that doesn't set its own
+    #[serde(default)]
+    pub defaults: Defaults,
+    /// named groups of hosts the syncs can fan out to, see [FileSync::hosts], and aliases of
+    /// hosts, see [HostAlias]
+    #[serde(default)]
+    pub hosts: HashMap<String, HostEntry>,
+}
+
+/// The src passed to rsync: with a trailing slash to copy the contents of a directory, without
+/// one to copy the directory itself. Files never get one
+fn normalize_trailing_slash(src: PathBuf, copy_contents: Option<bool>) -> PathBuf {
+    let s = src.as_os_str().to_string_lossy();
+    match s.strip_suffix('/') {
+        // the root has to keep its slash
+        Some(stripped) if copy_contents != Some(true) && !stripped.is_empty() => {
+            PathBuf::from(stripped.to_owned())
+        }
+        None if copy_contents == Some(true) && !src.is_file() => PathBuf::from(format!("{s}/")),
+        _ => src,
+    }
+}
+
+/// A copy of the logs of atune in a file, see `--log-file`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Logging {
+    /// The log file, overridden by `--log-file`
+    pub file: Option<PathBuf>,
+    /// Rotate the file once it would grow larger than this many MiB
+    /// default=10
+    #[serde(default = "default_log_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Rotate the file once it's older than this, e.g. `1d`. By default only its size counts
+    #[serde(
+        default,
+        deserialize_with = "duration_str::deserialize_option_duration"
+    )]
+    pub max_age: Option<Duration>,
+    /// Number of rotated files to keep, `<file>.1` being the newest
+    /// default=5
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+}
+
+fn default_log_max_size_mb() -> u64 {
+    10
+}
+
+fn default_log_keep() -> usize {
+    5
+}
+
+/// Settings inherited by the projects and syncs. `debounce`, `max_wait` and `shell` apply to
+/// every project at the top level of the config already
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    #[serde(default, deserialize_with = "deser_rsync_flags")]
+    pub rsync_flags: Option<String>,
+    #[serde(default, deserialize_with = "deser_rsync_flags")]
+    pub extra_rsync_flags: Option<String>,
+    pub restart: Option<bool>,
+    /// added to the ignore patterns of every sync
+    #[serde(default)]
+    pub ignore_patterns: Vec<String>,
+    /// environment variables of every command, the variables of a command take precedence
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// see [FileSync::nice]
+    pub nice: Option<i32>,
+    /// see [FileSync::ionice]
+    pub ionice: Option<crate::niceness::IoNice>,
+    /// see [FileSync::cpus]
+    pub cpus: Option<crate::niceness::CpuSet>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Execution {
+    /// Every sync runs in a new `atune sync-project` process, which reads the config again
+    #[default]
+    #[serde(alias = "subprocess")]
+    Subprocess,
+    /// The syncs run on worker threads of `watch`, with the config read at its start. Running
+    /// processes, e.g. rsync, are killed when their sync is cancelled
+    #[serde(alias = "in-process")]
+    InProcess,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// merged into the `variables` of the config
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// overrides of the syncs, keyed by `project` or `project:sync` like `--only`
+    #[serde(default)]
+    pub overrides: HashMap<String, SyncOverride>,
+}
+
+/// Fields of the matching syncs replaced by a profile
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncOverride {
+    #[serde(default, deserialize_with = "deser_dst")]
+    pub dst: Option<PathBuf>,
+    #[serde(default, deserialize_with = "deser_rsync_flags")]
+    pub rsync_flags: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_opt_command_list")]
+    pub on_sync: Option<Vec<CommandConfig>>,
+}
+
+fn deser_opt_command_list<'de, D>(deserializer: D) -> Result<Option<Vec<CommandConfig>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deser_command_list(deserializer).map(Some)
+}
+
+fn expand_path(path: &mut PathBuf, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+    if let Some(s) = path.to_str() {
+        *path = PathBuf::from(crate::template::expand(s, lookup)?);
+    }
+    Ok(())
+}
+
+/// Keep the `{{ host }}` placeholder of a sync fanning out to hosts, it's replaced per host by
+/// [FileSync::destinations]
+fn keep_host(
+    s: &FileSync,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> impl Fn(&str) -> Option<String> {
+    let fan_out = s.hosts.is_some();
+    move |name| match name {
+        "host" if fan_out => Some("{{ host }}".to_owned()),
+        _ => lookup(name),
+    }
+}
+
+fn expand_matches(s: &mut FileSync, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+    if let Some(dst) = s.dst.as_mut() {
+        expand_path(dst, &lookup)?;
+    }
+    if let Some(f) = s.password_file.as_mut() {
+        expand_path(f, &lookup)?;
+    }
+    for flags in s
+        .rsync_flags
+        .iter_mut()
+        .chain(s.extra_rsync_flags.iter_mut())
+    {
+        *flags = crate::template::expand(flags, &lookup)?;
+    }
+    for c in s.on_sync.iter_mut() {
+        expand_command(c, &lookup)?;
+    }
+    Ok(())
+}
+
+fn expand_command(
+    cmd: &mut CommandConfig,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<()> {
+    cmd.command = crate::template::expand(&cmd.command, &lookup)?;
+    if let Some(cwd) = cmd.cwd.as_mut() {
+        expand_path(cwd, &lookup)?;
+    }
+    for value in cmd.env.values_mut() {
+        *value = crate::template::expand(value, &lookup)?;
+    }
+    Ok(())
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            projects: Default::default(),
+            debounce: default_debounce(),
+            max_wait: default_max_wait(),
+            shell: None,
+            log_dir: None,
+            logging: None,
+            api_addr: None,
+            api_token: None,
+            notifications: Default::default(),
+            on_start: Default::default(),
+            on_stop: Default::default(),
+            ssh_multiplexing: false,
+            execution: Default::default(),
+            variables: Default::default(),
+            profiles: Default::default(),
+            defaults: Default::default(),
+            hosts: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationConfig {
+    pub url: String,
+    /// Shape of the POSTed payload
+    /// default=Generic
+    #[serde(default)]
+    pub kind: NotificationKind,
+    /// Events to send. If omitted, then all events are sent
+    #[serde(default = "default_notification_events")]
+    pub on: Vec<NotificationEvent>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum NotificationKind {
+    /// JSON object with the event, project, src, dst, duration, error, changed and deleted fields
+    #[default]
+    Generic,
+    Slack,
+    Discord,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum NotificationEvent {
+    Start,
+    Success,
+    Failure,
+}
+
+impl NotificationEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NotificationEvent::Start => "start",
+            NotificationEvent::Success => "success",
+            NotificationEvent::Failure => "failure",
+        }
+    }
+}
+
+fn default_notification_events() -> Vec<NotificationEvent> {
+    vec![
+        NotificationEvent::Start,
+        NotificationEvent::Success,
+        NotificationEvent::Failure,
+    ]
+}
+
+#[derive(Default, Debug, Clone, serde_derive::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Project {
+    pub sync: Vec<FileSync>,
+    /// cancel the in-progress sync of an entry if a new change to the same entry happens while
+    /// it is running. If false, the change is queued and synced once the running sync finishes
+    /// default=true
+    pub restart: Option<bool>,
+    /// shell used to run the hook commands of this project
+    pub shell: Option<Vec<String>>,
+    /// rsync executable of the syncs of this project, overrides `--rsync`
+    pub rsync: Option<PathBuf>,
+    /// ssh options for the remote destinations of this project, e.g. a jump host. Used by rsync
+    /// unless rsync_flags set `-e`, and by everything else atune runs over ssh
+    pub ssh_opts: Option<SshOptions>,
+    /// long-running commands (e.g. a dev server) started after the initial sync and restarted
+    /// whenever a sync completes. Only the command, env, cwd and shell fields are used
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_command_list")]
+    pub run: Vec<CommandConfig>,
+    /// commands run once after a batch of syncs of this project completed, instead of once per
+    /// sync entry. `on: Init` commands run once the initial syncs of the project succeeded,
+    /// `on: Delete` commands if the batch deleted files
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_command_list")]
+    pub on_sync: Vec<CommandConfig>,
+    /// projects whose initial sync must succeed before the initial sync of this project starts
+    #[serde(default)]
+    pub depends_on: Vec<ProjectName>,
+    /// labels selecting the project with `--tags`, e.g. `[frontend, deploy]`. Inherited by its
+    /// syncs
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// filesystem watcher backend
+    /// default=Auto
+    #[serde(default)]
+    pub watcher: WatcherKind,
+    /// how often the Poll watcher scans the files, and the Shallow watcher the directories
+    /// default=1s
+    #[serde(default = "default_poll_interval")]
+    #[serde(deserialize_with = "duration_str::deserialize_duration")]
+    pub poll_interval: Duration,
+    /// commands checking that the destinations are reachable, e.g. `ssh host true`, before
+    /// `watch` starts the syncs of the project. While they fail, the changes are queued and the
+    /// check is retried every healthcheck_interval, then the queue is synced once they succeed
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_command_list")]
+    pub healthcheck: Vec<CommandConfig>,
+    /// how often the healthcheck is retried while failing, and how long a success is trusted
+    /// default=10s
+    #[serde(default = "default_healthcheck_interval")]
+    #[serde(deserialize_with = "duration_str::deserialize_duration")]
+    pub healthcheck_interval: Duration,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum WatcherKind {
+    /// Use the native watcher, falling back to polling if it can't be initialized
+    #[default]
+    Auto,
+    /// The platform's native watcher, e.g. inotify on Linux
+    #[serde(alias = "Inotify")]
+    Native,
+    /// Scan the files periodically. Works on network and container volumes where the native
+    /// watcher receives no events
+    Poll,
+    /// For huge trees: natively watch only src and its top-level directories, and find changes
+    /// further down by hashing the mtimes of the directories every `poll_interval`. A change
+    /// below a top-level directory syncs that directory. Only adding, removing and renaming
+    /// entries changes the mtime of a directory, so in place writes of existing files are missed
+    /// deeper down, saves of editors replacing the file are not
+    Shallow,
+    /// Don't watch the filesystem. Changes are only synced when reported by
+    /// `atune notify-change`, e.g. from an editor on save, or triggered through the API
+    Manual,
+}
+
+fn default_poll_interval() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_healthcheck_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_debounce() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_max_wait() -> Duration {
+    Duration::from_secs(1)
+}
+
+#[derive(Default, Debug, Clone, serde_derive::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSync {
+    /// wether this sync is enabled. if disabled, then this sync is ignored
+    /// default=true
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// labels selecting the sync with `--tags`, in addition to the tags of its project
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// may contain `*` and `?` wildcards, e.g. `packages/*/dist`, matching within a path
+    /// component. The sync is repeated for every matching path when the config is loaded, with
+    /// `{{ match.0 }}` being the path and `{{ match.N }}` the name matched by the Nth component
+    /// with wildcards
+    pub src: PathBuf,
+    /// Copy the contents of the src directory into dst, like a trailing slash of src does for
+    /// rsync, instead of the directory itself. atune resolves src, dropping a trailing slash, so
+    /// this decides. If unset, then the directory is copied into dst
+    pub copy_contents: Option<bool>,
+    /// Watch src recursively. If src is a file then this flag is ignored. Otherwise only the
+    /// entries of src, and of the directories created in it while watching, are watched
+    /// default=true
+    #[serde(default = "default_true")]
+    pub recursive: bool,
+    /// How symbolic links in src are handled, by both the watcher and the transfer.
+    /// `Copy` transfers them as links, `Follow` transfers what they point to and watches the
+    /// linked directories, `Skip` ignores them. If omitted, then it's up to rsync_flags, and the
+    /// watcher follows links
+    pub symlinks: Option<SymlinkPolicy>,
+    /// If omitted, then no sync is performed, only the commands are run.
+    /// Local paths, `[user@]host:path` over ssh, and rsync daemon destinations
+    /// `rsync://host[:port]/module/path` or `host::module/path` are supported. `{host: <name>,
+    /// path: ...}` syncs to a host of the top-level `hosts`, see [HostAlias]
+    #[serde(default, deserialize_with = "deser_dst")]
+    pub dst: Option<PathBuf>,
+    /// Sync to every host of a list, or of a group of the top-level `hosts`, in parallel. The
+    /// `{{ host }}` placeholder of dst is replaced by each host, e.g. `{{ host }}:/srv/app/`.
+    /// The sync fails if any of them failed, and its commands run once all of them finished, with
+    /// the destinations in `ATUNE_SYNC_DSTS` separated by newlines
+    #[serde(default, deserialize_with = "deser_hosts")]
+    pub hosts: Option<Hosts>,
+    /// File holding the password of an rsync daemon destination, passed as `--password-file`
+    pub password_file: Option<PathBuf>,
+    /// Environment variable holding the password of an rsync daemon destination.
+    /// Passed to rsync as `RSYNC_PASSWORD`
+    pub password_env: Option<String>,
+    /// Password of an rsync daemon destination, resolved when syncing and passed to rsync as
+    /// `RSYNC_PASSWORD`, e.g. `{Command: "pass show rsync/backup"}`
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub password: Option<Secret>,
+    /// Flags of rsync, replacing the defaults, as a string split like a shell would or a list,
+    /// e.g. `[-av, --delete]`. See `atune rsync-args` for the defaults
+    #[serde(default, deserialize_with = "deser_rsync_flags")]
+    pub rsync_flags: Option<String>,
+    /// Flags appended to `rsync_flags` or the defaults, e.g. `[--chmod=D755,F644]`
+    #[serde(default, deserialize_with = "deser_rsync_flags")]
+    pub extra_rsync_flags: Option<String>,
+    /// Program used to transfer the files
+    /// default=Rsync
+    #[serde(default)]
+    pub backend: SyncBackend,
+    /// Only pass the changed files to rsync using `--files-from`, instead of scanning the whole
+    /// src. Falls back to a full sync on initialization and when files were deleted. Renames
+    /// are synced as the deletion of the old path and the transfer of the new one, local dsts
+    /// repeat them in place
+    /// default=false
+    #[serde(default)]
+    pub partial: bool,
+    /// Keep a manifest of the synced files and their sizes, modification times and hashes.
+    /// On changes only the watched paths are compared to it, and the differences are passed to
+    /// rsync using `--files-from`, skipping rsync's scan of the whole tree. Deletions are
+    /// propagated with `--delete-missing-args`. Intended for very large trees
+    /// default=false
+    #[serde(default)]
+    pub manifest: bool,
+    /// Bandwidth limit of the transfer, passed to rsync as `--bwlimit`, e.g. `500` (KiB/s) or `2m`
+    pub bwlimit: Option<String>,
+    /// Before syncing, check with a dry run of rsync whether dst has changes that didn't come
+    /// from atune: files rsync would delete or overwrite without a change of them in src. If so,
+    /// the sync is refused and retried with the next change, so they can be rescued first.
+    /// Without known changes, e.g. on the initial sync, only the deletions are checked
+    /// default=false
+    #[serde(default)]
+    pub protect_dst: bool,
+    /// Before a full transfer, e.g. the initial sync, count the files a dry run of rsync would
+    /// delete in dst. Above `confirm_delete_threshold` the sync asks for a confirmation in the
+    /// terminal, and is refused without one, e.g. in the background. `--confirm` enables it for
+    /// every sync, `--yes` confirms everything
+    /// default=false
+    #[serde(default)]
+    pub confirm_delete: bool,
+    /// Deletions needing a confirmation: more than a number of files, e.g. `100`, or than a
+    /// percentage of the files in dst, e.g. `20%`
+    /// default=20%
+    #[serde(default)]
+    pub confirm_delete_threshold: crate::confirm::DeleteThreshold,
+    /// Abort syncs deleting more files in dst: full transfers, e.g. the initial sync, are counted
+    /// with a dry run of rsync first and fail without deleting anything, and rsync is passed
+    /// `--max-delete`, stopping partial transfers at the limit. The on_failure commands run then
+    pub max_delete: Option<u64>,
+    /// Skip files larger than this, e.g. a disk image saved into the tree by accident, passed to
+    /// rsync as `--max-size`. Units are powers of 1024, e.g. `500k`, `100M` or `2G`. The
+    /// skipped files among the changes are listed in a warning
+    pub max_file_size: Option<crate::limits::FileSize>,
+    /// Warn when a sync involves more changed or deleted files, which usually means that a
+    /// filter is missing, e.g. for a build directory
+    pub warn_file_count: Option<u64>,
+    /// Command starting the agent of the Agent backend on the host of dst, which is passed
+    /// `agent <path>`
+    /// default=atune
+    pub agent: Option<String>,
+    /// After syncing, check that dst is an exact copy of src: with a dry run of rsync with the
+    /// flags of the sync plus `--checksum --delete`, or by comparing the bytes of the files with
+    /// the Copy backend. If they differ, e.g. because files changed in dst during the sync or the
+    /// flags don't delete extra files, then the sync fails listing the differing paths
+    /// default=false
+    #[serde(default)]
+    pub verify: bool,
+    /// Sync into a staging directory next to the copy of src in dst, hard linking the unchanged
+    /// files of the copy, and swap it into place once complete, so readers of dst never see a
+    /// half synced copy. Local dsts are swapped in a single rename on Linux, others with two
+    /// renames in quick succession. Needs the Rsync backend and a local or ssh destination
+    /// default=false
+    #[serde(default)]
+    pub atomic: bool,
+    /// Create dst and its parents before the initial sync if they're missing, locally or with
+    /// `mkdir -p` over ssh, as rsync only creates the last directory of dst
+    /// default=false
+    #[serde(default)]
+    pub create_dst: bool,
+    /// Change the permissions of the synced files, e.g. `D755,F644` or `Dg+s,ug+w,Fo-w`, passed to
+    /// rsync as `--chmod`. The Copy backend applies them itself on unix
+    pub chmod: Option<crate::perms::Chmod>,
+    /// Owner of the synced files, `user`, `user:group` or `:group`, passed to rsync as `--chown`.
+    /// The Copy backend applies it itself on unix. Usually needs root on the receiving side
+    pub chown: Option<crate::perms::Chown>,
+    /// Owner enforced on the copy of src in dst after every sync, `user`, `user:group` or
+    /// `:group`, e.g. for the user of a service reading the files. Changed with `chown -R` over
+    /// ssh for remote destinations, also fixing files created by something else than the sync
+    pub dst_owner: Option<crate::perms::Chown>,
+    /// Permissions enforced on the copy of src in dst after every sync, rules like the ones of
+    /// chmod, e.g. `D755,F644`. Changed with `chmod` over ssh for remote destinations
+    pub dst_mode: Option<crate::perms::Chmod>,
+    /// Keep the numeric user and group ids instead of mapping them by name, rsync's
+    /// `--numeric-ids`
+    /// default=false
+    #[serde(default)]
+    pub numeric_ids: bool,
+    /// Tune the transfer for `Code`, `Assets` or `LargeFiles`. Adds rsync flags after
+    /// rsync_flags, so extra_rsync_flags can override them, and raises the debounce of the
+    /// project, see [TransferProfile]
+    pub transfer_profile: Option<TransferProfile>,
+    /// Compress the transfer: `On`, `Off`, or `Auto` to compress remote transfers only. zstd is
+    /// used if the rsync on both ends supports it, the support of remote hosts is checked over
+    /// ssh once a day. If unset, then rsync_flags decide
+    pub compress: Option<Compress>,
+    /// When multiple syncs of the project are pending, then the ones with higher priority run
+    /// first. Lower priority syncs wait until the higher priority ones finished
+    /// default=0
+    #[serde(default)]
+    pub priority: i32,
+    /// Start a sync of this entry at most once per this interval, e.g. `30s`, so a file written
+    /// continuously, like a log in the tree, can't keep it syncing. The changes in between are
+    /// collected into the next sync. By default the debounce alone decides. Only used by `watch`
+    #[serde(
+        default,
+        deserialize_with = "duration_str::deserialize_option_duration"
+    )]
+    pub min_interval: Option<Duration>,
+    /// Niceness of rsync and the hooks of the entry, from -20 (highest priority) to 19, e.g. 10
+    /// so a large sync doesn't slow down the other work on the machine. Raising the priority
+    /// above that of atune needs privileges. Inherits [Defaults::nice]
+    pub nice: Option<i32>,
+    /// IO priority of rsync and the hooks, like `ionice`: `idle`, `best-effort` or `realtime`,
+    /// optionally with a level from 0 to 7, e.g. `best-effort:7`. Linux only. Inherits
+    /// [Defaults::ionice]
+    pub ionice: Option<crate::niceness::IoNice>,
+    /// CPUs rsync and the hooks may run on, like `taskset -c`, e.g. `0-3,6`. Linux only.
+    /// Inherits [Defaults::cpus]
+    pub cpus: Option<crate::niceness::CpuSet>,
+    /// Syncs of the same lock group never run at the same time, even across projects, e.g. the
+    /// entries of several projects writing to the same remote directory. The others wait until
+    /// the running one finished. Coordinated by `watch`
+    pub lock_group: Option<String>,
+    /// Entries of the same share group, e.g. the same node_modules synced by several projects,
+    /// reuse each other's copies on the same host: they are passed to rsync as `--copy-dest`, so
+    /// files already there are copied on the receiving side, and the others are transferred as a
+    /// delta against them. The content hashes of src are cached in the state directory, so the
+    /// files shared by the entries are read once. Remote dsts must be absolute paths
+    pub share: Option<String>,
+    /// Seed a copy of the share group missing on its host from a copy with the same content on
+    /// another host, with rsync between the hosts instead of transferring it from this machine,
+    /// e.g. to push node_modules over a slow uplink once. The normal sync follows and transfers
+    /// what differs. Needs `share`, a directory src and ssh access from the hosts of the dsts to
+    /// each other. default=false
+    #[serde(default)]
+    pub share_relay: bool,
+    /// The copies of src in the dsts of the other entries of the share group, resolved by
+    /// [Config::load]
+    #[serde(skip)]
+    pub shared_copies: Vec<PathBuf>,
+    /// Arguments of ssh connecting to the hosts of the dsts, from their [HostAlias] and
+    /// [Project::ssh_opts], resolved by [Config::parse]
+    #[serde(skip)]
+    pub ssh_args: BTreeMap<String, Vec<String>>,
+    /// Whether `watch` syncs the entry, running its `on: Init` commands, when it starts
+    /// default=Always
+    #[serde(default)]
+    pub initial_sync: InitialSync,
+    /// When an initial sync runs the `on: Init` commands, e.g. to only seed a database or create
+    /// a venv the first time a destination is set up. DstMissing runs them if the copy of src
+    /// in dst doesn't exist before the sync, StateMissing if the state file has no successful
+    /// initialization of the entry
+    /// default=Always
+    #[serde(default)]
+    pub on_init_when: OnInitWhen,
+    /// Sync the entry on a schedule instead of on changes: an interval, e.g. `30m`, or a cron
+    /// expression in local time, e.g. `0 3 * * *`. Its src isn't watched. Only used by `watch`
+    pub schedule: Option<crate::schedule::Schedule>,
+    /// Only react to changes of files with these extensions, e.g. `[py, html]`. Directories are
+    /// always watched. If empty, then all files are
+    #[serde(default)]
+    pub include_extensions: Vec<String>,
+    /// Ignore changes of files with these extensions
+    #[serde(default)]
+    pub exclude_extensions: Vec<String>,
+    /// Ignore changes of paths matching these globs, e.g. `["*.swp", ".#*", "*~"]`. Patterns
+    /// without a `/` match any component of the path, others the path relative to src.
+    /// Only filters the events, the files are still synced along with other changes
+    #[serde(default)]
+    pub ignore_patterns: Vec<String>,
+    /// Only sync the paths matching these globs, e.g. `["*.py", "static/**"]`, and the
+    /// directories leading to them. Patterns without a `/` match the name of any component of
+    /// the path, others the path relative to src. Added to the rsync flags as `--include` rules
+    /// followed by `--exclude=*`, after rsync_flags, and applied to the events. The Copy backend
+    /// applies them too, the Agent backend doesn't support them
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Don't sync the paths matching these globs, e.g. `["target", "*.log"]`, matched like
+    /// include and taking precedence over it. Added to the rsync flags as `--exclude` rules and
+    /// applied to the events
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Keep the files of dst a sync deletes or overwrites, so they can be recovered with
+    /// `atune restore`
+    pub backup: Option<Backup>,
+    /// How dst is updated
+    /// default=Mirror
+    #[serde(default)]
+    pub mode: SyncMode,
+    /// Number of releases kept in Snapshot mode, including the current one
+    /// default=5
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    /// commands to run after sync.
+    /// The files changed since the last sync are passed in the `ATUNE_CHANGED_FILES` environment
+    /// variable separated by newlines, and in the file at `ATUNE_CHANGED_FILES_LIST`. Under
+    /// `watch`, `ATUNE_CHANGE_ID` is the `change_id` logged since the first change was seen.
+    /// A command can pass values to the following commands of the sync by writing `KEY=value`
+    /// lines, or `KEY<<DELIMITER` followed by lines up to `DELIMITER` for multiline values, to
+    /// the file at `ATUNE_OUTPUT`. They are set as environment variables of the following commands
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_command_list")]
+    pub on_sync: Vec<CommandConfig>,
+    /// Watch a local dst for changes made by something other than atune, e.g. a container it's
+    /// mounted into. `Warn` logs them and runs the on_drift commands, `Resync` also syncs the
+    /// entry again, restoring the mirror. Only used by `watch`. By default dst isn't watched
+    pub watch_dst: Option<WatchDst>,
+    /// commands to run when a watched dst changed, with the changed paths of dst in the
+    /// `ATUNE_DRIFTED_PATHS` environment variable separated by newlines
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_command_list")]
+    pub on_drift: Vec<CommandConfig>,
+    /// commands to run when a sync failed, e.g. aborted by `max_delete`, with the kind of the
+    /// failure in the `ATUNE_SYNC_ERROR` environment variable. Only used by `watch`
+    #[serde(default)]
+    #[serde(deserialize_with = "deser_command_list")]
+    pub on_failure: Vec<CommandConfig>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandConfig {
+    /// Run by the shell, or `plugin:<name> [args]` running the executable `atune-<name>` found on
+    /// the PATH, see [crate::plugin], or `lua:<file>` running a Lua script, see [crate::script]
+    pub command: String,
+    #[serde(default)]
+    pub on: CommandOn,
+    /// Run the following commands even if this one fails, and don't count its failure as a
+    /// failure of the sync
+    /// default=false
+    #[serde(default)]
+    pub continue_on_failure: bool,
+    /// Shell condition, run like the command. If it fails, then the command is skipped, e.g.
+    /// `test -f package.json`
+    pub run_if: Option<String>,
+    /// Whether the command of a sync entry runs in the initial sync, the syncs of changes, or
+    /// both
+    /// default=Both
+    #[serde(default)]
+    pub only_on: OnlyOn,
+    /// extra environment variables set for the command
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// working directory of the command. If omitted, then atune's working directory is used
+    pub cwd: Option<PathBuf>,
+    /// shell used to run this command
+    pub shell: Option<Vec<String>>,
+    /// Where the command runs. `Remote` runs it on the host of the `[user@]host:path` dst of the
+    /// sync entry, by the login shell of the host over ssh. The commands share a master
+    /// connection to the host that `watch` keeps open, instead of connecting for every command.
+    /// The environment variables of the hook are exported to it, with `ATUNE_SYNC_DST` set to the
+    /// path on the host, and its output is recorded in the history like a local command's.
+    /// cwd is a directory on the host then, run_if is still checked locally, and
+    /// `ATUNE_CHANGED_FILES_LIST` and `ATUNE_OUTPUT` aren't available
+    /// default=Local
+    #[serde(rename = "where")]
+    pub location: Option<CommandLocation>,
+    /// Shorthand for `where: Remote`
+    /// default=false
+    #[serde(default)]
+    pub remote: bool,
+}
+
+impl CommandConfig {
+    /// Whether the command runs on the host of dst, see [CommandConfig::location]
+    pub fn is_remote(&self) -> bool {
+        self.location
+            .map_or(self.remote, |l| l == CommandLocation::Remote)
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum InitialSync {
+    #[default]
+    #[serde(alias = "always")]
+    Always,
+    /// Skip the initial sync if the entry was initialized before and is unchanged since its last
+    /// successful sync, according to the state file. If it changed, then it is synced without
+    /// running the `on: Init` commands again
+    #[serde(alias = "if-needed")]
+    IfNeeded,
+    /// Only sync on changes, the `on: Init` commands never run
+    #[serde(alias = "never")]
+    Never,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum OnInitWhen {
+    #[default]
+    #[serde(alias = "always")]
+    Always,
+    #[serde(alias = "dst-missing")]
+    DstMissing,
+    #[serde(alias = "state-missing")]
+    StateMissing,
+}
+
+/// What `watch` does when a local dst changed outside of atune, see [FileSync::watch_dst]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum WatchDst {
+    #[serde(alias = "warn")]
+    Warn,
+    #[serde(alias = "resync")]
+    Resync,
+}
+
+/// Bundles of rsync flags and debounce settings for the kind of files a sync transfers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TransferProfile {
+    /// Many small text files: compressed delta transfers
+    #[serde(alias = "code")]
+    Code,
+    /// Binary files that are often compressed already: whole files without compression. Syncs
+    /// wait for at least 1s of quiet, as exporters write many files at once
+    #[serde(alias = "assets")]
+    Assets,
+    /// Large files that take a while to write: whole files without compression, interrupted
+    /// transfers resume in place. Syncs wait for at least 5s of quiet, and up to 30s
+    #[serde(alias = "large-files")]
+    LargeFiles,
+}
+
+impl TransferProfile {
+    pub fn rsync_flags(self) -> &'static [&'static str] {
+        match self {
+            TransferProfile::Code => &["--compress"],
+            TransferProfile::Assets => &["--whole-file", "--no-compress"],
+            TransferProfile::LargeFiles => {
+                &["--partial", "--inplace", "--whole-file", "--no-compress"]
+            }
+        }
+    }
+
+    /// Lower bounds of the quiet period and the max wait of the debounce
+    pub fn min_debounce(self) -> (Duration, Duration) {
+        match self {
+            TransferProfile::Code => (Duration::ZERO, Duration::ZERO),
+            TransferProfile::Assets => (Duration::from_secs(1), Duration::from_secs(5)),
+            TransferProfile::LargeFiles => (Duration::from_secs(5), Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Compression of rsync transfers, see [FileSync::compress]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Compress {
+    /// Compress transfers to remote hosts and rsync daemons, not local ones
+    #[serde(alias = "auto")]
+    Auto,
+    #[serde(alias = "on")]
+    On,
+    #[serde(alias = "off")]
+    Off,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum SyncMode {
+    /// dst is updated in place
+    #[default]
+    Mirror,
+    /// Every sync goes into a new `releases/<timestamp>` directory of dst, unchanged files are
+    /// hard linked to the previous release. Once complete, the `current` symlink of dst is
+    /// switched to it atomically, and the releases exceeding `keep` are removed
+    Snapshot,
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Backup {
+    /// Directory of the backups, relative to dst. Every sync that changes dst backs up into a
+    /// new subdirectory named after its UTC start time, e.g. `20240131T120000Z`
+    /// default=.atune-backup
+    #[serde(default = "default_backup_dir")]
+    pub dir: PathBuf,
+    /// Number of backups to keep, the oldest ones are removed after a sync
+    pub keep: Option<usize>,
+    /// Remove backups older than this after a sync, e.g. `7d`
+    #[serde(
+        default,
+        deserialize_with = "duration_str::deserialize_option_duration"
+    )]
+    pub max_age: Option<Duration>,
+}
+
+fn default_backup_dir() -> PathBuf {
+    PathBuf::from(".atune-backup")
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub enum SyncBackend {
+    #[default]
+    Rsync,
+    /// Built-in copy for local destinations, for systems without rsync.
+    /// Mirrors src into dst, deleting extraneous files. rsync_flags are ignored, except the
+    /// `--filter ':- .gitignore'` rule of the default flags, which skips the ignored files
+    Copy,
+    /// Sends the changed files to `atune agent` running on the host of dst, started over ssh for
+    /// `host:path` destinations, over a connection kept open between syncs instead of a new
+    /// rsync and ssh process per change. The connection persists with `execution: InProcess`,
+    /// `sync-project` processes open one per sync. Files are compared by size and modification
+    /// time, rsync_flags are ignored
+    Agent,
+    /// A backend registered by a library user, see [crate::backend::register]
+    Custom(String),
+}
+
+impl<'de> serde::Deserialize<'de> for SyncBackend {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        Ok(match name.as_str() {
+            "Rsync" => SyncBackend::Rsync,
+            "Copy" => SyncBackend::Copy,
+            "Agent" => SyncBackend::Agent,
+            _ => SyncBackend::Custom(name),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum SymlinkPolicy {
+    Copy,
+    Follow,
+    Skip,
+}
+
+impl SymlinkPolicy {
+    /// rsync flag implementing the policy, overriding the links flags of rsync_flags
+    pub fn rsync_flag(self) -> &'static str {
+        match self {
+            SymlinkPolicy::Copy => "--links",
+            SymlinkPolicy::Follow => "--copy-links",
+            SymlinkPolicy::Skip => "--no-links",
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+pub enum CommandOn {
+    #[default]
+    Change,
+    /// Only run the given command at initialization
+    Init,
+    /// Only run the given command if files were deleted since the last sync.
+    /// The deleted paths are passed in the `ATUNE_DELETED_PATHS` environment variable,
+    /// separated by newlines
+    Delete,
+}
+
+/// Options of the ssh connections to a host, see [Project::ssh_opts]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SshOptions {
+    /// Hosts to connect through, passed as `-J`, e.g. `bastion` or `admin@bastion:2222`
+    pub proxy_jump: Option<String>,
+    pub port: Option<u16>,
+    pub identity_file: Option<PathBuf>,
+    /// Other options, passed as `-o Key=Value`
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+}
+
+impl SshOptions {
+    /// Arguments of ssh setting the options
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(jump) = self.proxy_jump.as_ref() {
+            args.extend(["-J".to_owned(), jump.clone()]);
+        }
+        if let Some(port) = self.port {
+            args.extend(["-p".to_owned(), port.to_string()]);
+        }
+        if let Some(file) = self.identity_file.as_ref() {
+            args.extend(["-i".to_owned(), file.display().to_string()]);
+        }
+        for (k, v) in self.options.iter() {
+            args.extend(["-o".to_owned(), format!("{k}={v}")]);
+        }
+        args
+    }
+}
+
+/// Where a command runs, see [CommandConfig::location]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum CommandLocation {
+    #[serde(alias = "local")]
+    Local,
+    /// On the host of dst
+    #[serde(alias = "remote")]
+    Remote,
+}
+
+/// Which syncs of an entry run a command, see [CommandConfig::only_on]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum OnlyOn {
+    #[default]
+    #[serde(alias = "both")]
+    Both,
+    /// Only the initial sync, when the `on: Init` commands run too
+    #[serde(alias = "init")]
+    Init,
+    /// Only the syncs of changes after the initial one
+    #[serde(alias = "change")]
+    Change,
+}
+
+impl OnlyOn {
+    /// Whether the command runs in an initial sync, if `initialize`, or the sync of changes
+    pub fn runs(self, initialize: bool) -> bool {
+        match self {
+            OnlyOn::Both => true,
+            OnlyOn::Init => initialize,
+            OnlyOn::Change => !initialize,
+        }
+    }
+}
+
+impl FromStr for CommandConfig {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(CommandConfig {
+            command: s.to_owned(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Reference to a secret, resolved only when it's needed, so the config can be shared
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub enum Secret {
+    /// environment variable holding the secret
+    Env(String),
+    /// file holding the secret, the trailing newline is removed
+    File(PathBuf),
+    /// command printing the secret, run in the default shell, e.g. `pass show rsync/backup`
+    Command(String),
+}
+
+impl Secret {
+    /// Read the secret. Errors don't include the value
+    pub fn resolve(&self) -> anyhow::Result<String> {
+        let value = match self {
+            Secret::Env(var) => std::env::var(var)
+                .with_context(|| format!("The secret environment variable {var} is not set"))?,
+            Secret::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the secret file {}", path.display()))?,
+            Secret::Command(command) => {
+                let shell = default_shell();
+                let out = std::process::Command::new(&shell[0])
+                    .args(&shell[1..])
+                    .arg(command)
+                    .stdin(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::inherit())
+                    .output()
+                    .with_context(|| format!("Failed to run the secret command {command:?}"))?;
+                anyhow::ensure!(
+                    out.status.success(),
+                    "The secret command {command:?} failed with {}",
+                    out.status
+                );
+                String::from_utf8(out.stdout).with_context(|| {
+                    format!("The secret command {command:?} printed invalid UTF-8")
+                })?
+            }
+        };
+        Ok(value.trim_end_matches(['\r', '\n']).to_owned())
+    }
+}
+
+pub fn default_shell() -> Vec<String> {
+    if cfg!(windows) {
+        vec!["cmd".to_owned(), "/C".to_owned()]
+    } else {
+        vec!["sh".to_owned(), "-c".to_owned()]
+    }
+}
+
+/// Split an rsync style remote destination `[user@]host:path` into the host and path
+///
+/// Returns `None` for local paths, including Windows drive letters, and for rsync daemon
+/// destinations (`host::module`, `rsync://host/module`)
+pub fn remote_dst(dst: &Path) -> Option<(&str, &str)> {
+    let dst = dst.to_str()?;
+    if dst.starts_with("rsync://") {
+        return None;
+    }
+    let (host, path) = dst.split_once(':')?;
+    if host.is_empty() || host.contains(['/', '\\']) || path.starts_with(':') {
+        return None;
+    }
+    if host.len() == 1 && host.chars().all(|c| c.is_ascii_alphabetic()) {
+        // drive letter
+        return None;
+    }
+    Some((host, path))
+}
+
+/// Arguments of ssh connecting to the host of `dst` in `ssh_args`, see [FileSync::ssh_args].
+/// None for local dsts
+pub fn ssh_args_of<'a>(ssh_args: &'a BTreeMap<String, Vec<String>>, dst: &Path) -> &'a [String] {
+    remote_dst(dst)
+        .and_then(|(host, _)| ssh_args.get(host))
+        .map_or(&[], Vec::as_slice)
+}
+
+/// Split an rsync daemon destination `rsync://host[:port]/module/path` or `host::module/path`
+/// into the host and the module
+pub fn rsync_daemon_dst(dst: &Path) -> Option<(&str, &str)> {
+    let dst = dst.to_str()?;
+    let (host, rest) = match dst.strip_prefix("rsync://") {
+        Some(url) => url.split_once('/').unwrap_or((url, "")),
+        None => dst.split_once("::")?,
+    };
+    if host.is_empty() || host.contains(['/', '\\']) {
+        return None;
+    }
+    let module = rest.split('/').next().unwrap_or_default();
+    Some((host, module))
+}
+
+fn default_true() -> bool {
+    true
+}
+
+struct CommandConfigDe(pub CommandConfig);
+
+impl<'de> Deserialize<'de> for CommandConfigDe {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct CommandVisitor;
+        impl<'de> Visitor<'de> for CommandVisitor {
+            type Value = CommandConfigDe;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("string or map")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<CommandConfigDe, E>
+            where
+                E: de::Error,
+            {
+                Ok(CommandConfigDe(FromStr::from_str(value).unwrap()))
+            }
+
+            fn visit_map<M>(self, map: M) -> Result<CommandConfigDe, M::Error>
+            where
+                M: MapAccess<'de>,
+            {
+                Deserialize::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(CommandConfigDe)
+            }
+        }
+
+        deserializer.deserialize_any(CommandVisitor)
+    }
+}
+
+fn deser_command_list<'de, D>(deserializer: D) -> Result<Vec<CommandConfig>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct V;
+    impl<'de> Visitor<'de> for V {
+        type Value = Vec<CommandConfig>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("List of CommandConfigs")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            let mut v = Vec::with_capacity(seq.size_hint().unwrap_or(1));
+            while let Some(CommandConfigDe(c)) = seq.next_element()? {
+                v.push(c);
+            }
+            Ok(v)
+        }
+    }
+
+    deserializer.deserialize_any(V)
+}
+
+/// rsync flags given as a string or as a list, the list is joined into a string that splits
+/// back into its items
+fn deser_rsync_flags<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct V;
+    impl<'de> Visitor<'de> for V {
+        type Value = Option<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("rsync flags as a string or a list of strings")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Some(v.to_owned()))
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(None)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            let mut flags = Vec::<String>::with_capacity(seq.size_hint().unwrap_or(4));
+            while let Some(flag) = seq.next_element()? {
+                flags.push(flag);
+            }
+            Ok(Some(shell_words::join(flags)))
+        }
+    }
+
+    deserializer.deserialize_any(V)
+}
+
+/// An entry of the top-level `hosts`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum HostEntry {
+    /// Hosts the syncs can fan out to, see [FileSync::hosts]
+    Group(Vec<String>),
+    Alias(HostAlias),
+}
+
+/// Connection details of a host, so switching servers is a change of one entry.
+///
+/// dsts on the alias, `<name>:path` or `{host: <name>, path: ...}`, are synced to the host, with
+/// their relative paths in root. The port and ssh options are used for every connection to the
+/// host, like [Project::ssh_opts]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostAlias {
+    pub user: Option<String>,
+    pub hostname: String,
+    pub port: Option<u16>,
+    pub ssh_opts: Option<SshOptions>,
+    /// Directory holding the relative paths of the dsts on the host
+    pub root: Option<PathBuf>,
+}
+
+impl HostAlias {
+    /// `[user@]hostname` passed to ssh and rsync
+    pub fn host(&self) -> String {
+        match self.user.as_deref() {
+            Some(user) => format!("{user}@{}", self.hostname),
+            None => self.hostname.clone(),
+        }
+    }
+
+    /// dst of `path` on the host
+    pub fn dst(&self, path: &str) -> PathBuf {
+        let path = match self.root.as_deref() {
+            Some(root) if !path.starts_with(['/', '~']) => root.join(path),
+            _ => PathBuf::from(path),
+        };
+        PathBuf::from(format!("{}:{}", self.host(), path.display()))
+    }
+
+    /// Arguments of ssh connecting to the host
+    pub fn ssh_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(port) = self.port {
+            args.extend(["-p".to_owned(), port.to_string()]);
+        }
+        args.extend(self.ssh_opts.iter().flat_map(SshOptions::args));
+        args
+    }
+}
+
+/// `[user@]host:path`, or `{host: <name>, path: ...}` referring to a [HostAlias]
+fn deser_dst<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct OnHost {
+        host: String,
+        #[serde(default)]
+        path: String,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Dst {
+        Path(PathBuf),
+        OnHost(OnHost),
+    }
+
+    Ok(match Option::<Dst>::deserialize(deserializer)? {
+        None => None,
+        Some(Dst::Path(p)) => Some(p),
+        Some(Dst::OnHost(d)) => Some(PathBuf::from(format!("{}:{}", d.host, d.path))),
+    })
+}
+
+/// The hosts a sync fans out to, see [FileSync::hosts]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hosts {
+    /// name of a group of the top-level `hosts`
+    Group(String),
+    List(Vec<String>),
+}
+
+fn deser_hosts<'de, D>(deserializer: D) -> Result<Option<Hosts>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct V;
+    impl<'de> Visitor<'de> for V {
+        type Value = Option<Hosts>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("the name of a host group or a list of hosts")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Some(Hosts::Group(v.to_owned())))
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(None)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            let mut hosts = Vec::with_capacity(seq.size_hint().unwrap_or(4));
+            while let Some(host) = seq.next_element()? {
+                hosts.push(host);
+            }
+            Ok(Some(Hosts::List(hosts)))
+        }
+    }
+
+    deserializer.deserialize_any(V)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_toml() {
+        let toml = r#"
+# comment
+debounce = "1s"
+numbers = [1, -2_000, 0x10, 1.5, ]
+since = 2024-05-01T12:00:00Z
+
+[projects.asd]
+restart = false
+
+[[projects.asd.sync]]
+src = 'asd'
+dst = "remote:~/asd" # trailing comment
+on_sync = [
+    "echo done",
+    { command = """
+set -x
+echo "hi\tthere"
+""", on = "Init" },
+]
+"#;
+        let value = parse_toml(toml).unwrap();
+        let expected: serde_yaml::Value = serde_yaml::from_str(
+            r#"
+debounce: 1s
+numbers: [1, -2000, 16, 1.5]
+since: 2024-05-01T12:00:00Z
+projects:
+  asd:
+    restart: false
+    sync:
+      - src: asd
+        dst: remote:~/asd
+        on_sync:
+          - echo done
+          - command: "set -x\necho \"hi\tthere\"\n"
+            on: Init
+"#,
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+
+        assert!(parse_toml("a = 1\na = 2").is_err());
+        assert!(parse_toml("[a]\nb = 1\n[a]\nc = 2").is_err());
+        assert!(parse_toml("a = \"unterminated").is_err());
+    }
+
+    #[test]
+    fn test_yaml_deser() {
+        let yaml = r#"
+debounce: 1s 30ms
+projects:
+    asd:
+      sync:
+          - src: asd
+            dst: remote:~/asd
+            on_sync:
+                - echo done
+                - command: echo hi
+                - command: echo hi
+                  on: Init
+                - command: make
+                  cwd: /tmp
+                  env:
+                    FOO: bar
+                  shell: [bash, -c]
+"#;
+
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+
+        assert_eq!(config.projects.len(), 1);
+
+        assert_eq!(config.projects["asd"].sync[0].src.as_os_str(), "asd");
+        assert_eq!(
+            config.projects["asd"].sync[0]
+                .dst
+                .as_ref()
+                .map(|x| x.as_os_str()),
+            Some(std::ffi::OsStr::new("remote:~/asd"))
+        );
+        assert_eq!(config.debounce, Duration::from_millis(1030));
+
+        let cmd = &config.projects["asd"].sync[0].on_sync[3];
+        assert_eq!(cmd.cwd.as_deref(), Some(std::path::Path::new("/tmp")));
+        assert_eq!(cmd.env["FOO"], "bar");
+        assert_eq!(
+            cmd.shell.as_deref(),
+            Some(&["bash".into(), "-c".into()][..])
+        );
+    }
+
+    #[test]
+    fn test_json_deser() {
+        let json = r#"{"debounce": "10ms", "projects": {"asd": {"sync": [{"src": "asd", "on_sync": ["echo done"]}]}}}"#;
+
+        let config = Config::parse(json, ConfigFormat::Json).unwrap();
+
+        assert_eq!(config.debounce, Duration::from_millis(10));
+        assert_eq!(
+            config.projects["asd"].sync[0].on_sync[0].command,
+            "echo done"
+        );
+    }
+
+    #[test]
+    fn test_rsync_flags_list() {
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            rsync_flags: [-a, "--filter=:- .gitignore"]
+            extra_rsync_flags: --chmod=D755,F644
+          - src: qwe
+            extra_rsync_flags: [--chmod=D755]
+"#;
+
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let sync = |i: usize| -> crate::sync::ParsedSync {
+            config.projects["asd"].sync[i].clone().try_into().unwrap()
+        };
+
+        assert_eq!(
+            sync(0).rsync_flags,
+            ["-a", "--filter=:- .gitignore", "--chmod=D755,F644"]
+        );
+        let flags = sync(1).rsync_flags;
+        assert_eq!(flags.last().unwrap(), "--chmod=D755");
+        assert_eq!(
+            flags[..flags.len() - 1],
+            crate::sync::DEFAULT_RSYCN_FLAGS[..]
+        );
+    }
+
+    #[test]
+    fn test_transfer_profile() {
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            rsync_flags: -a
+            transfer_profile: large-files
+            extra_rsync_flags: --compress
+          - src: qwe
+            transfer_profile: Code
+"#;
+
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let sync = &config.projects["asd"].sync;
+        assert_eq!(sync[1].transfer_profile, Some(TransferProfile::Code));
+        let parsed: crate::sync::ParsedSync = sync[0].clone().try_into().unwrap();
+        assert_eq!(
+            parsed.rsync_flags,
+            [
+                "-a",
+                "--partial",
+                "--inplace",
+                "--whole-file",
+                "--no-compress",
+                "--compress"
+            ]
+        );
+        assert_eq!(
+            TransferProfile::LargeFiles.min_debounce(),
+            (Duration::from_secs(5), Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn test_normalize_trailing_slash() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let with_slash = PathBuf::from(format!("{}/", dir.path().display()));
+
+        assert_eq!(
+            normalize_trailing_slash(dir.path().to_owned(), Some(true)).as_os_str(),
+            with_slash.as_os_str()
+        );
+        assert_eq!(
+            normalize_trailing_slash(with_slash.clone(), Some(false)).as_os_str(),
+            dir.path().as_os_str()
+        );
+        assert_eq!(
+            normalize_trailing_slash(with_slash, None).as_os_str(),
+            dir.path().as_os_str()
+        );
+        assert_eq!(
+            normalize_trailing_slash(file.clone(), Some(true)).as_os_str(),
+            file.as_os_str()
+        );
+        assert_eq!(
+            normalize_trailing_slash(PathBuf::from("/"), None).as_os_str(),
+            "/"
+        );
+    }
+
+    #[test]
+    fn test_command_conditions() {
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            on_sync:
+                - make
+                - command: deploy
+                  run_if: test -f .deploy
+                  only_on: change
+                  continue_on_failure: true
+"#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let commands = &config.projects["asd"].sync[0].on_sync;
+        assert_eq!(commands[0].only_on, OnlyOn::Both);
+        assert_eq!(commands[1].run_if.as_deref(), Some("test -f .deploy"));
+        assert_eq!(commands[1].only_on, OnlyOn::Change);
+        assert!(commands[1].continue_on_failure);
+        assert!(!commands[1].only_on.runs(true));
+
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            on_sync:
+                - command: setup
+                  on: Init
+                  only_on: change
+"#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let err = config.validate().unwrap_err();
+        assert!(format!("{err:#}").contains("never runs it"), "{err:#}");
+    }
+
+    #[test]
+    fn test_permission_flags() {
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            rsync_flags: -a
+            chmod: D755,F644
+            chown: www-data:www
+            numeric_ids: true
+"#;
+
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let sync: crate::sync::ParsedSync =
+            config.projects["asd"].sync[0].clone().try_into().unwrap();
+        assert_eq!(
+            sync.rsync_flags,
+            [
+                "-a",
+                "--chmod=D755,F644",
+                "--chown=www-data:www",
+                "--numeric-ids"
+            ]
+        );
+
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            chmod: u+q
+"#;
+        assert!(serde_yaml::from_str::<Config>(yaml).is_err());
+    }
+
+    #[test]
+    fn test_defaults() {
+        let yaml = r#"
+defaults:
+    rsync_flags: -a
+    restart: false
+    ignore_patterns: ["*.swp"]
+    env:
+        STAGE: dev
+        LEVEL: info
+    nice: 10
+    ionice: idle
+projects:
+    asd:
+      sync:
+          - src: asd
+            ignore_patterns: ["*~"]
+            on_sync:
+                - command: make
+                  env:
+                    LEVEL: debug
+    qwe:
+      restart: true
+      sync:
+          - src: qwe
+            rsync_flags: -av
+            nice: 5
+            cpus: 0-1
+"#;
+
+        let config = Config::parse(yaml, ConfigFormat::Yaml).unwrap();
+
+        let asd = &config.projects["asd"];
+        assert_eq!(asd.restart, Some(false));
+        assert_eq!(asd.sync[0].rsync_flags.as_deref(), Some("-a"));
+        assert_eq!(asd.sync[0].ignore_patterns, ["*~", "*.swp"]);
+        let env = &asd.sync[0].on_sync[0].env;
+        assert_eq!(env["STAGE"], "dev");
+        assert_eq!(env["LEVEL"], "debug");
+        assert_eq!(asd.sync[0].nice, Some(10));
+        assert_eq!(asd.sync[0].ionice, Some("idle".parse().unwrap()));
+
+        let qwe = &config.projects["qwe"];
+        assert_eq!(qwe.restart, Some(true));
+        assert_eq!(qwe.sync[0].rsync_flags.as_deref(), Some("-av"));
+        assert_eq!(qwe.sync[0].nice, Some(5));
+        assert_eq!(
+            qwe.sync[0].cpus.as_ref().map(|c| c.0.clone()),
+            Some(vec![0, 1])
+        );
+    }
+
+    #[test]
+    fn test_validate_reports_every_error() {
+        let yaml = r#"
+projects:
+    asd:
+      depends_on: [zxc]
+      sync:
+          - src: asd
+          - src: lib
+            rsync_flags: "-a 'unclosed"
+    qwe:
+      sync:
+          - src: qwe
+            dst: out
+            keep: 0
+            mode: Snapshot
+"#;
+
+        let err = Config::parse(yaml, ConfigFormat::Yaml).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.starts_with("3 errors in the config"), "{msg}");
+        assert!(
+            msg.contains("Project asd depends on unknown project zxc"),
+            "{msg}"
+        );
+        assert!(
+            msg.contains("Invalid sync 1 (lib) in project asd: Failed to split rsync flags"),
+            "{msg}"
+        );
+        assert!(
+            msg.contains("Invalid sync 0 (qwe) in project qwe: keep must be at least 1"),
+            "{msg}"
+        );
+    }
+
+    #[test]
+    fn test_watch_only_entries() {
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            on_sync:
+                - make
+"#;
+        let config = Config::parse(yaml, ConfigFormat::Yaml).unwrap();
+        assert!(config.projects["asd"].sync[0].dst.is_none());
+
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            protect_dst: true
+"#;
+        let err = Config::parse(yaml, ConfigFormat::Yaml).unwrap_err();
+        assert!(err.to_string().contains("need a dst"), "{err}");
+    }
+
+    #[test]
+    fn test_host_aliases() {
+        let yaml = r#"
+hosts:
+    boxes: [box1, box2]
+    devbox:
+      user: deploy
+      hostname: dev3.example.com
+      port: 2222
+      root: /srv
+projects:
+    web:
+      sync:
+          - src: web
+            dst:
+              host: devbox
+              path: web
+          - src: api
+            dst: devbox:/opt/api
+          - src: docs
+            dst: docs.example.com:docs
+          - src: all
+            dst: "{{ host }}:/srv/"
+            hosts: boxes
+"#;
+        let config = Config::parse(yaml, ConfigFormat::Yaml).unwrap();
+        let dsts = config.projects["web"]
+            .sync
+            .iter()
+            .map(|s| s.dst.clone().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dsts,
+            [
+                "deploy@dev3.example.com:/srv/web",
+                "deploy@dev3.example.com:/opt/api",
+                "docs.example.com:docs",
+                "{{ host }}:/srv/",
+            ]
+            .map(PathBuf::from)
+        );
+        let HostEntry::Alias(alias) = &config.hosts["devbox"] else {
+            panic!("devbox is not an alias");
+        };
+        assert_eq!(alias.ssh_args(), ["-p", "2222"]);
+    }
+
+    #[test]
+    fn test_ssh_opts() {
+        let yaml = r#"
+hosts:
+    devbox:
+      user: deploy
+      hostname: devbox
+      port: 2200
+projects:
+    web:
+      ssh_opts:
+        proxy_jump: bastion
+        options:
+          ServerAliveInterval: "30"
+      sync:
+          - src: web
+            dst: devbox:/srv/web
+          - src: docs
+            dst: docs
+    api:
+      ssh_opts:
+        port: 2222
+      sync:
+          - src: api
+            dst: deploy@devbox:/srv/api
+"#;
+        let config = Config::parse(yaml, ConfigFormat::Yaml).unwrap();
+        let web = &config.projects["web"].sync;
+        assert_eq!(
+            web[0].ssh_args_of(Path::new("deploy@devbox:/srv/web")),
+            [
+                "-p",
+                "2200",
+                "-J",
+                "bastion",
+                "-o",
+                "ServerAliveInterval=30"
+            ]
+        );
+        assert!(web[1].ssh_args.is_empty());
+        // the options of the projects don't leak into each other
+        let api = &config.projects["api"].sync[0];
+        assert_eq!(
+            api.ssh_args_of(api.dst.as_deref().unwrap()),
+            ["-p", "2200", "-p", "2222"]
+        );
+        assert!(api.ssh_args_of(Path::new("/srv/api")).is_empty());
+    }
+
+    #[test]
+    fn test_remote_commands() {
+        let yaml = |dst: &str| {
+            format!(
+                r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            dst: {dst}
+            on_sync:
+              - command: systemctl --user restart app
+                remote: true
+"#
+            )
+        };
+        Config::parse(&yaml("devbox:/srv/app"), ConfigFormat::Yaml).unwrap();
+        let err = Config::parse(&yaml("out"), ConfigFormat::Yaml).unwrap_err();
+        assert!(
+            err.to_string().contains("remote commands need a single"),
+            "{err}"
+        );
+
+        let yaml = yaml("devbox:/srv/app").replace("remote: true", "where: remote");
+        let config = Config::parse(&yaml, ConfigFormat::Yaml).unwrap();
+        assert!(config.projects["asd"].sync[0].on_sync[0].is_remote());
+        let yaml = yaml.replace(
+            "where: remote",
+            "where: local\n                remote: true",
+        );
+        let err = Config::parse(&yaml, ConfigFormat::Yaml).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("both remote: true and where: Local"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_custom_backend() {
+        let yaml = |execution: &str| {
+            format!(
+                r#"
+execution: {execution}
+projects:
+    asd:
+      sync:
+          - src: asd
+            dst: out
+            backend: test-backend
+"#
+            )
+        };
+        let err = Config::parse(&yaml("InProcess"), ConfigFormat::Yaml).unwrap_err();
+        assert!(
+            err.to_string().contains("Unknown backend test-backend"),
+            "{err}"
+        );
+
+        struct Noop;
+        impl crate::backend::SyncBackend for Noop {
+            fn transfer(
+                &self,
+                _: &crate::backend::TransferContext<'_>,
+                _: Option<&mut crate::sync::SyncOutput>,
+            ) -> anyhow::Result<()> {
+                Ok(())
+            }
+        }
+        crate::backend::register("test-backend", Noop);
+        let config = Config::parse(&yaml("InProcess"), ConfigFormat::Yaml).unwrap();
+        assert_eq!(
+            config.projects["asd"].sync[0].backend,
+            SyncBackend::Custom("test-backend".to_owned())
+        );
+        let err = Config::parse(&yaml("Subprocess"), ConfigFormat::Yaml).unwrap_err();
+        assert!(
+            err.to_string().contains("need execution: InProcess"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_host_groups() {
+        let yaml = r#"
+hosts:
+    test-boxes: [box1, box2, box3]
+projects:
+    asd:
+      sync:
+          - src: asd
+            dst: "deploy@{{ host }}:/srv/{{ project }}/"
+            hosts: test-boxes
+          - src: qwe
+            dst: "{{ host }}:/srv/qwe/"
+            hosts: [box4]
+"#;
+
+        let config = Config::parse(yaml, ConfigFormat::Yaml).unwrap();
+
+        let sync = &config.projects["asd"].sync;
+        assert_eq!(
+            sync[0].destinations().unwrap(),
+            [
+                "deploy@box1:/srv/asd/",
+                "deploy@box2:/srv/asd/",
+                "deploy@box3:/srv/asd/"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            sync[1].destinations().unwrap(),
+            [PathBuf::from("box4:/srv/qwe/")]
+        );
+
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            dst: "box:/srv/asd/"
+            hosts: [box1, box2]
+          - src: qwe
+            dst: "{{ host }}:/srv/qwe/"
+            hosts: staging
+"#;
+        let err = Config::parse(yaml, ConfigFormat::Yaml).unwrap_err();
+        assert!(
+            err.to_string().contains("Unknown host group staging"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_src_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        for p in ["packages/web/dist", "packages/api/dist"] {
+            std::fs::create_dir_all(dir.path().join(p)).unwrap();
+        }
+        let yaml = format!(
+            r#"
+variables:
+    root: {}
+projects:
+    asd:
+      sync:
+          - src: "{{{{ root }}}}/packages/*/dist"
+            dst: "host:/srv/{{{{ match.1 }}}}/"
+            on_sync:
+                - echo {{{{ match.0 }}}}
+          - src: qwe
+"#,
+            dir.path().display()
+        );
+
+        let mut config = Config::parse(&yaml, ConfigFormat::Yaml).unwrap();
+        config.expand_src_globs().unwrap();
+
+        let sync = &config.projects["asd"].sync;
+        assert_eq!(sync.len(), 3);
+        assert_eq!(sync[0].src, dir.path().join("packages/api/dist"));
+        assert_eq!(sync[0].dst.as_deref(), Some(Path::new("host:/srv/api/")));
+        assert_eq!(
+            sync[0].on_sync[0].command,
+            format!("echo {}", dir.path().join("packages/api/dist").display())
+        );
+        assert_eq!(sync[1].dst.as_deref(), Some(Path::new("host:/srv/web/")));
+        assert_eq!(sync[2].src, Path::new("qwe"));
+    }
+
+    #[test]
+    fn test_yaml_errors_point_at_the_entry() {
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+          - src: lib
+            dts: out
+"#;
+
+        let err = Config::parse(yaml, ConfigFormat::Yaml).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("projects.asd.sync[1]"), "{msg}");
+        assert!(msg.contains("line 7"), "{msg}");
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            on_synk:
+                - echo done
+"#;
+
+        let err = serde_yaml::from_str::<Config>(yaml).unwrap_err();
+        assert!(err.to_string().contains("on_synk"), "{err}");
+    }
+
+    #[test]
+    fn test_dependency_cycle_is_rejected() {
+        let yaml = r#"
+projects:
+    a:
+      depends_on: [b]
+      sync: []
+    b:
+      depends_on: [a]
+      sync: []
+"#;
+
+        let err = Config::parse(yaml, ConfigFormat::Yaml).unwrap_err();
+        assert!(err.to_string().contains("cycle"), "{err}");
+    }
+
+    #[test]
+    fn test_variables() {
+        let yaml = r#"
+variables:
+    host: staging.example.com
+projects:
+    web:
+      sync:
+          - src: web
+            dst: "deploy@{{ host }}:/srv/{{ project }}/"
+            on_sync:
+                - echo {{ project }}
+"#;
+
+        let config = Config::parse(yaml, ConfigFormat::Yaml).unwrap();
+        let sync = &config.projects["web"].sync[0];
+        assert_eq!(
+            sync.dst.as_deref(),
+            Some(Path::new("deploy@staging.example.com:/srv/web/"))
+        );
+        assert_eq!(sync.on_sync[0].command, "echo web");
+
+        let vars = HashMap::from([("host".to_owned(), "prod.example.com".to_owned())]);
+        let config = Config::parse_with(
+            yaml,
+            ConfigFormat::Yaml,
+            &ConfigOverrides {
+                profile: None,
+                vars,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            config.projects["web"].sync[0].dst.as_deref(),
+            Some(Path::new("deploy@prod.example.com:/srv/web/"))
+        );
+
+        let err = Config::parse(
+            "projects: {a: {sync: [{src: '{{ nope }}'}]}}",
+            ConfigFormat::Yaml,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("nope"), "{err}");
+    }
+
+    #[test]
+    fn test_profiles() {
+        let yaml = r#"
+variables:
+    host: dev.example.com
+profiles:
+    staging:
+        variables:
+            host: staging.example.com
+        overrides:
+            web:
+                rsync_flags: -av
+            "web:assets":
+                dst: "{{ host }}:/cdn"
+projects:
+    web:
+      sync:
+          - src: app
+            dst: "{{ host }}:/srv"
+          - src: assets
+            dst: "{{ host }}:/srv/assets"
+"#;
+        let parse = |profile: Option<&str>| {
+            Config::parse_with(
+                yaml,
+                ConfigFormat::Yaml,
+                &ConfigOverrides {
+                    profile: profile.map(str::to_owned),
+                    ..Default::default()
+                },
+            )
+        };
+        let config = parse(None).unwrap();
+        let sync = &config.projects["web"].sync;
+        assert_eq!(
+            sync[0].dst.as_deref(),
+            Some(Path::new("dev.example.com:/srv"))
+        );
+        assert_eq!(sync[0].rsync_flags, None);
+
+        let config = parse(Some("staging")).unwrap();
+        let sync = &config.projects["web"].sync;
+        assert_eq!(
+            sync[0].dst.as_deref(),
+            Some(Path::new("staging.example.com:/srv"))
+        );
+        assert_eq!(sync[0].rsync_flags.as_deref(), Some("-av"));
+        assert_eq!(
+            sync[1].dst.as_deref(),
+            Some(Path::new("staging.example.com:/cdn"))
+        );
+
+        let err = parse(Some("prod")).unwrap_err();
+        assert!(format!("{err:#}").contains("staging"), "{err:#}");
+    }
+
+    #[test]
+    fn test_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("password");
+        std::fs::write(&file, "from-file\n").unwrap();
+        assert_eq!(Secret::File(file).resolve().unwrap(), "from-file");
+        #[cfg(unix)]
+        assert_eq!(
+            Secret::Command("printf 'from-command\\n'".into())
+                .resolve()
+                .unwrap(),
+            "from-command"
+        );
+        let err = Secret::Env("ATUNE_TEST_UNSET_SECRET".into())
+            .resolve()
+            .unwrap_err();
+        assert!(err.to_string().contains("ATUNE_TEST_UNSET_SECRET"), "{err}");
+
+        let yaml = r#"
+projects:
+    backup:
+      sync:
+          - src: data
+            dst: rsync://nas/backup
+            password: {Command: pass show rsync/nas}
+            password_env: RSYNC_NAS
+"#;
+        let err = Config::parse(yaml, ConfigFormat::Yaml).unwrap_err();
+        assert!(format!("{err:#}").contains("Only one"), "{err:#}");
+    }
+
+    #[test]
+    fn test_remote_dst() {
+        assert_eq!(
+            remote_dst(Path::new("user@host:~/dst")),
+            Some(("user@host", "~/dst"))
+        );
+        assert_eq!(remote_dst(Path::new("/tmp/a:b")), None);
+        assert_eq!(remote_dst(Path::new("C:\\dst")), None);
+        assert_eq!(remote_dst(Path::new("host::module")), None);
+        assert_eq!(remote_dst(Path::new("dst")), None);
+        assert_eq!(remote_dst(Path::new("rsync://host/module")), None);
+    }
+
+    #[test]
+    fn test_rsync_daemon_dst() {
+        assert_eq!(
+            rsync_daemon_dst(Path::new("rsync://user@host:873/module/dir")),
+            Some(("user@host:873", "module"))
+        );
+        assert_eq!(
+            rsync_daemon_dst(Path::new("host::module/dir")),
+            Some(("host", "module"))
+        );
+        assert_eq!(rsync_daemon_dst(Path::new("host:dir")), None);
+
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            dst: rsync://host/
+"#;
+        let err = Config::parse(yaml, ConfigFormat::Yaml).unwrap_err();
+        assert!(format!("{err:#}").contains("no module"), "{err:#}");
+
+        let yaml = r#"
+projects:
+    asd:
+      sync:
+          - src: asd
+            dst: host:dir
+            password_env: RSYNC_PW
+"#;
+        let err = Config::parse(yaml, ConfigFormat::Yaml).unwrap_err();
+        assert!(format!("{err:#}").contains("password_env"), "{err:#}");
+    }
+
+    #[test]
+    fn test_select() {
+        let yaml = r#"
+projects:
+    a:
+      sync:
+          - src: /tmp/foo
+          - src: /tmp/bar
+    b:
+      sync:
+          - src: /tmp/baz
+    c:
+      sync: []
+"#;
+        let parse = || Config::parse(yaml, ConfigFormat::Yaml).unwrap();
+        let enabled = |config: &Config, project: &str| {
+            config.projects[project]
+                .sync
+                .iter()
+                .map(|s| s.enabled)
+                .collect::<Vec<_>>()
+        };
+
+        let mut config = parse();
+        config
+            .select(&["a:bar".parse().unwrap(), "b".parse().unwrap()], &[])
+            .unwrap();
+        assert_eq!(enabled(&config, "a"), [false, true]);
+        assert_eq!(enabled(&config, "b"), [true]);
+        assert!(!config.projects.contains_key("c"));
+
+        let mut config = parse();
+        config
+            .select(&[], &["a:0".parse().unwrap(), "c".parse().unwrap()])
+            .unwrap();
+        assert_eq!(enabled(&config, "a"), [false, true]);
+        assert_eq!(enabled(&config, "b"), [true]);
+        assert!(!config.projects.contains_key("c"));
+
+        let mut config = parse();
+        assert!(config.select(&["d".parse().unwrap()], &[]).is_err());
+    }
+
+    #[test]
+    fn test_select_tags() {
+        let yaml = r#"
+projects:
+    web:
+      tags: [frontend]
+      sync:
+          - src: /tmp/foo
+          - src: /tmp/bar
+            enabled: false
+    api:
+      sync:
+          - src: /tmp/baz
+            tags: [deploy]
+          - src: /tmp/qux
+    db:
+      sync:
+          - src: /tmp/quux
+"#;
+        let parse = || Config::parse(yaml, ConfigFormat::Yaml).unwrap();
+        let enabled = |config: &Config, project: &str| {
+            config.projects[project]
+                .sync
+                .iter()
+                .map(|s| s.enabled)
+                .collect::<Vec<_>>()
+        };
+
+        let mut config = parse();
+        assert!(config.select_tags(&["frontend".to_owned(), "deploy".to_owned()]));
+        assert_eq!(enabled(&config, "web"), [true, false]);
+        assert_eq!(enabled(&config, "api"), [true, false]);
+        assert!(!config.projects.contains_key("db"));
+
+        let mut config = parse();
+        assert!(config.select_tags(&[]));
+        assert_eq!(config.projects.len(), 3);
+        assert!(!config.select_tags(&["backend".to_owned()]));
+        assert!(config.projects.is_empty());
     }
 }
@@ -0,0 +1,240 @@
+//! Confirmation and limits of syncs deleting many files in dst, see
+//! [crate::config::FileSync::confirm_delete] and [crate::config::FileSync::max_delete]
+use std::{
+    io::{BufRead as _, IsTerminal as _, Write as _},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Deletions needing a confirmation: more than a number of files, e.g. `100`, or than a
+/// percentage of the files in dst, e.g. `20%`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteThreshold {
+    Count(u64),
+    Percent(u64),
+}
+
+impl Default for DeleteThreshold {
+    fn default() -> Self {
+        DeleteThreshold::Percent(20)
+    }
+}
+
+impl DeleteThreshold {
+    /// Whether deleting `deleted` of the `total` files in dst exceeds the threshold
+    pub fn exceeded(&self, deleted: u64, total: u64) -> bool {
+        match *self {
+            DeleteThreshold::Count(n) => deleted > n,
+            DeleteThreshold::Percent(p) => deleted > 0 && deleted * 100 > p * total,
+        }
+    }
+}
+
+impl FromStr for DeleteThreshold {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(p) => {
+                let p = p
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|p| *p <= 100)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid percentage {s:?}, expected 0-100%"))?;
+                Ok(DeleteThreshold::Percent(p))
+            }
+            None => s
+                .parse()
+                .map(DeleteThreshold::Count)
+                .map_err(|_| anyhow::anyhow!("Invalid threshold {s:?}, expected e.g. 100 or 20%")),
+        }
+    }
+}
+
+impl std::fmt::Display for DeleteThreshold {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeleteThreshold::Count(n) => write!(f, "{n} files"),
+            DeleteThreshold::Percent(p) => write!(f, "{p}%"),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for DeleteThreshold {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // a count may be written as a number
+        let s = match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::Number(n) => n.to_string(),
+            serde_yaml::Value::String(s) => s,
+            other => {
+                return Err(serde::de::Error::custom(format!(
+                    "Invalid threshold {other:?}, expected e.g. 100 or \"20%\""
+                )))
+            }
+        };
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Returned by [crate::sync::execute_sync] when a sync would delete more files than the
+/// threshold and wasn't confirmed
+#[derive(Debug)]
+pub struct DeleteRefused {
+    pub dst: PathBuf,
+    pub deleted: u64,
+    pub total: u64,
+    pub threshold: DeleteThreshold,
+}
+
+impl std::fmt::Display for DeleteRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Refusing to delete {} of {} files in {}, more than {}. Confirm it in a terminal or pass --yes",
+            self.deleted,
+            self.total,
+            self.dst.display(),
+            self.threshold
+        )
+    }
+}
+
+impl std::error::Error for DeleteRefused {}
+
+/// Returned by [crate::sync::execute_sync] when a sync would delete more files than
+/// `max_delete`
+#[derive(Debug)]
+pub struct DeleteLimit {
+    pub dst: PathBuf,
+    pub max: u64,
+    /// None if rsync stopped at `--max-delete` during the transfer
+    pub deleted: Option<u64>,
+}
+
+impl std::fmt::Display for DeleteLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.deleted {
+            Some(deleted) => write!(
+                f,
+                "Aborted the sync, it would delete {deleted} files in {}, more than max_delete {}",
+                self.dst.display(),
+                self.max
+            ),
+            None => write!(
+                f,
+                "rsync stopped deleting files in {} at max_delete {}",
+                self.dst.display(),
+                self.max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeleteLimit {}
+
+/// The paths a dry run of rsync with `--itemize-changes --stats` would delete, and the number of
+/// files in dst before the sync
+pub fn deletions(output: &str) -> (Vec<PathBuf>, u64) {
+    let paths = output
+        .lines()
+        .filter_map(|l| l.strip_prefix("*deleting"))
+        .map(|p| PathBuf::from(p.trim().trim_end_matches('/')))
+        .collect::<Vec<_>>();
+    let stat = |key: &str| {
+        output
+            .lines()
+            .find_map(|l| l.strip_prefix(key))
+            .and_then(|v| v.split_whitespace().next())
+            .map(|v| v.replace(',', ""))
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or_default()
+    };
+    // the files of src, without the ones the sync creates, and with the ones it deletes
+    let total = (stat("Number of files:") + paths.len() as u64)
+        .saturating_sub(stat("Number of created files:"));
+    (paths, total)
+}
+
+/// Number of deleted paths listed in the prompt
+const LISTED: usize = 20;
+
+/// Ask on the terminal whether to delete the paths. False without a terminal
+pub fn ask(label: &str, dst: &Path, paths: &[PathBuf], total: u64) -> std::io::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")?;
+    // syncs running concurrently, in this or other processes, ask one after the other
+    #[cfg(unix)]
+    // SAFETY: locks the file held until the end of the function
+    unsafe {
+        libc::flock(std::os::fd::AsRawFd::as_raw_fd(&tty), libc::LOCK_EX);
+    }
+    writeln!(
+        tty,
+        "{label} would delete {} of {total} files in {}:",
+        paths.len(),
+        dst.display()
+    )?;
+    for p in paths.iter().take(LISTED) {
+        writeln!(tty, "  {}", p.display())?;
+    }
+    if paths.len() > LISTED {
+        writeln!(tty, "  ... and {} more", paths.len() - LISTED)?;
+    }
+    write!(tty, "Delete them? [y/N] ")?;
+    tty.flush()?;
+    let mut answer = String::new();
+    std::io::BufReader::new(&tty).read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold() {
+        assert_eq!(
+            "100".parse::<DeleteThreshold>().unwrap(),
+            DeleteThreshold::Count(100)
+        );
+        assert_eq!(
+            "20%".parse::<DeleteThreshold>().unwrap(),
+            DeleteThreshold::Percent(20)
+        );
+        assert!("120%".parse::<DeleteThreshold>().is_err());
+        assert!("some".parse::<DeleteThreshold>().is_err());
+
+        assert!(!DeleteThreshold::Count(2).exceeded(2, 10));
+        assert!(DeleteThreshold::Count(2).exceeded(3, 10));
+        assert!(!DeleteThreshold::Percent(20).exceeded(2, 10));
+        assert!(DeleteThreshold::Percent(20).exceeded(3, 10));
+        assert!(!DeleteThreshold::Percent(0).exceeded(0, 0));
+        assert!(DeleteThreshold::Percent(0).exceeded(1, 1));
+    }
+
+    #[test]
+    fn test_deletions() {
+        let output = "\
+*deleting   old/a.txt
+*deleting   old/
+>f+++++++++ new.txt
+
+Number of files: 12 (reg: 10, dir: 2)
+Number of created files: 1 (reg: 1)
+Number of deleted files: 2 (reg: 1, dir: 1)
+";
+        let (paths, total) = deletions(output);
+        assert_eq!(paths, [PathBuf::from("old/a.txt"), PathBuf::from("old")]);
+        assert_eq!(total, 13);
+    }
+}
@@ -0,0 +1,215 @@
+//! Unix socket of a running watch, for editors to report saved files, see `atune notify-change`.
+//!
+//! The protocol is line based: the client sends `changed <path>` with an absolute path, and the
+//! watch replies `ok <project:sync> ...` with the syncs the change was queued for, or
+//! `error <message>`
+use std::{
+    io::{BufRead as _, BufReader, Write as _},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use crossbeam::channel;
+use tracing::{debug, warn};
+
+use crate::WatchControl;
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Location of the socket of the watch of `config`, next to its pidfile
+pub fn socket_path(config: &Path) -> PathBuf {
+    crate::lock::lock_path(config).with_extension("sock")
+}
+
+/// Absolute path of a changed file, with its parent resolved if the file was deleted
+pub fn changed_path(path: &Path) -> anyhow::Result<PathBuf> {
+    if let Ok(path) = crate::platform::canonicalize(path) {
+        return Ok(path);
+    }
+    let path = std::path::absolute(path)?;
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match crate::platform::canonicalize(parent) {
+            Ok(parent) => Ok(parent.join(name)),
+            Err(_) => Ok(path),
+        },
+        _ => Ok(path),
+    }
+}
+
+/// The listening socket, removed when dropped
+#[derive(Debug)]
+pub struct ControlSocket {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+}
+
+impl ControlSocket {
+    /// Listen on the socket of `config`. `sources` are the labels and the src paths of the
+    /// watched syncs, changes inside them are sent to `control`.
+    ///
+    /// Returns None if another watch of the config is listening already
+    pub fn start(
+        config: &Path,
+        sources: Vec<(String, PathBuf)>,
+        control: channel::Sender<WatchControl>,
+    ) -> anyhow::Result<Option<Self>> {
+        let path = socket_path(config);
+        if UnixStream::connect(&path).is_ok() {
+            warn!(
+                ?path,
+                "Another watch of the config owns the socket, notify-change goes to it"
+            );
+            return Ok(None);
+        }
+        // left behind by a watch that crashed
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to listen on {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let sources = Arc::new(sources);
+        std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let sources = sources.clone();
+                            let control = control.clone();
+                            std::thread::spawn(move || {
+                                if let Err(err) = handle(stream, &sources, &control) {
+                                    debug!(?err, "notify-change connection failed");
+                                }
+                            });
+                        }
+                        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                            std::thread::sleep(ACCEPT_POLL_INTERVAL)
+                        }
+                        Err(err) => {
+                            warn!(?err, "Failed to accept a notify-change connection");
+                            std::thread::sleep(ACCEPT_POLL_INTERVAL)
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Some(Self { path, stop }))
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn handle(
+    stream: UnixStream,
+    sources: &[(String, PathBuf)],
+    control: &channel::Sender<WatchControl>,
+) -> anyhow::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let reply = match line.split_once(' ') {
+            Some(("changed", path)) => {
+                let path = PathBuf::from(path);
+                let mut labels = sources
+                    .iter()
+                    .filter(|(_, src)| path.starts_with(src))
+                    .map(|(label, _)| label.as_str())
+                    .collect::<Vec<_>>();
+                // a src may be listed by its given and its canonical path
+                labels.dedup();
+                if labels.is_empty() {
+                    format!("error {} is not inside a watched src", path.display())
+                } else {
+                    debug!(?path, "Change reported by notify-change");
+                    let _ = control.send(WatchControl::Changed { path });
+                    format!("ok {}", labels.join(" "))
+                }
+            }
+            _ => format!("error unknown request {line:?}"),
+        };
+        writeln!(writer, "{reply}")?;
+    }
+    Ok(())
+}
+
+/// Connection to the socket of a running watch
+#[derive(Debug)]
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    pub fn connect(config: &Path) -> anyhow::Result<Self> {
+        let path = socket_path(config);
+        let writer = UnixStream::connect(&path).with_context(|| {
+            format!(
+                "atune is not watching {}, failed to connect to {}",
+                config.display(),
+                path.display()
+            )
+        })?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    /// Report a change of `path`, returning the labels of the syncs it was queued for
+    pub fn changed(&mut self, path: &Path) -> anyhow::Result<Vec<String>> {
+        let path = changed_path(path)?;
+        writeln!(self.writer, "changed {}", path.display())?;
+        let mut reply = String::new();
+        self.reader.read_line(&mut reply)?;
+        match reply.trim_end().split_once(' ') {
+            Some(("ok", labels)) => Ok(labels.split(' ').map(str::to_owned).collect()),
+            Some(("error", err)) => Err(anyhow::anyhow!("{err}")),
+            _ => Err(anyhow::anyhow!("Unexpected reply {reply:?}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notify_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("atune.yaml");
+        let src = crate::platform::canonicalize(dir.path())
+            .unwrap()
+            .join("src");
+        std::fs::create_dir(&src).unwrap();
+        let (tx, rx) = channel::unbounded();
+        let _socket = ControlSocket::start(&config, vec![("web:src".to_owned(), src.clone())], tx)
+            .unwrap()
+            .unwrap();
+
+        let mut client = Client::connect(&config).unwrap();
+        assert_eq!(
+            client.changed(&src.join("deleted.txt")).unwrap(),
+            vec!["web:src"]
+        );
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            WatchControl::Changed {
+                path: src.join("deleted.txt")
+            }
+        );
+        let err = client.changed(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("not inside"), "{err}");
+    }
+}
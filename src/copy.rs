@@ -0,0 +1,842 @@
+//! Native copy backend, for systems without rsync
+
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use tracing::debug;
+
+use crate::{
+    backend::{SyncBackend, TransferContext},
+    config::SymlinkPolicy,
+    perms::{Chmod, Chown},
+    sync::{Drift, EventFilter, SyncOutput},
+};
+
+/// The built-in backend mirroring src into a local dst, see [crate::config::SyncBackend::Copy]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CopyBackend;
+
+impl SyncBackend for CopyBackend {
+    fn transfer(
+        &self,
+        ctx: &TransferContext<'_>,
+        _: Option<&mut SyncOutput>,
+    ) -> anyhow::Result<()> {
+        let s = ctx.sync;
+        let backup = s.backup.as_ref().map(|b| {
+            crate::backup::backup_root(ctx.dst, b).join(crate::backup::timestamp(ctx.started))
+        });
+        if let Some(runner) = ctx.runner {
+            runner.check()?;
+        }
+        mirror(
+            &s.src,
+            ctx.dst,
+            s.symlinks.unwrap_or(SymlinkPolicy::Follow),
+            &s.filter,
+            reads_gitignore(&s.rsync_flags),
+            backup.as_deref(),
+            s.chmod.as_ref(),
+            s.chown.as_ref(),
+        )
+    }
+
+    /// Compare the bytes of the files, see [crate::config::FileSync::verify]
+    fn finalize(&self, ctx: &TransferContext<'_>) -> anyhow::Result<()> {
+        let s = ctx.sync;
+        if !s.verify {
+            return Ok(());
+        }
+        let paths = verify(
+            &s.src,
+            ctx.dst,
+            s.symlinks.unwrap_or(SymlinkPolicy::Follow),
+            &s.filter,
+            reads_gitignore(&s.rsync_flags),
+        )?;
+        if !paths.is_empty() {
+            return Err(Drift { paths }.into());
+        }
+        Ok(())
+    }
+}
+
+/// Whether the rsync flags exclude the entries listed in the `.gitignore` files, like the
+/// `--filter ':- .gitignore'` rule of [crate::sync::DEFAULT_RSYCN_FLAGS]
+fn reads_gitignore(flags: &[String]) -> bool {
+    const RULE: &str = ":- .gitignore";
+    flags
+        .windows(2)
+        .any(|w| matches!(w[0].as_str(), "--filter" | "-f") && w[1] == RULE)
+        || flags
+            .iter()
+            .any(|f| f.strip_prefix("--filter=") == Some(RULE))
+}
+
+/// The exclude patterns of the `.gitignore` files in src, each applying to the directory of its
+/// file and everything below it, like rsync's `--filter ':- .gitignore'`
+#[derive(Debug, Default, Clone)]
+struct Gitignore {
+    enabled: bool,
+    /// the directory of the file relative to src, and the pattern
+    patterns: Vec<(PathBuf, String)>,
+}
+
+impl Gitignore {
+    /// Add the patterns of the `.gitignore` of `dir`, at `rel` relative to src
+    fn enter(&self, dir: &Path, rel: &Path) -> Cow<'_, Self> {
+        let content = match self.enabled {
+            true => fs::read_to_string(dir.join(".gitignore")).ok(),
+            false => None,
+        };
+        let Some(content) = content else {
+            return Cow::Borrowed(self);
+        };
+        let mut ignore = self.clone();
+        // like rsync, negations and comments aren't rules
+        ignore.patterns.extend(
+            content
+                .lines()
+                .map(str::trim_end)
+                .filter(|l| !l.is_empty() && !l.starts_with(['#', '!']))
+                .map(|l| (rel.to_owned(), l.to_owned())),
+        );
+        Cow::Owned(ignore)
+    }
+
+    /// Whether `path`, relative to src, is ignored
+    fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        self.patterns.iter().any(|(dir, pattern)| {
+            let Ok(path) = path.strip_prefix(dir) else {
+                return false;
+            };
+            match pattern.strip_suffix('/') {
+                Some(pattern) => is_dir && crate::glob::matches_path(pattern, path),
+                None => crate::glob::matches_path(pattern, path),
+            }
+        })
+    }
+}
+
+/// Mirror `src` into the `dst` directory, like `rsync --delete -rt src dst` would.
+///
+/// Files are copied if their size or modification time differ, files missing from `src` are
+/// removed from `dst`. Symbolic links are handled according to `symlinks`, links are followed on
+/// platforms where they can't be created. Like rsync, entries left out by the include and
+/// exclude patterns of `filter`, or by the `.gitignore` files if `gitignore` is set, are neither
+/// copied nor removed from `dst`. If `backup` is given,
+/// then deleted and overwritten files are moved there, keeping their path relative to `dst`. On
+/// unix, the permissions and owner of the mirrored files and directories are changed by `chmod`
+/// and `chown`.
+#[allow(clippy::too_many_arguments)]
+pub fn mirror(
+    src: &Path,
+    dst: &Path,
+    symlinks: SymlinkPolicy,
+    filter: &EventFilter,
+    gitignore: bool,
+    backup: Option<&Path>,
+    chmod: Option<&Chmod>,
+    chown: Option<&Chown>,
+) -> anyhow::Result<()> {
+    let name = target(src);
+    fs::create_dir_all(dst).with_context(|| format!("Failed to create {}", dst.display()))?;
+    #[cfg(unix)]
+    let owner = chown.map(Chown::resolve).transpose()?;
+    #[cfg(not(unix))]
+    let _ = chown;
+    let mirror = Mirror {
+        root: dst,
+        src_root: src,
+        symlinks,
+        filter,
+        backup,
+        chmod,
+        #[cfg(unix)]
+        owner,
+    };
+    let ignore = Gitignore {
+        enabled: gitignore,
+        ..Default::default()
+    };
+    mirror.entry(src, &dst.join(name), &ignore)
+}
+
+struct Mirror<'a> {
+    root: &'a Path,
+    src_root: &'a Path,
+    symlinks: SymlinkPolicy,
+    filter: &'a EventFilter,
+    backup: Option<&'a Path>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    chmod: Option<&'a Chmod>,
+    /// uid and gid, None keeping the current one
+    #[cfg(unix)]
+    owner: Option<(Option<u32>, Option<u32>)>,
+}
+
+impl Mirror<'_> {
+    /// Remove `path` from dst, moving it into the backup if there is one
+    fn discard(&self, path: &Path, is_dir: bool) -> anyhow::Result<()> {
+        if let Some(backup) = self.backup {
+            let to = backup.join(path.strip_prefix(self.root)?);
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create {}", parent.display()))?;
+            }
+            debug!(?path, ?to, "Backing up");
+            return fs::rename(path, &to)
+                .with_context(|| format!("Failed to back up {}", path.display()));
+        }
+        debug!(?path, "Deleting");
+        if is_dir {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Apply chmod and chown to the mirrored `dst` of an entry with the metadata `meta` in src
+    fn set_attributes(&self, dst: &Path, meta: &fs::Metadata) -> anyhow::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+
+            if let Some(chmod) = self.chmod {
+                let mode = chmod.apply(meta.permissions().mode() & 0o7777, meta.is_dir());
+                if fs::metadata(dst)?.permissions().mode() & 0o7777 != mode {
+                    fs::set_permissions(dst, fs::Permissions::from_mode(mode))
+                        .with_context(|| format!("Failed to chmod {}", dst.display()))?;
+                }
+            }
+            if let Some((uid, gid)) = self.owner {
+                std::os::unix::fs::chown(dst, uid, gid)
+                    .with_context(|| format!("Failed to chown {}", dst.display()))?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (dst, meta);
+        Ok(())
+    }
+
+    fn entry(&self, src: &Path, dst: &Path, ignore: &Gitignore) -> anyhow::Result<()> {
+        let symlinks = self.symlinks;
+        let link = fs::symlink_metadata(src)
+            .with_context(|| format!("Failed to stat {}", src.display()))?
+            .is_symlink();
+        let dst_meta = fs::symlink_metadata(dst).ok();
+        if link && symlinks == SymlinkPolicy::Skip {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        if link && symlinks == SymlinkPolicy::Copy {
+            let target = fs::read_link(src)?;
+            if let Some(dst_meta) = dst_meta.as_ref() {
+                if dst_meta.is_symlink() && fs::read_link(dst).is_ok_and(|t| t == target) {
+                    return Ok(());
+                }
+                self.discard(dst, dst_meta.is_dir())?;
+            }
+            debug!(?src, ?dst, "Linking");
+            std::os::unix::fs::symlink(&target, dst)
+                .with_context(|| format!("Failed to create link {}", dst.display()))?;
+            return Ok(());
+        }
+        let meta =
+            fs::metadata(src).with_context(|| format!("Failed to stat {}", src.display()))?;
+        if meta.is_dir() {
+            if dst_meta.as_ref().is_some_and(|m| !m.is_dir()) {
+                self.discard(dst, false)?;
+            }
+            fs::create_dir_all(dst)
+                .with_context(|| format!("Failed to create {}", dst.display()))?;
+            self.set_attributes(dst, &meta)?;
+
+            let rel = src.strip_prefix(self.src_root).unwrap_or(Path::new(""));
+            let ignore = ignore.enter(src, rel);
+            let transfers = |name, is_dir| {
+                let path = rel.join(name);
+                self.filter.transfers(&path, is_dir) && !ignore.ignores(&path, is_dir)
+            };
+            let mut names = HashSet::new();
+            for entry in fs::read_dir(src)? {
+                let entry = entry?;
+                if !transfers(entry.file_name(), entry.path().is_dir()) {
+                    continue;
+                }
+                self.entry(&entry.path(), &dst.join(entry.file_name()), &ignore)?;
+                if symlinks != SymlinkPolicy::Skip || !entry.file_type()?.is_symlink() {
+                    names.insert(entry.file_name());
+                }
+            }
+            for entry in fs::read_dir(dst)? {
+                let entry = entry?;
+                let is_dir = entry.file_type()?.is_dir();
+                // excluded entries of dst are kept, like rsync without --delete-excluded
+                if names.contains(&entry.file_name()) || !transfers(entry.file_name(), is_dir) {
+                    continue;
+                }
+                self.discard(&entry.path(), is_dir)?;
+            }
+        } else {
+            if let Some(dst_meta) = dst_meta.as_ref() {
+                if dst_meta.is_dir() {
+                    self.discard(dst, true)?;
+                } else if dst_meta.is_symlink() {
+                    self.discard(dst, false)?;
+                } else if dst_meta.len() == meta.len() && dst_meta.modified()? == meta.modified()? {
+                    return self.set_attributes(dst, &meta);
+                } else if self.backup.is_some() {
+                    self.discard(dst, false)?;
+                }
+            }
+            debug!(?src, ?dst, "Copying");
+            fs::copy(src, dst).with_context(|| {
+                format!("Failed to copy {} to {}", src.display(), dst.display())
+            })?;
+            // keep the modification time, so unchanged files are skipped next time
+            fs::File::options()
+                .write(true)
+                .open(dst)?
+                .set_modified(meta.modified()?)?;
+            self.set_attributes(dst, &meta)?;
+        }
+        Ok(())
+    }
+}
+
+/// Paths, relative to `dst`, where the content of `dst` differs from what [mirror] would produce:
+/// files whose bytes differ, and entries missing from either side. Entries left out by `filter`,
+/// or by the `.gitignore` files if `gitignore` is set, aren't compared
+pub fn verify(
+    src: &Path,
+    dst: &Path,
+    symlinks: SymlinkPolicy,
+    filter: &EventFilter,
+    gitignore: bool,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let name = target(src);
+    let mut drift = Vec::new();
+    compare(
+        src,
+        &dst.join(name),
+        name,
+        symlinks,
+        filter,
+        &Gitignore {
+            enabled: gitignore,
+            ..Default::default()
+        },
+        &mut drift,
+    )?;
+    Ok(drift)
+}
+
+/// Where src is mirrored, relative to dst. Like rsync, a src with a trailing slash is mirrored
+/// into dst itself, otherwise into the directory named after src
+fn target(src: &Path) -> &Path {
+    src.strip_prefix(crate::sync::transfer_root(src))
+        .unwrap_or(src)
+}
+
+/// `rel` is relative to dst, starting with the name of src unless its contents are copied
+fn compare(
+    src: &Path,
+    dst: &Path,
+    rel: &Path,
+    symlinks: SymlinkPolicy,
+    filter: &EventFilter,
+    ignore: &Gitignore,
+    drift: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let link = fs::symlink_metadata(src)
+        .with_context(|| format!("Failed to stat {}", src.display()))?
+        .is_symlink();
+    if link && symlinks == SymlinkPolicy::Skip {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    if link && symlinks == SymlinkPolicy::Copy {
+        if fs::read_link(dst).ok() != Some(fs::read_link(src)?) {
+            drift.push(rel.to_owned());
+        }
+        return Ok(());
+    }
+    let meta = fs::metadata(src).with_context(|| format!("Failed to stat {}", src.display()))?;
+    if !meta.is_dir() {
+        if !same_content(src, dst)? {
+            drift.push(rel.to_owned());
+        }
+        return Ok(());
+    }
+    if !fs::symlink_metadata(dst).is_ok_and(|m| m.is_dir()) {
+        drift.push(rel.to_owned());
+        return Ok(());
+    }
+    // relative to src, for the filters
+    let src_rel = rel.iter().skip(1).collect::<PathBuf>();
+    let ignore = ignore.enter(src, &src_rel);
+    let filtered = |name: &std::ffi::OsStr, is_dir: bool| {
+        let path = src_rel.join(name);
+        !filter.transfers(&path, is_dir) || ignore.ignores(&path, is_dir)
+    };
+    let mut names = HashSet::new();
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if filtered(&name, entry.path().is_dir()) {
+            continue;
+        }
+        compare(
+            &entry.path(),
+            &dst.join(&name),
+            &rel.join(&name),
+            symlinks,
+            filter,
+            &ignore,
+            drift,
+        )?;
+        if symlinks != SymlinkPolicy::Skip || !entry.file_type()?.is_symlink() {
+            names.insert(name);
+        }
+    }
+    for entry in fs::read_dir(dst)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !names.contains(&name) && !filtered(&name, entry.file_type()?.is_dir()) {
+            drift.push(rel.join(name));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the regular file `dst` has the same bytes as `src`
+fn same_content(src: &Path, dst: &Path) -> anyhow::Result<bool> {
+    match fs::symlink_metadata(dst) {
+        Ok(m) if m.is_file() && m.len() == fs::metadata(src)?.len() => {}
+        _ => return Ok(false),
+    }
+    let mut a = fs::File::open(src).with_context(|| format!("Failed to open {}", src.display()))?;
+    let mut b = fs::File::open(dst).with_context(|| format!("Failed to open {}", dst.display()))?;
+    let mut buf_a = vec![0; 64 * 1024];
+    let mut buf_b = vec![0; 64 * 1024];
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirror() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        fs::write(src.join("sub/b.txt"), "b").unwrap();
+
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(dst.join("src/a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dst.join("src/sub/b.txt")).unwrap(), "b");
+
+        fs::remove_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "changed").unwrap();
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(dst.join("src/a.txt")).unwrap(),
+            "changed"
+        );
+        assert!(!dst.join("src/sub").exists());
+    }
+
+    #[test]
+    fn test_mirror_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        // copy_contents: true
+        let src = PathBuf::from(format!("{}/", dir.path().join("src").display()));
+        let dst = dir.path().join("dst");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/b.txt"), "b").unwrap();
+        fs::create_dir_all(&dst).unwrap();
+        fs::write(dst.join("stale.txt"), "stale").unwrap();
+
+        let filter = EventFilter::default();
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &filter,
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(dst.join("sub/b.txt")).unwrap(), "b");
+        assert!(!dst.join("src").exists());
+        assert!(!dst.join("stale.txt").exists());
+        assert!(verify(&src, &dst, SymlinkPolicy::Follow, &filter, false)
+            .unwrap()
+            .is_empty());
+
+        fs::write(dst.join("sub/b.txt"), "changed").unwrap();
+        assert_eq!(
+            verify(&src, &dst, SymlinkPolicy::Follow, &filter, false).unwrap(),
+            [PathBuf::from("sub/b.txt")]
+        );
+    }
+
+    #[test]
+    fn test_mirror_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::create_dir_all(src.join("app")).unwrap();
+        fs::create_dir_all(src.join("target")).unwrap();
+        fs::write(src.join("app/main.py"), "main").unwrap();
+        fs::write(src.join("README.md"), "readme").unwrap();
+        fs::write(src.join("target/gen.py"), "gen").unwrap();
+        let filter = EventFilter {
+            include: vec!["*.py".into()],
+            exclude: vec!["target".into()],
+            ..Default::default()
+        };
+        // excluded files of dst are kept, like with rsync
+        fs::create_dir_all(dst.join("src")).unwrap();
+        fs::write(dst.join("src/notes.txt"), "notes").unwrap();
+
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &filter,
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(dst.join("src/app/main.py")).unwrap(),
+            "main"
+        );
+        assert!(!dst.join("src/README.md").exists());
+        assert!(!dst.join("src/target").exists());
+        assert!(dst.join("src/notes.txt").exists());
+        assert!(verify(&src, &dst, SymlinkPolicy::Follow, &filter, false)
+            .unwrap()
+            .is_empty());
+
+        fs::remove_file(src.join("app/main.py")).unwrap();
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &filter,
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(!dst.join("src/app/main.py").exists());
+    }
+
+    #[test]
+    fn test_mirror_gitignore() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::create_dir_all(src.join("web/node_modules")).unwrap();
+        fs::create_dir_all(src.join("api/build")).unwrap();
+        fs::write(src.join(".gitignore"), "# generated\n*.log\nbuild/\n").unwrap();
+        fs::write(src.join("web/.gitignore"), "node_modules\n/dist.js\n").unwrap();
+        fs::write(src.join("debug.log"), "log").unwrap();
+        fs::write(src.join("api/build/out"), "out").unwrap();
+        fs::write(src.join("api/dist.js"), "api").unwrap();
+        fs::write(src.join("web/dist.js"), "web").unwrap();
+        fs::write(src.join("web/node_modules/dep.js"), "dep").unwrap();
+        fs::write(src.join("web/index.js"), "index").unwrap();
+        let filter = EventFilter::default();
+
+        let defaults = crate::sync::DEFAULT_RSYCN_FLAGS
+            .iter()
+            .map(|f| f.to_string());
+        assert!(reads_gitignore(&defaults.collect::<Vec<_>>()));
+        assert!(!reads_gitignore(&["-a".to_owned()]));
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &filter,
+            true,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let copied = |p: &str| dst.join("src").join(p).exists();
+        assert!(copied(".gitignore"));
+        assert!(copied("web/index.js"));
+        // the rules of a .gitignore are relative to its directory
+        assert!(copied("api/dist.js"));
+        assert!(!copied("web/dist.js"));
+        assert!(!copied("web/node_modules"));
+        assert!(!copied("debug.log"));
+        assert!(!copied("api/build"));
+        assert!(verify(&src, &dst, SymlinkPolicy::Follow, &filter, true)
+            .unwrap()
+            .is_empty());
+
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &filter,
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(copied("web/node_modules/dep.js"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        fs::write(src.join("sub/b.txt"), "b").unwrap();
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(verify(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false
+        )
+        .unwrap()
+        .is_empty());
+
+        // same size and modification time, so mirror skips it
+        let meta = fs::metadata(dst.join("src/a.txt")).unwrap();
+        fs::write(dst.join("src/a.txt"), "x").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(dst.join("src/a.txt"))
+            .unwrap()
+            .set_modified(meta.modified().unwrap())
+            .unwrap();
+        fs::write(dst.join("src/sub/extra.txt"), "extra").unwrap();
+        let mut drift = verify(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false,
+        )
+        .unwrap();
+        drift.sort();
+        assert_eq!(
+            drift,
+            [Path::new("src/a.txt"), Path::new("src/sub/extra.txt")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mirror_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink("a.txt", src.join("link")).unwrap();
+
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Copy,
+            &EventFilter::default(),
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_link(dst.join("src/link")).unwrap(),
+            Path::new("a.txt")
+        );
+
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(!dst.join("src/link").is_symlink());
+        assert_eq!(fs::read_to_string(dst.join("src/link")).unwrap(), "a");
+
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Skip,
+            &EventFilter::default(),
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert!(fs::symlink_metadata(dst.join("src/link")).is_err());
+        assert!(dst.join("src/a.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mirror_permissions() {
+        use std::os::unix::fs::{MetadataExt as _, PermissionsExt as _};
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/a.txt"), "a").unwrap();
+        fs::set_permissions(src.join("sub/a.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+        let chmod = "D750,F640".parse().unwrap();
+        // without root, only the own user can be set
+        let chown = Chown {
+            user: Some(fs::metadata(&src).unwrap().uid().to_string()),
+            group: None,
+        };
+
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false,
+            None,
+            Some(&chmod),
+            Some(&chown),
+        )
+        .unwrap();
+        let mode = |p: &str| fs::metadata(dst.join(p)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("src/sub"), 0o750);
+        assert_eq!(mode("src/sub/a.txt"), 0o640);
+
+        // unchanged files get them too
+        let chmod = "F644".parse().unwrap();
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false,
+            None,
+            Some(&chmod),
+            None,
+        )
+        .unwrap();
+        assert_eq!(mode("src/sub/a.txt"), 0o644);
+    }
+
+    #[test]
+    fn test_mirror_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        let backup = dst.join(".atune-backup/1");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("a.txt"), "a").unwrap();
+        fs::write(src.join("b.txt"), "b").unwrap();
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        fs::remove_file(src.join("b.txt")).unwrap();
+        fs::write(src.join("a.txt"), "changed").unwrap();
+        mirror(
+            &src,
+            &dst,
+            SymlinkPolicy::Follow,
+            &EventFilter::default(),
+            false,
+            Some(&backup),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(backup.join("src/a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(backup.join("src/b.txt")).unwrap(), "b");
+        assert_eq!(
+            fs::read_to_string(dst.join("src/a.txt")).unwrap(),
+            "changed"
+        );
+        assert!(!dst.join("src/b.txt").exists());
+    }
+}
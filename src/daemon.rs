@@ -0,0 +1,95 @@
+//! Running `watch` in the background, see `atune watch --daemon`
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read as _, Seek as _, SeekFrom},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+/// A watch exiting within this time after it was started failed to start, e.g. because the
+/// config is invalid or another atune watches it already
+const STARTUP_GRACE: Duration = Duration::from_secs(1);
+
+/// Default log file of the background watch of a config: `atune-<hash>.log` in the state dir,
+/// named like its pidfile, see [crate::lock::lock_path]
+pub fn log_path(config: &Path) -> PathBuf {
+    let fallback = crate::lock::lock_path(config).with_extension("log");
+    crate::state::state_path()
+        .and_then(|state| Some(state.parent()?.join(fallback.file_name()?)))
+        .unwrap_or(fallback)
+}
+
+/// Start atune with `args` detached from the terminal. Its output is written to the `log` passed
+/// as `--log-file`, its stderr is appended to it too, for the errors printed before the log file
+/// is opened. Returns its pid once it survived the start
+pub fn spawn(args: &[OsString], log: &Path) -> anyhow::Result<u32> {
+    if let Some(dir) = log.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let mut file = File::options()
+        .create(true)
+        .append(true)
+        .open(log)
+        .with_context(|| format!("Failed to open the log file {}", log.display()))?;
+    let start = file.seek(SeekFrom::End(0))?;
+    let mut cmd =
+        Command::new(std::env::current_exe().context("Failed to find the atune executable")?);
+    cmd.args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(file);
+    detach(&mut cmd);
+    let mut child = cmd.spawn().context("Failed to start atune")?;
+    let deadline = Instant::now() + STARTUP_GRACE;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            anyhow::bail!(
+                "atune watch exited with {status}:\n{}",
+                read_from(log, start).trim_end()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(child.id())
+}
+
+/// Run in a session of its own, so closing the terminal doesn't hang it up
+#[cfg(unix)]
+fn detach(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt as _;
+    // SAFETY: setsid is async-signal-safe
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(windows)]
+fn detach(cmd: &mut Command) {
+    use std::os::windows::process::CommandExt as _;
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    cmd.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+}
+
+#[cfg(not(any(unix, windows)))]
+fn detach(_cmd: &mut Command) {}
+
+fn read_from(log: &Path, offset: u64) -> String {
+    let mut out = Vec::new();
+    if let Ok(mut file) = File::open(log) {
+        let _ = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_to_end(&mut out));
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,342 @@
+//! Environment diagnostics for `atune doctor`
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{self, Config, ConfigFormat, ConfigOverrides, SyncBackend};
+
+#[derive(Debug, Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&mut self, check: impl Display) {
+        println!("✓ {check}");
+    }
+
+    fn warn(&mut self, check: impl Display, fix: impl Display) {
+        println!("! {check}\n    {fix}");
+    }
+
+    fn fail(&mut self, check: impl Display, err: impl Display, fix: impl Display) {
+        self.failures += 1;
+        println!("✗ {check}: {err}\n    {fix}");
+    }
+}
+
+/// Check the environment the config runs in, printing the results and suggested fixes.
+///
+/// Returns the number of failed checks
+pub fn run(
+    config_path: &Path,
+    format: ConfigFormat,
+    overrides: &ConfigOverrides,
+    rsync: &Path,
+) -> usize {
+    let mut report = Report::default();
+    let config = match std::fs::read_to_string(config_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|c| Config::parse_with(&c, format, overrides))
+    {
+        Ok(c) => {
+            report.ok(format_args!("config {}", config_path.display()));
+            c
+        }
+        Err(err) => {
+            report.fail(
+                format_args!("config {}", config_path.display()),
+                format_args!("{err:#}"),
+                "Fix the config file, `atune schema` prints the accepted fields",
+            );
+            return report.failures;
+        }
+    };
+    let syncs = config
+        .projects
+        .iter()
+        .flat_map(|(name, p)| p.sync.iter().map(move |s| (name, p, s)))
+        .filter(|(_, _, s)| s.enabled)
+        .collect::<Vec<_>>();
+
+    for (name, _, s) in syncs.iter() {
+        if !s.src.exists() {
+            report.fail(
+                format_args!("[{name}] src {}", s.src.display()),
+                "does not exist",
+                "Create the directory or fix the path in the config",
+            );
+        }
+    }
+
+    let rsyncs = syncs
+        .iter()
+        .filter(|(_, _, s)| s.dst.is_some() && s.backend == SyncBackend::Rsync)
+        .map(|(_, p, _)| p.rsync.as_deref().unwrap_or(rsync))
+        .collect::<BTreeSet<_>>();
+    for rsync in rsyncs {
+        check_rsync(&mut report, rsync);
+    }
+
+    #[cfg(target_os = "linux")]
+    check_inotify(
+        &mut report,
+        syncs
+            .iter()
+            .filter(|(_, p, _)| {
+                !matches!(
+                    p.watcher,
+                    config::WatcherKind::Poll | config::WatcherKind::Manual
+                )
+            })
+            .map(|(_, _, s)| *s),
+    );
+
+    let destinations = syncs
+        .iter()
+        .flat_map(|(name, p, s)| {
+            let destinations = s.destinations().unwrap_or_default();
+            destinations
+                .into_iter()
+                .map(move |dst| (*name, *p, *s, dst))
+        })
+        .collect::<Vec<_>>();
+    let mut hosts = BTreeMap::new();
+    for (name, p, s, dst) in destinations.iter() {
+        if let Some((host, module)) = config::rsync_daemon_dst(dst) {
+            let rsync = p.rsync.as_deref().unwrap_or(rsync);
+            check_rsync_daemon(&mut report, rsync, host, module, s);
+            continue;
+        }
+        match config::remote_dst(dst) {
+            Some((host, path)) => {
+                let ssh = s.ssh_args_of(dst);
+                let reachable = *hosts
+                    .entry((host, ssh))
+                    .or_insert_with(|| check_ssh(&mut report, host, ssh));
+                if reachable {
+                    check_remote_writable(&mut report, name, host, ssh, path);
+                }
+            }
+            None => check_local_writable(&mut report, name, dst),
+        }
+    }
+
+    println!();
+    match report.failures {
+        0 => println!("No problems found"),
+        n => println!("{n} check(s) failed"),
+    }
+    report.failures
+}
+
+fn check_rsync(report: &mut Report, rsync: &Path) {
+    let version = xshell::Shell::new()
+        .map_err(anyhow::Error::from)
+        .and_then(|sh| {
+            let out = xshell::cmd!(sh, "{rsync} --version").quiet().read()?;
+            Ok(out.lines().next().unwrap_or_default().to_owned())
+        });
+    match version {
+        Ok(version) => report.ok(format_args!("rsync: {version}")),
+        Err(err) => report.fail(
+            format_args!("rsync {}", rsync.display()),
+            err,
+            "Install rsync, pass its path with --rsync, or use `backend: Copy` for local destinations",
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_inotify<'a>(report: &mut Report, syncs: impl Iterator<Item = &'a config::FileSync>) {
+    let limit = match std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches") {
+        Ok(l) => l.trim().parse::<usize>().unwrap_or(usize::MAX),
+        Err(err) => {
+            report.warn(
+                format_args!("failed to read the inotify watch limit: {err}"),
+                "Set `watcher: Poll` on the projects if changes are not picked up",
+            );
+            return;
+        }
+    };
+    // inotify needs a watch per directory
+    let dirs = syncs
+        .map(|s| count_dirs(&s.src, s.recursive))
+        .sum::<usize>();
+    let check = format!("inotify watches: {dirs} directories, limit {limit}");
+    if dirs >= limit {
+        report.fail(
+            check,
+            "the watch limit is too low",
+            "Raise it with `sysctl fs.inotify.max_user_watches=524288` or set `watcher: Poll` on the project",
+        );
+    } else if dirs >= limit / 10 * 8 {
+        report.warn(
+            check,
+            "Close to the limit, other programs watching files may exhaust it. Raise it with `sysctl fs.inotify.max_user_watches=524288`",
+        );
+    } else {
+        report.ok(check);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn count_dirs(path: &Path, recursive: bool) -> usize {
+    if !path.is_dir() {
+        return 0;
+    }
+    let mut count = 1;
+    if recursive {
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for e in entries.flatten() {
+                if e.file_type().is_ok_and(|t| t.is_dir()) {
+                    count += count_dirs(&e.path(), true);
+                }
+            }
+        }
+    }
+    count
+}
+
+fn check_rsync_daemon(
+    report: &mut Report,
+    rsync: &Path,
+    host: &str,
+    module: &str,
+    sync: &config::FileSync,
+) {
+    let check = format!("rsync daemon module {host}/{module}");
+    let url = format!("rsync://{host}/{module}/");
+    let password_file = sync.password_file.as_ref().map(|f| {
+        let mut arg = std::ffi::OsString::from("--password-file=");
+        arg.push(f);
+        arg
+    });
+    let secret = sync
+        .password
+        .clone()
+        .or_else(|| sync.password_env.clone().map(config::Secret::Env));
+    let password = match secret.as_ref().map(config::Secret::resolve) {
+        Some(Ok(p)) => Some(p),
+        Some(Err(err)) => {
+            report.fail(
+                check,
+                format_args!("{err:#}"),
+                "Make the password available before running atune",
+            );
+            return;
+        }
+        None => None,
+    };
+    let result = xshell::Shell::new()
+        .map_err(anyhow::Error::from)
+        .and_then(|sh| {
+            let mut cmd = xshell::cmd!(
+                sh,
+                "{rsync} --list-only --contimeout=5 {password_file...} {url}"
+            )
+            .quiet()
+            .ignore_stdout();
+            if let Some(p) = password.as_deref() {
+                cmd = cmd.env("RSYNC_PASSWORD", p);
+            }
+            cmd.run()?;
+            Ok(())
+        });
+    match result {
+        Ok(()) => report.ok(check),
+        Err(err) => report.fail(
+            check,
+            err,
+            "Make sure the rsync daemon is running and the module exists. Modules requiring authentication need `password`, `password_file` or `password_env`",
+        ),
+    }
+}
+
+fn check_ssh(report: &mut Report, host: &str, ssh: &[String]) -> bool {
+    let result = xshell::Shell::new()
+        .map_err(anyhow::Error::from)
+        .and_then(|sh| {
+            xshell::cmd!(
+                sh,
+                "ssh -o BatchMode=yes -o ConnectTimeout=5 {ssh...} {host} true"
+            )
+            .quiet()
+            .ignore_stdout()
+            .run()?;
+            Ok(())
+        });
+    match result {
+        Ok(()) => {
+            report.ok(format_args!("ssh {host}"));
+            true
+        }
+        Err(err) => {
+            report.fail(
+            format_args!("ssh {host}"),
+            err,
+            format_args!(
+                "Make sure `ssh {host}` connects without a password prompt, e.g. with `ssh-copy-id {host}`"
+            ),
+            );
+            false
+        }
+    }
+}
+
+fn check_remote_writable(
+    report: &mut Report,
+    project: &str,
+    host: &str,
+    ssh: &[String],
+    path: &str,
+) {
+    let check = format!("[{project}] dst {host}:{path} is writable");
+    // rsync creates the last component of dst
+    let test = format!("test -w {path} || test -w \"$(dirname {path})\"");
+    let result = xshell::Shell::new()
+        .map_err(anyhow::Error::from)
+        .and_then(|sh| {
+            xshell::cmd!(
+                sh,
+                "ssh -o BatchMode=yes -o ConnectTimeout=5 {ssh...} {host} {test}"
+            )
+            .quiet()
+            .ignore_stdout()
+            .ignore_stderr()
+            .run()?;
+            Ok(())
+        });
+    match result {
+        Ok(()) => report.ok(check),
+        Err(_) => report.fail(
+            check,
+            "not writable",
+            format_args!("Create the directory or fix its permissions on {host}"),
+        ),
+    }
+}
+
+fn check_local_writable(report: &mut Report, project: &str, dst: &Path) {
+    let check = format!("[{project}] dst {} is writable", dst.display());
+    // rsync creates the last component of dst
+    let dir: PathBuf = if dst.exists() {
+        dst.to_owned()
+    } else {
+        match dst.parent() {
+            Some(p) if p.as_os_str().is_empty() => PathBuf::from("."),
+            Some(p) => p.to_owned(),
+            None => dst.to_owned(),
+        }
+    };
+    let probe = dir.join(format!(".atune-doctor-{}", std::process::id()));
+    match std::fs::write(&probe, "") {
+        Ok(()) => {
+            let _ = std::fs::remove_file(&probe);
+            report.ok(check);
+        }
+        Err(err) => report.fail(check, err, "Create the directory or fix its permissions"),
+    }
+}
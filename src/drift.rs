@@ -0,0 +1,157 @@
+//! Changes of local destinations made outside of atune, see [crate::config::FileSync::watch_dst]
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel;
+use notify::Watcher as _;
+use tracing::{debug, warn};
+
+use crate::{config, sync::ParsedSync};
+
+/// Changes are reported once dst was quiet for this long, so a burst of writes is reported once
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Watches the copies of the entries in their local dst
+pub struct DstWatcher {
+    watcher: notify::RecommendedWatcher,
+    rx: channel::Receiver<notify::Result<notify::Event>>,
+    /// dst directories waiting to be created by the first sync
+    pending: Vec<PathBuf>,
+    registered: HashSet<PathBuf>,
+    /// the entries and the path of their copy in dst, resolved once dst exists
+    entries: Vec<(PathBuf, PathBuf, Option<PathBuf>)>,
+    drifted: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+    last_change: Option<Instant>,
+}
+
+/// The local dst directory of the entry and the path of the copy of src in it, None if dst isn't
+/// local
+fn local_copy(s: &ParsedSync) -> Option<(PathBuf, PathBuf)> {
+    let dst = s.dst.as_deref()?;
+    if !s.fan_out.is_empty()
+        || config::remote_dst(dst).is_some()
+        || config::rsync_daemon_dst(dst).is_some()
+    {
+        return None;
+    }
+    let rel = s
+        .src
+        .strip_prefix(crate::sync::transfer_root(&s.src))
+        .unwrap_or(Path::new(""));
+    Some((dst.to_owned(), rel.to_owned()))
+}
+
+impl DstWatcher {
+    /// Watch the local dst of the entries with `watch_dst`, keyed by their resolved src. None if
+    /// there are none
+    pub fn new<'a>(syncs: impl IntoIterator<Item = (&'a PathBuf, &'a ParsedSync)>) -> Option<Self> {
+        let mut pending = Vec::new();
+        let mut entries = Vec::new();
+        for (a, s) in syncs {
+            if s.watch_dst.is_none() {
+                continue;
+            }
+            let Some((dst, rel)) = local_copy(s) else {
+                warn!(src = ?s.src, "watch_dst only watches local destinations, ignoring it");
+                continue;
+            };
+            if !pending.contains(&dst) {
+                pending.push(dst.clone());
+            }
+            entries.push((a.clone(), dst, Some(rel)));
+        }
+        if entries.is_empty() {
+            return None;
+        }
+        let (tx, rx) = channel::unbounded();
+        let watcher = match notify::recommended_watcher(tx) {
+            Ok(w) => w,
+            Err(err) => {
+                warn!(?err, "Failed to watch the destinations");
+                return None;
+            }
+        };
+        Some(Self {
+            watcher,
+            rx,
+            pending,
+            registered: HashSet::new(),
+            entries,
+            drifted: BTreeMap::new(),
+            last_change: None,
+        })
+    }
+
+    /// Register the dst directories created since the last call
+    fn register(&mut self) {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.retain(|dst| {
+            let Ok(resolved) = crate::platform::canonicalize(dst) else {
+                return true;
+            };
+            if self.registered.insert(resolved.clone()) {
+                if let Err(err) = self
+                    .watcher
+                    .watch(&resolved, notify::RecursiveMode::Recursive)
+                {
+                    warn!(?err, ?dst, "Failed to watch dst");
+                }
+            }
+            // the copies in dst are matched by the resolved path of the events
+            for (_, d, rel) in self.entries.iter_mut() {
+                if d == dst {
+                    if let Some(r) = rel.take() {
+                        *d = resolved.join(r);
+                    }
+                }
+            }
+            false
+        });
+        self.pending = pending;
+    }
+
+    /// The entries whose copy in dst changed, with the changed paths, once dst settled.
+    /// Changes of entries for which `syncing` is true are made by atune and ignored
+    pub fn poll(
+        &mut self,
+        syncing: impl Fn(&Path) -> bool,
+    ) -> BTreeMap<PathBuf, BTreeSet<PathBuf>> {
+        if !self.pending.is_empty() {
+            self.register();
+        }
+        for event in self.rx.try_iter() {
+            let event = match event {
+                Ok(e) => e,
+                Err(err) => {
+                    debug!(?err, "dst watcher error");
+                    continue;
+                }
+            };
+            if matches!(event.kind, notify::EventKind::Access(_)) {
+                continue;
+            }
+            for path in event.paths {
+                let entry = self
+                    .entries
+                    .iter()
+                    .find(|(_, copy, rel)| rel.is_none() && path.starts_with(copy));
+                let Some((a, _, _)) = entry else {
+                    continue;
+                };
+                if syncing(a) {
+                    continue;
+                }
+                self.drifted.entry(a.clone()).or_default().insert(path);
+                self.last_change = Some(Instant::now());
+            }
+        }
+        if self.last_change.is_none_or(|t| t.elapsed() < SETTLE) {
+            return BTreeMap::new();
+        }
+        self.last_change = None;
+        std::mem::take(&mut self.drifted)
+    }
+}
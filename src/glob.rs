@@ -0,0 +1,135 @@
+//! Minimal glob matching: `*` matches within a path component, `**` across components and `?`
+//! matches a single character
+use std::path::{Component, Path, PathBuf};
+
+/// Match `pattern` against `path`, relative to the watched root.
+///
+/// Like gitignore, patterns without a `/` match the file name of any component instead
+pub fn matches_path(pattern: &str, path: &Path) -> bool {
+    let path = path.to_string_lossy().replace('\\', "/");
+    if pattern.contains('/') {
+        matches(pattern.trim_start_matches('/'), &path)
+    } else {
+        path.split('/').any(|c| matches(pattern, c))
+    }
+}
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    fn go(p: &[char], t: &[char]) -> bool {
+        match p {
+            [] => t.is_empty(),
+            ['*', '*', rest @ ..] => {
+                // `**/` also matches no directories at all
+                let rest = rest.strip_prefix(&['/']).unwrap_or(rest);
+                (0..=t.len()).any(|i| go(rest, &t[i..]))
+            }
+            ['*', rest @ ..] => (0..=t.len())
+                .take_while(|i| *i == 0 || t[i - 1] != '/')
+                .any(|i| go(rest, &t[i..])),
+            ['?', rest @ ..] => t.first().is_some_and(|c| *c != '/') && go(rest, &t[1..]),
+            [c, rest @ ..] => t.first() == Some(c) && go(rest, &t[1..]),
+        }
+    }
+    let p = pattern.chars().collect::<Vec<_>>();
+    let t = text.chars().collect::<Vec<_>>();
+    go(&p, &t)
+}
+
+/// Whether `s` contains wildcards
+pub fn is_pattern(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// The existing paths matching `pattern`, whose wildcards match within a single component.
+///
+/// Each path comes with the names matched by the components of the pattern containing wildcards.
+/// Like a shell, wildcards don't match names starting with a `.` unless the pattern does
+pub fn expand(pattern: &Path) -> Vec<(PathBuf, Vec<String>)> {
+    let mut found = vec![(PathBuf::new(), Vec::new())];
+    for component in pattern.components() {
+        let glob = component.as_os_str().to_string_lossy();
+        if !matches!(component, Component::Normal(_)) || !is_pattern(&glob) {
+            for (path, _) in found.iter_mut() {
+                path.push(component);
+            }
+            continue;
+        }
+        let mut next = Vec::new();
+        for (dir, captures) in found {
+            let read = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir.as_path()
+            };
+            let Ok(entries) = std::fs::read_dir(read) else {
+                continue;
+            };
+            let mut names = entries
+                .filter_map(|e| e.ok()?.file_name().into_string().ok())
+                .filter(|n| (!n.starts_with('.') || glob.starts_with('.')) && matches(&glob, n))
+                .collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                let mut captures = captures.clone();
+                let path = dir.join(&name);
+                captures.push(name);
+                next.push((path, captures));
+            }
+        }
+        found = next;
+    }
+    found.retain(|(path, _)| path.exists());
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*.swp", ".main.rs.swp"));
+        assert!(matches("*~", "main.rs~"));
+        assert!(matches("?#*", ".#main.rs"));
+        assert!(!matches("*.rs", "src/main.rs"));
+        assert!(matches("**/*.rs", "main.rs"));
+        assert!(matches("**/*.rs", "src/bin/main.rs"));
+        assert!(matches("src/**", "src/bin/main.rs"));
+        assert!(!matches("*.rs", "main.rsx"));
+    }
+
+    #[test]
+    fn test_matches_path() {
+        assert!(matches_path("*.swp", Path::new("src/.main.rs.swp")));
+        assert!(matches_path(
+            "node_modules",
+            Path::new("web/node_modules/x.js")
+        ));
+        assert!(matches_path("/src/*.rs", Path::new("src/main.rs")));
+        assert!(!matches_path("src/*.rs", Path::new("web/src/main.rs")));
+    }
+
+    #[test]
+    fn test_expand() {
+        let dir = tempfile::tempdir().unwrap();
+        for p in [
+            "packages/web/dist",
+            "packages/api/dist",
+            "packages/docs",
+            ".cache/dist",
+        ] {
+            std::fs::create_dir_all(dir.path().join(p)).unwrap();
+        }
+
+        let found = expand(&dir.path().join("packages/*/dist"));
+        assert_eq!(
+            found,
+            vec![
+                (dir.path().join("packages/api/dist"), vec!["api".to_owned()]),
+                (dir.path().join("packages/web/dist"), vec!["web".to_owned()]),
+            ]
+        );
+        assert_eq!(expand(&dir.path().join("*/dist")), vec![]);
+        assert_eq!(expand(&dir.path().join(".c?che/dist")).len(), 1);
+    }
+}
@@ -0,0 +1,345 @@
+//! Content hashes of local files, cached by their size and modification time, so the files shared
+//! by several entries and destinations are read once. Also records the content the copies of the
+//! share groups hold, see [crate::config::FileSync::share_relay]
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead as _, Write as _},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use tracing::{debug, warn};
+
+use crate::state::Fnv;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    /// modification time in nanoseconds since the Unix epoch
+    mtime: u128,
+    hash: u64,
+}
+
+/// Content hashes of files, keyed by their absolute path
+#[derive(Debug, Default)]
+pub struct HashCache {
+    entries: HashMap<PathBuf, Entry>,
+    /// hashes were added since it was loaded
+    dirty: bool,
+}
+
+impl HashCache {
+    fn read(file: &Path) -> anyhow::Result<Self> {
+        let f = match std::fs::File::open(file) {
+            Ok(f) => f,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).context("Failed to open the hash cache"),
+        };
+        let mut entries = HashMap::new();
+        for line in std::io::BufReader::new(f).lines() {
+            let line = line.context("Failed to read the hash cache")?;
+            // hash, size, mtime, path
+            let mut parts = line.splitn(4, '\t');
+            let (Some(hash), Some(size), Some(mtime), Some(path)) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                anyhow::bail!("Malformed hash cache line: {line:?}");
+            };
+            entries.insert(
+                PathBuf::from(path),
+                Entry {
+                    size: size.parse().context("Malformed size")?,
+                    mtime: mtime.parse().context("Malformed mtime")?,
+                    hash: u64::from_str_radix(hash, 16).context("Malformed hash")?,
+                },
+            );
+        }
+        Ok(Self {
+            entries,
+            dirty: false,
+        })
+    }
+
+    fn write(&mut self, file: &Path) -> anyhow::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        // files removed since they were hashed
+        self.entries.retain(|p, _| p.exists());
+        let s = self
+            .entries
+            .iter()
+            .map(|(p, e)| {
+                format!(
+                    "{:016x}\t{}\t{}\t{}\n",
+                    e.hash,
+                    e.size,
+                    e.mtime,
+                    p.display()
+                )
+            })
+            .collect::<String>();
+        replace(file, &s)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// The content hash of the file at `path`, read only if it changed since it was last hashed
+    pub fn hash(&mut self, path: &Path) -> Option<u64> {
+        let meta = std::fs::metadata(path).ok()?;
+        let size = meta.len();
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default()
+            .as_nanos();
+        match self.entries.get(path) {
+            Some(e) if e.size == size && e.mtime == mtime => Some(e.hash),
+            _ => {
+                let hash = hash_file(path)?;
+                self.entries
+                    .insert(path.to_owned(), Entry { size, mtime, hash });
+                self.dirty = true;
+                Some(hash)
+            }
+        }
+    }
+
+    /// Hash of the content of the tree at `src`: the paths relative to src, the content of the
+    /// files and the targets of the symlinks. Equal for copies of the same tree
+    pub fn digest(&mut self, src: &Path) -> u64 {
+        let mut files = BTreeMap::new();
+        collect(src, Path::new(""), &mut files);
+        let mut digest = Fnv::default();
+        for (rel, kind) in files {
+            digest.write(rel.as_os_str().as_encoded_bytes());
+            match kind {
+                Kind::Dir => digest.write(b"d"),
+                Kind::Link(target) => {
+                    digest.write(b"l");
+                    digest.write(target.as_os_str().as_encoded_bytes());
+                }
+                Kind::File => {
+                    let hash = self.hash(&join(src, &rel)).unwrap_or_default();
+                    digest.write(&hash.to_le_bytes());
+                }
+            }
+        }
+        digest.0
+    }
+}
+
+/// Where the cache is stored, next to the state file
+fn location() -> anyhow::Result<PathBuf> {
+    let state = crate::state::state_path().context("Failed to determine the state directory")?;
+    Ok(state.with_file_name("hashes"))
+}
+
+/// The cache of the process, loaded on first use
+fn cache() -> &'static Mutex<HashCache> {
+    static CACHE: OnceLock<Mutex<HashCache>> = OnceLock::new();
+    CACHE.get_or_init(|| {
+        let cache = location()
+            .and_then(|f| HashCache::read(&f))
+            .unwrap_or_else(|err| {
+                warn!(?err, "Failed to load the hash cache, starting over");
+                HashCache::default()
+            });
+        Mutex::new(cache)
+    })
+}
+
+/// [HashCache::hash] with the cache of the process
+pub fn hash(path: &Path) -> Option<u64> {
+    cache().lock().unwrap().hash(path)
+}
+
+/// [HashCache::digest] with the cache of the process
+pub fn digest(src: &Path) -> u64 {
+    cache().lock().unwrap().digest(src)
+}
+
+/// Write the hashes added by this process. Errors are logged, the cache is only an optimization
+pub fn save() {
+    let saved = location().and_then(|f| cache().lock().unwrap().write(&f));
+    if let Err(err) = saved {
+        warn!(?err, "Failed to save the hash cache");
+    }
+}
+
+/// Where the digests of the copies of the share groups are stored, next to the state file
+fn copies_location() -> anyhow::Result<PathBuf> {
+    let state = crate::state::state_path().context("Failed to determine the state directory")?;
+    Ok(state.with_file_name("shared_copies"))
+}
+
+fn read_copies(file: &Path) -> anyhow::Result<BTreeMap<PathBuf, u64>> {
+    let content = match std::fs::read_to_string(file) {
+        Ok(c) => c,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err).context("Failed to read the shared copies"),
+    };
+    content
+        .lines()
+        .map(|line| {
+            // digest, copy
+            let (digest, copy) = line
+                .split_once('\t')
+                .with_context(|| format!("Malformed shared copy line: {line:?}"))?;
+            let digest = u64::from_str_radix(digest, 16).context("Malformed digest")?;
+            Ok((PathBuf::from(copy), digest))
+        })
+        .collect()
+}
+
+/// Record that `copy`, a local path or `host:path`, holds the content with `digest`
+pub fn record_copy(copy: &Path, digest: u64) {
+    let recorded = copies_location().and_then(|file| {
+        let mut copies = read_copies(&file).unwrap_or_default();
+        copies.insert(copy.to_owned(), digest);
+        let s = copies
+            .iter()
+            .map(|(c, d)| format!("{d:016x}\t{}\n", c.display()))
+            .collect::<String>();
+        replace(&file, &s)
+    });
+    match recorded {
+        Ok(()) => debug!(?copy, "Recorded the content of the shared copy"),
+        Err(err) => warn!(?err, "Failed to record the content of the shared copy"),
+    }
+}
+
+/// The digest of the content `copy` held when it was last synced, see [record_copy]
+pub fn copy_digest(copy: &Path) -> Option<u64> {
+    let copies = copies_location().and_then(|f| read_copies(&f));
+    match copies {
+        Ok(copies) => copies.get(copy).copied(),
+        Err(err) => {
+            warn!(?err, "Failed to read the shared copies");
+            None
+        }
+    }
+}
+
+/// Replace `file` atomically, so concurrent readers never see a partial file
+fn replace(file: &Path, content: &str) -> anyhow::Result<()> {
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir).context("Failed to create the state directory")?;
+    }
+    let tmp = file.with_extension(std::process::id().to_string());
+    let mut f = std::fs::File::create(&tmp)
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    f.write_all(content.as_bytes())?;
+    drop(f);
+    std::fs::rename(&tmp, file).with_context(|| format!("Failed to replace {}", file.display()))
+}
+
+enum Kind {
+    File,
+    Dir,
+    Link(PathBuf),
+}
+
+/// Collect the entries at `rel`, relative to `src`, recursively
+fn collect(src: &Path, rel: &Path, out: &mut BTreeMap<PathBuf, Kind>) {
+    let path = join(src, rel);
+    let Ok(meta) = std::fs::symlink_metadata(&path) else {
+        return;
+    };
+    let kind = if meta.is_symlink() {
+        Kind::Link(std::fs::read_link(&path).unwrap_or_default())
+    } else if meta.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(&path) {
+            for e in entries.flatten() {
+                collect(src, &rel.join(e.file_name()), out);
+            }
+        }
+        Kind::Dir
+    } else {
+        Kind::File
+    };
+    out.insert(rel.to_owned(), kind);
+}
+
+/// `src.join(rel)`, without adding a trailing separator if src is a file and rel is empty
+fn join(src: &Path, rel: &Path) -> PathBuf {
+    if rel.as_os_str().is_empty() {
+        src.to_owned()
+    } else {
+        src.join(rel)
+    }
+}
+
+fn hash_file(path: &Path) -> Option<u64> {
+    let content = std::fs::read(path).ok()?;
+    let mut hash = Fnv::default();
+    hash.write(&content);
+    Some(hash.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_is_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a");
+        std::fs::write(&file, "hello").unwrap();
+        let mut cache = HashCache::default();
+        let hash = cache.hash(&file).unwrap();
+        assert!(cache.dirty);
+
+        let cache_file = dir.path().join("hashes");
+        cache.write(&cache_file).unwrap();
+        let mut cache = HashCache::read(&cache_file).unwrap();
+        assert_eq!(cache.hash(&file), Some(hash));
+        assert!(!cache.dirty, "the unchanged file was hashed again");
+
+        std::fs::write(&file, "world").unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(1);
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_ne!(cache.hash(&file), Some(hash));
+        assert!(cache.dirty);
+    }
+
+    #[test]
+    fn test_digest_of_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        for copy in ["a", "b"] {
+            let root = dir.path().join(copy);
+            std::fs::create_dir_all(root.join("pkg")).unwrap();
+            std::fs::write(root.join("pkg/index.js"), "module.exports = 1").unwrap();
+            std::fs::write(root.join("README"), "hi").unwrap();
+        }
+        let mut cache = HashCache::default();
+        let a = cache.digest(&dir.path().join("a"));
+        assert_eq!(a, cache.digest(&dir.path().join("b")));
+
+        std::fs::write(dir.path().join("b/README"), "changed").unwrap();
+        assert_ne!(a, cache.digest(&dir.path().join("b")));
+        std::fs::rename(dir.path().join("a/README"), dir.path().join("a/README.md")).unwrap();
+        assert_ne!(a, cache.digest(&dir.path().join("a")));
+    }
+
+    #[test]
+    fn test_read_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("shared_copies");
+        assert!(read_copies(&file).unwrap().is_empty());
+        replace(&file, "00000000000000ff\thost:/srv/a/node_modules\n").unwrap();
+        let copies = read_copies(&file).unwrap();
+        assert_eq!(
+            copies.get(Path::new("host:/srv/a/node_modules")),
+            Some(&0xff)
+        );
+    }
+}
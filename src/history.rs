@@ -0,0 +1,345 @@
+//! Rolling history of the executed hooks, see `atune history`.
+//!
+//! Every run appends a record to the history file when it starts and another one when it
+//! finishes, so a run whose process died in between is still listed. The output of every run is
+//! kept in a log file of its own
+use std::{
+    collections::BTreeMap,
+    io::Write as _,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::warn;
+
+use crate::{config::CommandConfig, output::OutputTail};
+
+/// Once the history file is larger, the older half of it is dropped
+const MAX_HISTORY_BYTES: u64 = 1024 * 1024;
+/// Longer values of the recorded environment variables are cut, e.g. the changed files
+const MAX_ENV_VALUE: usize = 200;
+
+/// Location of the history file, next to the [crate::state::state_path]
+pub fn history_path() -> Option<PathBuf> {
+    Some(crate::state::state_path()?.with_file_name("history.jsonl"))
+}
+
+fn log_dir() -> Option<PathBuf> {
+    Some(crate::state::state_path()?.with_file_name("hooks"))
+}
+
+/// A recorded run of a hook
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde_derive::Deserialize)]
+pub struct HookRun {
+    pub id: String,
+    #[serde(default)]
+    pub project: String,
+    /// e.g. `on_sync` or `init`
+    #[serde(default)]
+    pub hook: String,
+    #[serde(default)]
+    pub command: String,
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Unix timestamp in seconds
+    #[serde(default)]
+    pub started: u64,
+    /// process running the hook
+    #[serde(default)]
+    pub pid: u32,
+    pub log: Option<PathBuf>,
+    /// None until the run finished
+    pub success: Option<bool>,
+    /// None if the command was killed by a signal
+    pub exit_code: Option<i32>,
+    pub duration_ms: Option<u64>,
+    /// the last lines of the output
+    #[serde(default)]
+    pub tail: Vec<String>,
+}
+
+impl HookRun {
+    pub fn started(&self) -> SystemTime {
+        UNIX_EPOCH + std::time::Duration::from_secs(self.started)
+    }
+
+    /// Whether the run didn't finish, but its process is gone, e.g. because atune crashed
+    pub fn interrupted(&self) -> bool {
+        self.success.is_none() && !process_alive(self.pid)
+    }
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks for the existence of the process
+    pid != 0 && unsafe { libc::kill(pid as i32, 0) } == 0
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+/// The record of the start of a run, see [HookRun]
+#[derive(serde_derive::Serialize)]
+struct StartRecord<'a> {
+    id: &'a str,
+    project: &'a str,
+    hook: &'a str,
+    command: &'a str,
+    cwd: Option<&'a Path>,
+    env: BTreeMap<String, String>,
+    started: u64,
+    pid: u32,
+    log: Option<&'a Path>,
+}
+
+/// The record of the end of a run, see [HookRun]
+#[derive(serde_derive::Serialize)]
+struct FinishRecord<'a> {
+    id: &'a str,
+    success: bool,
+    exit_code: Option<i32>,
+    duration_ms: u128,
+    tail: Vec<String>,
+}
+
+/// A hook run whose start was recorded
+#[derive(Debug)]
+pub struct Started {
+    id: String,
+    log: Option<PathBuf>,
+    start: Instant,
+}
+
+/// Record the start of `cmd` as the `hook` of `project`, with the extra environment variables
+/// `env` passed to it. Failures to record are logged, as they must not stop the hook
+pub fn start(project: &str, hook: &str, cmd: &CommandConfig, env: &[(&str, &str)]) -> Started {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let pid = std::process::id();
+    let id = format!(
+        "{started}-{pid}-{}",
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let log = log_dir().map(|d| d.join(format!("{id}.log")));
+    let vars = env
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .chain(cmd.env.iter().map(|(k, v)| (k.clone(), v.clone())))
+        .map(|(k, v)| {
+            let v = match v.char_indices().nth(MAX_ENV_VALUE) {
+                Some((end, _)) => format!("{}...", &v[..end]),
+                None => v,
+            };
+            (k, v)
+        })
+        .collect::<BTreeMap<_, _>>();
+    append(&StartRecord {
+        id: &id,
+        project,
+        hook,
+        command: &cmd.command,
+        cwd: cmd.cwd.as_deref(),
+        env: vars,
+        started,
+        pid,
+        log: log.as_deref(),
+    });
+    Started {
+        id,
+        log,
+        start: Instant::now(),
+    }
+}
+
+impl Started {
+    /// The log file the output of the run goes to
+    pub fn log(&self) -> Option<&Path> {
+        self.log.as_deref()
+    }
+
+    /// Record the end of the run, `status` None if it failed to start
+    pub fn finish(self, status: Option<ExitStatus>, tail: &OutputTail) {
+        let success = status.is_some_and(|s| s.success());
+        self.record(success, status.and_then(|s| s.code()), tail);
+    }
+
+    /// Record the end of a run without a process, e.g. of a [crate::script]
+    pub fn finish_script(self, success: bool) {
+        self.record(success, None, &OutputTail::default());
+    }
+
+    fn record(self, success: bool, exit_code: Option<i32>, tail: &OutputTail) {
+        append(&FinishRecord {
+            id: &self.id,
+            success,
+            exit_code,
+            duration_ms: self.start.elapsed().as_millis(),
+            tail: tail.lines(),
+        });
+    }
+}
+
+/// Run `cmd` until it exits, recording it in the history. Its output is forwarded to the output
+/// of atune as it is, plugins get the payload of the hook on stdin
+pub fn run(
+    project: &str,
+    hook: &str,
+    config: &CommandConfig,
+    env: &[(&str, &str)],
+    mut cmd: Command,
+) -> std::io::Result<ExitStatus> {
+    let run = start(project, hook, config, env);
+    let tail = OutputTail::default();
+    let payload =
+        crate::plugin::is_plugin(config).then(|| crate::plugin::payload(project, hook, env));
+    let status = cmd
+        .stdin(if payload.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(payload) = payload {
+                crate::plugin::feed(&mut child, payload);
+            }
+            let output = crate::output::capture_copy(
+                &mut child,
+                None,
+                None,
+                &OutputTail::default(),
+                run.log(),
+                &tail,
+            );
+            let status = child.wait();
+            crate::output::join_capture(output);
+            status
+        });
+    run.finish(status.as_ref().ok().copied(), &tail);
+    status
+}
+
+/// Append a record to the history file, dropping the older half of it once it grew too large
+fn append(record: &impl serde::Serialize) {
+    let Some(path) = history_path() else {
+        return;
+    };
+    let line = match serde_json::to_string(record) {
+        Ok(line) => line,
+        Err(err) => {
+            warn!(?err, "Failed to serialize the hook run");
+            return;
+        }
+    };
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if std::fs::metadata(&path).is_ok_and(|m| m.len() > MAX_HISTORY_BYTES) {
+        if let Err(err) = truncate(&path) {
+            warn!(?err, ?path, "Failed to truncate the hook history");
+        }
+    }
+    // a single write of the whole line, so concurrent syncs don't interleave their records
+    let res = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(format!("{line}\n").as_bytes()));
+    if let Err(err) = res {
+        warn!(?err, ?path, "Failed to record the hook run");
+    }
+}
+
+fn truncate(path: &Path) -> std::io::Result<()> {
+    let content = std::fs::read_to_string(path)?;
+    let half = content.len() / 2;
+    let keep_from = content.as_bytes()[half..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map_or(content.len(), |i| half + i + 1);
+    let (dropped, kept) = content.split_at(keep_from);
+    for run in parse(dropped) {
+        if let Some(log) = run.log {
+            let _ = std::fs::remove_file(log);
+        }
+    }
+    // replace atomically, so concurrent readers never see a partial file
+    let tmp = path.with_extension(format!("jsonl.{}", std::process::id()));
+    std::fs::write(&tmp, kept)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Merge the start and finish records by their id, in the order the runs started
+fn parse(content: &str) -> Vec<HookRun> {
+    let mut runs = Vec::<HookRun>::new();
+    let mut index = BTreeMap::new();
+    for line in content.lines().filter(|l| !l.trim().is_empty()) {
+        // lines cut by a crash are skipped
+        let Ok(record) = serde_json::from_str::<HookRun>(line) else {
+            continue;
+        };
+        match index.get(&record.id) {
+            Some(&i) => {
+                let run: &mut HookRun = &mut runs[i];
+                run.success = record.success;
+                run.exit_code = record.exit_code;
+                run.duration_ms = record.duration_ms;
+                run.tail = record.tail;
+            }
+            None => {
+                index.insert(record.id.clone(), runs.len());
+                runs.push(record);
+            }
+        }
+    }
+    runs
+}
+
+/// The recorded runs, oldest first, optionally only those of `project`
+pub fn load(project: Option<&str>) -> Vec<HookRun> {
+    let Some(content) = history_path().and_then(|p| std::fs::read_to_string(p).ok()) else {
+        return Vec::new();
+    };
+    let mut runs = parse(&content);
+    if let Some(project) = project {
+        runs.retain(|r| r.project == project);
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_merges_records() {
+        let content = concat!(
+            r#"{"id":"1-10-0","project":"web","hook":"on_sync","command":"make","cwd":null,"env":{"ATUNE_SYNC_SRC":"/src"},"started":1,"pid":10,"log":"/logs/1-10-0.log"}"#,
+            "\n",
+            r#"{"id":"2-10-1","project":"web","hook":"on_sync","command":"deploy","cwd":"/app","env":{},"started":2,"pid":10,"log":null}"#,
+            "\n",
+            r#"{"id":"1-10-0","success":false,"exit_code":2,"duration_ms":15,"tail":["make: *** Error 2"]}"#,
+            "\n",
+            r#"{"id":"3-10-2","proj"#,
+        );
+        let runs = parse(content);
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].command, "make");
+        assert_eq!(runs[0].success, Some(false));
+        assert_eq!(runs[0].exit_code, Some(2));
+        assert_eq!(runs[0].tail, ["make: *** Error 2"]);
+        assert_eq!(runs[0].env["ATUNE_SYNC_SRC"], "/src");
+        assert_eq!(runs[1].cwd.as_deref(), Some(Path::new("/app")));
+        assert_eq!(runs[1].success, None);
+    }
+}
@@ -0,0 +1,432 @@
+//! Syncs run on worker threads of `watch` instead of `sync-project` processes, see
+//! [crate::config::Execution::InProcess]
+use std::{
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel;
+use tracing::error;
+
+use crate::{
+    config::{NotificationConfig, NotificationEvent},
+    notifications::SyncNotification,
+    output::{OutputTail, Progress},
+    rusage::ResourceUsage,
+    sync::{DstConflict, HookFailed, ParsedSync, SyncChanges, SyncError},
+};
+
+/// How often a running process is checked for exit and cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Returned by [crate::sync::execute_sync] when the sync was cancelled
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The sync was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Runs the processes of an in-process sync: their output is forwarded like the output of a
+/// `sync-project` process, and they are killed once the sync is cancelled
+#[derive(Clone)]
+pub struct ProcessRunner {
+    label: String,
+    log_file: Option<PathBuf>,
+    on_progress: Option<Arc<dyn Fn(Progress) + Send + Sync>>,
+    cancel: Arc<AtomicBool>,
+    tail: OutputTail,
+    /// of the processes, see [crate::niceness]
+    niceness: crate::niceness::Niceness,
+    /// of the processes that exited
+    usage: Arc<Mutex<ResourceUsage>>,
+}
+
+impl std::fmt::Debug for ProcessRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProcessRunner")
+            .field("label", &self.label)
+            .field("log_file", &self.log_file)
+            .field("cancel", &self.cancel)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProcessRunner {
+    /// Returns an error once the sync is cancelled
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+
+    /// Run `cmd` until it exits, or kill it once the sync is cancelled
+    pub fn run(&self, mut cmd: Command) -> anyhow::Result<ExitStatus> {
+        self.check()?;
+        self.niceness.apply_to(&mut cmd);
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = crate::process_group::spawn(&mut cmd)?;
+        let on_progress = self
+            .on_progress
+            .clone()
+            .map(|f| Box::new(move |progress| f(progress)) as Box<dyn Fn(Progress) + Send>);
+        crate::output::capture_into(
+            &mut child,
+            &self.label,
+            self.log_file.as_deref(),
+            on_progress,
+            &self.tail,
+        );
+        self.wait(child)
+    }
+
+    /// [ProcessRunner::run] `cmd`, returning its stdout instead of forwarding it, e.g. of the dry
+    /// runs of rsync. Fails if it exits with an error
+    pub fn read(&self, mut cmd: Command) -> anyhow::Result<String> {
+        use std::io::Read as _;
+
+        self.check()?;
+        self.niceness.apply_to(&mut cmd);
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = crate::process_group::spawn(&mut cmd)?;
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let reader = std::thread::spawn(move || {
+            let mut out = String::new();
+            stdout.read_to_string(&mut out).map(|_| out)
+        });
+        crate::output::capture_into(
+            &mut child,
+            &self.label,
+            self.log_file.as_deref(),
+            None,
+            &self.tail,
+        );
+        let status = self.wait(child)?;
+        let out = reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("Failed to read the output"))??;
+        anyhow::ensure!(
+            status.success(),
+            "{} failed with {status}",
+            cmd.get_program().to_string_lossy()
+        );
+        Ok(out)
+    }
+
+    /// [ProcessRunner::run] a hook, also writing its output to `log` if given and returning its
+    /// last lines. `payload` is written to the stdin of plugins, see [crate::plugin]
+    pub fn run_logged(
+        &self,
+        mut cmd: Command,
+        log: Option<&std::path::Path>,
+        payload: Option<String>,
+    ) -> anyhow::Result<(ExitStatus, OutputTail)> {
+        self.check()?;
+        self.niceness.apply_to(&mut cmd);
+        cmd.stdin(if payload.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+        let mut child = crate::process_group::spawn(&mut cmd)?;
+        if let Some(payload) = payload {
+            crate::plugin::feed(&mut child, payload);
+        }
+        let tail = OutputTail::default();
+        let output = crate::output::capture_copy(
+            &mut child,
+            Some(&self.label),
+            self.log_file.as_deref(),
+            &self.tail,
+            log,
+            &tail,
+        );
+        let status = self.wait(child)?;
+        crate::output::join_capture(output);
+        Ok((status, tail))
+    }
+
+    fn wait(&self, mut child: std::process::Child) -> anyhow::Result<ExitStatus> {
+        loop {
+            if let Some((status, usage)) = crate::rusage::try_wait(&mut child)? {
+                crate::process_group::forget(&child);
+                self.usage.lock().unwrap().add(&usage);
+                return Ok(status);
+            }
+            if self.cancel.load(Ordering::Relaxed) {
+                crate::process_group::kill(&mut child, crate::process_group::KILL_GRACE);
+                return Err(Cancelled.into());
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running the in-process syncs. They exit once every clone of the pool is dropped
+#[derive(Debug, Clone)]
+pub struct WorkerPool {
+    jobs: channel::Sender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize) -> Self {
+        let (jobs, rx) = channel::unbounded::<Job>();
+        for _ in 0..workers.max(1) {
+            let rx = rx.clone();
+            std::thread::spawn(move || {
+                for job in rx {
+                    job();
+                }
+            });
+        }
+        Self { jobs }
+    }
+
+    /// A worker per CPU
+    pub fn with_available_parallelism() -> Self {
+        Self::new(std::thread::available_parallelism().map_or(4, |n| n.get()))
+    }
+}
+
+/// What an in-process sync runs, see [SyncTask::start]
+#[derive(Clone)]
+pub struct SyncJob {
+    pub config_path: PathBuf,
+    pub project: String,
+    pub sync: ParsedSync,
+    pub rsync: Option<PathBuf>,
+    pub initialize: bool,
+    pub changes: SyncChanges,
+    pub notifications: Arc<Vec<NotificationConfig>>,
+    pub log_file: Option<PathBuf>,
+    pub on_progress: Option<Arc<dyn Fn(Progress) + Send + Sync>>,
+    /// parent of the spans of the sync
+    pub span: tracing::Span,
+}
+
+/// An in-process sync, queued or running on a [WorkerPool]
+#[derive(Debug)]
+pub struct SyncTask {
+    cancel: Arc<AtomicBool>,
+    started: Arc<AtomicBool>,
+    result: channel::Receiver<Result<(), SyncError>>,
+    finished: Option<Result<(), SyncError>>,
+    usage: Arc<Mutex<ResourceUsage>>,
+}
+
+impl SyncTask {
+    /// Queue `job` on `pool`. The output of its processes is kept in the returned tail
+    pub fn start(pool: &WorkerPool, job: SyncJob) -> (Self, OutputTail) {
+        let tail = OutputTail::default();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let started = Arc::new(AtomicBool::new(false));
+        let (tx, result) = channel::bounded(1);
+        let usage = Arc::new(Mutex::new(ResourceUsage::default()));
+        let runner = ProcessRunner {
+            label: crate::sync::sync_label(&job.project, &job.sync.src),
+            log_file: job.log_file.clone(),
+            on_progress: job.on_progress.clone(),
+            cancel: cancel.clone(),
+            tail: tail.clone(),
+            niceness: job.sync.niceness.clone(),
+            usage: usage.clone(),
+        };
+        let run = {
+            let started = started.clone();
+            move || {
+                started.store(true, Ordering::Relaxed);
+                if runner.check().is_err() {
+                    return;
+                }
+                let _span = job.span.clone().entered();
+                let _ = tx.send(run_job(&job, &runner));
+            }
+        };
+        if pool.jobs.send(Box::new(run)).is_err() {
+            error!("The sync workers are gone");
+        }
+        let task = Self {
+            cancel,
+            started,
+            result,
+            finished: None,
+            usage,
+        };
+        (task, tail)
+    }
+
+    /// The result of the sync, or None if it is still queued or running
+    pub fn try_wait(&mut self) -> Option<Result<(), SyncError>> {
+        if self.finished.is_none() {
+            self.finished = match self.result.try_recv() {
+                Ok(result) => Some(result),
+                Err(channel::TryRecvError::Empty) => None,
+                Err(channel::TryRecvError::Disconnected) => {
+                    Some(Err(SyncError::Failed { exit_code: None }))
+                }
+            };
+        }
+        self.finished.clone()
+    }
+
+    /// Resources used by the processes of the sync that exited so far
+    pub fn usage(&self) -> ResourceUsage {
+        *self.usage.lock().unwrap()
+    }
+
+    /// Cancel the sync, waiting until its running process was killed
+    pub fn cancel(self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        // a queued sync is skipped when a worker picks it up
+        if self.started.load(Ordering::Relaxed) {
+            let _ = self.result.recv();
+        }
+    }
+}
+
+fn run_job(job: &SyncJob, runner: &ProcessRunner) -> Result<(), SyncError> {
+    let notification = |event, duration, error| SyncNotification {
+        event,
+        project: job.project.as_str(),
+        src: job.sync.src.as_path(),
+        dst: job.sync.dst.as_deref(),
+        duration,
+        error,
+        changed: &job.changes.changed,
+        deleted: &job.changes.deleted,
+    };
+    crate::notifications::notify(
+        &job.notifications,
+        &notification(NotificationEvent::Start, None, None),
+    );
+    let start = Instant::now();
+    let res = crate::sync::execute_sync(
+        &job.config_path,
+        &job.project,
+        &job.sync,
+        job.rsync.as_deref().map(|r| r.as_os_str()),
+        job.initialize,
+        &job.changes,
+        None,
+        Some(runner),
+    );
+    if let Err(err) = res.as_ref() {
+        if err.downcast_ref::<Cancelled>().is_some() {
+            return Err(SyncError::Failed { exit_code: None });
+        }
+        // shown with the rest of the output of the sync if it failed
+        runner.tail.push(format!("{err:#}"));
+    }
+    let n = match res.as_ref() {
+        Ok(_) => notification(NotificationEvent::Success, Some(start.elapsed()), None),
+        Err(err) => notification(
+            NotificationEvent::Failure,
+            Some(start.elapsed()),
+            Some(format!("{err:#}")),
+        ),
+    };
+    crate::notifications::notify(&job.notifications, &n);
+    res.map_err(|err| {
+        if err.downcast_ref::<HookFailed>().is_some() {
+            SyncError::HookFailed
+        } else if err.downcast_ref::<DstConflict>().is_some() {
+            SyncError::DstConflict
+        } else if err.downcast_ref::<crate::sync::Unreachable>().is_some() {
+            SyncError::Unreachable
+        } else if err
+            .downcast_ref::<crate::confirm::DeleteRefused>()
+            .is_some()
+        {
+            SyncError::DeleteRefused
+        } else if err.downcast_ref::<crate::confirm::DeleteLimit>().is_some() {
+            SyncError::DeleteLimit
+        } else {
+            SyncError::Failed { exit_code: None }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cancel_kills_the_running_process() {
+        let runner = ProcessRunner {
+            label: "web:src".to_owned(),
+            log_file: None,
+            on_progress: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+            tail: OutputTail::default(),
+            niceness: Default::default(),
+            usage: Default::default(),
+        };
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo started; sleep 10");
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            let handle = s.spawn(|| runner.run(cmd));
+            while runner.tail.lines().is_empty() {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            runner.cancel.store(true, Ordering::Relaxed);
+            let err = handle.join().unwrap().unwrap_err();
+            assert!(err.is::<Cancelled>(), "{err}");
+        });
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(runner.tail.lines(), vec!["started"]);
+        assert!(runner.run(Command::new("true")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read() {
+        let runner = ProcessRunner {
+            label: "web:src".to_owned(),
+            log_file: None,
+            on_progress: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+            tail: OutputTail::default(),
+            niceness: Default::default(),
+            usage: Default::default(),
+        };
+        let sh = |script: &str| {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(script);
+            cmd
+        };
+        let out = runner.read(sh("echo out; echo err >&2")).unwrap();
+        assert_eq!(out, "out\n");
+        assert!(runner.read(sh("exit 3")).is_err());
+
+        // killed once cancelled
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            let handle = s.spawn(|| runner.read(sh("sleep 10")));
+            std::thread::sleep(Duration::from_millis(100));
+            runner.cancel.store(true, Ordering::Relaxed);
+            let err = handle.join().unwrap().unwrap_err();
+            assert!(err.is::<Cancelled>(), "{err}");
+        });
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,59 @@
+pub mod agent;
+pub mod api;
+#[cfg(feature = "async")]
+pub mod async_watch;
+mod atomic;
+pub mod backend;
+pub mod backup;
+pub mod compress;
+pub mod config;
+pub mod confirm;
+#[cfg(unix)]
+pub mod control_socket;
+pub mod copy;
+pub mod daemon;
+pub mod doctor;
+mod drift;
+mod glob;
+pub mod hash_cache;
+pub mod history;
+pub mod in_process;
+pub mod limits;
+pub mod lock;
+pub mod log_file;
+pub mod manifest;
+pub mod niceness;
+pub mod notifications;
+mod offline;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod output;
+pub mod perms;
+pub mod platform;
+pub mod plugin;
+mod process_group;
+pub mod reload;
+pub mod rusage;
+pub mod schedule;
+pub mod schema;
+pub mod script;
+pub mod service;
+mod shallow;
+pub mod snapshot;
+pub mod ssh;
+pub mod state;
+pub mod stats;
+pub mod sync;
+#[cfg(unix)]
+pub mod systemd;
+mod template;
+#[cfg(unix)]
+pub mod tui;
+pub mod verbosity;
+pub mod watcher;
+
+pub use sync::{SyncError, WatchControl, WatchEvent, WatchOptions};
+pub use watcher::Watcher;
+
+#[cfg(feature = "async")]
+pub use async_watch::{async_watch, AsyncWatcher};
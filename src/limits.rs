@@ -0,0 +1,131 @@
+//! Limits on the files of a sync, see [crate::config::FileSync::max_file_size] and
+//! [crate::config::FileSync::warn_file_count]
+use std::{path::PathBuf, str::FromStr};
+
+use tracing::warn;
+
+use crate::sync::SyncChanges;
+
+/// A size in bytes, e.g. `500`, `100k`, `20M` or `2G`. Units are powers of 1024
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSize(pub u64);
+
+impl FileSize {
+    /// The rsync flag skipping the larger files
+    pub fn rsync_flag(&self) -> String {
+        format!("--max-size={}", self.0)
+    }
+}
+
+impl FromStr for FileSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || anyhow::anyhow!("Invalid size {s:?}, expected e.g. 500, 100k, 20M or 2G");
+        let (number, unit) = match s.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c),
+            _ => (s, 'B'),
+        };
+        let scale: u64 = match unit.to_ascii_uppercase() {
+            'B' => 1,
+            'K' => 1 << 10,
+            'M' => 1 << 20,
+            'G' => 1 << 30,
+            'T' => 1 << 40,
+            _ => return Err(invalid()),
+        };
+        number
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(scale))
+            .map(FileSize)
+            .ok_or_else(invalid)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FileSize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // a number of bytes may be written as a number
+        let s = match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::Number(n) => n.to_string(),
+            serde_yaml::Value::String(s) => s,
+            other => {
+                return Err(serde::de::Error::custom(format!(
+                    "Invalid size {other:?}, expected e.g. 500 or \"20M\""
+                )))
+            }
+        };
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The changed files larger than `max`, with their sizes
+pub fn oversized(changes: &SyncChanges, max: FileSize) -> Vec<(PathBuf, u64)> {
+    changes
+        .changed
+        .iter()
+        .filter_map(|p| {
+            let meta = std::fs::metadata(p).ok()?;
+            (meta.is_file() && meta.len() > max.0).then(|| (p.clone(), meta.len()))
+        })
+        .collect()
+}
+
+/// Warn about the changed files skipped by `max_file_size` and about syncs involving more files
+/// than `warn_file_count`
+pub fn check(changes: &SyncChanges, max_file_size: Option<FileSize>, warn_file_count: Option<u64>) {
+    if let Some(max) = max_file_size {
+        let skipped = oversized(changes, max);
+        if !skipped.is_empty() {
+            warn!(
+                ?skipped,
+                max_file_size = max.0,
+                "Skipping files larger than max_file_size"
+            );
+        }
+    }
+    let count = (changes.changed.len() + changes.deleted.len()) as u64;
+    if let Some(limit) = warn_file_count.filter(|limit| count > *limit) {
+        warn!(
+            count,
+            warn_file_count = limit,
+            "The sync involves unusually many files, is a filter missing?"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_size() {
+        assert_eq!("500".parse::<FileSize>().unwrap(), FileSize(500));
+        assert_eq!("100k".parse::<FileSize>().unwrap(), FileSize(100 * 1024));
+        assert_eq!("20M".parse::<FileSize>().unwrap(), FileSize(20 << 20));
+        assert_eq!("2G".parse::<FileSize>().unwrap(), FileSize(2 << 30));
+        assert!("2X".parse::<FileSize>().is_err());
+        assert!("big".parse::<FileSize>().is_err());
+        assert!("".parse::<FileSize>().is_err());
+    }
+
+    #[test]
+    fn test_oversized() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("small.txt");
+        let large = dir.path().join("large.iso");
+        std::fs::write(&small, [0; 10]).unwrap();
+        std::fs::write(&large, [0; 100]).unwrap();
+        let mut changes = SyncChanges::default();
+        changes.changed.insert(small);
+        changes.changed.insert(large.clone());
+        changes.changed.insert(dir.path().join("gone.txt"));
+
+        assert_eq!(oversized(&changes, FileSize(50)), [(large, 100)]);
+    }
+}
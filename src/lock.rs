@@ -0,0 +1,277 @@
+//! Per-config lock, so only one `atune watch` syncs a config at a time, and per-entry locks, so
+//! only one sync of an entry runs at a time
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read as _, Seek as _, Write as _},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tracing::info;
+
+use crate::state::Fnv;
+
+/// How often a sync waiting for the lock of its entry checks it again
+const SYNC_LOCK_POLL: Duration = Duration::from_millis(100);
+
+/// How long `--replace` waits for the running daemon to run its `on_stop` hooks and exit
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn runtime_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Location of the pidfile of a config: `$XDG_RUNTIME_DIR/atune-<hash>.pid`, falling back to
+/// the temp dir
+pub fn lock_path(config: &Path) -> PathBuf {
+    let config = crate::platform::canonicalize(config).unwrap_or_else(|_| config.to_owned());
+    let mut hash = Fnv::default();
+    hash.write(config.to_string_lossy().as_bytes());
+    runtime_dir().join(format!("atune-{:016x}.pid", hash.0))
+}
+
+/// Location of the lock of the sync entry `src` of `project` in `config`:
+/// `$XDG_RUNTIME_DIR/atune-<config hash>-<entry hash>.lock`
+pub fn sync_lock_path(config: &Path, project: &str, src: &Path) -> PathBuf {
+    let config = crate::platform::canonicalize(config).unwrap_or_else(|_| config.to_owned());
+    let mut config_hash = Fnv::default();
+    config_hash.write(config.to_string_lossy().as_bytes());
+    let mut entry = Fnv::default();
+    entry.write(project.as_bytes());
+    entry.write(&[0]);
+    entry.write(src.as_os_str().as_encoded_bytes());
+    runtime_dir().join(format!(
+        "atune-{:016x}-{:016x}.lock",
+        config_hash.0, entry.0
+    ))
+}
+
+/// Exclusive lock on a config, held until dropped.
+///
+/// The lock is an flock on the pidfile, so it's released by the OS even if atune crashes
+#[derive(Debug)]
+pub struct ConfigLock {
+    // keeps the lock
+    _file: File,
+    pub path: PathBuf,
+}
+
+impl ConfigLock {
+    /// Lock `config`. If another process holds the lock this fails, unless `replace` is set:
+    /// then the other process is terminated and its lock taken over
+    pub fn acquire(config: &Path, replace: bool) -> anyhow::Result<Self> {
+        let path = lock_path(config);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open the lock file {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let pid = read_pid(&mut file);
+                let pid_str = pid.map_or("unknown".to_owned(), |p| p.to_string());
+                anyhow::ensure!(
+                    replace,
+                    "atune is already watching {} (pid {pid_str}). Stop it, or pass --replace to take over",
+                    config.display()
+                );
+                let pid = pid.with_context(|| {
+                    format!(
+                        "Failed to read the pid of the running atune from {}",
+                        path.display()
+                    )
+                })?;
+                info!(pid, "Stopping the running atune");
+                terminate(pid)?;
+                wait_for_lock(&file, pid)?;
+            }
+            Err(TryLockError::Error(err)) => {
+                return Err(err).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        file.flush()?;
+        Ok(Self { _file: file, path })
+    }
+}
+
+/// Exclusive lock on a sync entry, held until dropped, so syncs of the same entry never overlap,
+/// e.g. the `sync-project` of a restarted daemon and the one it left behind
+#[derive(Debug)]
+pub struct SyncLock {
+    // keeps the lock
+    _file: File,
+}
+
+impl SyncLock {
+    /// Lock the entry `src` of `project`, waiting for the sync holding it to finish. `check` is
+    /// called while waiting and stops it if it fails, e.g. once the sync is cancelled
+    pub fn acquire(
+        config: &Path,
+        project: &str,
+        src: &Path,
+        check: impl Fn() -> anyhow::Result<()>,
+    ) -> anyhow::Result<Self> {
+        let path = sync_lock_path(config, project, src);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open the lock file {}", path.display()))?;
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {
+                    if !waiting {
+                        waiting = true;
+                        let pid =
+                            read_pid(&mut file).map_or("unknown".to_owned(), |p| p.to_string());
+                        info!(pid, "Waiting for another sync of the entry to finish");
+                    }
+                    check()?;
+                    std::thread::sleep(SYNC_LOCK_POLL);
+                }
+                Err(TryLockError::Error(err)) => {
+                    return Err(err).with_context(|| format!("Failed to lock {}", path.display()));
+                }
+            }
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        file.flush()?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// Stop the atune watching `config`, waiting until it ran its `on_stop` hooks and released the
+/// lock. Returns its pid, None if no atune watches the config
+pub fn stop(config: &Path) -> anyhow::Result<Option<u32>> {
+    let path = lock_path(config);
+    let Ok(mut file) = OpenOptions::new().read(true).write(true).open(&path) else {
+        return Ok(None);
+    };
+    match file.try_lock() {
+        // a pidfile left behind, released again when the file is dropped
+        Ok(()) => return Ok(None),
+        Err(TryLockError::WouldBlock) => {}
+        Err(TryLockError::Error(err)) => {
+            return Err(err).with_context(|| format!("Failed to lock {}", path.display()));
+        }
+    }
+    let pid = read_pid(&mut file).with_context(|| {
+        format!(
+            "Failed to read the pid of the running atune from {}",
+            path.display()
+        )
+    })?;
+    info!(pid, "Stopping the running atune");
+    terminate(pid)?;
+    wait_for_lock(&file, pid)?;
+    Ok(Some(pid))
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut s = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut s).ok()?;
+    s.trim().parse().ok()
+}
+
+fn wait_for_lock(file: &File, pid: u32) -> anyhow::Result<()> {
+    let deadline = Instant::now() + REPLACE_TIMEOUT;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(TryLockError::WouldBlock) => {
+                anyhow::bail!("atune (pid {pid}) didn't stop within {REPLACE_TIMEOUT:?}")
+            }
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+    }
+}
+
+/// Ask the process to stop, it runs its `on_stop` hooks before releasing the lock
+#[cfg(unix)]
+fn terminate(pid: u32) -> anyhow::Result<()> {
+    // SAFETY: sending a signal has no memory safety requirements
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        let err = std::io::Error::last_os_error();
+        // exited in the meantime
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            return Err(err).with_context(|| format!("Failed to stop atune (pid {pid})"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn terminate(pid: u32) -> anyhow::Result<()> {
+    let status = std::process::Command::new("taskkill")
+        .args(["/PID", &pid.to_string()])
+        .status()
+        .context("Failed to run taskkill")?;
+    anyhow::ensure!(status.success(), "Failed to stop atune (pid {pid})");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("atune.yaml");
+        std::fs::write(&config, "").unwrap();
+
+        let lock = ConfigLock::acquire(&config, false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&lock.path).unwrap().trim(),
+            std::process::id().to_string()
+        );
+        let err = ConfigLock::acquire(&config, false).unwrap_err();
+        assert!(err.to_string().contains("--replace"), "{err}");
+        drop(lock);
+        ConfigLock::acquire(&config, false).unwrap();
+    }
+
+    #[test]
+    fn test_sync_lock_waits() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("atune.yaml");
+        std::fs::write(&config, "").unwrap();
+        let src = Path::new("/src");
+
+        let lock = SyncLock::acquire(&config, "web", src, || Ok(())).unwrap();
+        // other entries aren't blocked
+        SyncLock::acquire(&config, "api", src, || Ok(())).unwrap();
+        let err =
+            SyncLock::acquire(&config, "web", src, || anyhow::bail!("cancelled")).unwrap_err();
+        assert_eq!(err.to_string(), "cancelled");
+
+        let waiter = std::thread::spawn({
+            let config = config.clone();
+            move || SyncLock::acquire(&config, "web", src, || Ok(())).map(drop)
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!waiter.is_finished());
+        drop(lock);
+        waiter.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,210 @@
+//! Copy of the output of atune in a file, rotated by size and age, see `--log-file`
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::config;
+
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+/// the output goes to the log file only, see [init]
+static DETACHED: AtomicBool = AtomicBool::new(false);
+
+/// When the log file is rotated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// in bytes
+    pub max_size: u64,
+    pub max_age: Option<Duration>,
+    /// number of rotated files kept, `<file>.1` being the newest
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_size: 10 * 1024 * 1024,
+            max_age: None,
+            keep: 5,
+        }
+    }
+}
+
+impl From<&config::Logging> for Rotation {
+    fn from(logging: &config::Logging) -> Self {
+        Self {
+            max_size: logging.max_size_mb.saturating_mul(1024 * 1024),
+            max_age: logging.max_age,
+            keep: logging.keep,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    created: SystemTime,
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: Rotation) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::options().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+        Ok(Self {
+            path: path.to_owned(),
+            rotation,
+            file,
+            size: meta.len(),
+            created: meta.created().unwrap_or_else(|_| SystemTime::now()),
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let too_large = self.size + bytes.len() as u64 > self.rotation.max_size;
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|age| self.created.elapsed().is_ok_and(|e| e >= age));
+        if self.size > 0 && (too_large || too_old) {
+            rotate(&self.path, self.rotation.keep)?;
+            *self = Self::open(&self.path, self.rotation)?;
+        }
+        self.file.write_all(bytes)?;
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, i: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{i}"));
+    PathBuf::from(name)
+}
+
+/// Shift `<path>.1..` by one, dropping the oldest, and move the current file to `<path>.1`
+fn rotate(path: &Path, keep: usize) -> std::io::Result<()> {
+    if keep == 0 {
+        return std::fs::remove_file(path);
+    }
+    let _ = std::fs::remove_file(rotated(path, keep));
+    for i in (1..keep).rev() {
+        let from = rotated(path, i);
+        if from.exists() {
+            std::fs::rename(from, rotated(path, i + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated(path, 1))
+}
+
+/// Copy the output to the file at `path` from now on. If `detached`, then it isn't written to
+/// stdout and stderr anymore, e.g. in the background watch of `watch --daemon`.
+/// The first log file stays, later calls do nothing
+pub fn init(path: &Path, rotation: Rotation, detached: bool) -> std::io::Result<()> {
+    let mut log = LOG_FILE.lock().unwrap();
+    if log.is_some() {
+        return Ok(());
+    }
+    *log = Some(LogFile::open(path, rotation)?);
+    DETACHED.store(detached, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether the output goes to the log file only
+pub fn is_detached() -> bool {
+    DETACHED.load(Ordering::Relaxed)
+}
+
+/// Append `bytes` to the log file, if there is one, without terminal colors
+pub fn write(bytes: &[u8]) {
+    let mut log = LOG_FILE.lock().unwrap();
+    if let Some(log) = log.as_mut() {
+        // the log must not break the output it copies
+        let _ = log.write(&strip_ansi(bytes));
+    }
+}
+
+/// A writer also copying everything to the log file, see [write]
+#[derive(Debug)]
+pub struct Tee<W>(pub W);
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.0.write(buf)?;
+        write(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Remove the escape sequences of terminal colors, e.g. `\x1b[32m`
+fn strip_ansi(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == 0x1b && bytes.get(i + 1) == Some(&b'[') {
+            i += 2;
+            while i < bytes.len() && !(0x40..=0x7e).contains(&bytes[i]) {
+                i += 1;
+            }
+            i += 1;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/atune.log");
+        let rotation = Rotation {
+            max_size: 10,
+            max_age: None,
+            keep: 2,
+        };
+        let mut log = LogFile::open(&path, rotation).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.write(line.as_bytes()).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            std::fs::read_to_string(rotated(&path, 1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(rotated(&path, 2)).unwrap(),
+            "second\n"
+        );
+        assert!(!rotated(&path, 3).exists());
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(
+            strip_ansi(b"\x1b[2m2024\x1b[0m \x1b[32m INFO\x1b[0m syncing"),
+            b"2024  INFO syncing"
+        );
+    }
+}
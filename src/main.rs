@@ -1,33 +1,101 @@
-mod config;
-mod sync;
-
-use std::{collections::HashSet, process};
+use std::{
+    collections::{HashMap, HashSet},
+    process,
+};
 
 use anyhow::Context;
+use atune::notifications::{notify, SyncNotification};
+use atune::{config, platform, sync};
 use clap::Parser as _;
 use clap_derive::Parser;
 use clap_derive::Subcommand;
-use signal_hook::{
-    consts::{SIGINT, SIGQUIT, SIGTERM},
-    iterator::Signals,
-};
+use config::NotificationEvent;
+use signal_hook::consts::{SIGINT, SIGTERM};
 use sync::sync_all_once;
 use sync::DEFAULT_RSYCN_FLAGS;
-use tracing::{debug, warn};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// Path to the atune config file.
-    /// If omitted, then all parent directories are scanned for an `atune.yaml` file
+    /// If omitted, then all parent directories are scanned for an `atune.yaml`, `atune.toml` or
+    /// `atune.json` file.
+    /// `watch` accepts several configs and directories of config files, and restarts the watch of
+    /// a config when its file changes. Can be repeated
     #[arg(long, short, env("ATUNE_CONFIG_PATH"), value_name = "FILE")]
-    config: Option<std::path::PathBuf>,
+    config: Vec<std::path::PathBuf>,
+
+    /// Format of the config file. If omitted, then it's guessed from the file extension
+    #[arg(long, env("ATUNE_CONFIG_FORMAT"))]
+    format: Option<config::ConfigFormat>,
 
     /// Path to rsync
     #[arg(long, short, env("ATUNE_RSYNC"), default_value("rsync"))]
     rsync: std::path::PathBuf,
 
+    /// Set a `{{ name }}` placeholder of the config, overriding its `variables`. Can be repeated
+    #[arg(
+        long = "var",
+        value_name = "NAME=VALUE",
+        env("ATUNE_VARS"),
+        value_delimiter = '\n',
+        value_parser = parse_var
+    )]
+    vars: Vec<(String, String)>,
+
+    /// Apply the named profile of the config
+    #[arg(long, env("ATUNE_PROFILE"))]
+    profile: Option<String>,
+
+    /// Ask before syncs delete many files in dst, as if every sync with a dst set
+    /// `confirm_delete`
+    #[arg(long, env("ATUNE_CONFIRM"), global = true)]
+    confirm: bool,
+
+    /// Don't ask before syncs delete many files in dst, confirming the deletions of
+    /// `confirm_delete`
+    #[arg(
+        long,
+        short,
+        env("ATUNE_YES"),
+        global = true,
+        conflicts_with = "confirm"
+    )]
+    yes: bool,
+
+    /// Log more: `-v` adds the debug logs of atune, `-vv` everything down to its traces.
+    /// Passed on to the syncs and hooks as `ATUNE_VERBOSITY`
+    #[arg(
+        long,
+        short,
+        action = clap::ArgAction::Count,
+        global = true,
+        conflicts_with = "quiet"
+    )]
+    verbose: u8,
+
+    /// Log less: `-q` only warnings, `-qq` only errors. Quiet syncs drop the flags making rsync
+    /// print every file or its progress
+    #[arg(long, short, action = clap::ArgAction::Count, global = true)]
+    quiet: u8,
+
+    /// Tracing filter directives applied after those of the verbosity, e.g.
+    /// `atune::sync=trace,notify=debug`. Without it and `-v`/`-q`, `RUST_LOG` is used
+    #[arg(
+        long,
+        env("ATUNE_LOG_FILTER"),
+        value_name = "DIRECTIVES",
+        global = true
+    )]
+    log_filter: Option<String>,
+
+    /// Copy the logs to this file too, rotated like the `logging` of the config says.
+    /// Overrides the file of `logging`
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<std::path::PathBuf>,
+
     #[command(subcommand)]
     command: Command,
 }
@@ -36,15 +104,61 @@ struct Args {
 enum Command {
     /// Open the config file in your $EDITOR
     Edit,
-    Watch,
+    Watch {
+        #[clap(flatten)]
+        filter: SyncFilter,
+        /// Stop the atune already watching this config and take over
+        #[arg(long)]
+        replace: bool,
+        /// Keep watching in the background, detached from the terminal. The pid is written to
+        /// the lock file of the config, see `atune stop`. The logs go to `--log-file`, by default
+        /// `atune-<hash of the config path>.log` in the state directory
+        #[arg(long)]
+        daemon: bool,
+        /// Write the output to the log file only, set for the background watch of `--daemon`
+        #[arg(long, hide = true)]
+        detached: bool,
+    },
+    /// Stop the atune watching the config, e.g. started by `watch --daemon`
+    Stop,
+    /// Restart the watch of the config in the background, replacing the running one
+    Restart {
+        #[clap(flatten)]
+        filter: SyncFilter,
+    },
+    /// Watch like `watch`, showing a dashboard of the projects instead of the logs. The syncs
+    /// of the selected project can be paused, resumed and triggered from it
+    Tui {
+        #[clap(flatten)]
+        filter: SyncFilter,
+        /// Stop the atune already watching this config and take over
+        #[arg(long)]
+        replace: bool,
+    },
     /// Perform all sync actions once, then exit
     SyncOnce {
+        #[clap(flatten)]
+        filter: SyncFilter,
         #[arg(long, short)]
         no_run_commands: bool,
         /// Name of the project(s) to sync in the config
         /// If omitted, then all projects are synced
         #[arg(long, short)]
         project: Option<Vec<String>>,
+        /// Stop at the first failed sync, cancelling the syncs in progress
+        #[arg(long, conflicts_with = "keep_going")]
+        fail_fast: bool,
+        /// Run every sync even if some of them failed. This is the default
+        #[arg(long)]
+        keep_going: bool,
+        /// Skip the syncs whose source and settings didn't change since their last successful
+        /// sync
+        #[arg(long)]
+        skip_unchanged: bool,
+        /// Format of the report printed after the syncs finished.
+        /// With `json` the logs and the output of the syncs are written to stderr
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
     },
     /// Execute project sync once
     SyncProject {
@@ -59,6 +173,26 @@ enum Command {
 
         #[arg(long, short)]
         no_run_commands: bool,
+
+        /// Path changed since the last sync, passed to the hooks. Can be repeated
+        #[arg(long)]
+        changed: Vec<std::path::PathBuf>,
+
+        /// Path deleted since the last sync, passed to the hooks. Can be repeated
+        #[arg(long)]
+        deleted: Vec<std::path::PathBuf>,
+
+        /// Old and new path of a file moved since the last sync. Can be repeated
+        #[arg(long, num_args = 2, value_names = ["FROM", "TO"])]
+        renamed: Vec<std::path::PathBuf>,
+
+        /// Write rsync's stats and the hook results to the given file
+        #[arg(long, hide = true)]
+        report: Option<std::path::PathBuf>,
+
+        /// Print the overall progress of rsync, for the progress display of `watch`
+        #[arg(long, hide = true)]
+        progress: bool,
     },
     /// Print the rsync command invoked by the project
     ProjectRsync {
@@ -68,6 +202,238 @@ enum Command {
     },
     /// Print the default args passed to rsync
     RsyncArgs,
+    /// Print the JSON Schema of the config file
+    Schema,
+    /// Show when each sync last succeeded and whether its source or settings changed since.
+    /// Reports the state persisted by the last syncs, so it works without a running `watch`
+    Status {
+        /// Only show when each sync last succeeded, without checking whether it changed since,
+        /// which reads every file of its source
+        #[arg(long)]
+        last: bool,
+    },
+    /// Show the counters of the syncs run by `watch`: how many ran and failed, the bytes rsync
+    /// transferred and the average time from the first change to the end of the sync
+    Stats,
+    /// Show the last recorded runs of the hooks, oldest first, with the last lines of the output
+    /// of the failed ones
+    History {
+        /// Only show the hooks of this project
+        #[arg(long, short)]
+        project: Option<String>,
+        /// Number of runs to show. With `-v` the environment, output and log file of every run
+        /// are shown, not only of the failed ones
+        #[arg(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+    },
+    /// Copy the files a sync deleted or overwrote in dst back into src, from the backups kept
+    /// with the `backup` option. Lists the backups if `--from` is omitted
+    Restore {
+        /// Name of the project in the config
+        #[arg(long, short)]
+        project: String,
+        /// Only restore the sync of this src. If omitted, then all syncs of the project are
+        #[arg(long)]
+        src: Option<std::path::PathBuf>,
+        /// Name of the backup, e.g. `20240131T120000Z`
+        #[arg(long)]
+        from: Option<String>,
+        /// Replace the existing files of src too, instead of only restoring the missing ones
+        #[arg(long)]
+        overwrite: bool,
+    },
+    /// Tell the running `watch` of the config that files changed, e.g. from an editor on save.
+    /// They are synced right away, without waiting for the filesystem watcher
+    NotifyChange {
+        /// Changed or deleted files
+        #[arg(required_unless_present = "stdin")]
+        paths: Vec<std::path::PathBuf>,
+        /// Read the paths from stdin, one per line, and reply to each with `ok <project:sync>...`
+        /// or `error <message>` on stdout. For editor plugins keeping the process running
+        #[arg(long)]
+        stdin: bool,
+    },
+    /// Serve the Agent backend: write the files received on stdin into root. Started by the
+    /// syncing host, usually over ssh
+    Agent {
+        /// Directory receiving the files
+        root: std::path::PathBuf,
+    },
+    /// Check the environment for common problems: rsync, ssh access to the destinations,
+    /// inotify limits, the config file and destination permissions
+    Doctor,
+    /// Manage a user service running `watch` on the config: a systemd user unit, or a launchd
+    /// agent on macOS
+    Service {
+        /// Name of the service. Defaults to `atune-<directory of the config>`
+        #[arg(long)]
+        name: Option<String>,
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ServiceAction {
+    /// Write the unit file, using the current executable and config
+    Install {
+        /// Overwrite an existing unit file
+        #[arg(long)]
+        force: bool,
+        /// Only print the unit file
+        #[arg(long)]
+        print: bool,
+    },
+    /// Stop the service and remove the unit file
+    Uninstall,
+    /// Start the service now and on login
+    Enable,
+    /// Stop the service and don't start it on login
+    Disable,
+    Start,
+    Stop,
+    Status,
+}
+
+/// The global options of this invocation passed on to a `watch` running elsewhere, e.g. as a
+/// service
+fn forwarded_args(args: &Args) -> Vec<std::ffi::OsString> {
+    let mut forwarded = Vec::<std::ffi::OsString>::new();
+    if args.rsync != std::path::Path::new("rsync") {
+        forwarded.extend(["--rsync".into(), args.rsync.clone().into_os_string()]);
+    }
+    if let Some(profile) = args.profile.as_ref() {
+        forwarded.extend(["--profile".into(), profile.into()]);
+    }
+    for (k, v) in args.vars.iter() {
+        forwarded.extend(["--var".into(), format!("{k}={v}").into()]);
+    }
+    if args.confirm {
+        forwarded.push("--confirm".into());
+    }
+    if args.yes {
+        forwarded.push("--yes".into());
+    }
+    forwarded
+}
+
+/// Copy the output to `--log-file`, or the file of `logging`
+fn init_log_file(args: &Args, logging: Option<&config::Logging>, detached: bool) {
+    let Some(path) = args
+        .log_file
+        .clone()
+        .or_else(|| logging.and_then(|l| l.file.clone()))
+    else {
+        return;
+    };
+    let rotation = logging.map(Into::into).unwrap_or_default();
+    if let Err(err) = atune::log_file::init(&path, rotation, detached) {
+        warn!(?err, ?path, "Failed to open the log file");
+    }
+}
+
+/// The config files of the given paths, listing the configs of the directories
+fn expand_configs(paths: &[std::path::PathBuf]) -> anyhow::Result<Vec<std::path::PathBuf>> {
+    let mut configs = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            configs.extend(config::config_files(path)?);
+        } else {
+            configs.push(path.clone());
+        }
+    }
+    Ok(configs)
+}
+
+/// Start `watch` of the configs in the background, see `watch --daemon`
+fn start_daemon(
+    args: &Args,
+    configs: &[std::path::PathBuf],
+    filter: &SyncFilter,
+    replace: bool,
+) -> anyhow::Result<()> {
+    let log = args
+        .log_file
+        .clone()
+        .unwrap_or_else(|| atune::daemon::log_path(&configs[0]));
+    let mut watch_args = Vec::<std::ffi::OsString>::new();
+    for config in configs {
+        let config = platform::canonicalize(config)
+            .with_context(|| format!("Config {} not found", config.display()))?;
+        watch_args.extend(["-c".into(), config.into_os_string()]);
+    }
+    if let Some(format) = args.format {
+        use clap::ValueEnum as _;
+        if let Some(value) = format.to_possible_value() {
+            watch_args.extend(["--format".into(), value.get_name().into()]);
+        }
+    }
+    watch_args.extend(forwarded_args(args));
+    watch_args.extend(["--log-file".into(), log.clone().into_os_string()]);
+    watch_args.extend(["watch".into(), "--detached".into()]);
+    for only in filter.only.iter() {
+        watch_args.extend(["--only".into(), only.to_string().into()]);
+    }
+    for skip in filter.skip.iter() {
+        watch_args.extend(["--skip".into(), skip.to_string().into()]);
+    }
+    if !filter.tags.is_empty() {
+        watch_args.extend(["--tags".into(), filter.tags.join(",").into()]);
+    }
+    if replace {
+        watch_args.push("--replace".into());
+    }
+    let pid = atune::daemon::spawn(&watch_args, &log)?;
+    println!(
+        "atune is watching in the background (pid {pid}), logging to {}",
+        log.display()
+    );
+    Ok(())
+}
+
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.trim().to_owned(), v.to_owned()))
+        .ok_or_else(|| format!("expected NAME=VALUE, got {s:?}"))
+}
+
+#[derive(Debug, Default, Clone, Copy, clap::ValueEnum)]
+enum OutputFormat {
+    /// Summary table
+    #[default]
+    Human,
+    Json,
+}
+
+/// Select a subset of the configured syncs for this run
+#[derive(Debug, clap_derive::Args)]
+struct SyncFilter {
+    /// Only run the given project, or a single sync of it. The sync is selected by its index,
+    /// the file name of its src or its src path. Can be repeated
+    #[arg(long, value_name = "PROJECT[:SYNC]")]
+    only: Vec<config::SyncSelector>,
+
+    /// Skip the given project, or a single sync of it. Can be repeated
+    #[arg(long, value_name = "PROJECT[:SYNC]")]
+    skip: Vec<config::SyncSelector>,
+
+    /// Only run the syncs having any of the tags, their own or of their project. Comma
+    /// separated, can be repeated
+    #[arg(long, value_name = "TAG", value_delimiter = ',')]
+    tags: Vec<String>,
+}
+
+impl SyncFilter {
+    /// Select the syncs of the filter in `config`
+    fn apply(&self, config: &mut config::Config) -> anyhow::Result<()> {
+        config.select(&self.only, &self.skip)?;
+        anyhow::ensure!(
+            config.select_tags(&self.tags),
+            "No sync has the tags {}",
+            self.tags.join(", ")
+        );
+        Ok(())
+    }
 }
 
 #[derive(Debug, clap_derive::Args)]
@@ -82,57 +448,633 @@ struct SyncId {
     src: Option<std::path::PathBuf>,
 }
 
+fn print_summary(reports: &[sync::SyncReport]) {
+    let rows = reports
+        .iter()
+        .map(|r| {
+            let status = match &r.status {
+                sync::SyncStatus::Success => "ok".to_owned(),
+                sync::SyncStatus::Failed(atune::SyncError::HookFailed) => "hook failed".to_owned(),
+                sync::SyncStatus::Failed(atune::SyncError::DstConflict) => {
+                    "dst conflict".to_owned()
+                }
+                sync::SyncStatus::Failed(atune::SyncError::Unreachable) => "unreachable".to_owned(),
+                sync::SyncStatus::Failed(atune::SyncError::DeleteRefused) => {
+                    "deletions refused".to_owned()
+                }
+                sync::SyncStatus::Failed(atune::SyncError::DeleteLimit) => {
+                    "too many deletions".to_owned()
+                }
+                sync::SyncStatus::Failed(atune::SyncError::Failed {
+                    exit_code: Some(code),
+                }) => format!("failed ({code})"),
+                sync::SyncStatus::Failed(atune::SyncError::Failed { exit_code: None }) => {
+                    "failed".to_owned()
+                }
+                sync::SyncStatus::Skipped => "skipped".to_owned(),
+                sync::SyncStatus::Cancelled => "cancelled".to_owned(),
+                sync::SyncStatus::Unchanged => "unchanged".to_owned(),
+            };
+            [
+                r.project.clone(),
+                r.src.display().to_string(),
+                status,
+                match r.status {
+                    sync::SyncStatus::Skipped | sync::SyncStatus::Unchanged => "-".to_owned(),
+                    _ => format!("{:.2?}", r.duration),
+                },
+                r.usage
+                    .map_or("-".to_owned(), |u| format!("{:.2?}", u.cpu())),
+                r.usage
+                    .map_or("-".to_owned(), |u| atune::stats::format_bytes(u.max_rss)),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(
+        ["PROJECT", "SYNC", "STATUS", "DURATION", "CPU", "MAX RSS"],
+        &rows,
+    );
+}
+
+/// Print the state of the enabled syncs recorded in the state file, and if `check_changes`,
+/// whether they changed since
+fn print_status(
+    config_path: &std::path::Path,
+    config: config::Config,
+    check_changes: bool,
+) -> anyhow::Result<()> {
+    let state = atune::state::SyncState::load();
+    let now = std::time::SystemTime::now();
+    let mut projects = config.projects.into_iter().collect::<Vec<_>>();
+    projects.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut rows = Vec::new();
+    for (name, project) in projects {
+        for (i, f) in project.sync.into_iter().enumerate() {
+            if !f.enabled {
+                continue;
+            }
+            let sync = sync::parse_sync(&name, i, f)?;
+            let entry = state.get(config_path, &name, &sync.src);
+            let (last, status) = match entry {
+                Some(e) => {
+                    let age = now.duration_since(e.last_success()).unwrap_or_default();
+                    let status = if !check_changes {
+                        "synced"
+                    } else if e.fingerprint == atune::state::fingerprint(&sync) {
+                        "up to date"
+                    } else {
+                        "changed"
+                    };
+                    (format_age(age), status)
+                }
+                None => ("never".to_owned(), "never synced"),
+            };
+            rows.push([
+                name.clone(),
+                sync.src.display().to_string(),
+                last,
+                status.to_owned(),
+            ]);
+        }
+    }
+    print_table(["PROJECT", "SYNC", "LAST SYNC", "STATUS"], &rows);
+    Ok(())
+}
+
+fn print_stats(config_path: &std::path::Path) -> anyhow::Result<()> {
+    use atune::stats::{format_bytes, Stats};
+
+    let stats = Stats::load();
+    let mut entries = stats.of_config(config_path).collect::<Vec<_>>();
+    entries.sort_by(|a, b| {
+        b.syncs
+            .cmp(&a.syncs)
+            .then_with(|| a.project.cmp(&b.project))
+    });
+    let latency = |e: &atune::stats::EntryStats| {
+        e.average_latency()
+            .map(|l| format!("{l:.1?}"))
+            .unwrap_or_else(|| "-".to_owned())
+    };
+    let mut rows = entries
+        .iter()
+        .map(|e| {
+            [
+                e.project.clone(),
+                e.src.display().to_string(),
+                e.syncs.to_string(),
+                e.failures.to_string(),
+                format_bytes(e.bytes),
+                latency(e),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let total = Stats::total(entries);
+    rows.push([
+        "TOTAL".to_owned(),
+        String::new(),
+        total.syncs.to_string(),
+        total.failures.to_string(),
+        format_bytes(total.bytes),
+        latency(&total),
+    ]);
+    print_table(
+        [
+            "PROJECT",
+            "SYNC",
+            "SYNCS",
+            "FAILED",
+            "TRANSFERRED",
+            "AVG LATENCY",
+        ],
+        &rows,
+    );
+    Ok(())
+}
+
+fn format_age(age: std::time::Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{secs}s ago"),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let header = header.map(str::to_owned);
+    let mut widths = [0; N];
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        for (w, col) in widths.iter_mut().zip(row) {
+            *w = (*w).max(col.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(col, w)| format!("{col:w$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+fn print_history(project: Option<&str>, limit: usize, verbose: bool) {
+    let mut runs = atune::history::load(project);
+    let runs = runs.split_off(runs.len().saturating_sub(limit));
+    let status = |r: &atune::history::HookRun| match (r.success, r.exit_code) {
+        (Some(true), _) => "ok".to_owned(),
+        (Some(false), Some(code)) => format!("failed ({code})"),
+        (Some(false), None) => "failed".to_owned(),
+        (None, _) if r.interrupted() => "interrupted".to_owned(),
+        (None, _) => "running".to_owned(),
+    };
+    let rows = runs
+        .iter()
+        .map(|r| {
+            let command = r.command.lines().next().unwrap_or_default();
+            [
+                atune::backup::timestamp(r.started()),
+                if r.project.is_empty() {
+                    "-".to_owned()
+                } else {
+                    r.project.clone()
+                },
+                r.hook.clone(),
+                status(r),
+                r.duration_ms.map_or("-".to_owned(), |ms| {
+                    format!("{:.2?}", std::time::Duration::from_millis(ms))
+                }),
+                command.chars().take(60).collect(),
+            ]
+        })
+        .collect::<Vec<_>>();
+    print_table(
+        [
+            "STARTED", "PROJECT", "HOOK", "STATUS", "DURATION", "COMMAND",
+        ],
+        &rows,
+    );
+    for r in runs.iter() {
+        if !verbose && r.success != Some(false) && !r.interrupted() {
+            continue;
+        }
+        println!(
+            "\n{} {} {}: {}",
+            atune::backup::timestamp(r.started()),
+            r.hook,
+            status(r),
+            r.command
+        );
+        if let Some(cwd) = r.cwd.as_ref() {
+            println!("  cwd: {}", cwd.display());
+        }
+        if verbose {
+            for (k, v) in r.env.iter() {
+                println!("  {k}={v}");
+            }
+        }
+        if let Some(log) = r.log.as_ref().filter(|l| l.exists()) {
+            println!("  log: {}", log.display());
+        }
+        for line in r.tail.iter() {
+            println!("  | {line}");
+        }
+    }
+}
+
+#[cfg(unix)]
+fn notify_change(
+    config: &std::path::Path,
+    paths: &[std::path::PathBuf],
+    stdin: bool,
+) -> anyhow::Result<()> {
+    use std::io::BufRead as _;
+
+    let mut client = atune::control_socket::Client::connect(config)?;
+    if stdin {
+        for line in std::io::stdin().lock().lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match client.changed(std::path::Path::new(&line)) {
+                Ok(labels) => println!("ok {}", labels.join(" ")),
+                Err(err) => println!("error {err}"),
+            }
+        }
+        return Ok(());
+    }
+    let mut failed = false;
+    for path in paths {
+        match client.changed(path) {
+            Ok(labels) => info!(?path, syncs = labels.join(" "), "Change queued"),
+            Err(err) => {
+                error!(?path, "{err}");
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify_change(
+    _config: &std::path::Path,
+    _paths: &[std::path::PathBuf],
+    _stdin: bool,
+) -> anyhow::Result<()> {
+    anyhow::bail!("notify-change is only supported on unix")
+}
+
+/// Block until a termination signal is received, returning the signal
+#[cfg(unix)]
+fn wait_for_signal() -> std::io::Result<i32> {
+    let mut signals =
+        signal_hook::iterator::Signals::new([SIGINT, SIGTERM, signal_hook::consts::SIGQUIT])?;
+    let sig = signals.wait().next();
+    Ok(sig.unwrap_or(SIGTERM))
+}
+
+/// Block until a termination signal is received, returning the signal
+#[cfg(not(unix))]
+fn wait_for_signal() -> std::io::Result<i32> {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let received = Arc::new(AtomicUsize::new(0));
+    for sig in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_usize(sig, received.clone(), sig as usize)?;
+    }
+    loop {
+        let sig = received.load(Ordering::Relaxed);
+        if sig != 0 {
+            return Ok(sig as i32);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+}
+
 fn main() -> anyhow::Result<()> {
     use std::io::IsTerminal;
-    let is_tty = std::io::stdout().is_terminal();
 
+    let args = Args::parse();
+
+    let verbosity = if args.verbose > 0 || args.quiet > 0 {
+        (args.verbose.min(2) as i8) - (args.quiet.min(2) as i8)
+    } else {
+        atune::verbosity::from_env()
+    };
+    // inherited by the sync processes and hooks
+    std::env::set_var(atune::verbosity::ENV, verbosity.to_string());
+    if let Some(filter) = args.log_filter.as_deref() {
+        std::env::set_var("ATUNE_LOG_FILTER", filter);
+    }
+
+    // keep stdout clean for machine readable output
+    let log_to_stderr = matches!(
+        args.command,
+        Command::SyncOnce {
+            output: OutputFormat::Json,
+            ..
+        } | Command::Agent { .. }
+    );
+    let is_tty = if log_to_stderr {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    // structured logs when running as a systemd service
+    #[cfg(unix)]
+    let journal = (!log_to_stderr && atune::systemd::stdout_is_journal())
+        .then(atune::systemd::JournalLayer::new)
+        .flatten();
+    #[cfg(not(unix))]
+    let journal: Option<tracing_subscriber::layer::Identity> = None;
+    let journal_enabled = journal.is_some();
+    let fmt = journal.is_none().then(|| {
+        tracing_subscriber::fmt::layer()
+            // the dashboard of `tui` shows the logs as plain text
+            .with_ansi(is_tty && !matches!(args.command, Command::Tui { .. }))
+            .with_writer(move || -> Box<dyn std::io::Write> {
+                if log_to_stderr {
+                    Box::new(atune::log_file::Tee(std::io::stderr()))
+                } else {
+                    // below the logs `watch` may draw the progress of the syncs
+                    Box::new(atune::output::StatusAwareStdout::default())
+                }
+            })
+    });
+    // sends the pending spans and metrics when main returns
+    #[cfg(feature = "otel")]
+    let (otel, _otel_guard) = atune::otel::layer_from_env().unzip();
+    #[cfg(not(feature = "otel"))]
+    let otel: Option<tracing_subscriber::layer::Identity> = None;
     let reg = tracing_subscriber::registry()
-        .with(
+        .with(if verbosity == 0 && args.log_filter.is_none() {
             tracing_subscriber::EnvFilter::builder()
                 .with_default_directive(tracing::level_filters::LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
-        .with(tracing_subscriber::fmt::layer().with_ansi(is_tty));
+                .from_env_lossy()
+        } else {
+            tracing_subscriber::EnvFilter::builder().parse_lossy(atune::verbosity::filter(
+                verbosity,
+                args.log_filter.as_deref(),
+            ))
+        })
+        .with(journal)
+        .with(fmt)
+        .with(otel);
 
     reg.try_init()?;
 
-    let args = Args::parse();
     debug!(?args, "parsed arguments");
 
-    let mut fname = None;
-    match args.config {
-        Some(x) => fname = Some(x),
-        None => {
-            for dir in std::path::Path::new(".")
-                .canonicalize()
-                .unwrap()
-                .ancestors()
+    if let Command::Schema = args.command {
+        // doesn't need a config
+        print!("{}", atune::schema::CONFIG_SCHEMA);
+        return Ok(());
+    }
+
+    if let Command::Agent { root } = &args.command {
+        // runs on the host of dst, which has no config
+        return atune::agent::serve(root, std::io::stdin().lock(), std::io::stdout().lock());
+    }
+
+    if let Command::History { project, limit } = &args.command {
+        // the history is shared by all configs
+        print_history(project.as_deref(), *limit, args.verbose > 0);
+        return Ok(());
+    }
+
+    let mut configs = args.config.clone();
+    if configs.is_empty() {
+        for dir in platform::canonicalize(".").unwrap().ancestors() {
+            if let Some(f) = config::CONFIG_FILE_NAMES
+                .iter()
+                .map(|name| dir.join(name))
+                .find(|f| f.exists())
             {
-                let f = dir.join("atune.yaml");
-                if f.exists() {
-                    fname = Some(f);
-                    break;
-                }
-            }
-            if fname.is_none() {
-                anyhow::bail!(
-                    "Failed to find atune.yaml config file in any of the parent directories."
-                );
+                configs.push(f);
+                break;
             }
         }
+        if configs.is_empty() {
+            anyhow::bail!("Failed to find an atune config file in any of the parent directories.");
+        }
+    }
+    let multiple_configs = configs.len() > 1 || configs[0].is_dir();
+    anyhow::ensure!(
+        !multiple_configs
+            || matches!(
+                args.command,
+                Command::Watch { .. } | Command::Stop | Command::Restart { .. }
+            ),
+        "Only watch supports several configs or a directory of configs"
+    );
+    let fname = configs[0].clone();
+    let format = args
+        .format
+        .unwrap_or_else(|| config::ConfigFormat::from_path(&fname));
+
+    let overrides = config::ConfigOverrides {
+        profile: args.profile.clone(),
+        vars: args.vars.iter().cloned().collect::<HashMap<_, _>>(),
+        confirm_delete: if args.yes {
+            Some(false)
+        } else {
+            args.confirm.then_some(true)
+        },
     };
-    let fname = fname.unwrap();
+    // inherited by the sync processes
+    if args.confirm {
+        std::env::set_var("ATUNE_CONFIRM", "true");
+    }
+    if args.yes {
+        std::env::set_var("ATUNE_YES", "true");
+    }
+    if let Some(profile) = args.profile.as_deref() {
+        // inherited by the sync processes
+        std::env::set_var("ATUNE_PROFILE", profile);
+    }
+    if !args.vars.is_empty() {
+        // inherited by the sync processes
+        let vars = args
+            .vars
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>();
+        std::env::set_var("ATUNE_VARS", vars.join("\n"));
+    }
+
+    if let Command::Service { name, action } = &args.command {
+        // doesn't need a valid config to uninstall
+        let config = platform::canonicalize(&fname).context("Failed to resolve the config path")?;
+        let service_args = forwarded_args(&args);
+        let service = atune::service::Service {
+            manager: atune::service::Manager::current()?,
+            name: name
+                .clone()
+                .unwrap_or_else(|| atune::service::Service::default_name(&config)),
+            executable: std::env::current_exe().context("Failed to find the atune executable")?,
+            config,
+            args: service_args,
+        };
+        return match action {
+            ServiceAction::Install { print: true, .. } => {
+                print!("{}", service.render());
+                Ok(())
+            }
+            ServiceAction::Install { force, .. } => service.install(*force),
+            ServiceAction::Uninstall => service.uninstall(),
+            ServiceAction::Enable => service.enable(),
+            ServiceAction::Disable => service.disable(),
+            ServiceAction::Start => service.start(),
+            ServiceAction::Stop => service.stop(),
+            ServiceAction::Status => service.status(),
+        };
+    }
+
+    if let Command::NotifyChange { paths, stdin } = &args.command {
+        // only needs the config path to find the running watch
+        return notify_change(&fname, paths, *stdin);
+    }
+
+    if let Command::Doctor = args.command {
+        // reports config errors itself
+        if atune::doctor::run(&fname, format, &overrides, &args.rsync) > 0 {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Command::Stop = args.command {
+        for config in expand_configs(&configs)? {
+            match atune::lock::stop(&config)? {
+                Some(pid) => println!("Stopped atune (pid {pid}) watching {}", config.display()),
+                None => println!("atune isn't watching {}", config.display()),
+            }
+        }
+        return Ok(());
+    }
+
+    match &args.command {
+        Command::Watch {
+            filter,
+            replace,
+            daemon: true,
+            ..
+        } => return start_daemon(&args, &configs, filter, *replace),
+        Command::Restart { filter } => return start_daemon(&args, &configs, filter, true),
+        _ => {}
+    }
 
-    let config = std::fs::OpenOptions::new()
-        .read(true)
-        .open(&fname)
-        .context("Failed to open config file")?;
-    let mut config: config::Config =
-        serde_yaml::from_reader(config).context("Failed to parse config file")?;
+    if let Command::Watch {
+        filter,
+        replace,
+        detached,
+        ..
+    } = &args.command
+    {
+        let (cancel_tx, cancel_rx) = crossbeam::channel::bounded(1);
+        let mut consumers = Vec::new();
+        // readiness and status for systemd services with `Type=notify`
+        #[cfg(unix)]
+        if std::env::var_os("NOTIFY_SOCKET").is_some() {
+            let (tx, rx) = crossbeam::channel::unbounded();
+            std::thread::spawn(move || atune::systemd::notify_watch_events(rx));
+            consumers.push(tx);
+        }
+        let progress = is_tty && !log_to_stderr && !journal_enabled && verbosity >= 0;
+        if progress {
+            let (tx, rx) = crossbeam::channel::unbounded();
+            std::thread::spawn(move || atune::output::show_progress(rx));
+            consumers.push(tx);
+        }
+        let events = match consumers.len() {
+            0 => None,
+            1 => consumers.pop(),
+            _ => {
+                let (tx, rx) = crossbeam::channel::unbounded::<atune::WatchEvent>();
+                std::thread::spawn(move || {
+                    for event in rx {
+                        for c in consumers.iter() {
+                            let _ = c.send(event.clone());
+                        }
+                    }
+                });
+                Some(tx)
+            }
+        };
+        std::thread::spawn(move || match wait_for_signal() {
+            Ok(sig) => {
+                println!("Signal ({sig}) received. Stopping...");
+                #[cfg(unix)]
+                atune::systemd::notify("STOPPING=1");
+                let _ = cancel_tx.send(());
+            }
+            Err(err) => {
+                warn!(?err, "Failed to register signal handler");
+            }
+        });
+        let load = |path: &std::path::Path| {
+            let mut config = config::Config::load(path, args.format, &overrides)?;
+            if multiple_configs {
+                // the selectors apply to the configs having their projects
+                let known = |s: &&config::SyncSelector| config.projects.contains_key(&s.project);
+                let only = filter
+                    .only
+                    .iter()
+                    .filter(known)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let skip = filter
+                    .skip
+                    .iter()
+                    .filter(known)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if only.is_empty() && !filter.only.is_empty() {
+                    config.projects.clear();
+                }
+                config.select(&only, &skip)?;
+                // the tags may be used by some of the configs only
+                config.select_tags(&filter.tags);
+            } else {
+                filter.apply(&mut config)?;
+            }
+            init_log_file(&args, config.logging.as_ref(), *detached);
+            debug!(?config, "Loaded config");
+            Ok(config)
+        };
+        // two daemons racing `--delete` on the same destinations can clobber each other, so
+        // every config is locked
+        return atune::reload::watch_configs(
+            &configs,
+            load,
+            cancel_rx,
+            atune::reload::ConfigWatchOptions {
+                replace: *replace,
+                watch: sync::WatchOptions {
+                    rsync: Some(args.rsync.clone()),
+                    events,
+                    progress,
+                    ..Default::default()
+                },
+            },
+        );
+    }
 
-    for s in config.projects.values_mut().flat_map(|p| p.sync.iter_mut()) {
-        let src = std::mem::take(&mut s.src);
-        s.src = std::fs::canonicalize(&src).unwrap_or(src);
+    let mut config = config::Config::load(&fname, Some(format), &overrides)?;
+    // the output of `sync-project` is copied by the atune running it
+    if !matches!(args.command, Command::SyncProject { .. }) {
+        init_log_file(&args, config.logging.as_ref(), false);
     }
     debug!(?config, "Loaded config");
 
@@ -148,33 +1090,45 @@ fn main() -> anyhow::Result<()> {
             cmd.wait().context("Failed to wait for editor")?;
             Ok(())
         }
-        Command::Watch => {
-            let (cancel_tx, cancel_rx) = crossbeam::channel::bounded(1);
-
-            let h = std::thread::spawn(|| {
-                crate::sync::watch(fname, config, cancel_rx, Some(args.rsync))
-            });
-            match Signals::new([SIGINT, SIGTERM, SIGQUIT]) {
-                Ok(mut signals) => {
-                    if let Some(sig) = signals.wait().next() {
-                        println!("Signal ({sig}) received. Stopping...");
-                        cancel_tx.send(()).unwrap();
-                        h.join()
-                            .expect("Failed to join watch thread")
-                            .expect("Watch error");
-                        signals.handle().close();
-                    }
-                }
-                Err(err) => {
-                    warn!(?err, "Failed to register signal handler");
-                }
+        #[cfg(unix)]
+        Command::Tui { filter, replace } => {
+            anyhow::ensure!(
+                is_tty,
+                "atune tui needs a terminal, use atune watch instead"
+            );
+            filter.apply(&mut config)?;
+            let _lock = atune::lock::ConfigLock::acquire(&fname, replace)?;
+            let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+            for sig in [SIGTERM, signal_hook::consts::SIGHUP] {
+                signal_hook::flag::register(sig, stop.clone())?;
             }
-            Ok(())
+            let projects = config.projects.keys().cloned().collect::<Vec<_>>();
+            let watcher = atune::Watcher::start(
+                fname,
+                config,
+                sync::WatchOptions {
+                    rsync: Some(args.rsync),
+                    progress: true,
+                    ..Default::default()
+                },
+            );
+            let res = atune::tui::run(&watcher, projects, &stop);
+            println!("Stopping...");
+            watcher.stop()?;
+            res
         }
+        #[cfg(not(unix))]
+        Command::Tui { .. } => anyhow::bail!("atune tui is only supported on unix"),
         Command::SyncOnce {
+            filter,
             no_run_commands,
             project,
+            fail_fast,
+            keep_going: _,
+            skip_unchanged,
+            output,
         } => {
+            filter.apply(&mut config)?;
             if let Some(project_filter) = project.map(|p| p.into_iter().collect::<HashSet<_>>()) {
                 config.projects.retain(|k, _| project_filter.contains(k));
             }
@@ -186,7 +1140,25 @@ fn main() -> anyhow::Result<()> {
                     }
                 }
             }
-            sync_all_once(no_run_commands, fname, config)
+            let reports = sync_all_once(
+                fname,
+                config,
+                sync::SyncOnceOptions {
+                    skip_commands: no_run_commands,
+                    fail_fast,
+                    collect_output: matches!(output, OutputFormat::Json),
+                    skip_unchanged,
+                    rsync: Some(args.rsync),
+                },
+            )?;
+            match output {
+                OutputFormat::Human => print_summary(&reports),
+                OutputFormat::Json => println!("{}", sync::reports_json(&reports)),
+            }
+            if reports.iter().any(|r| !r.status.is_success()) {
+                process::exit(1);
+            }
+            Ok(())
         }
         Command::SyncProject {
             project,
@@ -197,6 +1169,11 @@ fn main() -> anyhow::Result<()> {
                 },
             initialize,
             no_run_commands,
+            changed,
+            deleted,
+            renamed,
+            report,
+            progress,
         } => {
             let mut config = config;
             if no_run_commands {
@@ -207,41 +1184,185 @@ fn main() -> anyhow::Result<()> {
                 }
             }
 
-            let sync = match (sync_index, sync_src) {
-                (None, Some(sync_src)) => {
-                    let sync_src = std::fs::canonicalize(&sync_src).unwrap();
-                    std::mem::take(
-                        config
-                            .projects
-                            .remove(&project)
-                            .with_context(|| format!("Failed to find project {project}"))?
-                            .sync
-                            .iter_mut()
-                            .find(|s| s.src == sync_src)
-                            .with_context(|| {
-                                format!("Failed to find sync {}", sync_src.display())
-                            })?,
-                    )
-                }
-                (Some(sync_index), None) => std::mem::take(
-                    config
-                        .projects
-                        .remove(&project)
-                        .context("Failed to find project")?
-                        .sync
-                        .get_mut(sync_index)
-                        .context("Failed to find sync")?,
-                ),
+            let rsync = config
+                .projects
+                .get(&project)
+                .and_then(|p| p.rsync.clone())
+                .unwrap_or(args.rsync);
+            let mut syncs = config
+                .projects
+                .remove(&project)
+                .with_context(|| format!("Failed to find project {project}"))?
+                .sync;
+            let index = match (sync_index, sync_src) {
+                (None, Some(sync_src)) => syncs
+                    .iter()
+                    .position(|s| {
+                        platform::resolve(&sync_src, s.follows_symlinks()).is_ok_and(|p| p == s.src)
+                    })
+                    .with_context(|| format!("Failed to find sync {}", sync_src.display()))?,
+                (Some(sync_index), None) => sync_index,
                 _ => unreachable!(),
             };
+            let sync = std::mem::take(syncs.get_mut(index).context("Failed to find sync")?);
+            let mut sync = sync::parse_sync(&project, index, sync)?;
+            sync.ssh_multiplexing = config.ssh_multiplexing;
+            sync.progress = progress;
+            // inherited by rsync and the hooks
+            if let Err(err) = sync.niceness.apply() {
+                warn!(?err, "Failed to set the priority of the sync");
+            }
+            if verbosity < 0 {
+                atune::verbosity::quiet_rsync_flags(&mut sync.rsync_flags);
+            }
 
-            crate::sync::execute_sync(
-                &sync.try_into().context("Failed to parse sync spec")?,
-                Some(args.rsync.as_os_str()),
+            let changes = sync::SyncChanges {
+                changed: changed.into_iter().collect(),
+                deleted: deleted.into_iter().collect(),
+                renamed: renamed
+                    .chunks_exact(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect(),
+                // set by `watch`
+                id: std::env::var(sync::CHANGE_ID_ENV)
+                    .ok()
+                    .and_then(|id| id.parse().ok()),
+            };
+            let notification = |event, duration, error| SyncNotification {
+                event,
+                project: project.as_str(),
+                src: sync.src.as_path(),
+                dst: sync.dst.as_deref(),
+                duration,
+                error,
+                changed: &changes.changed,
+                deleted: &changes.deleted,
+            };
+            notify(
+                &config.notifications,
+                &notification(NotificationEvent::Start, None, None),
+            );
+            let start = std::time::Instant::now();
+            let mut output = sync::SyncOutput::default();
+            let res = sync::execute_sync(
+                &fname,
+                &project,
+                &sync,
+                Some(rsync.as_os_str()),
                 initialize,
-            )
-            .context("Failed to sync")
+                &changes,
+                report.is_some().then_some(&mut output),
+                None,
+            );
+            if let Some(report) = report {
+                let written = serde_yaml::to_string(&output)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|o| Ok(std::fs::write(&report, o)?));
+                if let Err(err) = written {
+                    warn!(?err, "Failed to write the sync report");
+                }
+            }
+            let n = match res.as_ref() {
+                Ok(_) => notification(NotificationEvent::Success, Some(start.elapsed()), None),
+                Err(err) => notification(
+                    NotificationEvent::Failure,
+                    Some(start.elapsed()),
+                    Some(format!("{err:#}")),
+                ),
+            };
+            notify(&config.notifications, &n);
+            // not flushed on exit
+            #[cfg(feature = "otel")]
+            atune::otel::flush();
+
+            if let Err(err) = res.as_ref() {
+                if err.downcast_ref::<sync::HookFailed>().is_some() {
+                    // let the watcher tell hook failures apart from sync failures
+                    error!("{err:#}");
+                    process::exit(sync::EXIT_HOOK_FAILED);
+                }
+                if err.downcast_ref::<sync::DstConflict>().is_some() {
+                    error!("{err:#}");
+                    process::exit(sync::EXIT_DST_CONFLICT);
+                }
+                if err.downcast_ref::<sync::Unreachable>().is_some() {
+                    error!("{err:#}");
+                    process::exit(sync::EXIT_UNREACHABLE);
+                }
+                if err
+                    .downcast_ref::<atune::confirm::DeleteRefused>()
+                    .is_some()
+                {
+                    error!("{err:#}");
+                    process::exit(sync::EXIT_DELETE_REFUSED);
+                }
+                if err.downcast_ref::<atune::confirm::DeleteLimit>().is_some() {
+                    error!("{err:#}");
+                    process::exit(sync::EXIT_DELETE_LIMIT);
+                }
+            }
+            res.context("Failed to sync")
+        }
+        Command::Status { last } => print_status(&fname, config, !last),
+        Command::Stats => print_stats(&fname),
+        Command::Restore {
+            project,
+            src,
+            from,
+            overwrite,
+        } => {
+            let p = config
+                .projects
+                .get(&project)
+                .with_context(|| format!("Failed to find project {project}"))?;
+            let rsync = p.rsync.as_deref().unwrap_or(&args.rsync);
+            let syncs = p
+                .sync
+                .iter()
+                .filter(|s| {
+                    src.as_ref().is_none_or(|src| {
+                        platform::resolve(src, s.follows_symlinks()).is_ok_and(|p| p == s.src)
+                    })
+                })
+                .filter_map(|s| Some((s, s.dst.as_deref()?, s.backup.as_ref()?)))
+                .collect::<Vec<_>>();
+            anyhow::ensure!(
+                !syncs.is_empty(),
+                "No matching sync of {project} has backups enabled"
+            );
+            for (s, dst, backup) in syncs {
+                match from.as_deref() {
+                    None => {
+                        println!("{}", s.src.display());
+                        for name in atune::backup::list(dst, s.ssh_args_of(dst), backup)? {
+                            println!("  {name}");
+                        }
+                    }
+                    Some(name) => {
+                        info!(src = ?s.src, dst = ?dst, "Restoring backup {name}");
+                        atune::backup::restore(
+                            rsync.as_os_str(),
+                            dst,
+                            s.ssh_args_of(dst),
+                            backup,
+                            name,
+                            sync::transfer_root(&s.src),
+                            overwrite,
+                        )?;
+                    }
+                }
+            }
+            Ok(())
         }
+        Command::Schema
+        | Command::Agent { .. }
+        | Command::History { .. }
+        | Command::Doctor
+        | Command::Service { .. }
+        | Command::NotifyChange { .. }
+        | Command::Watch { .. }
+        | Command::Stop
+        | Command::Restart { .. } => unreachable!(),
         Command::RsyncArgs => {
             println!("{}", DEFAULT_RSYCN_FLAGS.join(" "));
             Ok(())
@@ -254,14 +1375,17 @@ fn main() -> anyhow::Result<()> {
             for sync in project.sync.iter() {
                 print!("{} -", sync.src.display(),);
                 match sync.rsync_flags.as_ref() {
-                    Some(flags) => println!("{flags}"),
+                    Some(flags) => print!(" {flags}"),
                     None => {
                         for f in DEFAULT_RSYCN_FLAGS {
                             print!(" {f}");
                         }
-                        println!();
                     }
                 }
+                if let Some(extra) = sync.extra_rsync_flags.as_ref() {
+                    print!(" {extra}");
+                }
+                println!();
             }
             Ok(())
         }
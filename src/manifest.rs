@@ -0,0 +1,275 @@
+//! Local record of the synced files, so changes can be fed to rsync without a full scan
+use std::{
+    collections::BTreeMap,
+    io::{BufRead as _, Write as _},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+
+use crate::state::Fnv;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    /// modification time in nanoseconds since the Unix epoch
+    mtime: u128,
+    /// content hash, computed lazily when the mtime changes but the size doesn't
+    hash: Option<u64>,
+}
+
+/// Files of a sync entry as of its last successful sync
+#[derive(Debug)]
+pub struct Manifest {
+    src: PathBuf,
+    file: PathBuf,
+    /// keyed by the path relative to src
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+/// Paths that differ from the manifest
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ManifestDiff {
+    pub changed: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.deleted.is_empty()
+    }
+}
+
+impl Manifest {
+    /// Where the manifest of the src, dst pair is stored, next to the state file
+    fn location(src: &Path, dst: &Path) -> anyhow::Result<PathBuf> {
+        let state =
+            crate::state::state_path().context("Failed to determine the state directory")?;
+        let mut hash = Fnv::default();
+        hash.write(src.as_os_str().as_encoded_bytes());
+        hash.write(dst.as_os_str().as_encoded_bytes());
+        Ok(state
+            .with_file_name("manifests")
+            .join(format!("{:016x}", hash.0)))
+    }
+
+    /// Load the manifest of the entry. Returns None if there is none yet
+    pub fn load(src: &Path, dst: &Path) -> anyhow::Result<Option<Self>> {
+        Self::read(src, Self::location(src, dst)?)
+    }
+
+    fn read(src: &Path, file: PathBuf) -> anyhow::Result<Option<Self>> {
+        let f = match std::fs::File::open(&file) {
+            Ok(f) => f,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("Failed to open manifest"),
+        };
+        let mut entries = BTreeMap::new();
+        for line in std::io::BufReader::new(f).lines() {
+            let line = line.context("Failed to read manifest")?;
+            // hash, size, mtime, path
+            let mut parts = line.splitn(4, '\t');
+            let (Some(hash), Some(size), Some(mtime), Some(path)) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                anyhow::bail!("Malformed manifest line: {line:?}");
+            };
+            entries.insert(
+                PathBuf::from(path),
+                Entry {
+                    size: size.parse()?,
+                    mtime: mtime.parse()?,
+                    hash: match hash {
+                        "-" => None,
+                        h => Some(u64::from_str_radix(h, 16)?),
+                    },
+                },
+            );
+        }
+        Ok(Some(Self {
+            src: src.to_owned(),
+            file,
+            entries,
+        }))
+    }
+
+    /// Build the manifest from the current state of src
+    pub fn build(src: &Path, dst: &Path) -> anyhow::Result<Self> {
+        let mut manifest = Self {
+            src: src.to_owned(),
+            file: Self::location(src, dst)?,
+            entries: BTreeMap::new(),
+        };
+        manifest.diff([src]);
+        Ok(manifest)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create the manifest directory")?;
+        }
+        let tmp = self.file.with_extension(std::process::id().to_string());
+        let mut f = std::io::BufWriter::new(
+            std::fs::File::create(&tmp).context("Failed to create manifest")?,
+        );
+        for (path, e) in self.entries.iter() {
+            let hash = e
+                .hash
+                .map(|h| format!("{h:016x}"))
+                .unwrap_or_else(|| "-".to_owned());
+            writeln!(f, "{hash}\t{}\t{}\t{}", e.size, e.mtime, path.display())?;
+        }
+        f.flush()?;
+        drop(f);
+        std::fs::rename(&tmp, &self.file).context("Failed to replace manifest")?;
+        Ok(())
+    }
+
+    /// Compare the given paths, and the files below them, to the manifest and update it.
+    ///
+    /// Returns the absolute paths that changed or were deleted since the manifest was saved
+    pub fn diff<'a>(&mut self, paths: impl IntoIterator<Item = &'a Path>) -> ManifestDiff {
+        let mut diff = ManifestDiff::default();
+        for path in paths {
+            let Ok(rel) = path.strip_prefix(&self.src) else {
+                continue;
+            };
+            let rel = rel.to_owned();
+            let mut current = BTreeMap::new();
+            collect(&self.src, &rel, &mut current);
+
+            let known = self
+                .entries
+                .range(rel.clone()..)
+                .take_while(|(p, _)| p.starts_with(&rel))
+                .map(|(p, _)| p.clone())
+                .collect::<Vec<_>>();
+            if current.is_empty() && !known.is_empty() && !self.entries.contains_key(&rel) {
+                // a removed directory, so it's removed from dst too
+                diff.deleted.push(path.to_owned());
+            }
+            for p in known {
+                if !current.contains_key(&p) {
+                    self.entries.remove(&p);
+                    diff.deleted.push(join(&self.src, &p));
+                }
+            }
+            for (p, mut entry) in current {
+                let abs = join(&self.src, &p);
+                let changed = match self.entries.get(&p) {
+                    None => true,
+                    Some(old) if old.size != entry.size => true,
+                    Some(old) if old.mtime == entry.mtime => {
+                        entry.hash = old.hash;
+                        false
+                    }
+                    // touched, compare the contents
+                    Some(old) => {
+                        entry.hash = crate::hash_cache::hash(&abs);
+                        old.hash.is_none() || old.hash != entry.hash
+                    }
+                };
+                if changed {
+                    diff.changed.push(abs);
+                }
+                self.entries.insert(p, entry);
+            }
+        }
+        diff.changed.sort();
+        diff.changed.dedup();
+        diff.deleted.sort();
+        diff.deleted.dedup();
+        diff
+    }
+}
+
+/// Collect the files at `rel`, relative to `src`, recursively
+fn collect(src: &Path, rel: &Path, out: &mut BTreeMap<PathBuf, Entry>) {
+    let path = join(src, rel);
+    let Ok(meta) = std::fs::symlink_metadata(&path) else {
+        return;
+    };
+    if meta.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(&path) {
+            for e in entries.flatten() {
+                collect(src, &rel.join(e.file_name()), out);
+            }
+        }
+        return;
+    }
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_nanos();
+    out.insert(
+        rel.to_owned(),
+        Entry {
+            size: meta.len(),
+            mtime,
+            hash: None,
+        },
+    );
+}
+
+/// `src.join(rel)`, without adding a trailing separator if src is a file and rel is empty
+fn join(src: &Path, rel: &Path) -> PathBuf {
+    if rel.as_os_str().is_empty() {
+        src.to_owned()
+    } else {
+        src.join(rel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a.txt"), "a").unwrap();
+        std::fs::write(src.join("sub/b.txt"), "b").unwrap();
+
+        let mut manifest = Manifest {
+            src: src.clone(),
+            file: dir.path().join("manifest"),
+            entries: BTreeMap::new(),
+        };
+        let diff = manifest.diff([src.as_path()]);
+        assert_eq!(diff.changed, [src.join("a.txt"), src.join("sub/b.txt")]);
+        assert!(manifest.diff([src.as_path()]).is_empty());
+
+        std::fs::write(src.join("sub/c.txt"), "c").unwrap();
+        std::fs::remove_file(src.join("sub/b.txt")).unwrap();
+        std::fs::remove_file(src.join("a.txt")).unwrap();
+        assert_eq!(
+            manifest.diff([src.join("a.txt").as_path()]).deleted,
+            [src.join("a.txt")]
+        );
+        let diff = manifest.diff([src.join("sub").as_path()]);
+        assert_eq!(
+            diff,
+            ManifestDiff {
+                changed: vec![src.join("sub/c.txt")],
+                deleted: vec![src.join("sub/b.txt")],
+            }
+        );
+
+        std::fs::remove_dir_all(src.join("sub")).unwrap();
+        assert_eq!(
+            manifest.diff([src.join("sub").as_path()]).deleted,
+            [src.join("sub"), src.join("sub/c.txt")]
+        );
+
+        manifest.save().unwrap();
+        let loaded = Manifest::read(&src, manifest.file.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.entries, manifest.entries);
+    }
+}
@@ -0,0 +1,271 @@
+//! CPU and IO priority of the processes of a sync, see [crate::config::FileSync::nice],
+//! [crate::config::FileSync::ionice] and [crate::config::FileSync::cpus]
+use std::str::FromStr;
+
+/// IO scheduling class and level, like `ionice -c`: `idle`, `best-effort` or `realtime`,
+/// optionally followed by a level from 0 (highest) to 7, e.g. `best-effort:7`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoNice {
+    pub class: IoClass,
+    pub level: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl FromStr for IoNice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (s, None),
+        };
+        let class = match class {
+            "realtime" => IoClass::Realtime,
+            "best-effort" | "best_effort" => IoClass::BestEffort,
+            "idle" => IoClass::Idle,
+            _ => anyhow::bail!(
+                "Invalid ionice class {class:?}, expected idle, best-effort or realtime"
+            ),
+        };
+        let level = level
+            .map(|l| {
+                l.parse::<u8>()
+                    .ok()
+                    .filter(|l| *l <= 7)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid ionice level {l:?}, expected 0-7"))
+            })
+            .transpose()?;
+        anyhow::ensure!(
+            class != IoClass::Idle || level.is_none(),
+            "The idle ionice class has no levels"
+        );
+        Ok(IoNice { class, level })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for IoNice {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// CPUs the processes may run on, like `taskset -c`: a comma separated list of CPU numbers and
+/// ranges, e.g. `0-3,6`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet(pub Vec<usize>);
+
+impl FromStr for CpuSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cpus = Vec::new();
+        for part in s.split(',').map(str::trim) {
+            let parse = |n: &str| {
+                n.trim()
+                    .parse::<usize>()
+                    .map_err(|_| anyhow::anyhow!("Invalid CPU {n:?} in {s:?}"))
+            };
+            match part.split_once('-') {
+                Some((from, to)) => {
+                    let (from, to) = (parse(from)?, parse(to)?);
+                    anyhow::ensure!(from <= to, "Invalid CPU range {part:?}");
+                    cpus.extend(from..=to);
+                }
+                None => cpus.push(parse(part)?),
+            }
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuSet(cpus))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for CpuSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // a single CPU may be written as a number
+        let s = match serde_yaml::Value::deserialize(deserializer)? {
+            serde_yaml::Value::Number(n) => n.to_string(),
+            serde_yaml::Value::String(s) => s,
+            other => {
+                return Err(serde::de::Error::custom(format!(
+                    "Invalid cpus {other:?}, expected e.g. \"0-3,6\""
+                )))
+            }
+        };
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// The priority settings of a sync
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Niceness {
+    pub nice: Option<i32>,
+    pub ionice: Option<IoNice>,
+    pub cpus: Option<CpuSet>,
+}
+
+impl Niceness {
+    pub fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Apply to the calling process, inherited by the processes it starts from then on
+    pub fn apply(&self) -> std::io::Result<()> {
+        self.prepare().apply()
+    }
+
+    /// Apply to the process started by `cmd`. Failures are ignored, the process runs with the
+    /// priority of atune then
+    pub fn apply_to(&self, cmd: &mut std::process::Command) {
+        if self.is_default() {
+            return;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt as _;
+            let prepared = self.prepare();
+            // SAFETY: only makes syscalls, no allocations
+            unsafe {
+                cmd.pre_exec(move || {
+                    let _ = prepared.apply();
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = cmd;
+    }
+
+    fn prepare(&self) -> Prepared {
+        Prepared {
+            nice: self.nice,
+            #[cfg(target_os = "linux")]
+            ioprio: self.ionice.map(|io| {
+                let (class, default_level) = match io.class {
+                    IoClass::Realtime => (1, 4),
+                    IoClass::BestEffort => (2, 4),
+                    IoClass::Idle => (3, 0),
+                };
+                (class << 13) | libc::c_int::from(io.level.unwrap_or(default_level))
+            }),
+            #[cfg(target_os = "linux")]
+            cpus: self.cpus.as_ref().map(|cpus| {
+                // SAFETY: cpu_set_t is a plain bit set, all zeroes is the empty set
+                let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+                for cpu in cpus.0.iter().copied() {
+                    // SAFETY: CPU_SET ignores CPUs beyond the size of the set
+                    unsafe { libc::CPU_SET(cpu, &mut set) };
+                }
+                set
+            }),
+        }
+    }
+}
+
+/// [Niceness] converted ahead of the fork, so applying it in the child doesn't allocate
+#[derive(Clone, Copy)]
+struct Prepared {
+    nice: Option<i32>,
+    #[cfg(target_os = "linux")]
+    ioprio: Option<libc::c_int>,
+    #[cfg(target_os = "linux")]
+    cpus: Option<libc::cpu_set_t>,
+}
+
+impl Prepared {
+    /// `ionice` and `cpus` are only supported on Linux and ignored elsewhere
+    fn apply(&self) -> std::io::Result<()> {
+        let check = |ret: libc::c_int| {
+            if ret == -1 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+        #[cfg(unix)]
+        if let Some(nice) = self.nice {
+            // SAFETY: changes the priority of the calling process only
+            check(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(ioprio) = self.ioprio {
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            // SAFETY: changes the priority of the calling process only
+            let ret = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+            check(ret as libc::c_int)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(cpus) = self.cpus.as_ref() {
+            // SAFETY: the set outlives the call
+            check(unsafe {
+                libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), cpus)
+            })?;
+        }
+        let _ = check;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "best-effort:7".parse::<IoNice>().unwrap(),
+            IoNice {
+                class: IoClass::BestEffort,
+                level: Some(7)
+            }
+        );
+        assert_eq!(
+            "idle".parse::<IoNice>().unwrap(),
+            IoNice {
+                class: IoClass::Idle,
+                level: None
+            }
+        );
+        assert!("idle:3".parse::<IoNice>().is_err());
+        assert!("best-effort:8".parse::<IoNice>().is_err());
+        assert!("low".parse::<IoNice>().is_err());
+
+        assert_eq!("0-3,6,2".parse::<CpuSet>().unwrap().0, [0, 1, 2, 3, 6]);
+        assert!("3-1".parse::<CpuSet>().is_err());
+        assert!("a".parse::<CpuSet>().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_apply_to() {
+        let niceness = Niceness {
+            nice: Some(19),
+            ionice: Some("idle".parse().unwrap()),
+            cpus: Some(CpuSet(vec![0])),
+        };
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c")
+            .arg("cut -d' ' -f19 /proc/self/stat; grep Cpus_allowed_list /proc/self/status");
+        niceness.apply_to(&mut cmd);
+        let out = String::from_utf8(cmd.output().unwrap().stdout).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(lines.next(), Some("19"));
+        assert_eq!(
+            lines.next().map(|l| l.split_whitespace().last()),
+            Some(Some("0"))
+        );
+    }
+}
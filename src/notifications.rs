@@ -0,0 +1,158 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::Context;
+use tracing::{debug, warn};
+
+use crate::config::{NotificationConfig, NotificationEvent, NotificationKind};
+
+/// Webhooks that don't answer within this are given up on, so a sync isn't held up for long
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sync event sent to the configured webhooks
+#[derive(Debug)]
+pub struct SyncNotification<'a> {
+    pub event: NotificationEvent,
+    pub project: &'a str,
+    pub src: &'a Path,
+    pub dst: Option<&'a Path>,
+    pub duration: Option<Duration>,
+    pub error: Option<String>,
+    /// Paths changed and deleted since the last sync, empty for a full sync
+    pub changed: &'a BTreeSet<PathBuf>,
+    pub deleted: &'a BTreeSet<PathBuf>,
+}
+
+/// The body of [NotificationKind::Generic]
+#[derive(serde_derive::Serialize)]
+struct GenericPayload<'a> {
+    event: &'static str,
+    project: &'a str,
+    src: Cow<'a, str>,
+    dst: Option<Cow<'a, str>>,
+    duration_ms: Option<u128>,
+    error: Option<&'a str>,
+    changed: Vec<Cow<'a, str>>,
+    deleted: Vec<Cow<'a, str>>,
+}
+
+impl<'a> SyncNotification<'a> {
+    fn summary(&self) -> String {
+        let mut s = format!(
+            "atune [{}] sync {}: {}",
+            self.project,
+            self.event.as_str(),
+            self.src.display()
+        );
+        if let Some(dst) = self.dst {
+            let _ = write!(s, " -> {}", dst.display());
+        }
+        if let Some(d) = self.duration {
+            let _ = write!(s, " ({d:.2?})");
+        }
+        if !self.changed.is_empty() || !self.deleted.is_empty() {
+            let _ = write!(
+                s,
+                "\n{} changed, {} deleted",
+                self.changed.len(),
+                self.deleted.len()
+            );
+        }
+        if let Some(err) = self.error.as_deref() {
+            let _ = write!(s, "\n{err}");
+        }
+        s
+    }
+
+    /// Build the request body for the given webhook flavour
+    pub fn payload(&'a self, kind: NotificationKind) -> String {
+        let paths = |paths: &'a BTreeSet<PathBuf>| {
+            paths
+                .iter()
+                .map(|p| p.to_string_lossy())
+                .collect::<Vec<_>>()
+        };
+        match kind {
+            NotificationKind::Slack => serde_json::json!({ "text": self.summary() }).to_string(),
+            NotificationKind::Discord => {
+                serde_json::json!({ "content": self.summary() }).to_string()
+            }
+            NotificationKind::Generic => serde_json::to_string(&GenericPayload {
+                event: self.event.as_str(),
+                project: self.project,
+                src: self.src.to_string_lossy(),
+                dst: self.dst.map(Path::to_string_lossy),
+                duration_ms: self.duration.map(|d| d.as_millis()),
+                error: self.error.as_deref(),
+                changed: paths(self.changed),
+                deleted: paths(self.deleted),
+            })
+            .expect("Strings always serialize"),
+        }
+    }
+}
+
+/// POST the notification to every webhook subscribed to its event.
+///
+/// Failures are logged, but never fail the sync itself.
+pub fn notify(hooks: &[NotificationConfig], notification: &SyncNotification) {
+    for hook in hooks.iter().filter(|h| h.on.contains(&notification.event)) {
+        if let Err(err) = post(hook, notification) {
+            warn!(?err, url = hook.url, "Failed to send notification");
+        }
+    }
+}
+
+fn post(hook: &NotificationConfig, notification: &SyncNotification) -> anyhow::Result<()> {
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+    let agent = AGENT.get_or_init(|| {
+        ureq::Agent::config_builder()
+            .timeout_global(Some(TIMEOUT))
+            .build()
+            .into()
+    });
+    let body = notification.payload(hook.kind);
+    debug!(url = hook.url, body, "Sending notification");
+    // statuses other than 2xx are errors
+    agent
+        .post(hook.url.as_str())
+        .header("Content-Type", "application/json")
+        .send(body.as_str())
+        .context("Failed to POST the notification")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generic_payload() {
+        let changed = BTreeSet::from(["/tmp/a\"b/x.py".into(), "/tmp/a\"b/y.py".into()]);
+        let n = SyncNotification {
+            event: NotificationEvent::Failure,
+            project: "foo",
+            src: Path::new("/tmp/a\"b"),
+            dst: None,
+            duration: Some(Duration::from_millis(1500)),
+            error: Some("line1\nline2".to_owned()),
+            changed: &changed,
+            deleted: &BTreeSet::new(),
+        };
+
+        assert_eq!(
+            n.payload(NotificationKind::Generic),
+            r#"{"event":"failure","project":"foo","src":"/tmp/a\"b","dst":null,"duration_ms":1500,"error":"line1\nline2","changed":["/tmp/a\"b/x.py","/tmp/a\"b/y.py"],"deleted":[]}"#
+        );
+        assert_eq!(
+            n.payload(NotificationKind::Slack),
+            r#"{"text":"atune [foo] sync failure: /tmp/a\"b (1.50s)\n2 changed, 0 deleted\nline1\nline2"}"#
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Changes of sync entries whose dst was unreachable, kept next to the state file until `watch`
+//! replays them, so they survive a restart
+use std::{
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{state::Fnv, sync::SyncChanges};
+
+/// Where the queued changes of the entry of `project` are stored
+fn location(config: &Path, project: &str, src: &Path) -> Option<PathBuf> {
+    let config = crate::platform::canonicalize(config).unwrap_or_else(|_| config.to_owned());
+    let mut hash = Fnv::default();
+    hash.write(config.as_os_str().as_encoded_bytes());
+    hash.write(&[0]);
+    hash.write(project.as_bytes());
+    hash.write(&[0]);
+    hash.write(src.as_os_str().as_encoded_bytes());
+    let state = crate::state::state_path()?;
+    Some(
+        state
+            .with_file_name("offline")
+            .join(format!("{:016x}", hash.0)),
+    )
+}
+
+/// Replace the queued changes of the entry
+pub fn save(config: &Path, project: &str, src: &Path, changes: &SyncChanges) -> anyhow::Result<()> {
+    let file = location(config, project, src).context("Failed to determine the state directory")?;
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir).context("Failed to create the offline queue directory")?;
+    }
+    let tmp = file.with_extension(std::process::id().to_string());
+    let mut f = std::io::BufWriter::new(
+        std::fs::File::create(&tmp).context("Failed to create the offline queue")?,
+    );
+    f.write_all(format(changes).as_bytes())?;
+    f.flush()?;
+    drop(f);
+    std::fs::rename(&tmp, &file).context("Failed to replace the offline queue")?;
+    Ok(())
+}
+
+/// The queued changes of the entry, if any
+pub fn load(config: &Path, project: &str, src: &Path) -> Option<SyncChanges> {
+    let content = std::fs::read_to_string(location(config, project, src)?).ok()?;
+    Some(parse(&content))
+}
+
+/// Forget the queued changes of the entry, once they were synced
+pub fn remove(config: &Path, project: &str, src: &Path) {
+    if let Some(file) = location(config, project, src) {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+/// One path per line, prefixed by the kind of the change: `C` changed, `D` deleted, `R` renamed
+/// followed by the old and the new path separated by a tab
+fn format(changes: &SyncChanges) -> String {
+    let mut s = String::new();
+    for p in changes.changed.iter() {
+        s.push_str(&format!("C\t{}\n", p.display()));
+    }
+    for p in changes.deleted.iter() {
+        s.push_str(&format!("D\t{}\n", p.display()));
+    }
+    for (from, to) in changes.renamed.iter() {
+        s.push_str(&format!("R\t{}\t{}\n", from.display(), to.display()));
+    }
+    s
+}
+
+fn parse(content: &str) -> SyncChanges {
+    let mut changes = SyncChanges::default();
+    for line in content.lines() {
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            ["C", path] => {
+                changes.changed.insert(path.into());
+            }
+            ["D", path] => {
+                changes.deleted.insert(path.into());
+            }
+            ["R", from, to] => {
+                changes.renamed.insert(from.into(), to.into());
+            }
+            _ => {}
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_roundtrip() {
+        let mut changes = SyncChanges::default();
+        changes.changed.insert("/src/a.txt".into());
+        changes.changed.insert("/src/new name.txt".into());
+        changes.deleted.insert("/src/old name.txt".into());
+        changes
+            .renamed
+            .insert("/src/old name.txt".into(), "/src/new name.txt".into());
+        assert_eq!(parse(&format(&changes)), changes);
+    }
+}
@@ -0,0 +1,244 @@
+//! Export of the spans and the sync metrics to an OpenTelemetry collector over OTLP/HTTP.
+//! Configured by the standard environment variables: `OTEL_EXPORTER_OTLP_ENDPOINT`, or per
+//! signal `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` and `OTEL_EXPORTER_OTLP_METRICS_ENDPOINT`, plus
+//! `OTEL_EXPORTER_OTLP_HEADERS` and `OTEL_SERVICE_NAME`.
+//!
+//! A sync `watch` runs in a new process continues the trace of its change, passed in the
+//! `TRACEPARENT` environment variable
+use std::{collections::HashMap, path::Path, sync::OnceLock, time::Duration};
+
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram},
+    propagation::TextMapPropagator as _,
+    trace::TracerProvider as _,
+    ContextGuard, KeyValue,
+};
+use opentelemetry_otlp::{MetricExporter, SpanExporter};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    propagation::TraceContextPropagator,
+    trace::SdkTracerProvider,
+    Resource,
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+/// W3C trace context of the span a sync process continues
+pub const TRACEPARENT_ENV: &str = "TRACEPARENT";
+/// The metrics are sent this often
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+static PROVIDERS: OnceLock<Providers> = OnceLock::new();
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+#[derive(Debug)]
+struct Providers {
+    traces: Option<SdkTracerProvider>,
+    metrics: Option<SdkMeterProvider>,
+}
+
+/// Counters of the syncs of this process
+#[derive(Debug)]
+struct Instruments {
+    syncs: Counter<u64>,
+    failures: Counter<u64>,
+    transferred: Counter<u64>,
+    latency: Histogram<f64>,
+}
+
+/// Flushes and shuts down the exporters when dropped
+pub struct ExportGuard {
+    /// the span of `watch` continued by this process, from [TRACEPARENT_ENV]
+    _remote_parent: Option<ContextGuard>,
+}
+
+impl Drop for ExportGuard {
+    fn drop(&mut self) {
+        let Some(providers) = PROVIDERS.get() else {
+            return;
+        };
+        if let Some(traces) = providers.traces.as_ref() {
+            let _ = traces.shutdown();
+        }
+        if let Some(metrics) = providers.metrics.as_ref() {
+            let _ = metrics.shutdown();
+        }
+    }
+}
+
+/// The layer exporting the spans, if an endpoint is configured.
+///
+/// The guard must be kept on the thread the trace of [TRACEPARENT_ENV] is continued in
+pub fn layer_from_env<S>() -> Option<(impl Layer<S>, ExportGuard)>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    let var = |name| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let endpoint = var("OTEL_EXPORTER_OTLP_ENDPOINT").is_some();
+    let traces = endpoint || var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_some();
+    let metrics = endpoint || var("OTEL_EXPORTER_OTLP_METRICS_ENDPOINT").is_some();
+    if !traces && !metrics {
+        return None;
+    }
+    let mut resource = Resource::builder();
+    if var("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name("atune");
+    }
+    let resource = resource.build();
+    let providers = Providers {
+        traces: traces
+            .then(|| match SpanExporter::builder().with_http().build() {
+                Ok(exporter) => Some(
+                    SdkTracerProvider::builder()
+                        .with_batch_exporter(exporter)
+                        .with_resource(resource.clone())
+                        .build(),
+                ),
+                Err(err) => {
+                    tracing::warn!(?err, "Failed to set up the export of the traces");
+                    None
+                }
+            })
+            .flatten(),
+        metrics: metrics
+            .then(|| match MetricExporter::builder().with_http().build() {
+                Ok(exporter) => Some(
+                    SdkMeterProvider::builder()
+                        .with_reader(
+                            PeriodicReader::builder(exporter)
+                                .with_interval(EXPORT_INTERVAL)
+                                .build(),
+                        )
+                        .with_resource(resource)
+                        .build(),
+                ),
+                Err(err) => {
+                    tracing::warn!(?err, "Failed to set up the export of the metrics");
+                    None
+                }
+            })
+            .flatten(),
+    };
+    if let Some(metrics) = providers.metrics.clone() {
+        global::set_meter_provider(metrics);
+    }
+    let tracer = providers
+        .traces
+        .as_ref()
+        .map(|p| p.tracer(env!("CARGO_PKG_NAME")));
+    if PROVIDERS.set(providers).is_err() {
+        return None;
+    }
+    let layer = tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+    let remote_parent = var(TRACEPARENT_ENV).map(|t| remote_context(&t).attach());
+    Some((
+        layer,
+        ExportGuard {
+            _remote_parent: remote_parent,
+        },
+    ))
+}
+
+/// Send the pending spans and the metrics
+pub fn flush() {
+    let Some(providers) = PROVIDERS.get() else {
+        return;
+    };
+    if let Some(traces) = providers.traces.as_ref() {
+        let _ = traces.force_flush();
+    }
+    if let Some(metrics) = providers.metrics.as_ref() {
+        let _ = metrics.force_flush();
+    }
+}
+
+/// Count a finished sync in the exported metrics
+pub fn record_sync(
+    project: &str,
+    src: &Path,
+    success: bool,
+    bytes: u64,
+    latency: Option<Duration>,
+) {
+    if PROVIDERS.get().is_none_or(|p| p.metrics.is_none()) {
+        return;
+    }
+    let instruments = INSTRUMENTS.get_or_init(|| {
+        let meter = global::meter(env!("CARGO_PKG_NAME"));
+        Instruments {
+            syncs: meter.u64_counter("atune.syncs").with_unit("{sync}").build(),
+            failures: meter
+                .u64_counter("atune.sync.failures")
+                .with_unit("{sync}")
+                .build(),
+            transferred: meter
+                .u64_counter("atune.sync.transferred")
+                .with_unit("By")
+                .build(),
+            latency: meter
+                .f64_histogram("atune.sync.latency")
+                .with_unit("ms")
+                .build(),
+        }
+    });
+    let attributes = [
+        KeyValue::new("project", project.to_owned()),
+        KeyValue::new("src", src.display().to_string()),
+    ];
+    instruments.syncs.add(1, &attributes);
+    if !success {
+        instruments.failures.add(1, &attributes);
+    }
+    instruments.transferred.add(bytes, &attributes);
+    if let Some(latency) = latency {
+        instruments
+            .latency
+            .record(latency.as_secs_f64() * 1000.0, &attributes);
+    }
+}
+
+/// The W3C trace context of `span`, for [TRACEPARENT_ENV] of the processes it starts
+pub fn traceparent(span: &tracing::Span) -> Option<String> {
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+    carrier.remove("traceparent")
+}
+
+/// The context of the span of a [traceparent]
+fn remote_context(traceparent: &str) -> opentelemetry::Context {
+    let carrier = HashMap::from([("traceparent".to_owned(), traceparent.trim().to_owned())]);
+    TraceContextPropagator::new().extract(&carrier)
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt as _;
+
+    use super::*;
+
+    #[test]
+    fn test_traceparent() {
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let parent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+            let span = tracing::info_span!("sync");
+            let _ = span.set_parent(remote_context(parent));
+            let sync = traceparent(&span).unwrap();
+            // same trace, new span
+            assert!(sync.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+            assert_ne!(sync, parent);
+
+            let child = tracing::info_span!(parent: &span, "hook");
+            let child = traceparent(&child).unwrap();
+            assert_eq!(child[..36], sync[..36]);
+            assert_ne!(child, sync);
+        });
+        assert_eq!(traceparent(&tracing::Span::none()), None);
+        assert!(!opentelemetry::trace::TraceContextExt::has_active_span(
+            &remote_context("00-00-00-01")
+        ));
+    }
+}
@@ -0,0 +1,429 @@
+//! Capture of the output of sync processes, so concurrent syncs don't interleave unreadably,
+//! and the progress display of the running syncs
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{BufRead as _, BufReader, Read, Write},
+    path::Path,
+    process::Child,
+    sync::{Arc, Mutex},
+};
+
+use crossbeam::channel;
+use tracing::warn;
+
+use crate::WatchEvent;
+
+/// Number of lines kept by [OutputTail]
+pub const TAIL_LINES: usize = 20;
+
+/// The last lines of the output of a process
+#[derive(Debug, Clone, Default)]
+pub struct OutputTail(Arc<Mutex<VecDeque<String>>>);
+
+impl OutputTail {
+    pub(crate) fn push(&self, line: String) {
+        let mut lines = self.0.lock().unwrap();
+        if lines.len() == TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Where captured lines go
+#[derive(Clone)]
+enum Sink {
+    /// stdout or stderr of atune, prefixed with `[label]`
+    Forward(Arc<str>),
+    /// stdout or stderr of atune as they are, e.g. in a `sync-project` process whose output is
+    /// captured by `watch`
+    Raw,
+    File(Arc<Mutex<File>>),
+}
+
+/// Progress of a transfer, parsed from the output of rsync `--info=progress2`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    /// bytes transferred so far, as printed by rsync, e.g. `1.23M` with `-h`
+    pub transferred: String,
+    pub percent: u8,
+    /// e.g. `12.34MB/s`
+    pub rate: String,
+    /// estimated time remaining, e.g. `0:00:10`
+    pub eta: String,
+}
+
+impl Progress {
+    /// Parse a progress line, e.g. `  1,234,567  45%  12.34MB/s    0:00:10 (xfr#3, to-chk=5/10)`
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.split_whitespace();
+        let transferred = words.next()?;
+        let percent = words.next()?.strip_suffix('%')?.parse().ok()?;
+        let rate = words.next()?;
+        let eta = words.next()?;
+        if !transferred.starts_with(|c: char| c.is_ascii_digit())
+            || !rate.ends_with("/s")
+            || !eta.contains(':')
+        {
+            return None;
+        }
+        Some(Self {
+            transferred: transferred.to_owned(),
+            percent,
+            rate: rate.to_owned(),
+            eta: eta.to_owned(),
+        })
+    }
+}
+
+impl std::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>3}% {:>10} {:>12} eta {}",
+            self.percent, self.transferred, self.rate, self.eta
+        )
+    }
+}
+
+/// Forward the output of `child`, which must be spawned with piped stdout and stderr, line by
+/// line until it exits.
+///
+/// Lines are prefixed with `[label]` and written to the stdout or stderr of atune, or appended
+/// to `log_file` if given. If `on_progress` is given, then the progress lines of rsync are
+/// passed to it instead
+pub fn capture(
+    child: &mut Child,
+    label: &str,
+    log_file: Option<&Path>,
+    on_progress: Option<Box<dyn Fn(Progress) + Send>>,
+) -> OutputTail {
+    let tail = OutputTail::default();
+    capture_into(child, label, log_file, on_progress, &tail);
+    tail
+}
+
+/// [capture], keeping the last lines in `tail`, e.g. shared by the processes of a sync
+pub fn capture_into(
+    child: &mut Child,
+    label: &str,
+    log_file: Option<&Path>,
+    on_progress: Option<Box<dyn Fn(Progress) + Send>>,
+    tail: &OutputTail,
+) {
+    let sink = match log_file.and_then(open_log) {
+        Some(f) => Sink::File(f),
+        None => Sink::Forward(label.into()),
+    };
+    let tails = vec![tail.clone()];
+    if let Some(stdout) = child.stdout.take() {
+        forward(
+            stdout,
+            false,
+            vec![sink.clone()],
+            tails.clone(),
+            on_progress,
+        );
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward(stderr, true, vec![sink], tails, None);
+    }
+}
+
+/// Forward the output of `child` like [capture_into], or as it is without a label, and also
+/// append it to `copy` if given, keeping its last lines in `copy_tail`. E.g. for the log of a
+/// single command.
+///
+/// Returns the threads forwarding the output, which finish once the output is closed
+pub fn capture_copy(
+    child: &mut Child,
+    label: Option<&str>,
+    log_file: Option<&Path>,
+    tail: &OutputTail,
+    copy: Option<&Path>,
+    copy_tail: &OutputTail,
+) -> Vec<std::thread::JoinHandle<()>> {
+    let mut sinks = vec![match (log_file.and_then(open_log), label) {
+        (Some(f), _) => Sink::File(f),
+        (None, Some(label)) => Sink::Forward(label.into()),
+        (None, None) => Sink::Raw,
+    }];
+    sinks.extend(copy.and_then(open_log).map(Sink::File));
+    let tails = vec![tail.clone(), copy_tail.clone()];
+    let mut threads = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        threads.push(forward(stdout, false, sinks.clone(), tails.clone(), None));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        threads.push(forward(stderr, true, sinks, tails, None));
+    }
+    threads
+}
+
+/// Wait for the threads of [capture_copy] to forward the rest of the output, up to a second in
+/// case a background process of the command keeps it open
+pub fn join_capture(threads: Vec<std::thread::JoinHandle<()>>) {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+    while threads.iter().any(|t| !t.is_finished()) && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+fn open_log(path: &Path) -> Option<Arc<Mutex<File>>> {
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .inspect_err(|err| warn!(?err, ?path, "Failed to open the log file"))
+        .ok()
+        .map(|f| Arc::new(Mutex::new(f)))
+}
+
+fn forward(
+    stream: impl Read + Send + 'static,
+    stderr: bool,
+    sinks: Vec<Sink>,
+    tails: Vec<OutputTail>,
+    on_progress: Option<Box<dyn Fn(Progress) + Send>>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let chunk = String::from_utf8_lossy(&buf);
+            // progress updates are separated by carriage returns
+            for line in chunk.trim_end_matches(['\r', '\n']).split('\r') {
+                if let Some(on_progress) = on_progress.as_ref() {
+                    if let Some(progress) = Progress::parse(line) {
+                        on_progress(progress);
+                        continue;
+                    }
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+                for sink in sinks.iter() {
+                    match sink {
+                        Sink::Forward(label) => {
+                            write_above_status(stderr, format!("[{label}] {line}\n").as_bytes())
+                        }
+                        Sink::Raw => write_above_status(stderr, format!("{line}\n").as_bytes()),
+                        Sink::File(f) => {
+                            let _ = writeln!(f.lock().unwrap(), "{line}");
+                        }
+                    }
+                }
+                for tail in tails.iter() {
+                    tail.push(line.to_owned());
+                }
+            }
+        }
+    })
+}
+
+/// Lines drawn below the other output of the terminal, one per running sync
+#[derive(Debug)]
+struct StatusArea {
+    enabled: bool,
+    lines: BTreeMap<String, String>,
+    /// number of lines currently drawn
+    drawn: usize,
+    /// receives the output instead of the terminal, see [divert]
+    divert: Option<channel::Sender<String>>,
+}
+
+static STATUS: Mutex<StatusArea> = Mutex::new(StatusArea {
+    enabled: false,
+    lines: BTreeMap::new(),
+    drawn: 0,
+    divert: None,
+});
+
+impl StatusArea {
+    fn clear(&mut self, out: &mut impl Write) {
+        for _ in 0..self.drawn {
+            // up one line and erase it
+            let _ = out.write_all(b"\x1b[1A\x1b[2K");
+        }
+        self.drawn = 0;
+    }
+
+    fn draw(&mut self, out: &mut impl Write) {
+        for line in self.lines.values() {
+            // don't wrap, that would break the count of the drawn lines
+            let line = line.chars().take(100).collect::<String>();
+            let _ = writeln!(out, "\x1b[2K{line}");
+        }
+        self.drawn = self.lines.len();
+        let _ = out.flush();
+    }
+}
+
+/// Send the lines written by [write_above_status] to `lines` instead of the terminal, e.g.
+/// while a full screen UI owns it. `None` restores the output
+pub fn divert(lines: Option<channel::Sender<String>>) {
+    STATUS.lock().unwrap().divert = lines;
+}
+
+/// Write `bytes` to stdout, or stderr, above the status area, copying them to the log file
+pub fn write_above_status(stderr: bool, bytes: &[u8]) {
+    crate::log_file::write(bytes);
+    if crate::log_file::is_detached() {
+        return;
+    }
+    let mut status = STATUS.lock().unwrap();
+    if let Some(divert) = status.divert.as_ref() {
+        for line in String::from_utf8_lossy(bytes).lines() {
+            let _ = divert.send(line.to_owned());
+        }
+        return;
+    }
+    let mut stdout = std::io::stdout().lock();
+    if !status.enabled {
+        drop(status);
+        let _ = if stderr {
+            std::io::stderr().write_all(bytes)
+        } else {
+            stdout.write_all(bytes)
+        };
+        return;
+    }
+    status.clear(&mut stdout);
+    let _ = stdout.flush();
+    let _ = if stderr {
+        std::io::stderr().write_all(bytes)
+    } else {
+        stdout.write_all(bytes)
+    };
+    status.draw(&mut stdout);
+}
+
+/// Writer for logs that keeps them above the status area, e.g. for `tracing_subscriber`.
+/// The output is written once the writer is dropped
+#[derive(Debug, Default)]
+pub struct StatusAwareStdout(Vec<u8>);
+
+impl Write for StatusAwareStdout {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for StatusAwareStdout {
+    fn drop(&mut self) {
+        write_above_status(false, &self.0);
+    }
+}
+
+/// Draw the progress of the running syncs at the bottom of the terminal, one line per sync.
+/// Returns once the events are disconnected
+pub fn show_progress(events: channel::Receiver<WatchEvent>) {
+    STATUS.lock().unwrap().enabled = true;
+    for event in events {
+        let (key, line) = match event {
+            WatchEvent::SyncStarted { project, src, .. } => {
+                let label = crate::sync::sync_label(&project, &src);
+                (label.clone(), Some(format!("{label:<24} starting")))
+            }
+            WatchEvent::SyncProgress {
+                project,
+                src,
+                progress,
+            } => {
+                let label = crate::sync::sync_label(&project, &src);
+                (label.clone(), Some(format!("{label:<24} {progress}")))
+            }
+            WatchEvent::SyncFinished { project, src, .. }
+            | WatchEvent::SyncCancelled { project, src } => {
+                (crate::sync::sync_label(&project, &src), None)
+            }
+            _ => continue,
+        };
+        let mut status = STATUS.lock().unwrap();
+        let mut stdout = std::io::stdout().lock();
+        status.clear(&mut stdout);
+        match line {
+            Some(line) => status.lines.insert(key, line),
+            None => status.lines.remove(&key),
+        };
+        status.draw(&mut stdout);
+    }
+    let mut status = STATUS.lock().unwrap();
+    status.clear(&mut std::io::stdout());
+    status.enabled = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_progress() {
+        assert_eq!(
+            Progress::parse("      1,234,567  45%   12.34MB/s    0:00:10 (xfr#3, to-chk=5/10)"),
+            Some(Progress {
+                transferred: "1,234,567".to_owned(),
+                percent: 45,
+                rate: "12.34MB/s".to_owned(),
+                eta: "0:00:10".to_owned(),
+            })
+        );
+        assert_eq!(
+            Progress::parse("          1.23G 100%  105.20MB/s    0:00:11").map(|p| p.percent),
+            Some(100)
+        );
+        assert_eq!(Progress::parse("sending incremental file list"), None);
+        assert_eq!(Progress::parse("Number of files: 3 (reg: 2, dir: 1)"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_capture_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("logs/web-src.log");
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("for i in $(seq 1 30); do echo $i; done; echo oops >&2")
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let tail = capture(&mut child, "web:src", Some(&log), None);
+        child.wait().unwrap();
+        // the readers finish once the pipes are closed. stdout and stderr are read concurrently,
+        // so the line of stderr may have left the tail already, it is in the log though
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let content = loop {
+            let content = std::fs::read_to_string(&log).unwrap_or_default();
+            if content.contains("oops\n") && tail.lines().contains(&"30".to_owned()) {
+                break content;
+            }
+            assert!(std::time::Instant::now() < deadline, "{content:?}");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        let stdout = content.lines().filter(|l| *l != "oops").collect::<Vec<_>>();
+        assert_eq!(stdout, (1..=30).map(|i| i.to_string()).collect::<Vec<_>>());
+        let lines = tail.lines();
+        assert_eq!(lines.len(), TAIL_LINES);
+        assert!(lines.contains(&"30".to_owned()));
+        assert!(!lines.contains(&"1".to_owned()));
+    }
+}
@@ -0,0 +1,400 @@
+//! Permissions and ownership of the synced files, see [crate::config::FileSync::chmod] and
+//! [crate::config::FileSync::chown], and their fixups after the transfer, see
+//! [crate::config::FileSync::dst_owner] and [crate::config::FileSync::dst_mode]
+use std::{path::Path, str::FromStr};
+
+use anyhow::Context as _;
+
+/// Rules changing the permissions of the synced files, in the syntax of rsync's `--chmod`: a comma
+/// separated list of octal modes or symbolic `[ugoa]*[-+=][rwxXst]*` rules, each optionally
+/// prefixed with `D` to apply to directories only or `F` to files only, e.g. `D755,F644` or
+/// `Dg+s,ug+w,Fo-w`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chmod {
+    spec: String,
+    rules: Vec<ChmodRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChmodRule {
+    /// Some(true) for directories only, Some(false) for files only
+    dirs: Option<bool>,
+    change: ModeChange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ModeChange {
+    Octal(u32),
+    Symbolic {
+        /// bits of the `ugo` classes the rule applies to
+        who: u32,
+        ops: Vec<(char, String)>,
+    },
+}
+
+impl FromStr for Chmod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|rule| {
+                let (dirs, rest) = match rule.strip_prefix('D') {
+                    Some(rest) => (Some(true), rest),
+                    None => match rule.strip_prefix('F') {
+                        Some(rest) => (Some(false), rest),
+                        None => (None, rule),
+                    },
+                };
+                let change = parse_change(rest)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid chmod rule {rule:?}"))?;
+                Ok(ChmodRule { dirs, change })
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(|rules| Chmod {
+                spec: s.to_owned(),
+                rules,
+            })
+    }
+}
+
+impl std::fmt::Display for Chmod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+fn parse_change(s: &str) -> Option<ModeChange> {
+    if !s.is_empty() && s.chars().all(|c| c.is_digit(8)) {
+        let mode = u32::from_str_radix(s, 8).ok()?;
+        return (mode <= 0o7777).then_some(ModeChange::Octal(mode));
+    }
+    let op_start = s.find(['+', '-', '='])?;
+    let mut who = 0;
+    for c in s[..op_start].chars() {
+        who |= match c {
+            'u' => 0o4700,
+            'g' => 0o2070,
+            'o' => 0o1007,
+            'a' => 0o7777,
+            _ => return None,
+        };
+    }
+    if who == 0 {
+        who = 0o7777;
+    }
+    let mut ops = Vec::new();
+    let mut rest = &s[op_start..];
+    while let Some(op) = rest.chars().next() {
+        if !matches!(op, '+' | '-' | '=') {
+            return None;
+        }
+        let end = rest[1..]
+            .find(['+', '-', '='])
+            .map_or(rest.len(), |i| i + 1);
+        let perms = &rest[1..end];
+        if !perms.chars().all(|c| "rwxXst".contains(c)) {
+            return None;
+        }
+        ops.push((op, perms.to_owned()));
+        rest = &rest[end..];
+    }
+    Some(ModeChange::Symbolic { who, ops })
+}
+
+impl Chmod {
+    /// The mode of an entry whose source has the permissions `mode`
+    pub fn apply(&self, mut mode: u32, is_dir: bool) -> u32 {
+        for rule in self.rules.iter() {
+            if rule.dirs.is_some_and(|dirs| dirs != is_dir) {
+                continue;
+            }
+            match &rule.change {
+                ModeChange::Octal(m) => mode = (mode & !0o7777) | m,
+                ModeChange::Symbolic { who, ops } => {
+                    for (op, perms) in ops {
+                        let mut bits = 0;
+                        for c in perms.chars() {
+                            bits |= match c {
+                                'r' => 0o444,
+                                'w' => 0o222,
+                                'x' => 0o111,
+                                // execute if a directory or executable by anyone
+                                'X' if is_dir || mode & 0o111 != 0 => 0o111,
+                                's' => 0o6000,
+                                't' => 0o1000,
+                                _ => 0,
+                            };
+                        }
+                        let bits = bits & who;
+                        match op {
+                            '+' => mode |= bits,
+                            '-' => mode &= !bits,
+                            _ => mode = (mode & !(who & 0o7777)) | bits,
+                        }
+                    }
+                }
+            }
+        }
+        mode
+    }
+}
+
+impl Chmod {
+    /// The rules as `chmod` arguments, with Some(true) for directories only and Some(false) for
+    /// files only
+    fn chmod_args(&self) -> impl Iterator<Item = (Option<bool>, &str)> {
+        self.spec
+            .split(',')
+            .map(|rule| match rule.strip_prefix('D') {
+                Some(rest) => (Some(true), rest),
+                None => match rule.strip_prefix('F') {
+                    Some(rest) => (Some(false), rest),
+                    None => (None, rule),
+                },
+            })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Chmod {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Owner of the synced files, `user`, `user:group` or `:group`, by name or numeric id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chown {
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+impl FromStr for Chown {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, group),
+            None => (s, ""),
+        };
+        let some = |s: &str| (!s.is_empty()).then(|| s.to_owned());
+        let chown = Chown {
+            user: some(user),
+            group: some(group),
+        };
+        anyhow::ensure!(
+            chown.user.is_some() || chown.group.is_some(),
+            "chown needs a user or a group"
+        );
+        Ok(chown)
+    }
+}
+
+impl std::fmt::Display for Chown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.user.as_deref().unwrap_or_default())?;
+        if let Some(group) = self.group.as_deref() {
+            write!(f, ":{group}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Chown {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(unix)]
+impl Chown {
+    /// The uid and gid to set, None keeping the current one
+    pub fn resolve(&self) -> anyhow::Result<(Option<u32>, Option<u32>)> {
+        let uid = self.user.as_deref().map(user_id).transpose()?;
+        let gid = self.group.as_deref().map(group_id).transpose()?;
+        Ok((uid, gid))
+    }
+}
+
+#[cfg(unix)]
+fn user_id(user: &str) -> anyhow::Result<u32> {
+    if let Ok(id) = user.parse() {
+        return Ok(id);
+    }
+    let name = std::ffi::CString::new(user)?;
+    // SAFETY: the name is a valid C string, the result is only read before the next call
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    anyhow::ensure!(!pw.is_null(), "Unknown user {user}");
+    // SAFETY: checked for null above
+    Ok(unsafe { (*pw).pw_uid })
+}
+
+#[cfg(unix)]
+fn group_id(group: &str) -> anyhow::Result<u32> {
+    if let Ok(id) = group.parse() {
+        return Ok(id);
+    }
+    let name = std::ffi::CString::new(group)?;
+    // SAFETY: the name is a valid C string, the result is only read before the next call
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    anyhow::ensure!(!gr.is_null(), "Unknown group {group}");
+    // SAFETY: checked for null above
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+/// Set the owner and the permissions of `path`, local or `host:path` reached with the ssh
+/// arguments `ssh`, and everything below it
+pub fn fix_up(
+    path: &Path,
+    ssh: &[String],
+    owner: Option<&Chown>,
+    mode: Option<&Chmod>,
+) -> anyhow::Result<()> {
+    if owner.is_none() && mode.is_none() {
+        return Ok(());
+    }
+    match crate::config::remote_dst(path) {
+        Some((host, remote)) => {
+            let script = fix_up_script(remote, owner, mode);
+            let sh = xshell::Shell::new()?;
+            xshell::cmd!(sh, "ssh -o BatchMode=yes {ssh...} {host} {script}")
+                .quiet()
+                .run()
+                .with_context(|| format!("Failed to fix up the permissions of {}", path.display()))
+        }
+        None => fix_up_local(path, owner, mode),
+    }
+}
+
+/// Shell script fixing up `path` on a remote host
+fn fix_up_script(path: &str, owner: Option<&Chown>, mode: Option<&Chmod>) -> String {
+    let path = crate::backup::remote_quote(path);
+    let mut cmds = Vec::new();
+    if let Some(owner) = owner {
+        let owner = shell_words::quote(&owner.to_string()).into_owned();
+        cmds.push(format!("chown -R -- {owner} {path}"));
+    }
+    // chmod has no rules for directories or files only
+    for (dirs, rule) in mode.iter().flat_map(|m| m.chmod_args()) {
+        let kind = match dirs {
+            Some(true) => " -type d",
+            Some(false) => " -type f",
+            None => " ! -type l",
+        };
+        let rule = shell_words::quote(rule);
+        cmds.push(format!("find {path}{kind} -exec chmod {rule} {{}} +"));
+    }
+    cmds.join(" && ")
+}
+
+#[cfg(unix)]
+fn fix_up_local(path: &Path, owner: Option<&Chown>, mode: Option<&Chmod>) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let (uid, gid) = owner.map(Chown::resolve).transpose()?.unwrap_or_default();
+    let meta = std::fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    // links have no permissions of their own
+    if meta.is_symlink() {
+        return Ok(());
+    }
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(path, uid, gid)
+            .with_context(|| format!("Failed to chown {}", path.display()))?;
+    }
+    if let Some(mode) = mode {
+        let new = mode.apply(meta.permissions().mode() & 0o7777, meta.is_dir());
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(new))
+            .with_context(|| format!("Failed to chmod {}", path.display()))?;
+    }
+    if meta.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            fix_up_local(&entry?.path(), owner, mode)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn fix_up_local(_path: &Path, _owner: Option<&Chown>, _mode: Option<&Chmod>) -> anyhow::Result<()> {
+    anyhow::bail!("dst_owner and dst_mode of local destinations are only supported on unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chmod() {
+        let chmod: Chmod = "D755,F644".parse().unwrap();
+        assert_eq!(chmod.apply(0o700, true), 0o755);
+        assert_eq!(chmod.apply(0o777, false), 0o644);
+
+        let chmod: Chmod = "Dg+s,ug+w,Fo-w,+X".parse().unwrap();
+        assert_eq!(chmod.apply(0o755, true), 0o2775);
+        assert_eq!(chmod.apply(0o646, false), 0o664);
+        assert_eq!(chmod.apply(0o744, false), 0o775);
+
+        let chmod: Chmod = "go=r".parse().unwrap();
+        assert_eq!(chmod.apply(0o777, false), 0o744);
+
+        assert!("D8".parse::<Chmod>().is_err());
+        assert!("u+q".parse::<Chmod>().is_err());
+        assert!("".parse::<Chmod>().is_err());
+    }
+
+    #[test]
+    fn test_fix_up_script() {
+        let owner = "www-data:www".parse().unwrap();
+        let mode = "D2775,F664,o-w".parse().unwrap();
+        assert_eq!(
+            fix_up_script("/srv/my app", Some(&owner), Some(&mode)),
+            "chown -R -- www-data:www '/srv/my app' \
+             && find '/srv/my app' -type d -exec chmod 2775 {} + \
+             && find '/srv/my app' -type f -exec chmod 664 {} + \
+             && find '/srv/my app' ! -type l -exec chmod o-w {} +"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fix_up_local() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("app");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/a.txt"), "a").unwrap();
+        let mode = "D750,F640".parse().unwrap();
+        fix_up(&root, &[], None, Some(&mode)).unwrap();
+        let mode_of = |p: &str| {
+            std::fs::metadata(root.join(p))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode_of(""), 0o750);
+        assert_eq!(mode_of("sub"), 0o750);
+        assert_eq!(mode_of("sub/a.txt"), 0o640);
+    }
+
+    #[test]
+    fn test_chown() {
+        let chown: Chown = "www-data:www".parse().unwrap();
+        assert_eq!(chown.user.as_deref(), Some("www-data"));
+        assert_eq!(chown.group.as_deref(), Some("www"));
+        assert_eq!(chown.to_string(), "www-data:www");
+        let chown: Chown = ":1000".parse().unwrap();
+        assert_eq!(chown.user, None);
+        assert_eq!(chown.to_string(), ":1000");
+        assert!(":".parse::<Chown>().is_err());
+    }
+}
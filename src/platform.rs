@@ -0,0 +1,55 @@
+//! Platform specific path handling
+
+use std::path::{Path, PathBuf};
+
+/// [std::fs::canonicalize] without the `\\?\` prefix Windows adds to local paths, which rsync and
+/// most shells don't understand
+pub fn canonicalize(path: impl AsRef<Path>) -> std::io::Result<PathBuf> {
+    let path = std::fs::canonicalize(path)?;
+    Ok(strip_verbatim(path))
+}
+
+/// Absolute path of `path`. If `follow_symlinks` is false and `path` is a symlink, then only its
+/// parent is resolved, so the link itself is kept
+pub fn resolve(path: impl AsRef<Path>, follow_symlinks: bool) -> std::io::Result<PathBuf> {
+    let path = path.as_ref();
+    let is_link = std::fs::symlink_metadata(path)?.is_symlink();
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if is_link && !follow_symlinks => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            Ok(canonicalize(parent)?.join(name))
+        }
+        _ => canonicalize(path),
+    }
+}
+
+#[cfg(windows)]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    let Some(Component::Prefix(prefix)) = path.components().next() else {
+        return path;
+    };
+    match prefix.kind() {
+        // `\\?\C:\foo` -> `C:\foo`
+        Prefix::VerbatimDisk(_) => {
+            let s = path.to_string_lossy();
+            PathBuf::from(&s[4..])
+        }
+        // `\\?\UNC\server\share` -> `\\server\share`
+        Prefix::VerbatimUNC(_, _) => {
+            let s = path.to_string_lossy();
+            PathBuf::from(format!(r"\\{}", &s[8..]))
+        }
+        _ => path,
+    }
+}
+
+#[cfg(not(windows))]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    path
+}
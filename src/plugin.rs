@@ -0,0 +1,137 @@
+//! Commands provided by plugins: `plugin:<name> [args]` runs the executable `atune-<name>` found
+//! on the PATH with the arguments, like cargo runs its subcommands. Hooks get a JSON payload
+//! describing the event on stdin
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::Write as _,
+    path::PathBuf,
+    process::{Child, Command},
+};
+
+use anyhow::Context;
+use tracing::debug;
+
+use crate::config::CommandConfig;
+
+const PREFIX: &str = "plugin:";
+
+/// The plugin name and arguments of `command`, None if it's not a plugin command
+pub fn parse(command: &str) -> Option<anyhow::Result<(String, Vec<String>)>> {
+    let rest = command.trim_start().strip_prefix(PREFIX)?;
+    Some((|| {
+        let mut words = shell_words::split(rest).context("Failed to split the plugin command")?;
+        anyhow::ensure!(!words.is_empty(), "The plugin command has no plugin name");
+        let name = words.remove(0);
+        Ok((name, words))
+    })())
+}
+
+/// Whether `cmd` runs a plugin
+pub fn is_plugin(cmd: &CommandConfig) -> bool {
+    parse(&cmd.command).is_some()
+}
+
+/// Find the executable of the plugin `name` on the PATH
+pub fn resolve(name: &str) -> anyhow::Result<PathBuf> {
+    let mut file = OsString::from(format!("atune-{name}"));
+    file.push(std::env::consts::EXE_SUFFIX);
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(&file))
+        .find(|p| is_executable(p))
+        .with_context(|| {
+            format!(
+                "Plugin {name} not found, expected an executable named {} on the PATH",
+                file.to_string_lossy()
+            )
+        })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::PermissionsExt as _;
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &std::path::Path) -> bool {
+    path.is_file()
+}
+
+/// The program and arguments running the plugin command `command`, None if it's not one
+pub fn program(command: &str) -> Option<anyhow::Result<(PathBuf, Vec<String>)>> {
+    parse(command).map(|parsed| {
+        let (name, args) = parsed?;
+        let program = resolve(&name)?;
+        debug!(?program, ?args, "Running plugin {name}");
+        Ok((program, args))
+    })
+}
+
+/// The plugin command `cmd` with its environment and working directory, None if it's not one
+pub fn command(cmd: &CommandConfig) -> Option<anyhow::Result<Command>> {
+    program(&cmd.command).map(|program| {
+        let (program, args) = program?;
+        let mut proc = Command::new(program);
+        proc.args(args).envs(cmd.env.iter());
+        if let Some(cwd) = cmd.cwd.as_ref() {
+            proc.current_dir(cwd);
+        }
+        Ok(proc)
+    })
+}
+
+/// JSON describing the run of the `hook` commands of `project`, e.g. `on_sync`, with the
+/// environment variables of the commands
+pub fn payload(project: &str, hook: &str, env: &[(&str, &str)]) -> String {
+    #[derive(serde_derive::Serialize)]
+    struct Payload<'a> {
+        event: &'a str,
+        project: &'a str,
+        env: BTreeMap<&'a str, &'a str>,
+    }
+
+    let payload = Payload {
+        event: hook,
+        project,
+        // the later values of a variable win, like when they are set on the process
+        env: env.iter().copied().collect(),
+    };
+    serde_json::to_string(&payload).expect("Strings always serialize")
+}
+
+/// Write `payload` to the stdin of `child` on a thread, so a plugin not reading it can't block
+/// the sync
+pub fn feed(child: &mut Child, payload: String) {
+    let Some(mut stdin) = child.stdin.take() else {
+        return;
+    };
+    std::thread::spawn(move || {
+        // the plugin may exit without reading it
+        let _ = stdin.write_all(payload.as_bytes());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert!(parse("make build").is_none());
+        let (name, args) = parse("plugin:slack --channel 'dev ops'").unwrap().unwrap();
+        assert_eq!(name, "slack");
+        assert_eq!(args, ["--channel", "dev ops"]);
+        assert!(parse("plugin:").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(
+            payload("web", "on_sync", &[("ATUNE_SYNC_SRC", "src/\"a\"")]),
+            r#"{"event":"on_sync","project":"web","env":{"ATUNE_SYNC_SRC":"src/\"a\""}}"#
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Children started in their own process group, so stopping one stops everything it started too,
+//! e.g. the ssh of rsync or the server of a shell script. The groups are recorded in the state
+//! directory until they are reaped, so the ones left behind by a crashed `watch` are stopped by
+//! the next one
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    time::Duration,
+};
+
+use tracing::{debug, warn};
+
+/// Killed groups get this long to exit before they are killed with SIGKILL
+pub const KILL_GRACE: Duration = Duration::from_millis(500);
+#[cfg(unix)]
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where the groups are recorded: a file named after the process group id, holding the pid of
+/// the atune that started it and the start time of the group leader
+fn records_dir() -> Option<PathBuf> {
+    Some(crate::state::state_path()?.with_file_name("processes"))
+}
+
+/// Spawn `cmd` as the leader of a new process group, recorded until it's [forget]-ed or
+/// [kill]-ed
+pub fn spawn(cmd: &mut Command) -> std::io::Result<Child> {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(cmd, 0);
+    let child = cmd.spawn()?;
+    if let Some(dir) = records_dir() {
+        record(&dir, child.id());
+    }
+    Ok(child)
+}
+
+/// Stop tracking the group of `child` once it exited on its own. The rest of the group, e.g. an
+/// ssh master kept alive by ControlPersist, keeps running
+pub fn forget(child: &Child) {
+    unrecord(child.id());
+}
+
+fn unrecord(pgid: u32) {
+    if let Some(dir) = records_dir() {
+        let _ = std::fs::remove_file(dir.join(pgid.to_string()));
+    }
+}
+
+/// Stop the group of `child`: SIGTERM, then SIGKILL for whatever is left after `grace`.
+/// Children that don't lead a group are killed alone
+pub fn kill(child: &mut Child, grace: Duration) {
+    if !matches!(child.try_wait(), Ok(None)) {
+        forget(child);
+        return;
+    }
+    #[cfg(unix)]
+    {
+        let pgid = child.id() as libc::pid_t;
+        // SAFETY: only signals the group led by our child, which can't be reaped concurrently
+        if unsafe { libc::killpg(pgid, libc::SIGTERM) } == 0 {
+            let deadline = std::time::Instant::now() + grace;
+            while matches!(child.try_wait(), Ok(None)) && std::time::Instant::now() < deadline {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            // the id isn't reused while members of the group remain
+            unsafe { libc::killpg(pgid, libc::SIGKILL) };
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = grace;
+    if let Err(err) = child.kill() {
+        debug!(?err, "Failed to kill child process");
+    }
+    if let Err(err) = child.wait() {
+        warn!(?err, "Failed to wait for killed process");
+    }
+    forget(child);
+}
+
+/// [spawn] of a tokio command. Returns the child and the id of its group, which is still needed
+/// once the child was reaped and the child no longer knows its id
+#[cfg(feature = "async")]
+pub fn spawn_async(
+    cmd: &mut tokio::process::Command,
+) -> std::io::Result<(tokio::process::Child, u32)> {
+    #[cfg(unix)]
+    cmd.process_group(0);
+    let child = cmd.spawn()?;
+    let pgid = child.id().expect("The child wasn't polled yet");
+    if let Some(dir) = records_dir() {
+        record(&dir, pgid);
+    }
+    Ok((child, pgid))
+}
+
+/// [forget] of a child started by [spawn_async]
+#[cfg(feature = "async")]
+pub fn forget_async(pgid: u32) {
+    unrecord(pgid);
+}
+
+/// [kill] of a child started by [spawn_async]
+#[cfg(feature = "async")]
+pub async fn kill_async(child: &mut tokio::process::Child, pgid: u32, grace: Duration) {
+    if !matches!(child.try_wait(), Ok(None)) {
+        unrecord(pgid);
+        return;
+    }
+    #[cfg(unix)]
+    {
+        // SAFETY: only signals the group led by our child, which can't be reaped concurrently
+        if unsafe { libc::killpg(pgid as libc::pid_t, libc::SIGTERM) } == 0 {
+            let _ = tokio::time::timeout(grace, child.wait()).await;
+            // the id isn't reused while members of the group remain
+            unsafe { libc::killpg(pgid as libc::pid_t, libc::SIGKILL) };
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = grace;
+    if let Err(err) = child.kill().await {
+        debug!(?err, "Failed to kill child process");
+    }
+    unrecord(pgid);
+}
+
+/// Stop the recorded groups whose atune exited without stopping them, e.g. killed by SIGKILL
+pub fn reap_orphans() {
+    if let Some(dir) = records_dir() {
+        reap_orphans_in(&dir);
+    }
+}
+
+fn record(dir: &Path, pgid: u32) {
+    let content = format!(
+        "{} {}\n",
+        std::process::id(),
+        start_time(pgid).unwrap_or_default()
+    );
+    let written = std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(dir.join(pgid.to_string()), content));
+    if let Err(err) = written {
+        debug!(?err, pgid, "Failed to record the process group");
+    }
+}
+
+fn reap_orphans_in(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(pgid) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let mut fields = content.split_whitespace();
+        let owner = fields.next().and_then(|p| p.parse::<u32>().ok());
+        let started = fields.next();
+        if owner.is_some_and(|p| p == std::process::id() || alive(p)) {
+            continue;
+        }
+        // the id may belong to an unrelated process by now
+        if started.is_some() && start_time(pgid).as_deref() == started {
+            warn!(
+                pgid,
+                "Stopping the processes left running by a previous atune"
+            );
+            kill_orphan(pgid);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+#[cfg(unix)]
+fn alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks for the existence of the process
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(not(unix))]
+fn alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn kill_orphan(pgid: u32) {
+    let pgid = pgid as libc::pid_t;
+    // SAFETY: the group was verified to be led by the recorded process
+    unsafe { libc::killpg(pgid, libc::SIGTERM) };
+    let deadline = std::time::Instant::now() + KILL_GRACE;
+    while unsafe { libc::killpg(pgid, 0) } == 0 && std::time::Instant::now() < deadline {
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    unsafe { libc::killpg(pgid, libc::SIGKILL) };
+}
+
+#[cfg(not(unix))]
+fn kill_orphan(_pgid: u32) {}
+
+/// Identifies the process across pid reuse
+#[cfg(target_os = "linux")]
+fn start_time(pid: u32) -> Option<String> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // the fields after the command name, which may contain spaces, starting with the state
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19).map(str::to_owned)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn start_time(pid: u32) -> Option<String> {
+    let out = Command::new("ps")
+        .args(["-o", "lstart=", "-p", &pid.to_string()])
+        .output()
+        .ok()?;
+    let started = String::from_utf8_lossy(&out.stdout)
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    (!started.is_empty()).then(|| started.join("_"))
+}
+
+#[cfg(not(unix))]
+fn start_time(_pid: u32) -> Option<String> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::process::CommandExt as _;
+
+    use super::*;
+
+    fn spawn_group(dir: &Path, pid_file: &Path) -> Child {
+        // a grandchild, like the ssh started by rsync
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("sleep 60 & echo $! > {}; wait", pid_file.display()))
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        record(dir, child.id());
+        while std::fs::read_to_string(pid_file).map_or(true, |p| p.trim().is_empty()) {
+            assert!(matches!(child.try_wait(), Ok(None)));
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        child
+    }
+
+    fn grandchild_alive(pid_file: &Path) -> bool {
+        let pid = std::fs::read_to_string(pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        // reaped by init once orphaned, zombies of the shell don't count
+        alive(pid)
+            && start_time(pid).is_some()
+            && !std::fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|s| {
+                s.rsplit_once(')')
+                    .is_some_and(|(_, f)| f.trim_start().starts_with('Z'))
+            })
+    }
+
+    #[test]
+    fn test_kill_stops_the_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let mut child = spawn_group(dir.path(), &pid_file);
+        assert!(grandchild_alive(&pid_file));
+
+        kill(&mut child, KILL_GRACE);
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while grandchild_alive(&pid_file) {
+            assert!(std::time::Instant::now() < deadline, "grandchild survived");
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn test_reap_orphans() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = dir.path().join("processes");
+        let pid_file = dir.path().join("pid");
+        let mut child = spawn_group(&records, &pid_file);
+        let record = records.join(child.id().to_string());
+
+        // the group of a running atune is left alone
+        reap_orphans_in(&records);
+        assert!(record.exists());
+        assert!(grandchild_alive(&pid_file));
+
+        // owned by an atune that exited
+        let mut gone = Command::new("true").spawn().unwrap();
+        gone.wait().unwrap();
+        let content = std::fs::read_to_string(&record).unwrap();
+        let (_, started) = content.split_once(' ').unwrap();
+        std::fs::write(&record, format!("{} {started}", gone.id())).unwrap();
+        reap_orphans_in(&records);
+        assert!(!record.exists());
+        let _ = child.wait();
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while grandchild_alive(&pid_file) {
+            assert!(std::time::Instant::now() < deadline, "orphan survived");
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
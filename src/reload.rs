@@ -0,0 +1,233 @@
+//! Watch of several config files, each restarted independently when its file changes
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use crossbeam::{channel, select};
+use notify::Watcher as _;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{self, Config},
+    lock::ConfigLock,
+    sync::{self, WatchOptions},
+};
+
+/// Editors save in several steps, wait for them to finish before reloading
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+const EXIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default)]
+pub struct ConfigWatchOptions {
+    /// Stop the atunes already watching the configs and take over
+    pub replace: bool,
+    /// Options of the watch of every config. `control` is not supported
+    pub watch: WatchOptions,
+}
+
+/// The running watch of a config
+struct RunningWatch {
+    cancel: channel::Sender<()>,
+    thread: std::thread::JoinHandle<anyhow::Result<()>>,
+}
+
+impl RunningWatch {
+    fn stop(self) {
+        let _ = self.cancel.send(());
+        match self.thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!(?err, "Watch error"),
+            Err(_) => error!("Watch thread panicked"),
+        }
+    }
+}
+
+struct ConfigEntry {
+    _lock: ConfigLock,
+    /// content of the file when it was last loaded, or rejected as invalid
+    content: Vec<u8>,
+    watch: Option<RunningWatch>,
+    /// found in a directory of configs, so it's stopped when the file is removed
+    in_dir: bool,
+}
+
+struct Configs<'a, F> {
+    load: F,
+    options: &'a ConfigWatchOptions,
+    entries: BTreeMap<PathBuf, ConfigEntry>,
+}
+
+impl<F: Fn(&Path) -> anyhow::Result<Config>> Configs<'_, F> {
+    fn spawn(&self, path: &Path) -> anyhow::Result<RunningWatch> {
+        let config = (self.load)(path)
+            .with_context(|| format!("Failed to load the config {}", path.display()))?;
+        let (cancel, cancel_rx) = channel::bounded(1);
+        let options = WatchOptions {
+            control: None,
+            ..self.options.watch.clone()
+        };
+        let config_path = path.to_owned();
+        let thread =
+            std::thread::spawn(move || sync::watch(config_path, config, cancel_rx, options));
+        Ok(RunningWatch { cancel, thread })
+    }
+
+    fn start(&mut self, path: PathBuf, in_dir: bool) -> anyhow::Result<()> {
+        info!(config = ?path, "Starting the watch");
+        let lock = ConfigLock::acquire(&path, self.options.replace)?;
+        let content = std::fs::read(&path).unwrap_or_default();
+        let watch = self.spawn(&path)?;
+        self.entries.insert(
+            path,
+            ConfigEntry {
+                _lock: lock,
+                content,
+                watch: Some(watch),
+                in_dir,
+            },
+        );
+        Ok(())
+    }
+
+    /// Handle a change of the file `path`
+    fn changed(&mut self, path: &Path, dirs: &BTreeSet<PathBuf>) {
+        let Some(entry) = self.entries.get(path) else {
+            let in_dir = path.parent().is_some_and(|p| dirs.contains(p));
+            if in_dir && path.is_file() && config::is_config_file(path) {
+                if let Err(err) = self.start(path.to_owned(), true) {
+                    error!(?err, config = ?path, "Failed to start the watch of the new config");
+                }
+            }
+            return;
+        };
+        let content = match std::fs::read(path) {
+            Ok(c) => c,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && entry.in_dir => {
+                info!(config = ?path, "Config removed, stopping its watch");
+                if let Some(watch) = self.entries.remove(path).and_then(|e| e.watch) {
+                    watch.stop();
+                }
+                return;
+            }
+            // e.g. replaced by an editor, the new file follows
+            Err(_) => return,
+        };
+        if content == entry.content && entry.watch.is_some() {
+            return;
+        }
+        self.entries.get_mut(path).unwrap().content = content;
+        // check the new config before stopping the running one
+        if let Err(err) = (self.load)(path) {
+            error!(config = ?path, "Invalid config, keeping the running watch: {err:#}");
+            return;
+        }
+        info!(config = ?path, "Config changed, restarting its watch");
+        let entry = self.entries.get_mut(path).unwrap();
+        if let Some(watch) = entry.watch.take() {
+            watch.stop();
+        }
+        match self.spawn(path) {
+            Ok(watch) => self.entries.get_mut(path).unwrap().watch = Some(watch),
+            Err(err) => error!(?err, config = ?path, "Failed to restart the watch"),
+        }
+    }
+
+    /// Log the watches that exited on their own, e.g. because they failed to start
+    fn reap(&mut self) {
+        for (path, entry) in self.entries.iter_mut() {
+            if entry.watch.as_ref().is_some_and(|w| w.thread.is_finished()) {
+                warn!(config = ?path, "The watch exited");
+                entry.watch.take().unwrap().stop();
+            }
+        }
+    }
+}
+
+/// Watch the configs `paths`, config files or directories of them, until `cancel` receives.
+///
+/// `load` reads a config. The watch of a config is restarted when its file changes, and the
+/// configs of the directories are started and stopped as their files are added and removed
+pub fn watch_configs(
+    paths: &[PathBuf],
+    load: impl Fn(&Path) -> anyhow::Result<Config>,
+    cancel: channel::Receiver<()>,
+    options: ConfigWatchOptions,
+) -> anyhow::Result<()> {
+    let mut configs = Configs {
+        load,
+        options: &options,
+        entries: BTreeMap::new(),
+    };
+    let mut dirs = BTreeSet::new();
+    let mut initial = Vec::new();
+    for path in paths {
+        let path = crate::platform::canonicalize(path)
+            .with_context(|| format!("Config {} not found", path.display()))?;
+        if path.is_dir() {
+            initial.extend(config::config_files(&path)?.into_iter().map(|f| (f, true)));
+            dirs.insert(path);
+        } else {
+            initial.push((path, false));
+        }
+    }
+    for (path, in_dir) in initial {
+        if configs.entries.contains_key(&path) {
+            continue;
+        }
+        if let Err(err) = configs.start(path, in_dir) {
+            for entry in std::mem::take(&mut configs.entries).into_values() {
+                if let Some(watch) = entry.watch {
+                    watch.stop();
+                }
+            }
+            return Err(err);
+        }
+    }
+
+    let (tx, rx) = channel::unbounded();
+    let mut watcher =
+        notify::recommended_watcher(tx).context("Failed to watch the config files")?;
+    let watched_dirs = configs
+        .entries
+        .keys()
+        .filter_map(|p| p.parent().map(Path::to_owned))
+        .chain(dirs.iter().cloned())
+        .collect::<BTreeSet<_>>();
+    for dir in watched_dirs.iter() {
+        watcher
+            .watch(dir, notify::RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {}", dir.display()))?;
+    }
+
+    loop {
+        select! {
+            recv(cancel) -> _ => break,
+            recv(rx) -> event => {
+                let mut changed = BTreeSet::new();
+                let mut collect = |event: Result<notify::Result<notify::Event>, _>| {
+                    if let Ok(Ok(event)) = event {
+                        changed.extend(event.paths);
+                    }
+                };
+                collect(event);
+                while let Ok(event) = rx.recv_timeout(RELOAD_DEBOUNCE) {
+                    collect(Ok(event));
+                }
+                for path in changed {
+                    configs.changed(&path, &dirs);
+                }
+            },
+            default(EXIT_POLL_INTERVAL) => configs.reap(),
+        }
+    }
+    drop(watcher);
+    for (_, entry) in std::mem::take(&mut configs.entries) {
+        if let Some(watch) = entry.watch {
+            watch.stop();
+        }
+    }
+    Ok(())
+}
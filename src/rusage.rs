@@ -0,0 +1,130 @@
+//! CPU time and memory used by the processes of a sync, collected when they are reaped
+use std::{
+    process::{Child, ExitStatus},
+    time::Duration,
+};
+
+/// Resource usage of a process and the descendants it waited for, e.g. `sync-project` with its
+/// rsync and hooks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde_derive::Serialize)]
+#[serde(into = "UsageFields")]
+pub struct ResourceUsage {
+    /// CPU time in user mode
+    pub user: Duration,
+    /// CPU time in the kernel
+    pub system: Duration,
+    /// Largest resident set size of the processes, in bytes
+    pub max_rss: u64,
+}
+
+impl ResourceUsage {
+    pub fn cpu(&self) -> Duration {
+        self.user + self.system
+    }
+
+    /// Add the usage of another process: the CPU times are summed, the memory is the larger one
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.user += other.user;
+        self.system += other.system;
+        self.max_rss = self.max_rss.max(other.max_rss);
+    }
+}
+
+/// How [ResourceUsage] is serialized
+#[derive(serde_derive::Serialize)]
+struct UsageFields {
+    cpu_user_ms: u128,
+    cpu_system_ms: u128,
+    max_rss_bytes: u64,
+}
+
+impl From<ResourceUsage> for UsageFields {
+    fn from(usage: ResourceUsage) -> Self {
+        Self {
+            cpu_user_ms: usage.user.as_millis(),
+            cpu_system_ms: usage.system.as_millis(),
+            max_rss_bytes: usage.max_rss,
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.2?} CPU, {} max RSS",
+            self.cpu(),
+            crate::stats::format_bytes(self.max_rss)
+        )
+    }
+}
+
+/// [Child::try_wait], also returning the resource usage of the child once it exited. The child
+/// is reaped, so it must not be waited for again
+#[cfg(unix)]
+pub fn try_wait(child: &mut Child) -> std::io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    use std::os::unix::process::ExitStatusExt as _;
+
+    let mut status = 0;
+    // SAFETY: rusage is plain data, all zeroes is valid
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: waits for our own child, the pointers outlive the call
+    let pid = unsafe {
+        libc::wait4(
+            child.id() as libc::pid_t,
+            &mut status,
+            libc::WNOHANG,
+            &mut rusage,
+        )
+    };
+    match pid {
+        -1 => Err(std::io::Error::last_os_error()),
+        0 => Ok(None),
+        _ => Ok(Some((
+            ExitStatus::from_raw(status),
+            ResourceUsage {
+                user: timeval(rusage.ru_utime),
+                system: timeval(rusage.ru_stime),
+                // kilobytes on Linux, bytes on macOS
+                max_rss: if cfg!(target_os = "macos") {
+                    rusage.ru_maxrss as u64
+                } else {
+                    rusage.ru_maxrss as u64 * 1024
+                },
+            },
+        ))),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn try_wait(child: &mut Child) -> std::io::Result<Option<(ExitStatus, ResourceUsage)>> {
+    Ok(child.try_wait()?.map(|status| (status, Default::default())))
+}
+
+#[cfg(unix)]
+fn timeval(t: libc::timeval) -> Duration {
+    Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_wait() {
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done; exit 3")
+            .spawn()
+            .unwrap();
+        let (status, usage) = loop {
+            if let Some(exited) = try_wait(&mut child).unwrap() {
+                break exited;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(status.code(), Some(3));
+        assert!(usage.cpu() > Duration::ZERO, "{usage:?}");
+        assert!(usage.max_rss > 0, "{usage:?}");
+    }
+}
@@ -0,0 +1,183 @@
+//! Recurring syncs, see [crate::config::FileSync::schedule]
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Local, TimeZone};
+use croner::parser::{CronParser, Seconds, Year};
+
+/// When a scheduled sync runs: an interval, e.g. `30m`, or a cron expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// The first time the sync is due after `after`, or None if it never is
+    pub fn next(&self, after: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Every(interval) => Some(after + *interval),
+            Schedule::Cron(cron) => cron.next(after),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.split_whitespace().count() == 5 {
+            return Cron::from_str(s).map(Schedule::Cron);
+        }
+        let interval = duration_str::parse(s).map_err(|err| {
+            anyhow::anyhow!("Expected an interval or a cron expression, got {s:?}: {err}")
+        })?;
+        anyhow::ensure!(!interval.is_zero(), "The interval must not be zero");
+        Ok(Schedule::Every(interval))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Schedule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A cron expression of the fields `minute hour day-of-month month day-of-week`, in local time.
+///
+/// Fields are `*`, numbers, ranges `a-b` and lists `a,b`, optionally with a step `/n`, and the
+/// names of the months and weekdays. Sunday is both 0 and 7. Like cron, if both the day of month
+/// and the day of week are restricted, then either of them matches. A field starting with `*`,
+/// e.g. `*/2`, isn't restricted
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron(Box<croner::Cron>);
+
+impl Eq for Cron {}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let [_, _, days, _, weekdays] = fields[..] else {
+            anyhow::bail!("A cron expression has 5 fields, got {s:?}");
+        };
+        // cron only ORs the days if neither field starts with `*`, ANDing in an unrestricted
+        // field matches every day of the other one
+        let any_day = days.starts_with('*') || weekdays.starts_with('*');
+        let cron = CronParser::builder()
+            .seconds(Seconds::Disallowed)
+            .year(Year::Disallowed)
+            .dom_and_dow(any_day)
+            .build()
+            .parse(s)
+            .map_err(|err| anyhow::anyhow!("Invalid cron expression {s:?}: {err}"))?;
+        Ok(Cron(Box::new(cron)))
+    }
+}
+
+impl Cron {
+    /// The first matching minute after `after` in local time, searched within the next year
+    pub fn next(&self, after: SystemTime) -> Option<SystemTime> {
+        self.next_in(DateTime::<Local>::from(after))
+            .map(SystemTime::from)
+    }
+
+    /// The first matching minute after `after` in its time zone
+    fn next_in<Tz: TimeZone>(&self, after: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        self.0.find_next_occurrence(&after, false).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> Option<DateTime<Utc>> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .single()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "30m".parse::<Schedule>().unwrap(),
+            Schedule::Every(Duration::from_secs(1800))
+        );
+        assert!(matches!(
+            "0 3 * * *".parse::<Schedule>().unwrap(),
+            Schedule::Cron(_)
+        ));
+        assert!("0 24 * * *".parse::<Schedule>().is_err());
+        assert!("0s".parse::<Schedule>().is_err());
+        assert!("nightly".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_cron_next() {
+        let nightly: Cron = "0 3 * * *".parse().unwrap();
+        assert_eq!(
+            nightly.next_in(at(2024, 2, 28, 12, 0).unwrap()),
+            at(2024, 2, 29, 3, 0)
+        );
+        assert_eq!(
+            nightly.next_in(at(2024, 2, 29, 3, 0).unwrap()),
+            at(2024, 3, 1, 3, 0)
+        );
+
+        let quarter: Cron = "*/15 9-17 * * 1-5".parse().unwrap();
+        // a Saturday
+        assert_eq!(
+            quarter.next_in(at(2024, 6, 1, 10, 7).unwrap()),
+            at(2024, 6, 3, 9, 0)
+        );
+        assert_eq!(
+            quarter.next_in(at(2024, 6, 3, 10, 7).unwrap()),
+            at(2024, 6, 3, 10, 15)
+        );
+
+        // the 13th or any Friday
+        let either: Cron = "0 0 13 * 5".parse().unwrap();
+        assert_eq!(
+            either.next_in(at(2024, 6, 1, 0, 0).unwrap()),
+            at(2024, 6, 7, 0, 0)
+        );
+        assert_eq!(
+            either.next_in(at(2024, 6, 7, 0, 0).unwrap()),
+            at(2024, 6, 13, 0, 0)
+        );
+
+        // a step over every day isn't a restriction, so only Fridays match
+        let fridays: Cron = "0 0 */1 * 5".parse().unwrap();
+        assert_eq!(
+            fridays.next_in(at(2024, 6, 8, 0, 0).unwrap()),
+            at(2024, 6, 14, 0, 0)
+        );
+        let odd_fridays: Cron = "0 0 */2 * 5".parse().unwrap();
+        assert_eq!(
+            odd_fridays.next_in(at(2024, 6, 8, 0, 0).unwrap()),
+            at(2024, 6, 21, 0, 0)
+        );
+
+        let never: Cron = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(never.next_in(at(2024, 1, 1, 0, 0).unwrap()), None);
+    }
+
+    #[test]
+    fn test_cron_time_zone() {
+        let nightly: Cron = "0 3 * * *".parse().unwrap();
+        let berlin = chrono::FixedOffset::east_opt(2 * 3600).unwrap();
+        let next = nightly
+            .next_in(at(2024, 6, 1, 12, 0).unwrap().with_timezone(&berlin))
+            .unwrap();
+        assert_eq!(next.with_timezone(&Utc), at(2024, 6, 2, 1, 0).unwrap());
+    }
+}
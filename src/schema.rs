@@ -0,0 +1,77 @@
+//! JSON Schema of the config file
+
+/// JSON Schema describing the config file
+pub static CONFIG_SCHEMA: &str = include_str!("schema.json");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use serde::de::{self, Visitor};
+    use serde::Deserialize;
+
+    /// Deserializer recording the field names of the struct deserialized from it
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> de::Deserializer<'de> for FieldNames<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("done"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    fn fields<'de, T: Deserialize<'de>>() -> Vec<&'static str> {
+        let mut fields: &'static [&'static str] = &[];
+        let _ = T::deserialize(FieldNames(&mut fields));
+        let mut fields = fields.to_vec();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn test_schema_matches_config_types() {
+        let schema: serde_yaml::Value = serde_yaml::from_str(CONFIG_SCHEMA).unwrap();
+        let check = |def: &str, mut expected: Vec<&'static str>| {
+            let props = schema["$defs"][def]["properties"]
+                .as_mapping()
+                .unwrap_or_else(|| panic!("{def} is missing from the schema"));
+            let mut props = props
+                .keys()
+                .map(|k| k.as_str().unwrap())
+                .collect::<Vec<_>>();
+            props.sort();
+            expected.sort();
+            assert_eq!(props, expected, "schema of {def} is out of date");
+        };
+
+        check("Config", fields::<config::Config>());
+        check("NotificationConfig", fields::<config::NotificationConfig>());
+        check("Project", fields::<config::Project>());
+        check("FileSync", fields::<config::FileSync>());
+        check("CommandConfig", fields::<config::CommandConfig>());
+        check("Profile", fields::<config::Profile>());
+        check("Defaults", fields::<config::Defaults>());
+        check("SyncOverride", fields::<config::SyncOverride>());
+        check("Backup", fields::<config::Backup>());
+        check("Logging", fields::<config::Logging>());
+        check("SshOptions", fields::<config::SshOptions>());
+        check("HostAlias", fields::<config::HostAlias>());
+    }
+}
@@ -0,0 +1,210 @@
+//! Hooks written in Lua: `lua:<file>` commands run the script in atune, with the `event` table
+//! describing the hook and the `atune` table of functions. Needs the `lua` feature. A relative
+//! `file` is found in the `cwd` of the command, or next to the config file without one.
+//!
+//! `event` holds `hook`, e.g. `on_sync`, `project`, `src`, `dst` and `error` where they apply,
+//! the `changed` and `deleted` paths as lists, the working directory of the command in `cwd`, and
+//! every environment variable of the hook, including the `env` of the command, in `env`. `atune`
+//! provides:
+//!
+//! - `atune.log(message)`, logging the message
+//! - `atune.set(name, value)`, passing a variable to the following hooks, like `ATUNE_OUTPUT`
+//! - `atune.changed(path)`, syncing the path in the running `watch`, like `atune notify-change`
+use std::path::Path;
+
+const PREFIX: &str = "lua:";
+
+/// The script file of `command`, None if it's not a Lua command
+pub fn parse(command: &str) -> Option<&Path> {
+    command
+        .trim()
+        .strip_prefix(PREFIX)
+        .map(|file| Path::new(file.trim()))
+}
+
+/// The working directory of a script run in `cwd`, the directory of the config without one
+#[cfg(feature = "lua")]
+fn working_dir(config_path: &Path, cwd: Option<&Path>) -> std::path::PathBuf {
+    match cwd {
+        Some(cwd) => cwd.to_owned(),
+        None => config_path.parent().map(Path::to_owned).unwrap_or_default(),
+    }
+}
+
+/// Run the script `file` as the `hook` of `project` in the working directory `cwd`, with the
+/// environment variables `env` of the hook. Returns the variables it set with `atune.set`
+#[cfg(feature = "lua")]
+pub fn run(
+    config_path: &Path,
+    file: &Path,
+    cwd: Option<&Path>,
+    project: &str,
+    hook: &str,
+    env: &[(&str, &str)],
+) -> anyhow::Result<Vec<(String, String)>> {
+    use std::{cell::RefCell, rc::Rc};
+
+    use anyhow::Context;
+
+    let cwd = working_dir(config_path, cwd);
+    let file = cwd.join(file);
+    let file = file.as_path();
+    let code = std::fs::read_to_string(file)
+        .with_context(|| format!("Failed to read the script {}", file.display()))?;
+    let lua = mlua::Lua::new();
+    let exported = Rc::new(RefCell::new(Vec::new()));
+    let setup = || -> mlua::Result<()> {
+        let var = |name: &str| env.iter().find(|(k, _)| *k == name).map(|(_, v)| *v);
+        let paths = |name: &str| {
+            lua.create_sequence_from(
+                var(name)
+                    .unwrap_or_default()
+                    .lines()
+                    .filter(|l| !l.is_empty()),
+            )
+        };
+        let event = lua.create_table()?;
+        event.set("hook", hook)?;
+        event.set("project", project)?;
+        event.set("src", var("ATUNE_SYNC_SRC"))?;
+        event.set("dst", var("ATUNE_SYNC_DST"))?;
+        event.set("error", var("ATUNE_SYNC_ERROR"))?;
+        event.set("changed", paths("ATUNE_CHANGED_FILES")?)?;
+        event.set("deleted", paths("ATUNE_DELETED_PATHS")?)?;
+        event.set("cwd", cwd.to_string_lossy())?;
+        event.set("env", lua.create_table_from(env.iter().copied())?)?;
+        lua.globals().set("event", event)?;
+
+        let atune = lua.create_table()?;
+        atune.set(
+            "log",
+            lua.create_function(|_, message: String| {
+                tracing::info!("{message}");
+                Ok(())
+            })?,
+        )?;
+        let set = exported.clone();
+        atune.set(
+            "set",
+            lua.create_function(move |_, (name, value): (String, String)| {
+                set.borrow_mut().push((name, value));
+                Ok(())
+            })?,
+        )?;
+        let config_path = config_path.to_owned();
+        atune.set(
+            "changed",
+            lua.create_function(move |_, path: String| {
+                changed(&config_path, Path::new(&path)).map_err(mlua::Error::external)
+            })?,
+        )?;
+        lua.globals().set("atune", atune)?;
+        Ok(())
+    };
+    setup().map_err(|err| anyhow::anyhow!("Failed to set up the script: {err}"))?;
+    lua.load(code.as_str())
+        .set_name(format!("@{}", file.display()))
+        .exec()
+        .map_err(|err| anyhow::anyhow!("{err}"))?;
+    let values = exported.borrow().clone();
+    Ok(values)
+}
+
+#[cfg(not(feature = "lua"))]
+pub fn run(
+    _config_path: &Path,
+    file: &Path,
+    _cwd: Option<&Path>,
+    _project: &str,
+    _hook: &str,
+    _env: &[(&str, &str)],
+) -> anyhow::Result<Vec<(String, String)>> {
+    anyhow::bail!(
+        "Can't run {}, Lua scripts need atune built with the lua feature",
+        file.display()
+    )
+}
+
+#[cfg(all(feature = "lua", unix))]
+fn changed(config_path: &Path, path: &Path) -> anyhow::Result<Vec<String>> {
+    let path = crate::control_socket::changed_path(path)?;
+    crate::control_socket::Client::connect(config_path)?.changed(&path)
+}
+
+#[cfg(all(feature = "lua", not(unix)))]
+fn changed(_config_path: &Path, _path: &Path) -> anyhow::Result<Vec<String>> {
+    anyhow::bail!("atune.changed is only supported on unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("lua:hooks/deploy.lua"),
+            Some(Path::new("hooks/deploy.lua"))
+        );
+        assert_eq!(parse("make build"), None);
+    }
+
+    #[cfg(feature = "lua")]
+    #[test]
+    fn test_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("hook.lua");
+        std::fs::write(
+            &script,
+            r#"
+assert(event.hook == "on_sync")
+assert(event.project == "web")
+assert(#event.changed == 2 and event.changed[2] == "b.txt")
+assert(#event.deleted == 0)
+atune.set("COUNT", tostring(#event.changed))
+"#,
+        )
+        .unwrap();
+        let env = [
+            ("ATUNE_SYNC_SRC", "src"),
+            ("ATUNE_CHANGED_FILES", "a.txt\nb.txt"),
+            ("ATUNE_DELETED_PATHS", ""),
+        ];
+        let config_path = dir.path().join("config.yaml");
+        let values = run(&config_path, &script, None, "web", "on_sync", &env).unwrap();
+        assert_eq!(values, [("COUNT".to_owned(), "2".to_owned())]);
+
+        std::fs::write(&script, "error('no deploy today')").unwrap();
+        let err = run(&config_path, &script, None, "web", "on_sync", &env).unwrap_err();
+        assert!(err.to_string().contains("no deploy today"), "{err}");
+    }
+
+    #[cfg(feature = "lua")]
+    #[test]
+    fn test_run_relative() {
+        let dir = tempfile::tempdir().unwrap();
+        let hooks = dir.path().join("hooks");
+        std::fs::create_dir(&hooks).unwrap();
+        std::fs::write(hooks.join("hook.lua"), "atune.set('CWD', event.cwd)").unwrap();
+        let config_path = dir.path().join("config.yaml");
+        let file = Path::new("hooks/hook.lua");
+
+        // next to the config
+        let values = run(&config_path, file, None, "web", "on_sync", &[]).unwrap();
+        assert_eq!(values[0].1, dir.path().to_string_lossy());
+
+        // in the cwd of the command
+        let err = run(&config_path, file, Some(&hooks), "web", "on_sync", &[]).unwrap_err();
+        assert!(err.to_string().contains("hooks/hooks/hook.lua"), "{err}");
+        let values = run(
+            &config_path,
+            Path::new("hook.lua"),
+            Some(&hooks),
+            "web",
+            "on_sync",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(values[0].1, hooks.to_string_lossy());
+    }
+}
@@ -0,0 +1,324 @@
+//! Run `atune watch` as a user service: systemd units on Linux, launchd agents on macOS
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use tracing::info;
+
+/// Service manager of the platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Manager {
+    Systemd,
+    Launchd,
+}
+
+impl Manager {
+    pub fn current() -> anyhow::Result<Self> {
+        if cfg!(target_os = "macos") {
+            Ok(Manager::Launchd)
+        } else if cfg!(unix) {
+            Ok(Manager::Systemd)
+        } else {
+            anyhow::bail!("Services are only supported with systemd and launchd")
+        }
+    }
+}
+
+/// A user service watching a config
+#[derive(Debug, Clone)]
+pub struct Service {
+    pub manager: Manager,
+    /// unit name without the extension, e.g. `atune-myproject`
+    pub name: String,
+    pub executable: PathBuf,
+    pub config: PathBuf,
+    /// global arguments passed before `watch`, e.g. `--profile`
+    pub args: Vec<OsString>,
+}
+
+impl Service {
+    /// Name of the service of the config, derived from its directory
+    pub fn default_name(config: &Path) -> String {
+        let dir = config
+            .parent()
+            .and_then(|d| d.file_name())
+            .map(|d| d.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let dir = dir
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect::<String>();
+        match dir.trim_matches('-') {
+            "" => "atune".to_owned(),
+            dir => format!("atune-{dir}"),
+        }
+    }
+
+    fn label(&self) -> String {
+        format!("local.{}", self.name)
+    }
+
+    /// Location of the unit file or plist
+    pub fn path(&self) -> anyhow::Result<PathBuf> {
+        let home = || {
+            std::env::var_os("HOME")
+                .map(PathBuf::from)
+                .context("HOME is not set")
+        };
+        Ok(match self.manager {
+            Manager::Systemd => std::env::var_os("XDG_CONFIG_HOME")
+                .filter(|d| !d.is_empty())
+                .map(PathBuf::from)
+                .map_or_else(|| home().map(|h| h.join(".config")), Ok)?
+                .join("systemd/user")
+                .join(format!("{}.service", self.name)),
+            Manager::Launchd => home()?
+                .join("Library/LaunchAgents")
+                .join(format!("{}.plist", self.label())),
+        })
+    }
+
+    fn command(&self) -> Vec<OsString> {
+        let mut cmd = vec![
+            self.executable.clone().into_os_string(),
+            "-c".into(),
+            self.config.clone().into_os_string(),
+        ];
+        cmd.extend(self.args.iter().cloned());
+        cmd.push("watch".into());
+        cmd
+    }
+
+    /// Content of the unit file or plist
+    pub fn render(&self) -> String {
+        let dir = self.config.parent().unwrap_or(Path::new("/"));
+        match self.manager {
+            Manager::Systemd => {
+                let exec = self
+                    .command()
+                    .iter()
+                    .map(|a| systemd_quote(&a.to_string_lossy()))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!(
+                    "[Unit]\n\
+                     Description=atune watching {config}\n\
+                     After=network-online.target\n\
+                     \n\
+                     [Service]\n\
+                     Type=notify\n\
+                     ExecStart={exec}\n\
+                     WorkingDirectory={dir}\n\
+                     Restart=on-failure\n\
+                     RestartSec=5\n\
+                     \n\
+                     [Install]\n\
+                     WantedBy=default.target\n",
+                    config = self.config.display().to_string().replace('%', "%%"),
+                    dir = dir.display().to_string().replace('%', "%%"),
+                )
+            }
+            Manager::Launchd => {
+                let args = self
+                    .command()
+                    .iter()
+                    .map(|a| {
+                        format!(
+                            "    <string>{}</string>\n",
+                            xml_escape(&a.to_string_lossy())
+                        )
+                    })
+                    .collect::<String>();
+                format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                     <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+                     <plist version=\"1.0\">\n\
+                     <dict>\n\
+                     \x20 <key>Label</key>\n\
+                     \x20 <string>{label}</string>\n\
+                     \x20 <key>ProgramArguments</key>\n\
+                     \x20 <array>\n\
+                     {args}\
+                     \x20 </array>\n\
+                     \x20 <key>WorkingDirectory</key>\n\
+                     \x20 <string>{dir}</string>\n\
+                     \x20 <key>RunAtLoad</key>\n\
+                     \x20 <true/>\n\
+                     \x20 <key>KeepAlive</key>\n\
+                     \x20 <dict>\n\
+                     \x20   <key>SuccessfulExit</key>\n\
+                     \x20   <false/>\n\
+                     \x20 </dict>\n\
+                     </dict>\n\
+                     </plist>\n",
+                    label = xml_escape(&self.label()),
+                    dir = xml_escape(&dir.to_string_lossy()),
+                )
+            }
+        }
+    }
+
+    /// Write the unit file and make the service manager pick it up
+    pub fn install(&self, force: bool) -> anyhow::Result<()> {
+        let path = self.path()?;
+        anyhow::ensure!(
+            force || !path.exists(),
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        );
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&path, self.render())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        info!(path = ?path, "Installed service {}", self.name);
+        if self.manager == Manager::Systemd {
+            systemctl(&["daemon-reload"])?;
+        }
+        Ok(())
+    }
+
+    /// Stop the service and remove its unit file
+    pub fn uninstall(&self) -> anyhow::Result<()> {
+        let path = self.path()?;
+        // may not be loaded or running
+        let _ = self.disable();
+        if let Err(err) = std::fs::remove_file(&path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                return Err(err).with_context(|| format!("Failed to remove {}", path.display()));
+            }
+        }
+        if self.manager == Manager::Systemd {
+            systemctl(&["daemon-reload"])?;
+        }
+        info!(path = ?path, "Uninstalled service {}", self.name);
+        Ok(())
+    }
+
+    /// Start the service now and on login
+    pub fn enable(&self) -> anyhow::Result<()> {
+        match self.manager {
+            Manager::Systemd => systemctl(&["enable", "--now", &self.unit()]),
+            Manager::Launchd => launchctl(&["load", "-w", &self.path()?.to_string_lossy()]),
+        }
+    }
+
+    /// Stop the service and don't start it on login
+    pub fn disable(&self) -> anyhow::Result<()> {
+        match self.manager {
+            Manager::Systemd => systemctl(&["disable", "--now", &self.unit()]),
+            Manager::Launchd => launchctl(&["unload", "-w", &self.path()?.to_string_lossy()]),
+        }
+    }
+
+    pub fn start(&self) -> anyhow::Result<()> {
+        match self.manager {
+            Manager::Systemd => systemctl(&["start", &self.unit()]),
+            Manager::Launchd => launchctl(&["start", &self.label()]),
+        }
+    }
+
+    pub fn stop(&self) -> anyhow::Result<()> {
+        match self.manager {
+            Manager::Systemd => systemctl(&["stop", &self.unit()]),
+            Manager::Launchd => launchctl(&["stop", &self.label()]),
+        }
+    }
+
+    pub fn status(&self) -> anyhow::Result<()> {
+        match self.manager {
+            Manager::Systemd => systemctl(&["status", &self.unit()]),
+            Manager::Launchd => launchctl(&["list", &self.label()]),
+        }
+    }
+
+    fn unit(&self) -> String {
+        format!("{}.service", self.name)
+    }
+}
+
+fn systemctl(args: &[&str]) -> anyhow::Result<()> {
+    run(Command::new("systemctl").arg("--user").args(args))
+}
+
+fn launchctl(args: &[&str]) -> anyhow::Result<()> {
+    run(Command::new("launchctl").args(args))
+}
+
+fn run(cmd: &mut Command) -> anyhow::Result<()> {
+    let status = cmd
+        .status()
+        .with_context(|| format!("Failed to run {cmd:?}"))?;
+    anyhow::ensure!(status.success(), "{cmd:?} failed with {status}");
+    Ok(())
+}
+
+/// Quote a word of `ExecStart=`, escaping the specifiers and variables systemd would expand
+fn systemd_quote(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{escaped}\"")
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(manager: Manager) -> Service {
+        Service {
+            manager,
+            name: "atune-web".to_owned(),
+            executable: "/usr/bin/atune".into(),
+            config: "/home/me/my web/atune.yaml".into(),
+            args: vec!["--profile".into(), "dev%1".into()],
+        }
+    }
+
+    #[test]
+    fn test_default_name() {
+        assert_eq!(
+            Service::default_name(Path::new("/home/me/my web/atune.yaml")),
+            "atune-my-web"
+        );
+        assert_eq!(Service::default_name(Path::new("atune.yaml")), "atune");
+    }
+
+    #[test]
+    fn test_render_systemd() {
+        let unit = service(Manager::Systemd).render();
+        assert!(
+            unit.contains(
+                r#"ExecStart="/usr/bin/atune" "-c" "/home/me/my web/atune.yaml" "--profile" "dev%%1" "watch""#
+            ),
+            "{unit}"
+        );
+        assert!(unit.contains("Type=notify"), "{unit}");
+    }
+
+    #[test]
+    fn test_render_launchd() {
+        let plist = service(Manager::Launchd).render();
+        assert!(
+            plist.contains("<string>local.atune-web</string>"),
+            "{plist}"
+        );
+        assert!(
+            plist.contains("    <string>/home/me/my web/atune.yaml</string>\n"),
+            "{plist}"
+        );
+    }
+}
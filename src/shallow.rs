@@ -0,0 +1,135 @@
+//! Scans of the deeper levels of huge trees, see [crate::config::WatcherKind::Shallow]
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crossbeam::channel;
+use tracing::debug;
+
+use crate::state::Fnv;
+
+/// Periodically hashes the directory mtimes below every top-level directory of the roots,
+/// reporting the top-level directories whose hash changed as modified. Stops on drop
+pub struct Scanner {
+    _stop: channel::Sender<()>,
+}
+
+impl Scanner {
+    pub fn start(
+        roots: Vec<PathBuf>,
+        interval: Duration,
+        tx: channel::Sender<notify::Result<notify::Event>>,
+    ) -> Self {
+        let (stop, stopped) = channel::bounded::<()>(0);
+        std::thread::spawn(move || {
+            let mut hashes = roots
+                .iter()
+                .flat_map(|root| top_level_hashes(root))
+                .collect::<HashMap<_, _>>();
+            debug!(dirs = hashes.len(), "Scanning top-level directories");
+            while let Err(channel::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let current = roots
+                    .iter()
+                    .flat_map(|root| top_level_hashes(root))
+                    .collect::<HashMap<_, _>>();
+                let mut changed = current
+                    .iter()
+                    .filter(|(dir, hash)| hashes.get(*dir) != Some(hash))
+                    .map(|(dir, _)| dir.clone())
+                    .collect::<Vec<_>>();
+                // removed directories are reported by the native watch of their root
+                changed.sort();
+                hashes = current;
+                if changed.is_empty() {
+                    continue;
+                }
+                debug!(?changed, "Directories changed below the top level");
+                let ev =
+                    notify::Event::new(notify::EventKind::Modify(notify::event::ModifyKind::Any));
+                let ev = changed.into_iter().fold(ev, notify::Event::add_path);
+                if tx.send(Ok(ev)).is_err() {
+                    break;
+                }
+            }
+        });
+        Scanner { _stop: stop }
+    }
+}
+
+/// The top-level directories of `root`, without following symlinks
+pub fn top_level_dirs(root: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|e| e.path())
+        .collect()
+}
+
+fn top_level_hashes(root: &Path) -> Vec<(PathBuf, u64)> {
+    top_level_dirs(root)
+        .into_iter()
+        .map(|dir| {
+            let hash = subtree_hash(&dir);
+            (dir, hash)
+        })
+        .collect()
+}
+
+/// Hash of the paths and mtimes of the directories below `dir`, including itself. Adding,
+/// removing or renaming an entry changes the mtime of its directory, changing the hash. Writes to
+/// existing files don't, editors replacing files on save do
+pub fn subtree_hash(dir: &Path) -> u64 {
+    let mut hash = 0u64;
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let Ok(meta) = std::fs::symlink_metadata(&dir) else {
+            continue;
+        };
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let mut h = Fnv::default();
+        h.write(dir.as_os_str().as_encoded_bytes());
+        h.write(&mtime.as_nanos().to_le_bytes());
+        // independent of the order of the listing
+        hash = hash.wrapping_add(h.0);
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        stack.extend(
+            entries
+                .flatten()
+                .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+                .map(|e| e.path()),
+        );
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subtree_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let deep = dir.path().join("a/b/c");
+        std::fs::create_dir_all(&deep).unwrap();
+        let before = subtree_hash(&dir.path().join("a"));
+        assert_eq!(before, subtree_hash(&dir.path().join("a")));
+
+        // mtimes may have a coarse resolution
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(deep.join("new.txt"), "hello").unwrap();
+        assert_ne!(before, subtree_hash(&dir.path().join("a")));
+
+        assert_eq!(top_level_dirs(dir.path()), [dir.path().join("a")]);
+    }
+}
@@ -0,0 +1,128 @@
+//! Snapshot mode: every sync goes into a new release directory of dst, and the `current`
+//! symlink is switched to it once it's complete, see [crate::config::SyncMode::Snapshot]
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tracing::{debug, info};
+
+use crate::{
+    backup::{list_dir, remote_quote, remove_dirs},
+    config,
+};
+
+pub const RELEASES: &str = "releases";
+pub const CURRENT: &str = "current";
+
+/// Directory of the release `name` of `dst`
+pub fn release_path(dst: &Path, name: &str) -> PathBuf {
+    dst.join(RELEASES).join(name)
+}
+
+/// Create the releases directory of `dst`, returning the existing releases, oldest first.
+/// `ssh` are the arguments of ssh connecting to the host of a remote dst
+pub fn prepare(dst: &Path, ssh: &[String]) -> anyhow::Result<Vec<String>> {
+    let releases = dst.join(RELEASES);
+    match config::remote_dst(&releases) {
+        Some((host, path)) => {
+            let sh = xshell::Shell::new()?;
+            let mkdir = format!("mkdir -p -- {}", remote_quote(path));
+            xshell::cmd!(sh, "ssh -o BatchMode=yes {ssh...} {host} {mkdir}")
+                .quiet()
+                .run()
+                .with_context(|| format!("Failed to create {}", releases.display()))?;
+        }
+        None => std::fs::create_dir_all(&releases)
+            .with_context(|| format!("Failed to create {}", releases.display()))?,
+    }
+    list_dir(&releases, ssh)
+}
+
+/// Switch the `current` symlink of `dst` to the release `name`.
+///
+/// The new link is created next to the old one and renamed over it, so `current` always points
+/// to a complete release
+pub fn activate(dst: &Path, ssh: &[String], name: &str) -> anyhow::Result<()> {
+    let target = format!("{RELEASES}/{name}");
+    match config::remote_dst(dst) {
+        Some((host, path)) => {
+            let sh = xshell::Shell::new()?;
+            let script = format!(
+                "cd {} && ln -sfn {target} .{CURRENT}.tmp && mv -Tf .{CURRENT}.tmp {CURRENT}",
+                remote_quote(path)
+            );
+            xshell::cmd!(sh, "ssh -o BatchMode=yes {ssh...} {host} {script}")
+                .quiet()
+                .run()
+                .with_context(|| format!("Failed to switch {host}:{path}/{CURRENT}"))?;
+        }
+        None => symlink_atomic(&target, &dst.join(CURRENT))?,
+    }
+    info!(release = name, "Switched to the new release");
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink_atomic(target: &str, link: &Path) -> anyhow::Result<()> {
+    let tmp = link.with_file_name(format!(".{CURRENT}.tmp"));
+    let _ = std::fs::remove_file(&tmp);
+    std::os::unix::fs::symlink(target, &tmp)
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    std::fs::rename(&tmp, link).with_context(|| format!("Failed to switch {}", link.display()))
+}
+
+#[cfg(not(unix))]
+fn symlink_atomic(_target: &str, _link: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("Snapshot mode needs symbolic links, which are only supported on unix")
+}
+
+/// Remove the oldest releases of `dst`, keeping `keep` of them and the current one
+pub fn prune(dst: &Path, ssh: &[String], keep: usize, current: &str) -> anyhow::Result<()> {
+    let releases = dst.join(RELEASES);
+    let names = list_dir(&releases, ssh)?;
+    let expired = old_releases(&names, keep, current);
+    if expired.is_empty() {
+        return Ok(());
+    }
+    debug!(?expired, "Removing old releases");
+    remove_dirs(&releases, ssh, &expired)
+}
+
+/// `names` are sorted oldest first
+fn old_releases<'a>(names: &'a [String], keep: usize, current: &str) -> Vec<&'a String> {
+    let keep_from = names.len().saturating_sub(keep);
+    names[..keep_from]
+        .iter()
+        .filter(|n| n.as_str() != current)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_activate_and_prune() {
+        let dst = tempfile::tempdir().unwrap();
+        let names = ["20240101T000000Z", "20240102T000000Z", "20240103T000000Z"];
+        for name in names {
+            assert!(prepare(dst.path(), &[]).is_ok());
+            std::fs::create_dir(release_path(dst.path(), name)).unwrap();
+            std::fs::write(release_path(dst.path(), name).join("v"), name).unwrap();
+            activate(dst.path(), &[], name).unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(dst.path().join("current/v")).unwrap(),
+            names[2]
+        );
+        prune(dst.path(), &[], 2, names[2]).unwrap();
+        assert_eq!(prepare(dst.path(), &[]).unwrap(), &names[1..]);
+    }
+
+    #[test]
+    fn test_old_releases_keep_current() {
+        let names = ["a", "b", "c"].map(str::to_owned);
+        assert_eq!(old_releases(&names, 1, "a"), vec!["b"]);
+        assert_eq!(old_releases(&names, 5, "c"), Vec::<&String>::new());
+    }
+}
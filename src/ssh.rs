@@ -0,0 +1,194 @@
+//! Shared SSH master connections, so syncs and remote commands to a host skip the handshake.
+//! The ssh options of the hosts are resolved per sync, see [crate::config::FileSync::ssh_args]
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::state::Fnv;
+
+/// Control socket of the master connection to `host` with the ssh arguments `args`, so syncs
+/// connecting with different options don't share a master.
+///
+/// Placed in `$XDG_RUNTIME_DIR` if set, as it's private to the user, otherwise in the temp dir
+pub fn control_path(host: &str, args: &[String]) -> PathBuf {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let mut hash = Fnv::default();
+    hash.write(host.as_bytes());
+    for arg in args {
+        hash.write(&[0]);
+        hash.write(arg.as_bytes());
+    }
+    // socket paths are limited to ~100 bytes, so the host is hashed
+    dir.join(format!("atune-ssh-{:016x}", hash.0))
+}
+
+/// Remote shell for rsync (`RSYNC_RSH`) with the ssh arguments `args` of `host`, multiplexing
+/// over the master connection to `host` if `multiplex` is set.
+///
+/// If the master isn't running, then ssh connects on its own
+pub fn rsync_rsh(host: &str, args: &[String], multiplex: bool) -> String {
+    let mut args = args.to_vec();
+    if multiplex {
+        args.extend(multiplex_args(host, &args));
+    }
+    std::iter::once("ssh".to_owned())
+        .chain(args.iter().map(|a| shell_words::quote(a).into_owned()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// ssh options multiplexing over the master connection to `host` with the ssh arguments `args`.
+///
+/// If the master isn't running, then ssh connects on its own
+pub fn multiplex_args(host: &str, args: &[String]) -> [String; 4] {
+    [
+        "-o".to_owned(),
+        "ControlMaster=auto".to_owned(),
+        "-o".to_owned(),
+        format!("ControlPath={}", control_path(host, args).display()),
+    ]
+}
+
+/// Command line running `command` in the login shell of a remote host, in the directory `cwd`
+/// if given, with the environment variables `env`
+pub fn remote_script<'a>(
+    command: &str,
+    cwd: Option<&Path>,
+    env: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> String {
+    let mut script = String::new();
+    for (k, v) in env {
+        script.push_str(&format!("export {k}={}; ", shell_words::quote(v)));
+    }
+    if let Some(cwd) = cwd {
+        let cwd = crate::backup::remote_quote(&cwd.to_string_lossy());
+        script.push_str(&format!("cd {cwd} && "));
+    }
+    script.push_str(command);
+    script
+}
+
+/// Master connections owned by atune, closed on drop
+#[derive(Debug, Default)]
+pub struct Masters {
+    conns: Vec<(String, Vec<String>, Child)>,
+}
+
+impl Masters {
+    /// Open a master connection to every host with its ssh arguments. Failures are logged, syncs
+    /// to the host then connect on their own
+    pub fn start<'a>(hosts: impl IntoIterator<Item = (&'a str, &'a [String])>) -> Self {
+        let mut masters = Self::default();
+        for (host, args) in hosts {
+            let path = control_path(host, args);
+            if check(host, args) {
+                debug!(host, "Reusing the running ssh master connection");
+                continue;
+            }
+            // left behind by a master that didn't exit cleanly
+            if path.exists() {
+                let _ = std::fs::remove_file(&path);
+            }
+            let child = Command::new("ssh")
+                .args(["-M", "-N", "-o", "BatchMode=yes", "-o", "ControlMaster=yes"])
+                .args(args)
+                .arg("-o")
+                .arg(format!("ControlPath={}", path.display()))
+                .arg(host)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .spawn();
+            match child {
+                Ok(child) => {
+                    info!(host, "Started ssh master connection");
+                    masters.conns.push((host.to_owned(), args.to_vec(), child));
+                }
+                Err(err) => warn!(?err, host, "Failed to start ssh master connection"),
+            }
+        }
+        masters
+    }
+}
+
+impl Drop for Masters {
+    fn drop(&mut self) {
+        for (host, args, mut child) in self.conns.drain(..) {
+            let path = control_path(&host, &args);
+            let exited = Command::new("ssh")
+                .args(&args)
+                .args(["-O", "exit", "-o"])
+                .arg(format!("ControlPath={}", path.display()))
+                .arg(&host)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|s| s.success());
+            if !exited {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+            debug!(host, "Stopped ssh master connection");
+        }
+    }
+}
+
+/// Whether a master connection to `host` with the ssh arguments `args` is running
+fn check(host: &str, args: &[String]) -> bool {
+    Command::new("ssh")
+        .args(args)
+        .args(["-O", "check", "-o"])
+        .arg(format!(
+            "ControlPath={}",
+            control_path(host, args).display()
+        ))
+        .arg(host)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rsync_rsh() {
+        let opts = crate::config::SshOptions {
+            proxy_jump: Some("admin@bastion".to_owned()),
+            port: Some(2222),
+            ..Default::default()
+        };
+        assert_eq!(
+            rsync_rsh("test-rsh-host", &opts.args(), false),
+            "ssh -J admin@bastion -p 2222"
+        );
+        assert_eq!(rsync_rsh("other-host", &[], false), "ssh");
+        let multiplexed = rsync_rsh("test-rsh-host", &opts.args(), true);
+        assert!(multiplexed.starts_with("ssh -J admin@bastion -p 2222 -o "));
+        assert!(multiplexed.contains("ControlMaster=auto"));
+        assert_ne!(
+            control_path("test-rsh-host", &opts.args()),
+            control_path("test-rsh-host", &[])
+        );
+    }
+
+    #[test]
+    fn test_remote_script() {
+        assert_eq!(remote_script("make", None, []), "make");
+        assert_eq!(
+            remote_script(
+                "systemctl --user restart app",
+                Some(Path::new("~/my app")),
+                [("MODE", "dev build")]
+            ),
+            "export MODE='dev build'; cd ~/'my app' && systemctl --user restart app"
+        );
+    }
+}
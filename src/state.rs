@@ -0,0 +1,376 @@
+//! Persistent record of the last successful sync of every entry
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use crossbeam::channel;
+use tracing::{debug, warn};
+
+use crate::sync::{ChangeKind, ParsedSync};
+
+/// Location of the state file: `$XDG_STATE_HOME/atune/state.json`, falling back to
+/// `~/.local/state` or `%LOCALAPPDATA%` on Windows
+pub fn state_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+            } else {
+                std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state"))
+            }
+        })?;
+    Some(dir.join("atune").join("state.json"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct StateEntry {
+    pub config: PathBuf,
+    pub project: String,
+    pub src: PathBuf,
+    /// Unix timestamp in seconds
+    pub last_success: u64,
+    /// [fingerprint] of the entry at the time of the sync
+    pub fingerprint: String,
+    /// an initial sync of the entry succeeded, with its `on: Init` commands
+    #[serde(default)]
+    pub initialized: bool,
+}
+
+impl StateEntry {
+    pub fn last_success(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.last_success)
+    }
+}
+
+#[derive(Debug, Default, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct SyncState {
+    #[serde(default)]
+    pub syncs: Vec<StateEntry>,
+}
+
+/// Successful syncs recorded by [record_success_in_background]
+static RECORDER: OnceLock<channel::Sender<Record>> = OnceLock::new();
+
+enum Record {
+    Success {
+        config: PathBuf,
+        project: String,
+        sync: Box<ParsedSync>,
+        initialized: bool,
+    },
+    Flush(channel::Sender<()>),
+}
+
+impl SyncState {
+    /// Load the state file. Missing or corrupt files yield an empty state
+    pub fn load() -> Self {
+        let Some(path) = state_path() else {
+            return Self::default();
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|err| {
+                warn!(?err, ?path, "Failed to parse the state file, ignoring it");
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn get(&self, config: &Path, project: &str, src: &Path) -> Option<&StateEntry> {
+        let config = normalize(config);
+        self.syncs
+            .iter()
+            .find(|e| e.config == config && e.project == project && e.src == src)
+    }
+
+    /// Lock the state file for a read-modify-write, held until the file is dropped. An flock on
+    /// `state.lock` next to it, so concurrent watches and `sync-once` runs don't lose each
+    /// other's entries
+    fn lock() -> anyhow::Result<File> {
+        let path = state_path().context("Failed to determine the state directory")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create the state directory")?;
+        }
+        let path = path.with_file_name("state.lock");
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open the lock file {}", path.display()))?;
+        file.lock()
+            .with_context(|| format!("Failed to lock {}", path.display()))?;
+        Ok(file)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let path = state_path().context("Failed to determine the state directory")?;
+        let mut s = serde_json::to_string(self).context("Failed to serialize the state")?;
+        s.push('\n');
+        // replace atomically, so concurrent readers never see a partial file
+        let tmp = path.with_extension(format!("json.{}", std::process::id()));
+        std::fs::write(&tmp, s).context("Failed to write the state file")?;
+        std::fs::rename(&tmp, &path).context("Failed to replace the state file")?;
+        Ok(())
+    }
+}
+
+/// Record a successful sync of the entry, `initialized` if it ran the `on: Init` commands.
+/// Errors are logged, as the state is only used to skip unneeded syncs
+pub fn record_success(
+    config: &Path,
+    project: &str,
+    sync: &ParsedSync,
+    fingerprint: String,
+    initialized: bool,
+) {
+    let _lock = match SyncState::lock() {
+        Ok(lock) => lock,
+        Err(err) => {
+            warn!(?err, "Failed to save the sync state");
+            return;
+        }
+    };
+    let mut state = SyncState::load();
+    let config = normalize(config);
+    let mut was_initialized = false;
+    state.syncs.retain(|e| {
+        let same = e.config == config && e.project == project && e.src == sync.src;
+        was_initialized |= same && e.initialized;
+        !same
+    });
+    state.syncs.push(StateEntry {
+        config,
+        project: project.to_owned(),
+        src: sync.src.clone(),
+        last_success: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        fingerprint,
+        initialized: initialized || was_initialized,
+    });
+    match state.save() {
+        Ok(()) => debug!(project, src = ?sync.src, "Recorded sync state"),
+        Err(err) => warn!(?err, "Failed to save the sync state"),
+    }
+}
+
+/// [record_success] on a thread of its own, fingerprinting src there, so the caller isn't held
+/// up by large trees. The syncs are recorded in order, see [flush]
+pub fn record_success_in_background(
+    config: &Path,
+    project: &str,
+    sync: &ParsedSync,
+    initialized: bool,
+) {
+    let recorder = RECORDER.get_or_init(|| {
+        let (tx, rx) = channel::unbounded();
+        std::thread::spawn(move || {
+            for record in rx {
+                match record {
+                    Record::Success {
+                        config,
+                        project,
+                        sync,
+                        initialized,
+                    } => {
+                        let fingerprint = fingerprint(&sync);
+                        record_success(&config, &project, &sync, fingerprint, initialized);
+                    }
+                    Record::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        tx
+    });
+    let _ = recorder.send(Record::Success {
+        config: config.to_owned(),
+        project: project.to_owned(),
+        sync: Box::new(sync.clone()),
+        initialized,
+    });
+}
+
+/// Wait until the syncs passed to [record_success_in_background] are recorded
+pub fn flush() {
+    let Some(recorder) = RECORDER.get() else {
+        return;
+    };
+    let (done_tx, done) = channel::bounded(1);
+    if recorder.send(Record::Flush(done_tx)).is_ok() {
+        let _ = done.recv();
+    }
+}
+
+/// Hash of the sync settings and the path, size and modification time of every file in src
+/// that the entry syncs, following `recursive` and its filters.
+///
+/// Changes if the entry needs to be synced again
+pub fn fingerprint(sync: &ParsedSync) -> String {
+    let mut hash = Fnv::default();
+    // every setting, from the dst and the hooks to the filters
+    hash.write(format!("{sync:?}").as_bytes());
+    let mut files = Vec::new();
+    collect_files(sync, Path::new(""), &mut files);
+    files.sort();
+    for (path, len, mtime) in files {
+        hash.write(path.as_os_str().as_encoded_bytes());
+        hash.write(&len.to_le_bytes());
+        hash.write(&mtime.to_le_bytes());
+    }
+    format!("{:016x}", hash.0)
+}
+
+/// The files below `rel` in the src of `sync`
+fn collect_files(sync: &ParsedSync, rel: &Path, files: &mut Vec<(PathBuf, u64, u128)>) {
+    let path = sync.src.join(rel);
+    let Ok(meta) = std::fs::symlink_metadata(&path) else {
+        return;
+    };
+    if meta.is_dir() {
+        // a non-recursive sync only copies the files directly in src
+        if !sync.recursive && !rel.as_os_str().is_empty() {
+            return;
+        }
+        if let Ok(entries) = std::fs::read_dir(&path) {
+            for e in entries.flatten() {
+                let rel = rel.join(e.file_name());
+                if sync.filter.matches(&sync.src, &rel, ChangeKind::Changed) {
+                    collect_files(sync, &rel, files);
+                }
+            }
+        }
+    } else {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default()
+            .as_nanos();
+        files.push((path, meta.len(), mtime));
+    }
+}
+
+pub(crate) fn normalize(config: &Path) -> PathBuf {
+    crate::platform::canonicalize(config).unwrap_or_else(|_| config.to_owned())
+}
+
+/// 64 bit FNV-1a, stable across Rust versions unlike `DefaultHasher`
+pub(crate) struct Fnv(pub(crate) u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Fnv {
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+        // separate the fields
+        self.0 ^= 0xff;
+        self.0 = self.0.wrapping_mul(0x100000001b3);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_changes_with_content() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+        let sync = ParsedSync {
+            enabled: true,
+            src: dir.path().to_owned(),
+            recursive: true,
+            symlinks: None,
+            dst: Some("/tmp/dst".into()),
+            rsync_flags: vec![],
+            partial: false,
+            manifest: false,
+            password_file: None,
+            password: None,
+            ssh_multiplexing: false,
+            progress: false,
+            bwlimit: None,
+            protect_dst: false,
+            confirm_delete: None,
+            max_delete: None,
+            max_file_size: None,
+            warn_file_count: None,
+            agent: None,
+            verify: false,
+            atomic: false,
+            create_dst: false,
+            chmod: None,
+            chown: None,
+            dst_owner: None,
+            dst_mode: None,
+            compress: None,
+            lock_group: None,
+            shared_copies: vec![],
+            share_relay: false,
+            ssh_args: Default::default(),
+            backup: None,
+            mode: Default::default(),
+            keep: 5,
+            priority: 0,
+            min_interval: None,
+            niceness: Default::default(),
+            initial_sync: Default::default(),
+            on_init_when: Default::default(),
+            schedule: None,
+            fan_out: Vec::new(),
+            filter: Default::default(),
+            backend: Default::default(),
+            on_sync: vec![],
+            on_init: vec![],
+            on_delete: vec![],
+            watch_dst: None,
+            on_drift: vec![],
+            on_failure: vec![],
+        };
+        let before = fingerprint(&sync);
+        assert_eq!(before, fingerprint(&sync));
+
+        std::fs::write(dir.path().join("b.txt"), "b").unwrap();
+        let after = fingerprint(&sync);
+        assert_ne!(before, after);
+
+        // files the entry doesn't sync
+        std::fs::create_dir(dir.path().join("target")).unwrap();
+        std::fs::write(dir.path().join("target/out"), "out").unwrap();
+        let mut excluded = sync.clone();
+        excluded.filter.exclude = vec!["target/".to_owned()];
+        let excluded_before = fingerprint(&excluded);
+        std::fs::write(dir.path().join("target/out2"), "out").unwrap();
+        assert_eq!(excluded_before, fingerprint(&excluded));
+        let mut flat = sync.clone();
+        flat.recursive = false;
+        let flat_before = fingerprint(&flat);
+        std::fs::write(dir.path().join("target/out3"), "out").unwrap();
+        assert_eq!(flat_before, fingerprint(&flat));
+
+        // settings
+        let mut hooked = sync.clone();
+        hooked.on_sync = vec![crate::config::CommandConfig {
+            command: "make".to_owned(),
+            ..Default::default()
+        }];
+        assert_ne!(fingerprint(&sync), fingerprint(&hooked));
+    }
+}
@@ -0,0 +1,293 @@
+//! Counters of the syncs run by `watch`, summarized when it stops and accumulated in
+//! `stats.json` next to the state file for `atune stats`
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Context;
+use tracing::warn;
+
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+pub struct EntryStats {
+    pub config: PathBuf,
+    pub project: String,
+    pub src: PathBuf,
+    pub syncs: u64,
+    pub failures: u64,
+    /// Sent and received by rsync, as reported by its summary line
+    pub bytes: u64,
+    /// Sum of the times from the first change to the end of the sync, in milliseconds
+    pub latency_ms: u64,
+    /// Number of syncs summed into `latency_ms`, the initial syncs have no first change
+    pub latency_count: u64,
+}
+
+impl EntryStats {
+    pub fn average_latency(&self) -> Option<Duration> {
+        (self.latency_count > 0)
+            .then(|| Duration::from_millis(self.latency_ms / self.latency_count))
+    }
+
+    fn add(&mut self, other: &EntryStats) {
+        self.syncs += other.syncs;
+        self.failures += other.failures;
+        self.bytes += other.bytes;
+        self.latency_ms += other.latency_ms;
+        self.latency_count += other.latency_count;
+    }
+}
+
+#[derive(Debug, Default, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct Stats {
+    #[serde(default)]
+    pub syncs: Vec<EntryStats>,
+}
+
+// serializes the read-modify-write of the syncs of this process
+static STATS_LOCK: Mutex<()> = Mutex::new(());
+
+impl Stats {
+    /// Load the stats file. Missing or corrupt files yield empty stats
+    pub fn load() -> Self {
+        let Some(path) = stats_path() else {
+            return Self::default();
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|err| {
+                warn!(?err, ?path, "Failed to parse the stats file, ignoring it");
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// The entries of the config
+    pub fn of_config(&self, config: &Path) -> impl Iterator<Item = &EntryStats> {
+        let config = crate::state::normalize(config);
+        self.syncs.iter().filter(move |e| e.config == config)
+    }
+
+    /// Count a finished sync of the entry
+    pub fn record(
+        &mut self,
+        config: &Path,
+        project: &str,
+        src: &Path,
+        success: bool,
+        bytes: u64,
+        latency: Option<Duration>,
+    ) {
+        let config = crate::state::normalize(config);
+        let i = match self
+            .syncs
+            .iter()
+            .position(|e| e.config == config && e.project == project && e.src == src)
+        {
+            Some(i) => i,
+            None => {
+                self.syncs.push(EntryStats {
+                    config,
+                    project: project.to_owned(),
+                    src: src.to_owned(),
+                    ..Default::default()
+                });
+                self.syncs.len() - 1
+            }
+        };
+        let e = &mut self.syncs[i];
+        e.syncs += 1;
+        e.failures += u64::from(!success);
+        e.bytes += bytes;
+        if let Some(latency) = latency {
+            e.latency_ms += latency.as_millis() as u64;
+            e.latency_count += 1;
+        }
+    }
+
+    /// Sum of all entries
+    pub fn total<'a>(entries: impl IntoIterator<Item = &'a EntryStats>) -> EntryStats {
+        let mut total = EntryStats::default();
+        for e in entries {
+            total.add(e);
+        }
+        total
+    }
+
+    /// Summary of the counters, with the entries synced most often
+    pub fn summary(&self) -> String {
+        let total = Self::total(&self.syncs);
+        let mut s = format!(
+            "{} syncs, {} failed, {} transferred",
+            total.syncs,
+            total.failures,
+            format_bytes(total.bytes)
+        );
+        if let Some(latency) = total.average_latency() {
+            s.push_str(&format!(", {latency:.1?} from change to synced on average"));
+        }
+        let mut busiest = self.syncs.iter().collect::<Vec<_>>();
+        busiest.sort_by_key(|e| std::cmp::Reverse(e.syncs));
+        for e in busiest.iter().take(5) {
+            s.push_str(&format!(
+                "\n  {}: {} syncs, {} failed, {}",
+                crate::sync::sync_label(&e.project, &e.src),
+                e.syncs,
+                e.failures,
+                format_bytes(e.bytes)
+            ));
+        }
+        s
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let path = stats_path().context("Failed to determine the state directory")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create the state directory")?;
+        }
+        let mut s = serde_json::to_string(self).context("Failed to serialize the stats")?;
+        s.push('\n');
+        // replace atomically, so concurrent readers never see a partial file
+        let tmp = path.with_extension(format!("json.{}", std::process::id()));
+        std::fs::write(&tmp, s).context("Failed to write the stats file")?;
+        std::fs::rename(&tmp, &path).context("Failed to replace the stats file")?;
+        Ok(())
+    }
+}
+
+/// `stats.json` next to the [crate::state::state_path]
+pub fn stats_path() -> Option<PathBuf> {
+    Some(crate::state::state_path()?.with_file_name("stats.json"))
+}
+
+/// Add a finished sync to the stats file. Errors are logged, the stats are informational
+pub fn record(
+    config: &Path,
+    project: &str,
+    src: &Path,
+    success: bool,
+    bytes: u64,
+    latency: Option<Duration>,
+) {
+    let _lock = STATS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut stats = Stats::load();
+    stats.record(config, project, src, success, bytes, latency);
+    if let Err(err) = stats.save() {
+        warn!(?err, "Failed to save the sync stats");
+    }
+}
+
+/// Bytes sent and received by rsync, from the `sent 1.23K bytes  received 35 bytes` line it
+/// prints with `-v`. Zero if the output has no such line
+pub fn transferred_bytes<'a>(output: impl IntoIterator<Item = &'a str>) -> u64 {
+    let Some(line) = output
+        .into_iter()
+        .filter(|l| l.starts_with("sent ") && l.contains(" received "))
+        .last()
+    else {
+        return 0;
+    };
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    words
+        .windows(2)
+        .filter(|w| w[0] == "sent" || w[0] == "received")
+        .map(|w| parse_size(w[1]))
+        .sum()
+}
+
+/// A size printed by rsync: digits with thousands separators, or with `-h` a decimal followed
+/// by a unit of 1000
+fn parse_size(s: &str) -> u64 {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c),
+        _ => (s, 'B'),
+    };
+    let scale = match unit.to_ascii_uppercase() {
+        'K' => 1e3,
+        'M' => 1e6,
+        'G' => 1e9,
+        'T' => 1e12,
+        _ => 1.0,
+    };
+    let number = number.replace(',', "");
+    number
+        .parse::<f64>()
+        .map(|n| (n * scale) as u64)
+        .unwrap_or_default()
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS {
+        if value < 1000.0 {
+            break;
+        }
+        value /= 1000.0;
+        unit = u;
+    }
+    format!("{value:.1} {unit}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transferred_bytes() {
+        let output = [
+            "sending incremental file list",
+            "a.txt",
+            "",
+            "sent 1.23K bytes  received 35 bytes  2.52K bytes/sec",
+            "total size is 4.56M  speedup is 3,705.12",
+        ];
+        assert_eq!(transferred_bytes(output), 1265);
+        assert_eq!(
+            transferred_bytes(["sent 12,345 bytes  received 1,000 bytes  26,690.00 bytes/sec"]),
+            13345
+        );
+        assert_eq!(transferred_bytes(["sent a letter"]), 0);
+        assert_eq!(format_bytes(999), "999 B");
+        assert_eq!(format_bytes(1_265), "1.3 KB");
+        assert_eq!(format_bytes(4_560_000), "4.6 MB");
+    }
+
+    #[test]
+    fn test_record() {
+        let mut stats = Stats::default();
+        let config = Path::new("/config.yaml");
+        stats.record(config, "p", Path::new("/a"), true, 10, None);
+        stats.record(
+            config,
+            "p",
+            Path::new("/a"),
+            false,
+            0,
+            Some(Duration::from_millis(300)),
+        );
+        stats.record(
+            config,
+            "p",
+            Path::new("/b"),
+            true,
+            5,
+            Some(Duration::from_millis(100)),
+        );
+        let total = Stats::total(stats.of_config(config));
+        assert_eq!((total.syncs, total.failures, total.bytes), (3, 1, 15));
+        assert_eq!(total.average_latency(), Some(Duration::from_millis(200)));
+        assert_eq!(stats.syncs[0].syncs, 2);
+        assert_eq!(
+            Stats::total(stats.of_config(Path::new("/other.yaml"))).syncs,
+            0
+        );
+    }
+}
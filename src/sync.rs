@@ -1,10 +1,13 @@
 use crate::config::{self, CommandConfig, Config};
+use crate::in_process::{Cancelled, ProcessRunner, SyncJob, SyncTask, WorkerPool};
+use crate::rusage::ResourceUsage;
 use std::{
-    collections::{HashMap, HashSet},
-    ffi::OsStr,
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
     process,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::Context;
@@ -12,65 +15,899 @@ use crossbeam::{channel, select};
 use notify::Watcher;
 use tracing::{debug, error, info, warn};
 
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ChangeKind {
+    Changed,
+    Removed,
+}
+
 #[derive(Debug)]
 struct SyncOneRequest {
     path: PathBuf,
+    kind: ChangeKind,
+    /// the path was renamed from this one
+    renamed_from: Option<PathBuf>,
+    /// see [SyncChanges::id]
+    id: u64,
+    received: Instant,
+}
+
+/// Environment variable passing [SyncChanges::id] to `sync-project` and the hooks
+pub const CHANGE_ID_ENV: &str = "ATUNE_CHANGE_ID";
+
+/// Unique within the process
+pub(crate) fn next_change_id() -> u64 {
+    static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Paths changed since the last sync of an entry
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncChanges {
+    pub changed: BTreeSet<PathBuf>,
+    pub deleted: BTreeSet<PathBuf>,
+    /// Paths moved within src, from the old path in `deleted` to the new one in `changed`
+    pub renamed: BTreeMap<PathBuf, PathBuf>,
+    /// Id of the first filesystem event of the changes, logged as `change_id` from the watcher
+    /// to the hooks, so the time it took to sync a change can be traced
+    pub id: Option<u64>,
+}
+
+impl SyncChanges {
+    pub(crate) fn add(&mut self, path: PathBuf, kind: ChangeKind) {
+        match kind {
+            ChangeKind::Changed => {
+                // the path was recreated, e.g. by an editor's atomic save
+                self.deleted.remove(&path);
+                self.renamed.remove(&path);
+                self.changed.insert(path);
+            }
+            ChangeKind::Removed => {
+                self.changed.remove(&path);
+                self.renamed.retain(|_, to| *to != path);
+                self.deleted.insert(path);
+            }
+        }
+    }
+
+    /// Record the move of `from` to `to`, both inside the src of the entry
+    pub(crate) fn rename(&mut self, from: PathBuf, to: PathBuf) {
+        self.add(from.clone(), ChangeKind::Removed);
+        self.add(to.clone(), ChangeKind::Changed);
+        self.renamed.insert(from, to);
+    }
+
+    /// Merge changes that happened before `self`
+    pub(crate) fn merge_older(&mut self, older: SyncChanges) {
+        self.id = older.id.or(self.id);
+        for p in older.changed {
+            if !self.deleted.contains(&p) {
+                self.changed.insert(p);
+            }
+        }
+        for p in older.deleted {
+            if !self.changed.contains(&p) {
+                self.deleted.insert(p);
+            }
+        }
+        for (from, to) in older.renamed {
+            if self.deleted.contains(&from) && self.changed.contains(&to) {
+                self.renamed.entry(from).or_insert(to);
+            }
+        }
+    }
+}
+
+/// Event emitted by a running watch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    SyncStarted {
+        project: String,
+        src: PathBuf,
+        /// the sync runs the init commands too
+        initialize: bool,
+    },
+    SyncFinished {
+        project: String,
+        src: PathBuf,
+        result: Result<(), SyncError>,
+        duration: Duration,
+        /// CPU time and memory of rsync and the hooks
+        usage: ResourceUsage,
+    },
+    /// Progress of the transfer of a running sync, reported if [WatchOptions::progress] is set
+    SyncProgress {
+        project: String,
+        src: PathBuf,
+        progress: crate::output::Progress,
+    },
+    /// An in-progress sync was killed, because a new change arrived and `restart` is enabled
+    SyncCancelled { project: String, src: PathBuf },
+    /// A hook command of the sync failed. Followed by the corresponding `SyncFinished`
+    HookFailed { project: String, src: PathBuf },
+    /// The filesystem watcher of the project failed, changes are not picked up until it recovers
+    WatcherDegraded { project: String, error: String },
+    /// The filesystem watcher was re-registered after a failure. A full sync follows
+    WatcherRecovered { project: String },
+    /// The initial syncs of all projects finished and the `on_start` commands ran
+    Ready,
+    /// The project was paused by [WatchControl::Pause]
+    Paused { project: String },
+    /// The project was resumed by [WatchControl::Resume], its queued changes are synced
+    Resumed { project: String },
+    /// Something other than atune changed these paths of the watched dst of the entry, see
+    /// [config::FileSync::watch_dst]
+    DstDrift {
+        project: String,
+        src: PathBuf,
+        paths: Vec<PathBuf>,
+    },
+}
+
+/// Command for a running watch, see [WatchOptions::control]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchControl {
+    /// Don't start new syncs of the project. Its changes are queued until it's resumed
+    Pause {
+        project: String,
+    },
+    Resume {
+        project: String,
+    },
+    /// Sync all entries of the project, as if all of their files changed
+    Trigger {
+        project: String,
+    },
+    /// `path` changed, e.g. reported by an editor on save. It's synced right away, without
+    /// waiting for the filesystem watcher and the debounce
+    Changed {
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncError {
+    /// A hook command failed
+    HookFailed,
+    /// The sync was refused, because dst has changes that didn't come from atune
+    DstConflict,
+    /// rsync couldn't reach dst, see [Unreachable]
+    Unreachable,
+    /// The sync would delete more files than the threshold and wasn't confirmed, see
+    /// [crate::confirm::DeleteRefused]
+    DeleteRefused,
+    /// The sync would delete more files than `max_delete`, see [crate::confirm::DeleteLimit]
+    DeleteLimit,
+    /// The sync process failed
+    Failed { exit_code: Option<i32> },
+}
+
+impl SyncError {
+    /// Short name of the error, e.g. for `ATUNE_SYNC_ERROR` and the JSON events
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SyncError::HookFailed => "hook_failed",
+            SyncError::DstConflict => "dst_conflict",
+            SyncError::DeleteRefused => "delete_refused",
+            SyncError::DeleteLimit => "delete_limit",
+            SyncError::Unreachable => "unreachable",
+            SyncError::Failed { .. } => "failed",
+        }
+    }
+}
+
+/// Exit code of `sync-project` when a hook command failed
+pub const EXIT_HOOK_FAILED: i32 = 3;
+/// Exit code of `sync-project` when the sync was refused because of a [DstConflict]
+pub const EXIT_DST_CONFLICT: i32 = 4;
+/// Exit code of `sync-project` when dst was [Unreachable]
+pub const EXIT_UNREACHABLE: i32 = 5;
+/// Exit code of `sync-project` when the deletions weren't confirmed, see
+/// [crate::confirm::DeleteRefused]
+pub const EXIT_DELETE_REFUSED: i32 = 6;
+/// Exit code of `sync-project` when the sync would delete more files than `max_delete`, see
+/// [crate::confirm::DeleteLimit]
+pub const EXIT_DELETE_LIMIT: i32 = 7;
+
+/// Exit codes of rsync meaning that it lost or never had a connection to dst: socket and
+/// protocol stream errors, timeouts, and failures of ssh itself
+const RSYNC_UNREACHABLE: &[i32] = &[10, 12, 30, 35, 255];
+/// Exit code of rsync when `--max-delete` stopped the deletions
+const RSYNC_MAX_DELETE: i32 = 25;
+
+/// rsync stopped at `--max-delete`, turned into a [crate::confirm::DeleteLimit] by [transfer]
+#[derive(Debug)]
+struct MaxDeleteReached;
+
+impl std::fmt::Display for MaxDeleteReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rsync stopped at --max-delete")
+    }
+}
+
+impl std::error::Error for MaxDeleteReached {}
+
+/// Returned by [execute_sync] when rsync couldn't reach dst, e.g. while offline. `watch` keeps
+/// the changes for a retry, see [crate::offline]
+#[derive(Debug)]
+pub struct Unreachable {
+    pub exit_code: i32,
+}
+
+impl std::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "dst is unreachable, rsync failed with exit code {}",
+            self.exit_code
+        )
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+/// Returned by [execute_sync] when a hook command failed
+#[derive(Debug)]
+pub struct HookFailed {
+    pub command: String,
+}
+
+/// Details of an [execute_sync] run, for machine readable reports
+#[derive(Debug, Default, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct SyncOutput {
+    /// None if rsync didn't run
+    pub rsync_exit_code: Option<i32>,
+    /// Parsed from the `--stats` output of rsync
+    pub stats: Option<RsyncStats>,
+    pub hooks: Vec<HookResult>,
+}
+
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize,
+)]
+pub struct RsyncStats {
+    pub files_transferred: u64,
+    pub files_created: u64,
+    pub files_deleted: u64,
+    /// Total size of the transferred files
+    pub transferred_size: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl RsyncStats {
+    pub fn parse(stats: &str) -> Self {
+        let mut result = Self::default();
+        for line in stats.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            // numbers may contain thousands separators, and are followed by units or details
+            let value = value
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .filter(char::is_ascii_digit)
+                .collect::<String>()
+                .parse()
+                .unwrap_or_default();
+            let field = match key.trim() {
+                // older versions omit `regular`
+                "Number of regular files transferred" | "Number of files transferred" => {
+                    &mut result.files_transferred
+                }
+                "Number of created files" => &mut result.files_created,
+                "Number of deleted files" => &mut result.files_deleted,
+                "Total transferred file size" => &mut result.transferred_size,
+                "Total bytes sent" => &mut result.bytes_sent,
+                "Total bytes received" => &mut result.bytes_received,
+                _ => continue,
+            };
+            *field = value;
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct HookResult {
+    pub command: String,
+    pub success: bool,
+    pub duration: Duration,
+}
+
+impl std::fmt::Display for HookFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command failed\n{}", self.command)
+    }
+}
+
+impl std::error::Error for HookFailed {}
+
+/// Returned by [execute_sync] when `protect_dst` found changes of dst that didn't come from atune
+#[derive(Debug)]
+pub struct DstConflict {
+    /// paths in dst rsync would delete or overwrite, relative to the root of the transfer
+    pub paths: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for DstConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Refusing to sync, dst has changes that didn't come from atune:"
+        )?;
+        for p in self.paths.iter() {
+            write!(f, "\n{}", p.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DstConflict {}
+
+/// Returned by [execute_sync] when dst differs from src after the sync, see
+/// [config::FileSync::verify]
+#[derive(Debug)]
+pub struct Drift {
+    /// differing paths, relative to the root of the transfer
+    pub paths: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "dst differs from src after the sync:")?;
+        for p in self.paths.iter() {
+            write!(f, "\n{}", p.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Drift {}
+
+/// Options of a [watch] session
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    pub rsync: Option<PathBuf>,
+    /// atune executable invoked to run the syncs.
+    /// If omitted, then the current executable is used
+    pub executable: Option<PathBuf>,
+    /// Receives the events of the watch
+    pub events: Option<channel::Sender<WatchEvent>>,
+    /// Receives commands pausing, resuming or triggering the syncs of projects
+    pub control: Option<channel::Receiver<WatchControl>>,
+    /// Run rsync with `--info=progress2` and emit [WatchEvent::SyncProgress] instead of
+    /// printing its progress output
+    pub progress: bool,
+}
+
+/// Settings shared by the sync threads of a watch
+#[derive(Debug, Clone)]
+struct SyncContext {
+    config_path: PathBuf,
+    executable: OsString,
+    log_dir: Option<PathBuf>,
+    events: Option<channel::Sender<WatchEvent>>,
+    progress: bool,
+    initial_syncs: Arc<InitialSyncs>,
+    lock_groups: Arc<LockGroups>,
+    /// runs the syncs if they are executed in-process, see [config::Execution]
+    pool: Option<WorkerPool>,
+    rsync: Option<PathBuf>,
+    ssh_multiplexing: bool,
+    notifications: Arc<Vec<config::NotificationConfig>>,
+    /// counters of the syncs since the watch started, summarized when it stops
+    stats: Arc<Mutex<crate::stats::Stats>>,
+}
+
+/// Results of the initial syncs of the watched projects, used to order dependent projects
+#[derive(Debug, Default)]
+struct InitialSyncs(Mutex<HashMap<String, Option<bool>>>);
+
+impl InitialSyncs {
+    fn new<'a>(projects: impl IntoIterator<Item = &'a String>) -> Self {
+        Self(Mutex::new(
+            projects.into_iter().map(|p| (p.clone(), None)).collect(),
+        ))
+    }
+
+    fn finish(&self, project: &str, success: bool) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(project.to_owned(), Some(success));
+    }
+
+    fn all_finished(&self) -> bool {
+        self.0.lock().unwrap().values().all(Option::is_some)
+    }
+
+    /// Returns None if any of the dependencies is still syncing, otherwise whether all of them
+    /// succeeded. Dependencies that aren't watched are ignored
+    fn status(&self, deps: &[String]) -> Option<bool> {
+        let syncs = self.0.lock().unwrap();
+        let mut success = true;
+        for dep in deps {
+            match syncs.get(dep) {
+                Some(None) => return None,
+                Some(Some(s)) => success &= s,
+                None => {}
+            }
+        }
+        Some(success)
+    }
+}
+
+/// The entries holding the lock groups of the syncs in progress, shared by the projects, see
+/// [config::FileSync::lock_group]
+#[derive(Debug, Default)]
+struct LockGroups(Mutex<HashMap<String, (String, PathBuf)>>);
+
+impl LockGroups {
+    /// Whether the sync of the entry `src` of `project` may start, holding its group until it is
+    /// released. Entries without a group always may
+    fn acquire(&self, group: Option<&str>, project: &str, src: &Path) -> bool {
+        let Some(group) = group else {
+            return true;
+        };
+        let mut held = self.0.lock().unwrap();
+        match held.get(group) {
+            Some((p, s)) => p == project && s == src,
+            None => {
+                held.insert(group.to_owned(), (project.to_owned(), src.to_owned()));
+                true
+            }
+        }
+    }
+
+    fn release(&self, group: Option<&str>, project: &str, src: &Path) {
+        let Some(group) = group else {
+            return;
+        };
+        let mut held = self.0.lock().unwrap();
+        if held
+            .get(group)
+            .is_some_and(|(p, s)| p == project && s == src)
+        {
+            held.remove(group);
+        }
+    }
+
+    fn release_project(&self, project: &str) {
+        self.0.lock().unwrap().retain(|_, (p, _)| p != project);
+    }
+}
+
+impl SyncContext {
+    fn emit(&self, event: WatchEvent) {
+        if let Some(events) = self.events.as_ref() {
+            // the receiver may be dropped if the caller isn't interested in the events anymore
+            let _ = events.send(event);
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ParsedProject {
-    #[allow(unused)]
     pub name: String,
     pub sync: Vec<ParsedSync>,
     pub restart: bool,
+    pub rsync: Option<PathBuf>,
+    pub run: Vec<CommandConfig>,
+    /// run once per batch of syncs
+    pub on_sync: Vec<CommandConfig>,
+    pub on_init: Vec<CommandConfig>,
+    pub on_delete: Vec<CommandConfig>,
+    pub depends_on: Vec<String>,
+    pub watcher: config::WatcherKind,
+    pub poll_interval: Duration,
+    pub healthcheck: Vec<CommandConfig>,
+    pub healthcheck_interval: Duration,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ParsedSync {
     pub enabled: bool,
     pub src: PathBuf,
     pub recursive: bool,
+    pub symlinks: Option<config::SymlinkPolicy>,
+    /// the first destination if the sync fans out to hosts
     pub dst: Option<PathBuf>,
+    /// the destinations of a sync fanning out to hosts, see [config::FileSync::hosts]
+    pub fan_out: Vec<PathBuf>,
     pub rsync_flags: Vec<String>,
+    pub partial: bool,
+    pub manifest: bool,
+    pub password_file: Option<PathBuf>,
+    /// `password_env` is resolved as a [config::Secret::Env]
+    pub password: Option<config::Secret>,
+    /// multiplex remote syncs over the shared ssh connection, see [crate::ssh]
+    pub ssh_multiplexing: bool,
+    /// print the overall progress of rsync with `--info=progress2`
+    pub progress: bool,
+    pub bwlimit: Option<String>,
+    pub protect_dst: bool,
+    /// see [config::FileSync::confirm_delete]
+    pub confirm_delete: Option<crate::confirm::DeleteThreshold>,
+    pub max_delete: Option<u64>,
+    pub max_file_size: Option<crate::limits::FileSize>,
+    pub warn_file_count: Option<u64>,
+    pub agent: Option<String>,
+    pub verify: bool,
+    pub atomic: bool,
+    pub create_dst: bool,
+    pub chmod: Option<crate::perms::Chmod>,
+    pub chown: Option<crate::perms::Chown>,
+    pub dst_owner: Option<crate::perms::Chown>,
+    pub dst_mode: Option<crate::perms::Chmod>,
+    pub compress: Option<config::Compress>,
+    pub lock_group: Option<String>,
+    /// see [config::FileSync::shared_copies]
+    pub shared_copies: Vec<PathBuf>,
+    pub share_relay: bool,
+    /// see [config::FileSync::ssh_args]
+    pub ssh_args: BTreeMap<String, Vec<String>>,
+    pub backup: Option<config::Backup>,
+    pub mode: config::SyncMode,
+    pub keep: usize,
+    pub priority: i32,
+    pub min_interval: Option<Duration>,
+    pub niceness: crate::niceness::Niceness,
+    pub initial_sync: config::InitialSync,
+    pub on_init_when: config::OnInitWhen,
+    pub schedule: Option<crate::schedule::Schedule>,
+    pub filter: EventFilter,
+    pub backend: config::SyncBackend,
     pub on_sync: Vec<CommandConfig>,
     pub on_init: Vec<CommandConfig>,
+    pub on_delete: Vec<CommandConfig>,
+    pub watch_dst: Option<config::WatchDst>,
+    pub on_drift: Vec<CommandConfig>,
+    pub on_failure: Vec<CommandConfig>,
+}
+
+/// Which changes of a sync entry trigger a sync
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub include_extensions: Vec<String>,
+    pub exclude_extensions: Vec<String>,
+    pub ignore_patterns: Vec<String>,
+    /// see [config::FileSync::include]
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// if false, then changes below symlinked directories are ignored
+    pub follow_symlinks: bool,
+}
+
+impl EventFilter {
+    /// Whether `path`, relative to src, is transferred according to the include and exclude
+    /// patterns, like the rsync flags of [filter_flags]
+    pub(crate) fn transfers(&self, path: &Path, is_dir: bool) -> bool {
+        // rsync doesn't descend into excluded directories
+        let excluded = path
+            .ancestors()
+            .filter(|a| !a.as_os_str().is_empty())
+            .any(|a| {
+                self.exclude
+                    .iter()
+                    .any(|p| crate::glob::matches_path(p.trim_end_matches('/'), a))
+            });
+        if excluded {
+            return false;
+        }
+        // directories are kept for the includes below them
+        self.include.is_empty()
+            || is_dir
+            || self
+                .include
+                .iter()
+                .any(|p| crate::glob::matches_path(p.trim_end_matches('/'), path))
+    }
+
+    /// Whether the change of `path`, relative to `src`, should trigger a sync
+    pub(crate) fn matches(&self, src: &Path, path: &Path, kind: ChangeKind) -> bool {
+        if path.as_os_str().is_empty() {
+            // src itself
+            return true;
+        }
+        if self
+            .ignore_patterns
+            .iter()
+            .any(|p| crate::glob::matches_path(p, path))
+        {
+            return false;
+        }
+        // removed directories can't be told apart from files
+        let is_dir = !matches!(kind, ChangeKind::Changed) || src.join(path).is_dir();
+        if !self.transfers(path, is_dir) {
+            return false;
+        }
+        if !self.follow_symlinks && path.ancestors().skip(1).any(|a| src.join(a).is_symlink()) {
+            return false;
+        }
+        let has_ext = |exts: &[String]| {
+            path.extension()
+                .is_some_and(|e| exts.iter().any(|x| e == x.trim_start_matches('.')))
+        };
+        if has_ext(&self.exclude_extensions) {
+            return false;
+        }
+        if self.include_extensions.is_empty() || has_ext(&self.include_extensions) {
+            return true;
+        }
+        // directories are kept, removed ones can only be told apart by the missing extension
+        match kind {
+            ChangeKind::Removed => path.extension().is_none(),
+            ChangeKind::Changed => src.join(path).is_dir(),
+        }
+    }
+}
+
+/// rsync flags applying the include and exclude patterns of a sync, see
+/// [config::FileSync::include]. The excludes come first, so they win, and with includes the
+/// directories are included before everything else is excluded, so rsync finds the includes in
+/// them
+pub fn filter_flags(src: &Path, include: &[String], exclude: &[String]) -> Vec<String> {
+    // anchored rules are relative to the transfer root, the parent of src without a trailing slash
+    let root = transfer_root(src);
+    let prefix = src
+        .strip_prefix(root)
+        .ok()
+        .filter(|p| !p.as_os_str().is_empty())
+        .map(|p| p.to_string_lossy().replace('\\', "/"));
+    let rule = |pattern: &str| {
+        let anchored = pattern.starts_with('/') || pattern.trim_end_matches('/').contains('/');
+        if !anchored {
+            return pattern.to_owned();
+        }
+        let pattern = pattern.trim_start_matches('/');
+        match prefix.as_deref() {
+            Some(prefix) => format!("/{prefix}/{pattern}"),
+            None => format!("/{pattern}"),
+        }
+    };
+    let mut flags = exclude
+        .iter()
+        .map(|p| format!("--exclude={}", rule(p)))
+        .collect::<Vec<_>>();
+    flags.extend(include.iter().map(|p| format!("--include={}", rule(p))));
+    if !include.is_empty() {
+        flags.push("--include=*/".to_owned());
+        flags.push("--exclude=*".to_owned());
+    }
+    flags
+}
+
+/// `--copy-dest` flags for the copies of the share group of a sync to `dst` that are on the same
+/// host, see [config::FileSync::share]
+pub fn share_flags(src: &Path, dst: &Path, copies: &[PathBuf]) -> Vec<String> {
+    let rel = src
+        .strip_prefix(transfer_root(src))
+        .unwrap_or(Path::new(""));
+    let host = |p: &Path| config::remote_dst(p).map(|(h, _)| h.to_owned());
+    let own = own_copy(src, dst);
+    copies
+        .iter()
+        .filter(|c| **c != own && host(c) == host(dst))
+        .filter_map(|c| {
+            let path = config::remote_dst(c).map_or(c.as_path(), |(_, p)| Path::new(p));
+            // rsync looks up the files relative to the directory, under the same name as ours
+            let n = rel.components().count();
+            (path.is_absolute() && path.ends_with(rel)).then(|| path.ancestors().nth(n))?
+        })
+        // the maximum number of alternate directories of rsync
+        .take(20)
+        .map(|d| format!("--copy-dest={}", d.display()))
+        .collect()
+}
+
+/// The copy of src a sync to `dst` leaves, local dsts made absolute like the copies of the share
+/// group, see [config::FileSync::shared_copies]
+fn own_copy(src: &Path, dst: &Path) -> PathBuf {
+    let rel = src
+        .strip_prefix(transfer_root(src))
+        .unwrap_or(Path::new(""));
+    let dst = match config::remote_dst(dst) {
+        Some(_) => dst.to_owned(),
+        None => std::path::absolute(dst).unwrap_or_else(|_| dst.to_owned()),
+    };
+    dst.join(rel)
+}
+
+/// A copy of the share group on another host than `dst` holding the content with `digest`
+/// according to `recorded`, to seed the copy in dst from, see [config::FileSync::share_relay]
+pub fn relay_source<'a>(
+    dst: &Path,
+    copies: &'a [PathBuf],
+    digest: u64,
+    recorded: impl Fn(&Path) -> Option<u64>,
+) -> Option<&'a Path> {
+    let (host, _) = config::remote_dst(dst)?;
+    copies.iter().map(PathBuf::as_path).find(|c| {
+        config::remote_dst(c).is_some_and(|(h, p)| h != host && Path::new(p).is_absolute())
+            && recorded(c) == Some(digest)
+    })
+}
+
+/// Copy `source` into the missing copy `own` with rsync running on the host of own, see
+/// [config::FileSync::share_relay]
+fn seed_copy(
+    own: &Path,
+    source: &Path,
+    ssh: &[String],
+    runner: Option<&ProcessRunner>,
+) -> anyhow::Result<()> {
+    let quote = crate::backup::remote_quote;
+    let (host, path) = config::remote_dst(own).context("The copy isn't on a remote host")?;
+    let (source_host, source_path) =
+        config::remote_dst(source).context("The source isn't on a remote host")?;
+    let script = format!(
+        "mkdir -p -- {own} && rsync -a -- {source} {own}/",
+        own = quote(path),
+        source = quote(&format!("{source_host}:{source_path}/")),
+    );
+    let mut cmd = process::Command::new("ssh");
+    cmd.args(["-o", "BatchMode=yes"])
+        .args(ssh)
+        .args([host, &script])
+        .stdin(process::Stdio::null());
+    let status = match runner {
+        Some(runner) => runner.run(cmd)?,
+        None => cmd.status().context("Failed to run ssh")?,
+    };
+    anyhow::ensure!(
+        status.success(),
+        "Failed to seed {} from {}: ssh {status}",
+        own.display(),
+        source.display()
+    );
+    Ok(())
 }
 
 pub static DEFAULT_RSYCN_FLAGS: &[&str] = &["--delete", "-raPhv", "--filter", ":- .gitignore"];
 
+impl ParsedSync {
+    /// Arguments of ssh connecting to the host of `dst`, see [config::FileSync::ssh_args]
+    pub fn ssh_args_of(&self, dst: &Path) -> &[String] {
+        config::ssh_args_of(&self.ssh_args, dst)
+    }
+
+    /// The destinations a sync transfers to: none for a watch-only entry, more than one if it fans
+    /// out to hosts
+    pub fn destinations(&self) -> Vec<&Path> {
+        if self.fan_out.is_empty() {
+            self.dst.as_deref().into_iter().collect()
+        } else {
+            self.fan_out.iter().map(PathBuf::as_path).collect()
+        }
+    }
+}
+
 impl TryFrom<config::FileSync> for ParsedSync {
     type Error = anyhow::Error;
     fn try_from(s: config::FileSync) -> Result<Self, Self::Error> {
+        let follow_symlinks = s.follows_symlinks();
+        let fan_out = if s.hosts.is_some() {
+            s.destinations()?
+        } else {
+            Vec::new()
+        };
         let mut on_sync = Vec::new();
         let mut on_init = Vec::new();
+        let mut on_delete = Vec::new();
 
         for c in s.on_sync {
             match c.on {
                 config::CommandOn::Change => on_sync.push(c),
                 config::CommandOn::Init => on_init.push(c),
+                config::CommandOn::Delete => on_delete.push(c),
             }
         }
 
+        let mut rsync_flags = if let Some(flags) = s.rsync_flags.as_deref() {
+            shell_words::split(flags).context("Failed to split rsync flags")?
+        } else {
+            DEFAULT_RSYCN_FLAGS
+                .iter()
+                .copied()
+                .map(|x| x.to_owned())
+                .collect()
+        };
+        if let Some(profile) = s.transfer_profile {
+            rsync_flags.extend(profile.rsync_flags().iter().map(|f| (*f).to_owned()));
+        }
+        if let Some(chmod) = s.chmod.as_ref() {
+            rsync_flags.push(format!("--chmod={chmod}"));
+        }
+        if let Some(chown) = s.chown.as_ref() {
+            rsync_flags.push(format!("--chown={chown}"));
+        }
+        if s.numeric_ids {
+            rsync_flags.push("--numeric-ids".to_owned());
+        }
+        if let Some(max) = s.max_file_size {
+            rsync_flags.push(max.rsync_flag());
+        }
+        rsync_flags.extend(filter_flags(&s.src, &s.include, &s.exclude));
+        if let Some(extra) = s.extra_rsync_flags.as_deref() {
+            rsync_flags
+                .extend(shell_words::split(extra).context("Failed to split extra rsync flags")?);
+        }
+
         Ok(ParsedSync {
             enabled: s.enabled,
             src: s.src,
             recursive: s.recursive,
-            dst: s.dst,
-            rsync_flags: if let Some(flags) = s.rsync_flags.as_deref() {
-                shell_words::split(flags).context("Failed to split rsync flags")?
-            } else {
-                DEFAULT_RSYCN_FLAGS
-                    .iter()
-                    .copied()
-                    .map(|x| x.to_owned())
-                    .collect()
+            symlinks: s.symlinks,
+            dst: fan_out.first().cloned().or(s.dst),
+            fan_out,
+            partial: s.partial,
+            manifest: s.manifest,
+            password_file: s.password_file,
+            password: s.password.or(s.password_env.map(config::Secret::Env)),
+            ssh_multiplexing: false,
+            progress: false,
+            bwlimit: s.bwlimit,
+            protect_dst: s.protect_dst,
+            confirm_delete: s.confirm_delete.then_some(s.confirm_delete_threshold),
+            max_delete: s.max_delete,
+            max_file_size: s.max_file_size,
+            warn_file_count: s.warn_file_count,
+            agent: s.agent,
+            verify: s.verify,
+            atomic: s.atomic,
+            create_dst: s.create_dst,
+            chmod: s.chmod,
+            chown: s.chown,
+            dst_owner: s.dst_owner,
+            dst_mode: s.dst_mode,
+            compress: s.compress,
+            lock_group: s.lock_group,
+            shared_copies: s.shared_copies,
+            share_relay: s.share_relay,
+            ssh_args: s.ssh_args,
+            backup: s.backup,
+            mode: s.mode,
+            keep: s.keep,
+            priority: s.priority,
+            min_interval: s.min_interval,
+            niceness: crate::niceness::Niceness {
+                nice: s.nice,
+                ionice: s.ionice,
+                cpus: s.cpus,
             },
+            initial_sync: s.initial_sync,
+            on_init_when: s.on_init_when,
+            schedule: s.schedule,
+            filter: EventFilter {
+                follow_symlinks,
+                include_extensions: s.include_extensions,
+                exclude_extensions: s.exclude_extensions,
+                ignore_patterns: s.ignore_patterns,
+                include: s.include,
+                exclude: s.exclude,
+            },
+            backend: s.backend,
+            rsync_flags,
             on_sync,
             on_init,
+            on_delete,
+            watch_dst: s.watch_dst,
+            on_drift: s.on_drift,
+            on_failure: s.on_failure,
         })
     }
 }
 
+/// Parse the sync `i` of `project`, naming it in the error
+pub fn parse_sync(project: &str, i: usize, s: config::FileSync) -> anyhow::Result<ParsedSync> {
+    let src = s.src.clone();
+    s.try_into()
+        .with_context(|| format!("Invalid sync {i} ({}) in project {project}", src.display()))
+}
+
 impl TryFrom<(config::ProjectName, config::Project)> for ParsedProject {
     type Error = anyhow::Error;
 
@@ -78,197 +915,2312 @@ impl TryFrom<(config::ProjectName, config::Project)> for ParsedProject {
         (name, value): (config::ProjectName, config::Project),
     ) -> Result<Self, Self::Error> {
         let mut sync = Vec::with_capacity(value.sync.len());
-        for s in value.sync {
-            sync.push(s.try_into()?);
+        for (i, s) in value.sync.into_iter().enumerate() {
+            sync.push(parse_sync(&name, i, s)?);
+        }
+        let mut on_sync = Vec::new();
+        let mut on_init = Vec::new();
+        let mut on_delete = Vec::new();
+        for c in value.on_sync {
+            match c.on {
+                config::CommandOn::Change => on_sync.push(c),
+                config::CommandOn::Init => on_init.push(c),
+                config::CommandOn::Delete => on_delete.push(c),
+            }
         }
         anyhow::Ok(Self {
             name,
             sync,
-            restart: value.restart,
+            restart: value.restart.unwrap_or(true),
+            rsync: value.rsync,
+            run: value.run,
+            on_sync,
+            on_init,
+            on_delete,
+            depends_on: value.depends_on,
+            watcher: value.watcher,
+            poll_interval: value.poll_interval,
+            healthcheck: value.healthcheck,
+            healthcheck_interval: value.healthcheck_interval,
         })
     }
 }
 
-#[tracing::instrument(skip_all, fields(src))]
-pub fn execute_sync(s: &ParsedSync, rsync: Option<&OsStr>, initialize: bool) -> anyhow::Result<()> {
+/// Run rsync, collecting its exit code and stats into `output` if given.
+/// The command must be invoked with `--stats` if `output` is given
+fn run_rsync(
+    cmd: xshell::Cmd,
+    env: &[(&str, String)],
+    output: Option<&mut SyncOutput>,
+    runner: Option<&ProcessRunner>,
+) -> anyhow::Result<()> {
+    use std::io::Write as _;
+
+    let cmd = cmd.envs(env.iter().map(|(k, v)| (k, v)));
+    let check = |status: process::ExitStatus| match status.code() {
+        _ if status.success() => Ok(()),
+        Some(code) if RSYNC_UNREACHABLE.contains(&code) => {
+            Err(Unreachable { exit_code: code }.into())
+        }
+        Some(RSYNC_MAX_DELETE) => Err(MaxDeleteReached.into()),
+        code => Err(anyhow::anyhow!("rsync failed with exit code {code:?}")),
+    };
+    if let Some(runner) = runner {
+        return check(runner.run(cmd.into())?);
+    }
+    let Some(output) = output else {
+        // echoed like xshell's run does
+        eprintln!("$ {cmd}");
+        return check(process::Command::from(cmd).status()?);
+    };
+    let out = cmd.ignore_status().output()?;
+    let _ = std::io::stdout().write_all(&out.stdout);
+    let _ = std::io::stderr().write_all(&out.stderr);
+    output.rsync_exit_code = out.status.code();
+    output.stats = Some(RsyncStats::parse(&String::from_utf8_lossy(&out.stdout)));
+    check(out.status)
+}
+
+/// The output of a dry run of rsync. Runs through `runner` if given, so it's killed once the sync
+/// is cancelled
+fn read_rsync(
+    cmd: xshell::Cmd,
+    env: &[(&str, String)],
+    runner: Option<&ProcessRunner>,
+) -> anyhow::Result<String> {
+    let cmd = cmd.envs(env.iter().map(|(k, v)| (k, v))).quiet();
+    match runner {
+        Some(runner) => runner.read(cmd.into()),
+        None => Ok(cmd.read()?),
+    }
+}
+
+/// Sync the entry of `project` and run its hooks, recording them in the [crate::history].
+///
+/// If `output` is given, then rsync's stats and the hook results are collected into it.
+/// If `runner` is given, then the processes run through it, so the sync can be cancelled
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip_all, fields(src, change_id))]
+pub fn execute_sync(
+    config_path: &Path,
+    project: &str,
+    s: &ParsedSync,
+    rsync: Option<&OsStr>,
+    initialize: bool,
+    changes: &SyncChanges,
+    mut output: Option<&mut SyncOutput>,
+    runner: Option<&ProcessRunner>,
+) -> anyhow::Result<()> {
     tracing::Span::current().record("src", s.src.display().to_string());
+    if let Some(id) = changes.id {
+        tracing::Span::current().record("change_id", id);
+    }
+    // queued behind a sync of the same entry still running, e.g. one left by a restarted daemon
+    let _lock = crate::lock::SyncLock::acquire(config_path, project, &s.src, || {
+        runner.map_or(Ok(()), ProcessRunner::check)
+    })?;
 
     let sh = xshell::Shell::new().context("Failed to init shell")?;
+    let started = SystemTime::now();
+    // decided before the transfer creates the copy in dst
+    let run_init = initialize && init_wanted(config_path, project, s);
 
-    if let Some(dst) = s.dst.as_ref() {
-        info!("Syncing file •");
+    crate::limits::check(changes, s.max_file_size, s.warn_file_count);
+    let destinations = s.destinations();
+    let transfer_span = tracing::info_span!("transfer").entered();
+    match destinations[..] {
+        [] => {}
+        [dst] => transfer(
+            s,
+            dst,
+            rsync,
+            initialize,
+            changes,
+            output.as_deref_mut(),
+            runner,
+            started,
+        )?,
+        _ => fan_out(
+            s,
+            &destinations,
+            rsync,
+            initialize,
+            changes,
+            output.as_deref_mut(),
+            runner,
+            started,
+        )?,
+    }
+    drop(transfer_span);
 
-        let rsync = rsync.unwrap_or_else(|| OsStr::new("rsync"));
-        let rsync_flags = s.rsync_flags.iter();
-        let src = s.src.as_os_str();
-        let dst = dst.as_os_str();
+    let deleted = join_paths(&changes.deleted);
+    let changed = join_paths(&changes.changed);
+    // the env var may be too large to pass for big changesets, so the list is also written to a
+    // file
+    let changed_list =
+        PathListFile::new("changed", &changed).context("Failed to write changed files")?;
 
-        let cmd = xshell::cmd!(sh, "{rsync} {rsync_flags...} {src} {dst}");
-        cmd.run()?;
-        info!("Syncing file done ✓");
+    let src = s.src.display().to_string();
+    let dst = s.dst.as_ref().map(|d| d.display().to_string());
+    let dsts = join_paths(&s.fan_out.iter().cloned().collect());
+    let list = changed_list.0.display().to_string();
+    let change_id = changes.id.map(|id| id.to_string());
+    // recorded in the history
+    let mut hook_env = vec![
+        ("ATUNE_SYNC_SRC", src.as_str()),
+        ("ATUNE_DELETED_PATHS", deleted.as_str()),
+        ("ATUNE_CHANGED_FILES", changed.as_str()),
+        ("ATUNE_CHANGED_FILES_LIST", list.as_str()),
+    ];
+    hook_env.extend(dst.as_deref().map(|d| ("ATUNE_SYNC_DST", d)));
+    hook_env.extend(change_id.as_deref().map(|id| (CHANGE_ID_ENV, id)));
+    if !s.fan_out.is_empty() {
+        hook_env.push(("ATUNE_SYNC_DSTS", dsts.as_str()));
     }
 
-    let run = |cmd: &str| {
-        let mut proc = xshell::cmd!(sh, "sh -s")
-            .env("ATUNE_SYNC_SRC", s.src.as_os_str())
-            .quiet();
+    let hooks = std::cell::RefCell::new(Vec::new());
+    // values the commands wrote to `ATUNE_OUTPUT`, passed on to the following ones
+    let exported = std::cell::RefCell::new(BTreeMap::<String, String>::new());
+    let run = |name: &str, cmd: &CommandConfig| {
+        let exported_now = exported.borrow().clone();
+        let mut hook_env = hook_env.clone();
+        hook_env.extend(exported_now.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        if !cmd.only_on.runs(run_init) || !condition_met(cmd, &hook_env, runner)? {
+            return Ok(());
+        }
+        let _span = tracing::info_span!("hook", hook = name, command = cmd.command).entered();
+        let start = Instant::now();
+        // the cwd of remote commands is on the host
+        let _dir = cmd
+            .cwd
+            .as_ref()
+            .filter(|_| !cmd.is_remote())
+            .map(|cwd| sh.push_dir(cwd));
+        if let Some(script) = crate::script::parse(&cmd.command) {
+            let run = crate::history::start(project, name, cmd, &hook_env);
+            let cwd = cmd.cwd.as_ref().map(|_| sh.current_dir());
+            let env = script_env(&hook_env, cmd);
+            let res = crate::script::run(config_path, script, cwd.as_deref(), project, name, &env);
+            run.finish_script(res.is_ok());
+            hooks.borrow_mut().push(HookResult {
+                command: cmd.command.clone(),
+                success: res.is_ok(),
+                duration: start.elapsed(),
+            });
+            match res {
+                Ok(values) => exported.borrow_mut().extend(values),
+                Err(err) => {
+                    error!("{name} script failed: {err:#}");
+                    return Err(HookFailed {
+                        command: cmd.command.clone(),
+                    }
+                    .into());
+                }
+            }
+            return Ok(());
+        }
+        let output_file =
+            PathListFile::new("output", "").context("Failed to create the output file")?;
+        let shell = cmd.shell.clone().unwrap_or_else(config::default_shell);
+        let (program, shell_args) = shell
+            .split_first()
+            .context("Shell must have at least one element")?;
+        let command = cmd.command.as_str();
+        let remote = cmd
+            .is_remote()
+            .then(|| s.dst.as_deref().and_then(config::remote_dst))
+            .flatten();
+        let mut proc = match (remote, crate::plugin::program(command).transpose()?) {
+            (Some((host, path)), _) => {
+                let ssh = s
+                    .dst
+                    .as_deref()
+                    .map(|dst| s.ssh_args_of(dst))
+                    .unwrap_or_default();
+                let mut ssh_args = ssh.to_vec();
+                ssh_args.extend(crate::ssh::multiplex_args(host, ssh));
+                // the list file and ATUNE_OUTPUT are local files
+                let env = hook_env
+                    .iter()
+                    .filter(|(k, _)| *k != "ATUNE_CHANGED_FILES_LIST")
+                    .map(|&(k, v)| {
+                        if k == "ATUNE_SYNC_DST" {
+                            (k, path)
+                        } else {
+                            (k, v)
+                        }
+                    })
+                    .chain(cmd.env.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+                let script = crate::ssh::remote_script(command, cmd.cwd.as_deref(), env);
+                xshell::cmd!(sh, "ssh {ssh_args...} {host} {script}")
+            }
+            (None, Some((plugin, args))) => xshell::cmd!(sh, "{plugin} {args...}"),
+            (None, None) => xshell::cmd!(sh, "{program} {shell_args...} {command}"),
+        }
+        .env("ATUNE_SYNC_SRC", s.src.as_os_str())
+        .env("ATUNE_DELETED_PATHS", deleted.as_str())
+        .env("ATUNE_CHANGED_FILES", changed.as_str())
+        .env("ATUNE_CHANGED_FILES_LIST", changed_list.0.as_os_str())
+        .env("ATUNE_OUTPUT", output_file.0.as_os_str())
+        .envs(exported_now.iter())
+        .envs(cmd.env.iter())
+        .quiet();
         if let Some(dst) = s.dst.as_ref() {
             proc = proc.env("ATUNE_SYNC_DST", dst.as_os_str());
         }
-        let res = proc.stdin(cmd.as_bytes()).run();
-        anyhow::ensure!(res.is_ok(), "Command failed\n{cmd}");
-        Ok(())
+        if !s.fan_out.is_empty() {
+            proc = proc.env("ATUNE_SYNC_DSTS", dsts.as_str());
+        }
+        if let Some(id) = change_id.as_deref() {
+            proc = proc.env(CHANGE_ID_ENV, id);
+        }
+        let success = match runner {
+            Some(runner) => {
+                runner.check()?;
+                let run = crate::history::start(project, name, cmd, &hook_env);
+                let payload = crate::plugin::is_plugin(cmd)
+                    .then(|| crate::plugin::payload(project, name, &hook_env));
+                match runner.run_logged(proc.into(), run.log(), payload) {
+                    Ok((status, tail)) => {
+                        run.finish(Some(status), &tail);
+                        status.success()
+                    }
+                    Err(_) => {
+                        run.finish(None, &Default::default());
+                        false
+                    }
+                }
+            }
+            None => crate::history::run(project, name, cmd, &hook_env, proc.into())
+                .is_ok_and(|status| status.success()),
+        };
+        match std::fs::read_to_string(&output_file.0) {
+            Ok(content) => exported.borrow_mut().extend(parse_outputs(&content)),
+            Err(err) => warn!(?err, "Failed to read the values written to ATUNE_OUTPUT"),
+        }
+        hooks.borrow_mut().push(HookResult {
+            command: command.to_owned(),
+            success,
+            duration: start.elapsed(),
+        });
+        if !success {
+            return Err(HookFailed {
+                command: command.to_owned(),
+            }
+            .into());
+        }
+        anyhow::Ok(())
     };
 
-    if initialize && !s.on_init.is_empty() {
-        info!("Running init commands");
-        for cmd in s.on_init.iter() {
-            let res = run(cmd.command.as_str());
+    let run_all = |name: &str, cmds: &[CommandConfig]| {
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        info!("Running {name} commands");
+        for cmd in cmds.iter() {
+            let res = run(name, cmd);
             debug!(?res, "Command result");
             if !cmd.continue_on_failure {
                 res?;
             }
         }
-        info!("Running init commands done");
-    }
+        info!("Running {name} commands done");
+        anyhow::Ok(())
+    };
 
-    if !s.on_sync.is_empty() {
-        info!("Running on_sync commands");
-        for cmd in s.on_sync.iter() {
-            let res = run(cmd.command.as_str());
-            debug!(?res, "Command result");
-            if !cmd.continue_on_failure {
-                res?;
-            }
+    let result = (|| {
+        if run_init {
+            run_all("init", &s.on_init)?;
         }
-        info!("Running on_sync commands done");
+        if !changes.deleted.is_empty() {
+            run_all("on_delete", &s.on_delete)?;
+        }
+        run_all("on_sync", &s.on_sync)
+    })();
+    if let Some(output) = output {
+        output.hooks = hooks.into_inner();
     }
-    Ok(())
+    result
 }
 
-#[derive(Debug, Default)]
-struct SyncProcesses(Vec<process::Child>);
+/// Whether an initial sync of the entry runs its init commands, see [config::OnInitWhen]
+fn init_wanted(config_path: &Path, project: &str, s: &ParsedSync) -> bool {
+    let wanted = match s.on_init_when {
+        config::OnInitWhen::Always => true,
+        config::OnInitWhen::StateMissing => crate::state::SyncState::load()
+            .get(config_path, project, &s.src)
+            .is_none_or(|e| !e.initialized),
+        config::OnInitWhen::DstMissing => {
+            let rel = s
+                .src
+                .strip_prefix(transfer_root(&s.src))
+                .unwrap_or(Path::new(""));
+            s.destinations()
+                .into_iter()
+                .any(|dst| !dst_exists(&dst.join(rel), s.ssh_args_of(dst)))
+        }
+    };
+    if !wanted {
+        info!(when = ?s.on_init_when, "Skipping the init commands");
+    }
+    wanted
+}
 
-impl Drop for SyncProcesses {
-    fn drop(&mut self) {
-        self.cancel();
+/// Whether `path`, local or `host:path` reached with the ssh arguments `ssh`, exists.
+/// Unreachable hosts count as missing
+fn dst_exists(path: &Path, ssh: &[String]) -> bool {
+    let Some((host, remote)) = config::remote_dst(path) else {
+        return path.exists();
+    };
+    let test = format!("test -e {}", crate::backup::remote_quote(remote));
+    let status = process::Command::new("ssh")
+        .args(["-o", "BatchMode=yes"])
+        .args(ssh)
+        .args([host, &test])
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .status();
+    match status {
+        Ok(s) if s.code() == Some(255) => {
+            warn!(host, "Failed to probe dst, assuming it's missing");
+            false
+        }
+        Ok(s) => s.success(),
+        Err(err) => {
+            warn!(?err, "Failed to run ssh, assuming dst is missing");
+            false
+        }
     }
 }
 
-impl SyncProcesses {
-    pub fn cancel(&mut self) {
-        // cancel in-progress syncs
-        for mut proc in self.0.drain(..) {
-            match proc.try_wait() {
-                Ok(Some(_)) => {}
-                Ok(None) => {
-                    debug!("Killing in-progress sync");
-                    match proc.kill() {
+/// Create `dst`, local or `host:path`, and its parents, see [config::FileSync::create_dst]
+fn create_dst(dst: &Path, ssh: &[String]) -> anyhow::Result<()> {
+    match config::remote_dst(dst) {
+        Some((host, path)) => {
+            let mkdir = format!("mkdir -p -- {}", crate::backup::remote_quote(path));
+            let status = process::Command::new("ssh")
+                .args(["-o", "BatchMode=yes"])
+                .args(ssh)
+                .args([host, &mkdir])
+                .stdin(process::Stdio::null())
+                .status()
+                .context("Failed to run ssh")?;
+            anyhow::ensure!(
+                status.success(),
+                "Failed to create {}: ssh {status}",
+                dst.display()
+            );
+        }
+        None => std::fs::create_dir_all(dst)
+            .with_context(|| format!("Failed to create {}", dst.display()))?,
+    }
+    debug!(?dst, "Created dst");
+    Ok(())
+}
+
+/// Sync the entry to `dst` with its backend, the part of [execute_sync] before the hooks
+#[allow(clippy::too_many_arguments)]
+fn transfer(
+    s: &ParsedSync,
+    dst: &Path,
+    rsync: Option<&OsStr>,
+    initialize: bool,
+    changes: &SyncChanges,
+    output: Option<&mut SyncOutput>,
+    runner: Option<&ProcessRunner>,
+    started: SystemTime,
+) -> anyhow::Result<()> {
+    let backend = crate::backend::get(&s.backend)?;
+    let ctx = crate::backend::TransferContext {
+        sync: s,
+        dst,
+        rsync,
+        initialize,
+        changes,
+        runner,
+        started,
+    };
+    info!("Syncing file •");
+    if s.create_dst && initialize {
+        create_dst(dst, s.ssh_args_of(dst))?;
+    }
+    backend.prepare(&ctx)?;
+    backend.transfer(&ctx, output)?;
+    backend.finalize(&ctx)?;
+    if s.dst_owner.is_some() || s.dst_mode.is_some() {
+        let rel = s
+            .src
+            .strip_prefix(transfer_root(&s.src))
+            .unwrap_or(Path::new(""));
+        crate::perms::fix_up(
+            &dst.join(rel),
+            s.ssh_args_of(dst),
+            s.dst_owner.as_ref(),
+            s.dst_mode.as_ref(),
+        )?;
+    }
+    if let Some(backup) = s.backup.as_ref() {
+        if let Err(err) = crate::backup::prune(dst, s.ssh_args_of(dst), backup, started) {
+            warn!(?err, "Failed to remove old backups");
+        }
+    }
+    info!("Syncing file done ✓");
+    Ok(())
+}
+
+/// `--password-file` flag of rsync
+fn rsync_password_file(s: &ParsedSync) -> Option<OsString> {
+    s.password_file.as_ref().map(|f| {
+        let mut arg = OsString::from("--password-file=");
+        arg.push(f);
+        arg
+    })
+}
+
+/// Environment of the rsync processes syncing to `dst`
+fn rsync_env(s: &ParsedSync, dst: &Path) -> anyhow::Result<Vec<(&'static str, String)>> {
+    let password = s
+        .password
+        .as_ref()
+        .map(config::Secret::resolve)
+        .transpose()
+        .context("Failed to resolve the rsync password")?;
+    let mut env = Vec::new();
+    if let Some(password) = password {
+        env.push(("RSYNC_PASSWORD", password));
+    }
+    // an explicit RSYNC_RSH or `-e` flag takes precedence
+    if let Some((host, _)) = config::remote_dst(dst) {
+        let ssh = s.ssh_args_of(dst);
+        if std::env::var_os("RSYNC_RSH").is_none() && (s.ssh_multiplexing || !ssh.is_empty()) {
+            env.push((
+                "RSYNC_RSH",
+                crate::ssh::rsync_rsh(host, ssh, s.ssh_multiplexing),
+            ));
+        }
+    }
+    Ok(env)
+}
+
+/// The default backend, transferring with rsync, see [config::SyncBackend::Rsync]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RsyncBackend;
+
+impl crate::backend::SyncBackend for RsyncBackend {
+    fn transfer(
+        &self,
+        ctx: &crate::backend::TransferContext<'_>,
+        mut output: Option<&mut SyncOutput>,
+    ) -> anyhow::Result<()> {
+        let crate::backend::TransferContext {
+            sync: s,
+            dst,
+            rsync,
+            initialize,
+            changes,
+            runner,
+            started,
+        } = *ctx;
+        let sh = xshell::Shell::new().context("Failed to init shell")?;
+        let rsync = rsync.unwrap_or_else(|| OsStr::new("rsync"));
+        let rsync_flags = s.rsync_flags.iter();
+        let symlinks = s.symlinks.map(config::SymlinkPolicy::rsync_flag);
+        let stats = output.is_some().then_some("--stats");
+        // accurate totals need the whole file list up front
+        let progress = if s.progress {
+            &["--info=progress2", "--no-inc-recursive"][..]
+        } else {
+            &[]
+        };
+        let bwlimit = s.bwlimit.as_ref().map(|b| format!("--bwlimit={b}"));
+        let backup = s
+            .backup
+            .as_ref()
+            .map(|b| crate::backup::rsync_args(b, started))
+            .unwrap_or_default();
+        let password_file = rsync_password_file(s);
+        let env = rsync_env(s, dst)?;
+
+        let compress = s
+            .compress
+            .map(|c| {
+                crate::compress::rsync_flags(c, rsync, dst, s.ssh_args_of(dst), s.ssh_multiplexing)
+            })
+            .unwrap_or_default();
+
+        // the content of src, recorded for the other entries of the share group once synced
+        let relay = (s.share_relay && s.src.is_dir() && config::remote_dst(dst).is_some())
+            .then(|| crate::hash_cache::digest(&s.src));
+        if let Some(digest) = relay {
+            crate::hash_cache::save();
+            let own = own_copy(&s.src, dst);
+            let ssh = s.ssh_args_of(dst);
+            let source = relay_source(
+                dst,
+                &s.shared_copies,
+                digest,
+                crate::hash_cache::copy_digest,
+            );
+            if let Some(source) = source.filter(|_| !dst_exists(&own, ssh)) {
+                info!(?source, "Seeding the shared copy from another host");
+                if let Err(err) = seed_copy(&own, source, ssh, runner) {
+                    warn!(?err, "Failed to seed the shared copy, transferring it");
+                }
+            }
+        }
+
+        let mut manifest = None;
+        let transfer = if s.mode == config::SyncMode::Snapshot {
+            let name = crate::backup::timestamp(started);
+            let releases = crate::snapshot::prepare(dst, s.ssh_args_of(dst))?;
+            Transfer::Snapshot {
+                previous: releases.into_iter().next_back().filter(|r| *r != name),
+                name,
+            }
+        } else if s.atomic {
+            let staging = crate::atomic::Staging::new(&s.src, dst, s.ssh_args_of(dst))?;
+            staging.prepare()?;
+            Transfer::Atomic(staging)
+        } else if s.manifest {
+            let (m, transfer) = manifest_transfer(s, dst, initialize, changes)?;
+            manifest = Some(m);
+            transfer
+        } else if s.partial {
+            partial_files(&s.src, changes).map_or(
+                Transfer::Full,
+                |(base, files, delete_missing)| Transfer::Files {
+                    base,
+                    files,
+                    delete_missing,
+                },
+            )
+        } else {
+            Transfer::Full
+        };
+        if s.protect_dst && !matches!(transfer, Transfer::Nothing) {
+            let rsync_flags = rsync_flags.clone();
+            let password_file = password_file.clone();
+            let backup = backup.clone();
+            let src = s.src.as_os_str();
+            let dst = dst.as_os_str();
+            let cmd = xshell::cmd!(
+                sh,
+                "{rsync} {rsync_flags...} {symlinks...} {backup...} {password_file...} --dry-run --itemize-changes {src} {dst}"
+            );
+            let out = read_rsync(cmd, &env, runner).context("Failed to check dst for conflicts")?;
+            let paths = dst_conflicts(&out, &s.src, changes);
+            if !paths.is_empty() {
+                return Err(DstConflict { paths }.into());
+            }
+        }
+        // partial transfers only delete the files deleted in src
+        if (s.confirm_delete.is_some() || s.max_delete.is_some())
+            && matches!(transfer, Transfer::Full | Transfer::Atomic(_))
+        {
+            let rsync_flags = rsync_flags.clone();
+            let password_file = password_file.clone();
+            let backup = backup.clone();
+            let src = s.src.as_os_str();
+            let dst = dst.as_os_str();
+            let cmd = xshell::cmd!(
+                sh,
+                "{rsync} {rsync_flags...} {symlinks...} {backup...} {password_file...} --dry-run --itemize-changes --stats {src} {dst}"
+            );
+            let out =
+                read_rsync(cmd, &env, runner).context("Failed to count the deletions in dst")?;
+            let (paths, total) = crate::confirm::deletions(&out);
+            let deleted = paths.len() as u64;
+            if let Some(max) = s.max_delete.filter(|max| deleted > *max) {
+                return Err(crate::confirm::DeleteLimit {
+                    dst: dst.into(),
+                    max,
+                    deleted: Some(deleted),
+                }
+                .into());
+            }
+            if let Some(threshold) = s.confirm_delete.filter(|t| t.exceeded(deleted, total)) {
+                let label = s.src.display().to_string();
+                let confirmed = crate::confirm::ask(&label, dst.as_ref(), &paths, total)
+                    .unwrap_or_else(|err| {
+                        warn!(?err, "Failed to ask for a confirmation");
+                        false
+                    });
+                if !confirmed {
+                    return Err(crate::confirm::DeleteRefused {
+                        dst: dst.into(),
+                        deleted,
+                        total,
+                        threshold,
+                    }
+                    .into());
+                }
+            }
+        }
+        let share = share_flags(&s.src, dst, &s.shared_copies);
+        let max_delete = s.max_delete.map(|m| format!("--max-delete={m}"));
+        let max_delete_reached = |err: anyhow::Error| match s.max_delete {
+            Some(max) if err.is::<MaxDeleteReached>() => crate::confirm::DeleteLimit {
+                dst: dst.into(),
+                max,
+                deleted: None,
+            }
+            .into(),
+            _ => err,
+        };
+        let dst = dst.as_os_str();
+        match transfer {
+            Transfer::Nothing => {
+                info!("No changes according to the manifest");
+            }
+            Transfer::Files {
+                base,
+                files,
+                delete_missing,
+            } => {
+                debug!(?files, "Syncing changed files only");
+                if delete_missing {
+                    move_renamed(&base, dst.as_ref(), changes);
+                }
+                let list = PathListFile::new("files-from", &files)
+                    .context("Failed to write files-from list")?;
+                let list = list.0.as_os_str();
+                let base = base.as_os_str();
+                let delete_missing = delete_missing.then_some("--delete-missing-args");
+                let cmd = xshell::cmd!(
+                    sh,
+                    "{rsync} {rsync_flags...} {compress...} {symlinks...} {bwlimit...} {backup...} {share...} {password_file...} {stats...} {progress...} {delete_missing...} {max_delete...} --files-from {list} {base} {dst}"
+                );
+                run_rsync(cmd, &env, output.as_deref_mut(), runner).map_err(max_delete_reached)?;
+            }
+            Transfer::Snapshot { name, previous } => {
+                // the release holds the content of src
+                let mut src = s.src.as_os_str().to_owned();
+                src.push("/");
+                let release = crate::snapshot::release_path(dst.as_ref(), &name);
+                let link_dest = previous.map(|p| format!("--link-dest=../{p}"));
+                let cmd = xshell::cmd!(
+                    sh,
+                    "{rsync} {rsync_flags...} {compress...} {symlinks...} {bwlimit...} {password_file...} {stats...} {progress...} {link_dest...} {src} {release}"
+                );
+                run_rsync(cmd, &env, output.as_deref_mut(), runner)?;
+                let ssh = s.ssh_args_of(dst.as_ref());
+                crate::snapshot::activate(dst.as_ref(), ssh, &name)?;
+                if let Err(err) = crate::snapshot::prune(dst.as_ref(), ssh, s.keep, &name) {
+                    warn!(?err, "Failed to remove old releases");
+                }
+            }
+            Transfer::Atomic(staging) => {
+                // the staging directory holds the content of src
+                let mut src = s.src.as_os_str().to_owned();
+                if !src.to_string_lossy().ends_with('/') {
+                    src.push("/");
+                }
+                let link_dest = staging.link_dest();
+                let dir = staging.staging.as_os_str();
+                let cmd = xshell::cmd!(
+                    sh,
+                    "{rsync} {rsync_flags...} {compress...} {symlinks...} {bwlimit...} {password_file...} {stats...} {progress...} {link_dest} {src} {dir}"
+                );
+                run_rsync(cmd, &env, output.as_deref_mut(), runner)?;
+                staging.swap()?;
+            }
+            Transfer::Full => {
+                let src = s.src.as_os_str();
+                let cmd = xshell::cmd!(
+                    sh,
+                    "{rsync} {rsync_flags...} {compress...} {symlinks...} {bwlimit...} {backup...} {share...} {password_file...} {stats...} {progress...} {max_delete...} {src} {dst}"
+                );
+                run_rsync(cmd, &env, output, runner).map_err(max_delete_reached)?;
+            }
+        }
+        if let Some(manifest) = manifest {
+            if let Err(err) = manifest.save() {
+                warn!(?err, "Failed to save the manifest");
+            }
+            crate::hash_cache::save();
+        }
+        if let Some(digest) = relay {
+            crate::hash_cache::record_copy(&own_copy(&s.src, dst.as_ref()), digest);
+        }
+        Ok(())
+    }
+
+    /// Check with a dry run of rsync that dst is an exact copy of src, see
+    /// [config::FileSync::verify]
+    fn finalize(&self, ctx: &crate::backend::TransferContext<'_>) -> anyhow::Result<()> {
+        let s = ctx.sync;
+        if !s.verify {
+            return Ok(());
+        }
+        if let Some(runner) = ctx.runner {
+            runner.check()?;
+        }
+        let sh = xshell::Shell::new().context("Failed to init shell")?;
+        let rsync = ctx.rsync.unwrap_or_else(|| OsStr::new("rsync"));
+        let rsync_flags = s.rsync_flags.iter();
+        let symlinks = s.symlinks.map(config::SymlinkPolicy::rsync_flag);
+        let password_file = rsync_password_file(s);
+        let env = rsync_env(s, ctx.dst)?;
+        let src = s.src.as_os_str();
+        let dst = ctx.dst.as_os_str();
+        let cmd = xshell::cmd!(
+            sh,
+            "{rsync} {rsync_flags...} {symlinks...} {password_file...} --dry-run --checksum --delete --itemize-changes {src} {dst}"
+        );
+        let out = read_rsync(cmd, &env, ctx.runner).context("Failed to verify dst")?;
+        let paths = drift(&out);
+        if !paths.is_empty() {
+            return Err(Drift { paths }.into());
+        }
+        Ok(())
+    }
+}
+
+/// Sync the entry to every destination in parallel. Fails if any of them failed, listing the
+/// failed destinations. `output` gets the rsync stats of the first destination
+#[allow(clippy::too_many_arguments)]
+fn fan_out(
+    s: &ParsedSync,
+    destinations: &[&Path],
+    rsync: Option<&OsStr>,
+    initialize: bool,
+    changes: &SyncChanges,
+    mut output: Option<&mut SyncOutput>,
+    runner: Option<&ProcessRunner>,
+    started: SystemTime,
+) -> anyhow::Result<()> {
+    let collect = output.is_some();
+    let results = std::thread::scope(|scope| {
+        let handles = destinations
+            .iter()
+            .map(|dst| {
+                scope.spawn(move || {
+                    let mut out = SyncOutput::default();
+                    let out_ref = collect.then_some(&mut out);
+                    let res =
+                        transfer(s, dst, rsync, initialize, changes, out_ref, runner, started);
+                    (res, out)
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join().unwrap_or_else(|_| {
+                    (
+                        Err(anyhow::anyhow!("The sync panicked")),
+                        SyncOutput::default(),
+                    )
+                })
+            })
+            .collect::<Vec<_>>()
+    });
+    let mut failed = Vec::new();
+    let mut conflicts = Vec::new();
+    for (i, (dst, (res, out))) in destinations.iter().zip(results).enumerate() {
+        if i == 0 {
+            if let Some(output) = output.as_deref_mut() {
+                *output = out;
+            }
+        }
+        let Err(err) = res else {
+            continue;
+        };
+        if err.is::<Cancelled>() {
+            return Err(err);
+        }
+        error!(?dst, "Sync failed: {err:#}");
+        match err.downcast::<DstConflict>() {
+            Ok(conflict) => conflicts.extend(conflict.paths),
+            Err(_) => failed.push(dst.display().to_string()),
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "{} of {} destinations failed: {}",
+            failed.len(),
+            destinations.len(),
+            failed.join(", ")
+        );
+    }
+    if !conflicts.is_empty() {
+        conflicts.sort();
+        conflicts.dedup();
+        return Err(DstConflict { paths: conflicts }.into());
+    }
+    Ok(())
+}
+
+pub(crate) fn join_paths(paths: &BTreeSet<PathBuf>) -> String {
+    paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Files passed to rsync
+enum Transfer {
+    /// Scan the whole src
+    Full,
+    /// Only the listed files, relative to base
+    Files {
+        base: PathBuf,
+        files: String,
+        /// The list contains deleted paths, which should be deleted in dst
+        delete_missing: bool,
+    },
+    /// Nothing to do
+    Nothing,
+    /// Sync into the new release `name` of dst, hard linking the unchanged files of the
+    /// previous release
+    Snapshot {
+        name: String,
+        previous: Option<String>,
+    },
+    /// Sync into a staging directory next to the copy of src in dst and swap it into place
+    Atomic(crate::atomic::Staging),
+}
+
+/// Compare the changes to the manifest of the entry.
+///
+/// Initial syncs, syncs without known changes and entries without a manifest do a full sync and
+/// rebuild the manifest
+fn manifest_transfer(
+    s: &ParsedSync,
+    dst: &std::path::Path,
+    initialize: bool,
+    changes: &SyncChanges,
+) -> anyhow::Result<(crate::manifest::Manifest, Transfer)> {
+    use crate::manifest::Manifest;
+
+    let no_changes = changes.changed.is_empty() && changes.deleted.is_empty();
+    let base = s.src.parent();
+    let existing = match (initialize || no_changes, base) {
+        (false, Some(_)) => Manifest::load(&s.src, dst)?,
+        _ => None,
+    };
+    let (Some(mut manifest), Some(base)) = (existing, base) else {
+        let manifest = Manifest::build(&s.src, dst)?;
+        return Ok((manifest, Transfer::Full));
+    };
+    let diff = manifest.diff(
+        changes
+            .changed
+            .iter()
+            .chain(changes.deleted.iter())
+            .map(|p| p.as_path()),
+    );
+    if diff.is_empty() {
+        return Ok((manifest, Transfer::Nothing));
+    }
+    let files = diff
+        .changed
+        .iter()
+        .chain(diff.deleted.iter())
+        .filter_map(|p| p.strip_prefix(base).ok())
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let transfer = Transfer::Files {
+        base: base.to_owned(),
+        files,
+        delete_missing: !diff.deleted.is_empty(),
+    };
+    Ok((manifest, transfer))
+}
+
+/// Returns the `--files-from` base directory and list syncing only the changed files of `src`,
+/// and whether the list contains the old paths of renames, to delete with
+/// `--delete-missing-args`.
+///
+/// Returns None if a full sync is needed: nothing is known about the changes, or files were
+/// deleted other than by a rename, which rsync can only propagate by scanning the parent
+/// directory
+fn partial_files(src: &std::path::Path, changes: &SyncChanges) -> Option<(PathBuf, String, bool)> {
+    if changes.changed.is_empty()
+        || changes
+            .deleted
+            .iter()
+            .any(|p| !changes.renamed.contains_key(p))
+    {
+        return None;
+    }
+    // rsync `src dst` creates the last component of src inside dst, unless it has a trailing
+    // slash, so the list is relative to the transfer root to produce the same layout
+    let base = transfer_root(src);
+    let mut files = Vec::with_capacity(changes.changed.len() + changes.deleted.len());
+    for p in changes.changed.iter() {
+        if p == src || !p.starts_with(src) || !p.exists() {
+            // the whole tree changed, removed since the event, or outside of src
+            return None;
+        }
+        files.push(p.strip_prefix(base).ok()?.display().to_string());
+    }
+    for p in changes.deleted.iter() {
+        if p == src || !p.starts_with(src) {
+            return None;
+        }
+        files.push(p.strip_prefix(base).ok()?.display().to_string());
+    }
+    Some((
+        base.to_owned(),
+        files.join("\n"),
+        !changes.deleted.is_empty(),
+    ))
+}
+
+/// Repeat the renames of src in a local dst, so rsync finds the moved files in place instead of
+/// transferring them again. Renames that can't be repeated are left to rsync
+fn move_renamed(base: &Path, dst: &Path, changes: &SyncChanges) {
+    if config::remote_dst(dst).is_some() || config::rsync_daemon_dst(dst).is_some() {
+        return;
+    }
+    for (from, to) in changes.renamed.iter() {
+        let (Ok(from), Ok(to)) = (from.strip_prefix(base), to.strip_prefix(base)) else {
+            continue;
+        };
+        let (from, to) = (dst.join(from), dst.join(to));
+        if from.symlink_metadata().is_err() || to.symlink_metadata().is_ok() {
+            continue;
+        }
+        let moved = to
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::rename(&from, &to));
+        match moved {
+            Ok(()) => debug!(?from, ?to, "Moved in dst"),
+            Err(err) => warn!(
+                ?err,
+                ?from,
+                ?to,
+                "Failed to move in dst, transferring instead"
+            ),
+        }
+    }
+}
+
+/// The directory whose layout dst mirrors: rsync `src dst` creates the last component of src in
+/// dst, unless src has a trailing slash
+pub fn transfer_root(src: &Path) -> &Path {
+    if src.as_os_str().to_string_lossy().ends_with('/') {
+        src
+    } else {
+        src.parent().unwrap_or(src)
+    }
+}
+
+/// Paths of the `--itemize-changes` output of rsync that would delete or overwrite files of dst
+/// without a corresponding change in src.
+///
+/// Without known changes, only deletions are conflicts: overwriting is what a full sync is for
+fn dst_conflicts(itemized: &str, src: &Path, changes: &SyncChanges) -> Vec<PathBuf> {
+    let base = transfer_root(src);
+    let known = !changes.changed.is_empty() || !changes.deleted.is_empty();
+    let covered = |path: &Path| {
+        base.join(path)
+            .ancestors()
+            .any(|a| changes.changed.contains(a) || changes.deleted.contains(a))
+    };
+    itemized
+        .lines()
+        .filter_map(|line| {
+            if let Some(path) = line.strip_prefix("*deleting") {
+                return Some(path.trim());
+            }
+            // `YXcstpoguax name`: overwriting a file, as opposed to creating it (`>f+++++++++`)
+            let (flags, path) = line.split_once(' ')?;
+            let overwrite = flags.len() == 11
+                && (flags.starts_with(">f") || flags.starts_with("<f"))
+                && !flags[2..].starts_with('+');
+            (overwrite && known).then_some(path)
+        })
+        .map(|path| PathBuf::from(path.trim_end_matches('/')))
+        .filter(|path| !covered(path))
+        .collect()
+}
+
+/// Paths of the `--itemize-changes` output of a verifying dry run of rsync that it would transfer
+/// or delete. Changes of attributes only, e.g. of modification times, aren't drift
+fn drift(itemized: &str) -> Vec<PathBuf> {
+    itemized
+        .lines()
+        .filter_map(|line| {
+            if let Some(path) = line.strip_prefix("*deleting") {
+                return Some(path.trim());
+            }
+            let (flags, path) = line.split_once(' ')?;
+            (flags.len() == 11 && matches!(flags.as_bytes()[0], b'<' | b'>' | b'c' | b'h'))
+                .then_some(path)
+        })
+        .map(|path| PathBuf::from(path.trim_end_matches('/')))
+        .collect()
+}
+
+/// Temporary file holding a newline separated list of paths, removed on drop.
+///
+/// Created exclusively and only readable by the user, so other users of the temp dir can't
+/// plant the file, e.g. to inject values into `ATUNE_OUTPUT`
+struct PathListFile(tempfile::TempPath);
+
+impl PathListFile {
+    fn new(name: &str, content: &str) -> std::io::Result<Self> {
+        use std::io::Write as _;
+
+        let mut file = tempfile::Builder::new()
+            .prefix(&format!("atune-{name}-"))
+            .tempfile()?;
+        if !content.is_empty() {
+            writeln!(file, "{content}")?;
+        }
+        Ok(Self(file.into_temp_path()))
+    }
+}
+
+/// A running sync, see [config::Execution]
+#[derive(Debug)]
+enum SyncHandle {
+    Process {
+        proc: process::Child,
+        /// the result once it exited, the process is reaped and can't be waited for again
+        exited: Option<(Result<(), SyncError>, ResourceUsage)>,
+    },
+    Task(SyncTask),
+}
+
+impl SyncHandle {
+    fn process(proc: process::Child) -> Self {
+        SyncHandle::Process { proc, exited: None }
+    }
+
+    /// The result of the sync and the resources it used, or None if it is still running
+    fn try_wait(&mut self) -> Option<(Result<(), SyncError>, ResourceUsage)> {
+        match self {
+            SyncHandle::Process { proc, exited } => {
+                if exited.is_none() {
+                    *exited = match crate::rusage::try_wait(proc) {
+                        Ok(None) => None,
+                        Ok(Some((status, usage))) => {
+                            crate::process_group::forget(proc);
+                            Some((sync_result(status), usage))
+                        }
                         Err(err) => {
-                            error!(?err, "Failed to kill sync process");
+                            error!(?err, "Failed to wait for sync command");
+                            Some((
+                                Err(SyncError::Failed { exit_code: None }),
+                                Default::default(),
+                            ))
+                        }
+                    };
+                }
+                exited.clone()
+            }
+            SyncHandle::Task(task) => task.try_wait().map(|result| (result, task.usage())),
+        }
+    }
+
+    fn kill(self) {
+        match self {
+            SyncHandle::Process { proc, exited } => {
+                if exited.is_none() {
+                    kill_process(proc)
+                }
+            }
+            SyncHandle::Task(task) => task.cancel(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct InFlightSync {
+    proc: SyncHandle,
+    /// changes the sync was started with
+    changes: SyncChanges,
+    started: Instant,
+    output: crate::output::OutputTail,
+}
+
+/// A sync reaped by [SyncProcesses::reap]
+struct FinishedSync {
+    key: PathBuf,
+    result: Result<(), SyncError>,
+    usage: ResourceUsage,
+    duration: Duration,
+    /// changes the sync was started with
+    changes: SyncChanges,
+    output: crate::output::OutputTail,
+}
+
+/// In-flight sync processes, keyed by the canonical src path of the sync entry
+#[derive(Debug, Default)]
+struct SyncProcesses(HashMap<PathBuf, InFlightSync>);
+
+impl Drop for SyncProcesses {
+    fn drop(&mut self) {
+        for (_, s) in self.0.drain() {
+            s.proc.kill();
+        }
+    }
+}
+
+/// Map the exit status of a `sync-project` process to the result of the sync
+pub(crate) fn sync_result(status: process::ExitStatus) -> Result<(), SyncError> {
+    if status.success() {
+        Ok(())
+    } else if status.code() == Some(EXIT_HOOK_FAILED) {
+        Err(SyncError::HookFailed)
+    } else if status.code() == Some(EXIT_DST_CONFLICT) {
+        Err(SyncError::DstConflict)
+    } else if status.code() == Some(EXIT_UNREACHABLE) {
+        Err(SyncError::Unreachable)
+    } else if status.code() == Some(EXIT_DELETE_REFUSED) {
+        Err(SyncError::DeleteRefused)
+    } else if status.code() == Some(EXIT_DELETE_LIMIT) {
+        Err(SyncError::DeleteLimit)
+    } else {
+        Err(SyncError::Failed {
+            exit_code: status.code(),
+        })
+    }
+}
+
+/// Kill the sync process and everything it started, e.g. rsync and its ssh
+fn kill_process(mut proc: process::Child) {
+    match proc.try_wait() {
+        Ok(Some(_)) => crate::process_group::forget(&proc),
+        Ok(None) => {
+            debug!("Killing in-progress sync");
+            crate::process_group::kill(&mut proc, crate::process_group::KILL_GRACE);
+        }
+        Err(err) => {
+            error!(?err, "Failed to wait for sync command");
+        }
+    }
+}
+
+impl SyncProcesses {
+    pub fn insert(
+        &mut self,
+        key: PathBuf,
+        (proc, output): (SyncHandle, crate::output::OutputTail),
+        changes: SyncChanges,
+    ) {
+        let s = InFlightSync {
+            proc,
+            changes,
+            started: Instant::now(),
+            output,
+        };
+        if let Some(old) = self.0.insert(key, s) {
+            old.proc.kill();
+        }
+    }
+
+    /// cancel the in-progress sync of the given entry, if any.
+    /// Returns the changes the cancelled sync was started with
+    pub fn cancel(&mut self, key: &std::path::Path) -> Option<SyncChanges> {
+        self.0.remove(key).map(|s| {
+            s.proc.kill();
+            s.changes
+        })
+    }
+
+    /// Returns true if the given entry has a sync that wasn't reaped yet, even if it exited.
+    /// A new sync of the entry must not be started before, or the result of the old one is lost
+    pub fn contains(&self, key: &std::path::Path) -> bool {
+        self.0.contains_key(key)
+    }
+
+    /// Returns true if the given entry has a sync still running
+    pub fn is_running(&mut self, key: &std::path::Path) -> bool {
+        self.0
+            .get_mut(key)
+            .is_some_and(|s| s.proc.try_wait().is_none())
+    }
+
+    /// Remove the finished syncs
+    pub fn reap(&mut self) -> Vec<FinishedSync> {
+        let mut finished = Vec::new();
+        self.0.retain(|key, s| {
+            let Some((result, usage)) = s.proc.try_wait() else {
+                return true;
+            };
+            finished.push(FinishedSync {
+                key: key.clone(),
+                result,
+                usage,
+                duration: s.started.elapsed(),
+                changes: std::mem::take(&mut s.changes),
+                output: s.output.clone(),
+            });
+            false
+        });
+        finished
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The healthcheck of a project, run in the background so a hanging check doesn't block the
+/// queue, see [config::Project::healthcheck]
+#[derive(Debug)]
+struct Healthcheck {
+    commands: Arc<Vec<CommandConfig>>,
+    interval: Duration,
+    /// the result of the last check and when it finished
+    last: Option<(bool, Instant)>,
+    running: Option<std::thread::JoinHandle<bool>>,
+}
+
+impl Healthcheck {
+    fn new(commands: Vec<CommandConfig>, interval: Duration) -> Self {
+        Self {
+            commands: Arc::new(commands),
+            interval,
+            last: None,
+            running: None,
+        }
+    }
+
+    /// The last check failed, and no new one is running
+    fn failing(&mut self) -> bool {
+        self.collect();
+        self.running.is_none() && self.last.is_some_and(|(ok, _)| !ok)
+    }
+
+    /// Whether the syncs may start. Once the last result expired, they wait for a new check
+    fn healthy(&mut self) -> bool {
+        if self.commands.is_empty() {
+            return true;
+        }
+        self.collect();
+        if let Some((ok, _)) = self.last.filter(|(_, at)| at.elapsed() < self.interval) {
+            return ok;
+        }
+        if self.running.is_none() {
+            let commands = self.commands.clone();
+            self.running = Some(std::thread::spawn(move || {
+                commands.iter().all(|cmd| {
+                    let output = shell_command(cmd).and_then(|mut c| {
+                        Ok(c.stdin(process::Stdio::null()).output()?)
+                    });
+                    match output {
+                        Ok(o) if o.status.success() => true,
+                        Ok(o) => {
+                            let stderr = String::from_utf8_lossy(&o.stderr);
+                            debug!(command = cmd.command, status = %o.status, %stderr, "Healthcheck command failed");
+                            false
                         }
-                        Ok(_) => {
-                            // clean up
-                            if let Err(err) = proc.wait() {
-                                error!(?err, "Failed to wait for killed process");
-                            }
+                        Err(err) => {
+                            debug!(?err, command = cmd.command, "Failed to run the healthcheck");
+                            false
                         }
                     }
+                })
+            }));
+        }
+        false
+    }
+
+    /// Record the result of a finished check
+    fn collect(&mut self) {
+        let Some(check) = self.running.take_if(|c| c.is_finished()) else {
+            return;
+        };
+        let ok = check.join().unwrap_or(false);
+        match (self.last.map(|(ok, _)| ok), ok) {
+            (Some(true) | None, false) => {
+                warn!("Healthcheck failed, queueing the changes until it succeeds")
+            }
+            (Some(false), true) => info!("Healthcheck succeeded, syncing the queued changes"),
+            _ => debug!(ok, "Healthcheck finished"),
+        }
+        self.last = Some((ok, Instant::now()));
+    }
+}
+
+/// Keep the changes of the entry `a` for a retry once dst is reachable again, on disk so they
+/// survive a restart
+fn queue_offline(
+    offline: &mut HashMap<PathBuf, SyncChanges>,
+    config: &Path,
+    project: &str,
+    a: &Path,
+    src: &Path,
+    mut changes: SyncChanges,
+) {
+    if let Some(older) = offline.remove(a) {
+        changes.merge_older(older);
+    }
+    if let Err(err) = crate::offline::save(config, project, src, &changes) {
+        warn!(?err, "Failed to save the offline queue");
+    }
+    offline.insert(a.to_owned(), changes);
+}
+
+/// Minimum time between two starts of the same `run` command, if it keeps exiting on its own
+const RUN_RESPAWN_DELAY: Duration = Duration::from_secs(1);
+/// Stopped `run` commands get this long to shut down before they are killed
+const RUN_STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// Long-running `run` commands of a project
+#[derive(Debug, Default)]
+struct RunProcesses {
+    commands: Vec<CommandConfig>,
+    procs: Vec<Option<(process::Child, Instant)>>,
+}
+
+impl Drop for RunProcesses {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl RunProcesses {
+    pub fn new(commands: Vec<CommandConfig>) -> Self {
+        let procs = commands.iter().map(|_| None).collect();
+        Self { commands, procs }
+    }
+
+    fn stop(&mut self) {
+        for (mut proc, _) in self.procs.iter_mut().filter_map(Option::take) {
+            // run commands are spawned in their own process group, so that children of the
+            // shell are stopped too
+            crate::process_group::kill(&mut proc, RUN_STOP_GRACE);
+        }
+    }
+
+    /// (Re)start all run commands
+    pub fn restart(&mut self) {
+        if self.commands.is_empty() {
+            return;
+        }
+        info!("Restarting run commands");
+        self.stop();
+        for (cmd, proc) in self.commands.iter().zip(self.procs.iter_mut()) {
+            *proc = spawn_run_command(cmd);
+        }
+    }
+
+    /// Respawn the run commands that exited on their own
+    pub fn keep_alive(&mut self) {
+        for (cmd, slot) in self.commands.iter().zip(self.procs.iter_mut()) {
+            let Some((proc, started)) = slot.as_mut() else {
+                continue;
+            };
+            match proc.try_wait() {
+                Ok(None) => {}
+                Ok(Some(status)) => {
+                    crate::process_group::forget(proc);
+                    if started.elapsed() < RUN_RESPAWN_DELAY {
+                        continue;
+                    }
+                    warn!(command = cmd.command, %status, "Run command exited, restarting");
+                    *slot = spawn_run_command(cmd);
                 }
                 Err(err) => {
-                    error!(?err, "Failed to wait for sync command");
+                    error!(?err, "Failed to wait for run command");
                 }
             }
         }
     }
+}
 
-    pub fn wait(&mut self) {
-        for mut proc in self.0.drain(..) {
-            match proc.wait() {
-                Ok(_) => {}
-                Err(err) => {
-                    error!(?err, "Failed to wait for sync command");
+/// Build the process running the command in its shell
+/// The environment variables of a Lua hook: the ones of the hook, then the `env` of the command
+fn script_env<'a>(env: &[(&'a str, &'a str)], cmd: &'a CommandConfig) -> Vec<(&'a str, &'a str)> {
+    env.iter()
+        .copied()
+        .chain(cmd.env.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .collect()
+}
+
+fn shell_command(cmd: &CommandConfig) -> anyhow::Result<process::Command> {
+    anyhow::ensure!(
+        crate::script::parse(&cmd.command).is_none(),
+        "Lua scripts only run as hooks"
+    );
+    if let Some(plugin) = crate::plugin::command(cmd) {
+        return plugin;
+    }
+    let shell = cmd.shell.clone().unwrap_or_else(config::default_shell);
+    let (program, shell_args) = shell
+        .split_first()
+        .context("Shell must have at least one element")?;
+    let mut proc = process::Command::new(program);
+    proc.args(shell_args).arg(&cmd.command).envs(cmd.env.iter());
+    if let Some(cwd) = cmd.cwd.as_ref() {
+        proc.current_dir(cwd);
+    }
+    Ok(proc)
+}
+
+/// The values a command wrote to its `ATUNE_OUTPUT` file: `KEY=value` lines, or `KEY<<DELIMITER`
+/// followed by the lines of the value up to `DELIMITER`
+fn parse_outputs(content: &str) -> Vec<(String, String)> {
+    let valid =
+        |key: &str| !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let mut values = Vec::new();
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some((key, delimiter)) = line.split_once("<<").filter(|(k, _)| valid(k)) {
+            let value = lines
+                .by_ref()
+                .take_while(|l| *l != delimiter)
+                .collect::<Vec<_>>()
+                .join("\n");
+            values.push((key.to_owned(), value));
+        } else if let Some((key, value)) = line.split_once('=').filter(|(k, _)| valid(k)) {
+            values.push((key.to_owned(), value.to_owned()));
+        } else {
+            warn!(
+                line,
+                "Ignoring invalid line of ATUNE_OUTPUT, expected KEY=value"
+            );
+        }
+    }
+    values
+}
+
+/// Whether the `run_if` condition of `cmd` holds, checked like the command with the extra
+/// environment variables `env`
+fn condition_met(
+    cmd: &CommandConfig,
+    env: &[(&str, &str)],
+    runner: Option<&ProcessRunner>,
+) -> anyhow::Result<bool> {
+    let Some(condition) = cmd.run_if.as_ref() else {
+        return Ok(true);
+    };
+    let mut proc = shell_command(&CommandConfig {
+        command: condition.clone(),
+        ..cmd.clone()
+    })?;
+    proc.envs(env.iter().copied());
+    let met = match runner {
+        Some(runner) => runner.run(proc)?.success(),
+        None => proc
+            .status()
+            .context("Failed to run the run_if condition")?
+            .success(),
+    };
+    if !met {
+        info!(
+            command = cmd.command,
+            condition, "Skipping the command, its run_if condition failed"
+        );
+    }
+    Ok(met)
+}
+
+fn spawn_run_command(cmd: &CommandConfig) -> Option<(process::Child, Instant)> {
+    let mut proc = match shell_command(cmd) {
+        Ok(proc) => proc,
+        Err(err) => {
+            error!(?err, command = cmd.command, "Invalid run command");
+            return None;
+        }
+    };
+    match crate::process_group::spawn(&mut proc) {
+        Ok(child) => Some((child, Instant::now())),
+        Err(err) => {
+            error!(?err, command = cmd.command, "Failed to spawn run command");
+            None
+        }
+    }
+}
+
+/// How often the pending queue is checked for entries whose previous sync finished
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Changes of a watched dst this long after a sync of its entry finished are still attributed to
+/// the sync, e.g. events delivered late by the OS
+const DRIFT_GRACE: Duration = Duration::from_secs(1);
+
+/// Whether the entry is synced when the watch starts, and if so, whether the sync runs its init
+/// commands. `state` is needed by the [config::InitialSync::IfNeeded] entries
+pub(crate) fn initial_sync(
+    state: Option<&crate::state::SyncState>,
+    config_path: &Path,
+    project: &str,
+    f: &ParsedSync,
+) -> Option<bool> {
+    match f.initial_sync {
+        config::InitialSync::Always => Some(true),
+        config::InitialSync::Never => None,
+        config::InitialSync::IfNeeded => {
+            let entry = state
+                .and_then(|s| s.get(config_path, project, &f.src))
+                .filter(|e| e.initialized);
+            match entry {
+                None => Some(true),
+                Some(e) if e.fingerprint != crate::state::fingerprint(f) => {
+                    info!(src = ?f.src, "Changed since the last sync, syncing without the init commands");
+                    Some(false)
+                }
+                Some(_) => {
+                    info!(src = ?f.src, "Unchanged since the last sync, skipping the initial sync");
+                    None
                 }
             }
         }
     }
 }
 
+/// When to sync a burst of changes
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Debounce {
+    /// sync once no changes arrived for this long
+    pub quiet_period: Duration,
+    /// but at most this long after the first change
+    pub max_wait: Duration,
+}
+
+impl Debounce {
+    /// Raised to the minimum of the transfer profiles of the project
+    pub(crate) fn for_project(self, project: &config::Project) -> Self {
+        project
+            .sync
+            .iter()
+            .filter(|s| s.enabled)
+            .filter_map(|s| s.transfer_profile)
+            .map(config::TransferProfile::min_debounce)
+            .fold(self, |d, (quiet_period, max_wait)| Debounce {
+                quiet_period: d.quiet_period.max(quiet_period),
+                max_wait: d.max_wait.max(max_wait),
+            })
+    }
+
+    /// Collect the changes following `first` until the burst is over
+    fn collect<T>(&self, rx: &channel::Receiver<T>, first: T, mut f: impl FnMut(T)) {
+        let deadline = Instant::now() + self.max_wait;
+        f(first);
+        loop {
+            let quiet = (Instant::now() + self.quiet_period).min(deadline);
+            match rx.recv_deadline(quiet) {
+                Ok(next) => f(next),
+                // a disconnect is handled by the caller's next receive
+                Err(_) => break,
+            }
+        }
+    }
+}
+
 #[tracing::instrument(skip_all)]
 fn sync_files(
-    files: Vec<ParsedSync>,
+    project: ParsedProject,
     rx: channel::Receiver<SyncOneRequest>,
-    debounce: Duration,
-    config_path: &std::path::Path,
-    project: &str,
-    restart: bool,
+    control: channel::Receiver<WatchControl>,
+    debounce: Debounce,
+    ctx: &SyncContext,
 ) {
-    let cmd = move || sync_project_cmd(project, config_path);
-
-    let mut in_progress = SyncProcesses::default();
-    for f in files.iter() {
-        let proc = cmd()
-            .arg("--initialize")
-            .arg("--src")
-            .arg(f.src.as_os_str())
-            .spawn()
-            .expect("Failed to spawn sync command");
+    let ParsedProject {
+        name: project,
+        sync: files,
+        restart,
+        rsync,
+        run,
+        on_sync,
+        on_init,
+        on_delete,
+        depends_on,
+        healthcheck,
+        healthcheck_interval,
+        ..
+    } = project;
+    let project = project.as_str();
+    let rsync = rsync.or_else(|| ctx.rsync.clone());
+    let cmd = || sync_project_cmd(&ctx.executable, project, &ctx.config_path, rsync.as_deref());
+    let start = |a: &Path, s: &ParsedSync, initialize: bool, changes: &SyncChanges| {
+        let label = sync_label(project, &s.src);
+        let on_progress = ctx.progress.then(|| {
+            let ctx = ctx.clone();
+            let (project, src) = (project.to_owned(), s.src.clone());
+            Arc::new(move |progress| {
+                ctx.emit(WatchEvent::SyncProgress {
+                    project: project.clone(),
+                    src: src.clone(),
+                    progress,
+                })
+            }) as Arc<dyn Fn(crate::output::Progress) + Send + Sync>
+        });
+        let log_file = ctx
+            .log_dir
+            .as_ref()
+            .map(|d| d.join(format!("{}.log", label.replace([':', '/', '\\'], "-"))));
+        if let Some(pool) = ctx.pool.as_ref() {
+            let mut sync = s.clone();
+            sync.ssh_multiplexing = ctx.ssh_multiplexing;
+            sync.progress = ctx.progress;
+            if crate::verbosity::from_env() < 0 {
+                crate::verbosity::quiet_rsync_flags(&mut sync.rsync_flags);
+            }
+            let job = SyncJob {
+                config_path: ctx.config_path.clone(),
+                project: project.to_owned(),
+                sync,
+                rsync: rsync.clone(),
+                initialize,
+                changes: changes.clone(),
+                notifications: ctx.notifications.clone(),
+                log_file,
+                on_progress,
+                span: tracing::Span::current(),
+            };
+            let (task, output) = SyncTask::start(pool, job);
+            return (SyncHandle::Task(task), output);
+        }
 
-        in_progress.0.push(proc);
-    }
+        let mut cmd = cmd();
+        if initialize {
+            cmd.arg("--initialize");
+        }
+        cmd.arg("--src").arg(a.as_os_str());
+        for p in changes.changed.iter() {
+            cmd.arg("--changed").arg(p);
+        }
+        for p in changes.deleted.iter() {
+            cmd.arg("--deleted").arg(p);
+        }
+        for (from, to) in changes.renamed.iter() {
+            cmd.arg("--renamed").arg(from).arg(to);
+        }
+        if on_progress.is_some() {
+            cmd.arg("--progress");
+        }
+        if let Some(id) = changes.id {
+            cmd.env(CHANGE_ID_ENV, id.to_string());
+        }
+        // the sync continues the trace of the span it's started in
+        #[cfg(feature = "otel")]
+        if let Some(parent) = crate::otel::traceparent(&tracing::Span::current()) {
+            cmd.env(crate::otel::TRACEPARENT_ENV, parent);
+        }
+        cmd.stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped());
+        // in its own process group, so cancelling the sync stops rsync and ssh too
+        let mut proc = crate::process_group::spawn(&mut cmd).expect("Failed to spawn sync command");
+        let on_progress = on_progress.map(|f| {
+            Box::new(move |progress| f(progress)) as Box<dyn Fn(crate::output::Progress) + Send>
+        });
+        let output = crate::output::capture(&mut proc, &label, log_file.as_deref(), on_progress);
+        (SyncHandle::process(proc), output)
+    };
+    let mut run = RunProcesses::new(run);
+    let mut healthcheck = Healthcheck::new(healthcheck, healthcheck_interval);
 
     let files = files
         .iter()
-        .map(|s| (std::fs::canonicalize(s.src.as_path()).unwrap(), s))
+        .map(|s| {
+            // the src may not exist yet, the watcher picks it up once it is created
+            let follow = s.filter.follow_symlinks;
+            let src = crate::platform::resolve(s.src.as_path(), follow).unwrap_or_else(|err| {
+                warn!(?err, src = ?s.src, "Failed to resolve sync source");
+                s.src.clone()
+            });
+            (src, s)
+        })
         .collect::<HashMap<_, _>>();
+    let mut dst_watcher = crate::drift::DstWatcher::new(files.iter().map(|(a, s)| (a, *s)));
+    // when the last sync of each entry finished, its changes of dst aren't drift
+    let mut last_finished = HashMap::<PathBuf, Instant>::new();
+    // start of the last sync of each entry, for min_interval
+    let mut last_started = HashMap::<PathBuf, Instant>::new();
+    // first change of the entries waiting in to_sync, and of the ones syncing, for the latency
+    let mut first_change = HashMap::<PathBuf, Instant>::new();
+    let mut syncing_since = HashMap::<PathBuf, Instant>::new();
+    // span of each running sync, from its start until it's reaped
+    let mut sync_spans = HashMap::<PathBuf, tracing::Span>::new();
 
-    let mut to_sync = HashSet::new();
+    let mut in_progress = SyncProcesses::default();
+    // entries whose initial sync waits for their lock group
+    let mut initial_pending = Vec::new();
+    // the initial syncs are started once the dependencies finished their initial syncs
+    let mut waiting_for_dependencies = true;
+    // entries whose initial sync is still in progress, and whether it runs their init commands
+    let mut initializing = HashMap::new();
+    let mut initial_success = true;
+    // an initial sync ran the init commands of its entry, so the project's follow
+    let mut project_init = false;
+
+    // entries waiting for a sync, coalesced so that each entry is synced at most once more
+    let mut to_sync = HashMap::<PathBuf, SyncChanges>::new();
+    // changes of syncs refused by `protect_dst`, `confirm_delete` or `max_delete`, retried with
+    // the next change of the entry
+    let mut refused = HashMap::<PathBuf, SyncChanges>::new();
+    // changes that didn't reach an unreachable dst, retried every healthcheck_interval
+    let mut offline = HashMap::<PathBuf, SyncChanges>::new();
+    let mut offline_retry = Instant::now();
+    // entries with changes in the offline queue on disk, removed once a sync of them succeeded
+    let mut persisted = HashSet::new();
+    for (a, s) in files.iter() {
+        if let Some(changes) = crate::offline::load(&ctx.config_path, project, &s.src) {
+            info!(src = ?s.src, "Replaying the changes queued while dst was unreachable");
+            to_sync.insert(a.clone(), changes);
+            persisted.insert(a.clone());
+        }
+    }
+    // a sync succeeded since the run commands were last restarted
+    let mut synced = false;
+    // deleted by the syncs of the current batch, for the on_delete commands of the project
+    let mut batch_deleted = BTreeSet::new();
+    let mut paused = false;
     loop {
-        let Ok(req) = rx.recv() else {
-            break;
+        if waiting_for_dependencies {
+            match ctx.initial_syncs.status(&depends_on) {
+                None => {}
+                Some(true) => {
+                    waiting_for_dependencies = false;
+                    let state = files
+                        .values()
+                        .any(|f| f.initial_sync == config::InitialSync::IfNeeded)
+                        .then(crate::state::SyncState::load);
+                    for (a, f) in files.iter() {
+                        let Some(initialize) =
+                            initial_sync(state.as_ref(), &ctx.config_path, project, f)
+                        else {
+                            continue;
+                        };
+                        initial_pending.push(a.clone());
+                        initializing.insert(a.clone(), initialize);
+                        project_init |= initialize;
+                    }
+                    if initializing.is_empty() {
+                        ctx.initial_syncs.finish(project, true);
+                    }
+                }
+                Some(false) => {
+                    waiting_for_dependencies = false;
+                    error!(
+                        ?depends_on,
+                        "Dependencies failed their initial sync, skipping the initial sync"
+                    );
+                    ctx.initial_syncs.finish(project, false);
+                }
+            }
+        }
+
+        // the syncs wait while the healthcheck fails, checked only when there is something to sync
+        let healthy = initial_pending.is_empty() || healthcheck.healthy();
+        // an initial sync waits while another sync holds its lock group
+        initial_pending.retain(|a| {
+            if !healthy {
+                return true;
+            }
+            let f = files[a];
+            if !ctx.lock_groups.acquire(f.lock_group.as_deref(), project, a) {
+                return true;
+            }
+            let initialize = initializing[a];
+            let span = tracing::info_span!(parent: None, "sync", project, src = %f.src.display(), initialize, change_id = tracing::field::Empty, waited_ms = tracing::field::Empty, result = tracing::field::Empty);
+            let proc = span.in_scope(|| start(a, f, initialize, &SyncChanges::default()));
+            sync_spans.insert(a.clone(), span);
+            last_started.insert(a.clone(), Instant::now());
+
+            ctx.emit(WatchEvent::SyncStarted {
+                project: project.to_owned(),
+                src: f.src.clone(),
+                initialize,
+            });
+            in_progress.insert(a.clone(), proc, SyncChanges::default());
+            false
+        });
+
+        match rx.recv_timeout(QUEUE_POLL_INTERVAL) {
+            Ok(req) => {
+                debug!(changed=?req.path, change_id = req.id, "received change");
+                let queue = |req: SyncOneRequest| {
+                    if let Some(a) = req.path.ancestors().find(|a| files.contains_key(*a)) {
+                        let changes = to_sync.entry(a.to_owned()).or_default();
+                        let id = *changes.id.get_or_insert(req.id);
+                        if id != req.id {
+                            debug!(change_id = req.id, "coalesced into change {id}");
+                        }
+                        first_change.entry(a.to_owned()).or_insert(req.received);
+                        match req.renamed_from {
+                            Some(from) if from.starts_with(a) => changes.rename(from, req.path),
+                            _ => changes.add(req.path, req.kind),
+                        }
+                    }
+                };
+                debounce.collect(&rx, req, queue);
+            }
+            Err(channel::RecvTimeoutError::Timeout) => {}
+            Err(channel::RecvTimeoutError::Disconnected) => break,
+        }
+        for ctl in control.try_iter() {
+            match ctl {
+                WatchControl::Pause { .. } if !paused => {
+                    paused = true;
+                    info!("Paused");
+                    ctx.emit(WatchEvent::Paused {
+                        project: project.to_owned(),
+                    });
+                }
+                WatchControl::Resume { .. } if paused => {
+                    paused = false;
+                    info!("Resumed");
+                    ctx.emit(WatchEvent::Resumed {
+                        project: project.to_owned(),
+                    });
+                }
+                WatchControl::Trigger { .. } => {
+                    for a in files.keys() {
+                        to_sync
+                            .entry(a.clone())
+                            .or_default()
+                            .add(a.clone(), ChangeKind::Changed);
+                    }
+                }
+                WatchControl::Changed { path } => {
+                    let kind = if path.exists() {
+                        ChangeKind::Changed
+                    } else {
+                        ChangeKind::Removed
+                    };
+                    let entry = path.ancestors().find_map(|a| files.get_key_value(a));
+                    if let Some((a, s)) = entry {
+                        let rel = path.strip_prefix(a).unwrap_or(&path);
+                        if s.filter.matches(a, rel, kind) {
+                            to_sync.entry(a.clone()).or_default().add(path, kind);
+                        }
+                    }
+                }
+                WatchControl::Pause { .. } | WatchControl::Resume { .. } => {}
+            }
+        }
+
+        for FinishedSync {
+            key: a,
+            result,
+            usage,
+            duration,
+            changes,
+            output,
+        } in in_progress.reap()
+        {
+            last_finished.insert(a.clone(), Instant::now());
+            let latency = syncing_since.remove(&a).map(|t| t.elapsed());
+            let bytes = crate::stats::transferred_bytes(output.lines().iter().map(String::as_str));
+            let src = &files[&a].src;
+            if let Some(span) = sync_spans.remove(&a) {
+                match result.as_ref() {
+                    Ok(()) => span.record("result", "ok"),
+                    Err(err) => span.record("result", format!("{err:?}")),
+                };
+            }
+            #[cfg(feature = "otel")]
+            crate::otel::record_sync(project, src, result.is_ok(), bytes, latency);
+            ctx.stats.lock().unwrap().record(
+                &ctx.config_path,
+                project,
+                src,
+                result.is_ok(),
+                bytes,
+                latency,
+            );
+            crate::stats::record(
+                &ctx.config_path,
+                project,
+                src,
+                result.is_ok(),
+                bytes,
+                latency,
+            );
+            let initialized = initializing.remove(&a);
+            if initialized.is_some() {
+                initial_success &= result.is_ok();
+            }
+            let src = files[&a].src.clone();
+            info!(
+                ?src,
+                change_id = changes.id,
+                ?duration,
+                ?latency,
+                cpu = ?usage.cpu(),
+                max_rss = usage.max_rss,
+                "Sync finished"
+            );
+            if let Err(err) = result.as_ref() {
+                error!(
+                    ?src,
+                    change_id = changes.id,
+                    "Sync failed: {err:?}. Last output:\n{}",
+                    output.lines().join("\n")
+                );
+            }
+            if result == Err(SyncError::HookFailed) {
+                ctx.emit(WatchEvent::HookFailed {
+                    project: project.to_owned(),
+                    src: src.clone(),
+                });
+            }
+            if let Err(err) = result.as_ref() {
+                let sync_src = src.display().to_string();
+                let env = [
+                    ("ATUNE_PROJECT", project),
+                    ("ATUNE_SYNC_SRC", sync_src.as_str()),
+                    ("ATUNE_SYNC_ERROR", err.kind()),
+                ];
+                run_hooks(
+                    &ctx.config_path,
+                    project,
+                    "on_failure",
+                    &files[&a].on_failure,
+                    &env,
+                );
+            }
+            if result.is_ok() {
+                let sync = files[&a];
+                // fingerprinting src walks it, which would hold up the changes of the project
+                crate::state::record_success_in_background(
+                    &ctx.config_path,
+                    project,
+                    sync,
+                    initialized == Some(true),
+                );
+                batch_deleted.extend(changes.deleted);
+                if persisted.remove(&a) {
+                    crate::offline::remove(&ctx.config_path, project, &sync.src);
+                }
+            } else if matches!(
+                result,
+                Err(SyncError::DstConflict | SyncError::DeleteRefused | SyncError::DeleteLimit)
+            ) {
+                refused.insert(a.clone(), changes);
+            } else if result == Err(SyncError::Unreachable) {
+                warn!(?src, "dst is unreachable, keeping the changes for a retry");
+                queue_offline(&mut offline, &ctx.config_path, project, &a, &src, changes);
+                persisted.insert(a.clone());
+                offline_retry = Instant::now();
+            }
+            synced |= result.is_ok();
+            ctx.emit(WatchEvent::SyncFinished {
+                project: project.to_owned(),
+                src,
+                result,
+                duration,
+                usage,
+            });
+            // after the event, so Ready follows the last initial SyncFinished
+            if initialized.is_some() && initializing.is_empty() {
+                ctx.initial_syncs.finish(project, initial_success);
+                if initial_success && project_init {
+                    run_hooks(
+                        &ctx.config_path,
+                        project,
+                        "init",
+                        &on_init,
+                        &[("ATUNE_PROJECT", project)],
+                    );
+                }
+            }
+            // after the event, so the next sync of the group is reported after this one finished
+            ctx.lock_groups
+                .release(files[&a].lock_group.as_deref(), project, &a);
+        }
+        if synced && in_progress.is_empty() {
+            let deleted = join_paths(&std::mem::take(&mut batch_deleted));
+            let env = [
+                ("ATUNE_PROJECT", project),
+                ("ATUNE_DELETED_PATHS", &deleted),
+            ];
+            let hooks_ok = (deleted.is_empty()
+                || run_hooks(&ctx.config_path, project, "on_delete", &on_delete, &env))
+                && run_hooks(&ctx.config_path, project, "on_sync", &on_sync, &env);
+            // don't restart e.g. a dev server after its build failed
+            if hooks_ok {
+                run.restart();
+            }
+            synced = false;
+        }
+        run.keep_alive();
+
+        if let Some(watcher) = dst_watcher.as_mut() {
+            let syncing = |a: &Path| {
+                in_progress.contains(a)
+                    || last_finished
+                        .get(a)
+                        .is_some_and(|t| t.elapsed() < DRIFT_GRACE)
+            };
+            for (a, paths) in watcher.poll(syncing) {
+                let s = files[&a];
+                warn!(src = ?s.src, dst = ?s.dst, ?paths, "dst changed outside of atune");
+                ctx.emit(WatchEvent::DstDrift {
+                    project: project.to_owned(),
+                    src: s.src.clone(),
+                    paths: paths.iter().cloned().collect(),
+                });
+                let src = s.src.display().to_string();
+                let drifted = join_paths(&paths);
+                let env = [
+                    ("ATUNE_PROJECT", project),
+                    ("ATUNE_SYNC_SRC", src.as_str()),
+                    ("ATUNE_DRIFTED_PATHS", drifted.as_str()),
+                ];
+                run_hooks(&ctx.config_path, project, "on_drift", &s.on_drift, &env);
+                if s.watch_dst == Some(config::WatchDst::Resync) {
+                    to_sync
+                        .entry(a.clone())
+                        .or_default()
+                        .add(a.clone(), ChangeKind::Changed);
+                }
+            }
+        }
+
+        // e.g. triggered syncs and resyncs of a drifted dst
+        for (a, changes) in to_sync.iter_mut() {
+            changes.id.get_or_insert_with(next_change_id);
+            first_change.entry(a.clone()).or_insert_with(Instant::now);
+        }
+        if healthcheck.failing() && !to_sync.is_empty() {
+            for (a, changes) in std::mem::take(&mut to_sync) {
+                queue_offline(
+                    &mut offline,
+                    &ctx.config_path,
+                    project,
+                    &a,
+                    &files[&a].src,
+                    changes,
+                );
+                persisted.insert(a);
+            }
+            offline_retry = Instant::now();
+        }
+        if !offline.is_empty() && offline_retry.elapsed() >= healthcheck_interval {
+            debug!("Retrying the changes queued while dst was unreachable");
+            for (a, older) in offline.drain() {
+                to_sync.entry(a).or_default().merge_older(older);
+            }
+            offline_retry = Instant::now();
+        }
+        if waiting_for_dependencies || paused || (!to_sync.is_empty() && !healthcheck.healthy()) {
+            continue;
+        }
+        // higher priority entries first, lower priority ones wait until they finished
+        let mut pending = to_sync.keys().cloned().collect::<Vec<_>>();
+        pending.sort_by_key(|a| std::cmp::Reverse(files[a].priority));
+        let mut top_priority = files
+            .iter()
+            .filter(|(a, _)| in_progress.contains(a))
+            .map(|(_, s)| s.priority)
+            .max();
+        for a in pending {
+            let s = files[&a];
+            if s.min_interval
+                .is_some_and(|min| last_started.get(&a).is_some_and(|t| t.elapsed() < min))
+            {
+                // keep collecting the changes until the interval passed
+                continue;
+            }
+            if top_priority.is_some_and(|p| s.priority < p) {
+                break;
+            }
+            top_priority = Some(s.priority);
+            if in_progress.contains(&a) {
+                // a sync that exited since the last reap is reaped first, so its result isn't lost
+                if restart && in_progress.is_running(&a) {
+                    if let Some(cancelled) = in_progress.cancel(&a) {
+                        if let Some(span) = sync_spans.remove(&a) {
+                            span.record("result", "cancelled");
+                        }
+                        to_sync.get_mut(&a).unwrap().merge_older(cancelled);
+                        ctx.emit(WatchEvent::SyncCancelled {
+                            project: project.to_owned(),
+                            src: s.src.clone(),
+                        });
+                    }
+                } else {
+                    // keep it queued until the sync of this entry is reaped
+                    continue;
+                }
+            }
+            if initial_pending.contains(&a)
+                || !ctx
+                    .lock_groups
+                    .acquire(s.lock_group.as_deref(), project, &a)
+            {
+                // keep it queued until its lock group is released
+                continue;
+            }
+            let mut changes = to_sync.remove(&a).unwrap_or_default();
+            if let Some(older) = refused.remove(&a) {
+                changes.merge_older(older);
+            }
+            if let Some(older) = offline.remove(&a) {
+                changes.merge_older(older);
+            }
+            let since = first_change.remove(&a);
+            info!(src=?s.src, dst=?s.dst, change_id=changes.id, waited=?since.map(|t| t.elapsed()), "syncing");
+
+            let span = tracing::info_span!(parent: None, "sync", project, src = %s.src.display(), initialize = false, change_id = changes.id, waited_ms = since.map(|t| t.elapsed().as_millis() as u64), result = tracing::field::Empty);
+            let proc = span.in_scope(|| start(&a, s, false, &changes));
+            sync_spans.insert(a.clone(), span);
+            last_started.insert(a.clone(), Instant::now());
+            // a sync cancelled by restart keeps the first change of its changes
+            if let Some(t) = since {
+                syncing_since.entry(a.clone()).or_insert(t);
+            }
+
+            ctx.emit(WatchEvent::SyncStarted {
+                project: project.to_owned(),
+                src: s.src.clone(),
+                initialize: false,
+            });
+            in_progress.insert(a, proc, changes);
+        }
+    }
+    // stop the syncs before other projects may start theirs
+    drop(in_progress);
+    ctx.lock_groups.release_project(project);
+    info!("sync_files disconnected");
+}
+
+pub(crate) fn register_paths(watcher: &mut dyn Watcher, sync: &[ParsedSync]) -> anyhow::Result<()> {
+    for p in sync {
+        debug!(path=?p, "Registering");
+        let mode = if p.recursive {
+            notify::RecursiveMode::Recursive
+        } else {
+            notify::RecursiveMode::NonRecursive
         };
-        let path = &req.path;
-        if let Some(a) = path.ancestors().find(|a| files.contains_key(*a)) {
-            to_sync.insert(a.to_owned());
-        }
+        watcher
+            .watch(p.src.as_path(), mode)
+            .with_context(|| format!("Failed to register watcher for path {:?}", p))?;
+    }
+    Ok(())
+}
 
-        if restart {
-            in_progress.cancel();
-        } else {
-            in_progress.wait();
-        }
+type WatcherTx = channel::Sender<notify::Result<notify::Event>>;
 
-        std::thread::sleep(debounce);
-        for req in rx.try_iter() {
-            if let Some(a) = req.path.ancestors().find(|a| files.contains_key(*a)) {
-                to_sync.insert(a.to_owned());
+fn create_watcher(
+    kind: config::WatcherKind,
+    poll_interval: Duration,
+    sync: &[ParsedSync],
+    tx: &WatcherTx,
+) -> anyhow::Result<ProjectWatcher> {
+    if let Some(missing) = sync.iter().find(|s| !s.src.exists()) {
+        anyhow::bail!("Source {} does not exist", missing.src.display());
+    }
+    // a single watcher serves all entries, the events below links are filtered per entry
+    let follow_symlinks = sync.iter().any(|s| s.filter.follow_symlinks);
+    let poll = || -> anyhow::Result<Box<dyn Watcher + Send>> {
+        let config = notify::Config::default()
+            .with_poll_interval(poll_interval)
+            .with_follow_symlinks(follow_symlinks);
+        let mut watcher = notify::PollWatcher::new(tx.clone(), config)
+            .context("Failed to initialize poll watcher")?;
+        register_paths(&mut watcher, sync)?;
+        Ok(Box::new(watcher))
+    };
+    let native = || -> anyhow::Result<Box<dyn Watcher + Send>> {
+        let config = notify::Config::default().with_follow_symlinks(follow_symlinks);
+        let mut watcher = notify::RecommendedWatcher::new(tx.clone(), config)
+            .context("Failed to initialize watcher")?;
+        register_paths(&mut watcher, sync)?;
+        Ok(Box::new(watcher))
+    };
+    let shallow = || -> anyhow::Result<ProjectWatcher> {
+        let config = notify::Config::default().with_follow_symlinks(follow_symlinks);
+        let mut watcher = notify::RecommendedWatcher::new(tx.clone(), config)
+            .context("Failed to initialize watcher")?;
+        let mut roots = Vec::new();
+        for p in sync {
+            if !(p.recursive && p.src.is_dir()) {
+                register_paths(&mut watcher, std::slice::from_ref(p))?;
+                continue;
             }
+            debug!(path=?p, "Registering the top-level directories");
+            let dirs = crate::shallow::top_level_dirs(&p.src);
+            for dir in std::iter::once(&p.src).chain(dirs.iter()) {
+                watcher
+                    .watch(dir, notify::RecursiveMode::NonRecursive)
+                    .with_context(|| format!("Failed to register watcher for path {:?}", p))?;
+            }
+            roots.push(p.src.clone());
         }
+        Ok(ProjectWatcher {
+            watcher: Box::new(watcher),
+            backend: config::WatcherKind::Shallow,
+            _scanner: Some(crate::shallow::Scanner::start(
+                roots,
+                poll_interval,
+                tx.clone(),
+            )),
+        })
+    };
+    let (watcher, backend) = match kind {
+        config::WatcherKind::Native => (native()?, kind),
+        config::WatcherKind::Poll => (poll()?, kind),
+        config::WatcherKind::Shallow => return shallow(),
+        config::WatcherKind::Manual => (Box::new(notify::NullWatcher) as Box<_>, kind),
+        config::WatcherKind::Auto => match native() {
+            Ok(watcher) => (watcher, config::WatcherKind::Native),
+            Err(err) => {
+                warn!(?err, "Native watcher failed, falling back to polling");
+                (poll()?, config::WatcherKind::Poll)
+            }
+        },
+    };
+    Ok(ProjectWatcher {
+        watcher,
+        backend,
+        _scanner: None,
+    })
+}
 
-        for a in to_sync.drain() {
-            let s = files[&a];
-            info!(changed=?path, src=?s.src, dst=?s.dst, "syncing");
-
-            let proc = cmd()
-                .arg("--src")
-                .arg(a.as_os_str())
-                .spawn()
-                .expect("Failed to spawn sync command");
+/// Stops watching on drop
+struct ProjectWatcher {
+    watcher: Box<dyn Watcher + Send>,
+    /// the backend in use, never Auto
+    backend: config::WatcherKind,
+    /// scans the deeper levels of the Shallow watcher
+    _scanner: Option<crate::shallow::Scanner>,
+}
 
-            in_progress.0.push(proc);
+impl ProjectWatcher {
+    /// Watch a directory created in the src of a non-recursive entry, so the files written into
+    /// it after its creation are synced too
+    fn watch_new_dir(&mut self, dir: &Path) {
+        debug!(?dir, "Registering the new directory");
+        if let Err(err) = self.watcher.watch(dir, notify::RecursiveMode::NonRecursive) {
+            warn!(?err, ?dir, "Failed to watch the new directory");
         }
     }
-    info!("sync_files disconnected");
 }
 
-#[tracing::instrument(skip(project, debounce, cancel))]
+/// Suggest a fix for watcher errors caused by exhausted OS limits
+pub(crate) fn watcher_error_hint(err: &anyhow::Error) -> Option<&'static str> {
+    let err = err
+        .chain()
+        .find_map(|e| e.downcast_ref::<notify::Error>())?;
+    match &err.kind {
+        notify::ErrorKind::MaxFilesWatch => Some(
+            "The inotify watch limit was reached. Raise it with `sysctl fs.inotify.max_user_watches=524288` or set `watcher: Poll` on the project",
+        ),
+        notify::ErrorKind::Io(io) if io.raw_os_error() == Some(EMFILE) => Some(
+            "Too many open files. Raise `fs.inotify.max_user_instances` or the open file limit (`ulimit -n`), or set `watcher: Poll` on the project",
+        ),
+        _ => None,
+    }
+}
+
+const EMFILE: i32 = 24;
+pub(crate) const WATCHER_RETRY_MIN: Duration = Duration::from_secs(1);
+pub(crate) const WATCHER_RETRY_MAX: Duration = Duration::from_secs(60);
+
+#[tracing::instrument(skip(project, debounce, cancel, control, ctx))]
 fn watch_project(
     name: String,
     project: config::Project,
-    debounce: Duration,
+    debounce: Debounce,
     cancel: crossbeam::channel::Receiver<()>,
-    config_path: PathBuf,
-    rsync: Option<PathBuf>,
+    control: channel::Receiver<WatchControl>,
+    ctx: SyncContext,
 ) -> anyhow::Result<()> {
     let project: ParsedProject = (name, project)
         .try_into()
@@ -276,88 +3228,444 @@ fn watch_project(
 
     let (tx, rx) = channel::unbounded();
 
-    let mut watcher =
-        notify::recommended_watcher(tx.clone()).context("Failed to initialize watcher")?;
-
-    let mut sync = project.sync;
-    sync.retain(|p| p.enabled);
+    let mut project = project;
+    project.sync.retain(|p| p.enabled);
 
-    for p in sync.iter() {
-        debug!(path=?p, "Registering");
-        let mode = if p.recursive {
-            notify::RecursiveMode::Recursive
-        } else {
-            notify::RecursiveMode::NonRecursive
-        };
-        watcher
-            .watch(p.src.as_path(), mode)
-            .with_context(|| format!("Failed to register watcher for path {:?}", p))?;
-    }
+    let name = project.name.clone();
+    let watcher_kind = project.watcher;
+    let poll_interval = project.poll_interval;
+    // scheduled entries are synced when they are due instead of on changes
+    let watched = project
+        .sync
+        .iter()
+        .filter(|s| s.schedule.is_none())
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut scheduled = project
+        .sync
+        .iter()
+        .filter_map(|s| {
+            let schedule = s.schedule.clone()?;
+            let due = schedule.next(SystemTime::now());
+            if due.is_none() {
+                warn!(src = ?s.src, "The schedule never matches, the entry is never synced");
+            }
+            Some((s.src.clone(), schedule, due))
+        })
+        .collect::<Vec<_>>();
+    let roots = watched
+        .iter()
+        .flat_map(|s| {
+            [
+                Some(s.src.clone()),
+                crate::platform::canonicalize(&s.src).ok(),
+            ]
+        })
+        .flatten()
+        .collect::<HashSet<_>>();
+    // the directories created in these are watched as well
+    let shallow_roots = watched
+        .iter()
+        .filter(|s| !s.recursive)
+        .flat_map(|s| {
+            [
+                Some(s.src.clone()),
+                crate::platform::canonicalize(&s.src).ok(),
+            ]
+        })
+        .flatten()
+        .collect::<HashSet<_>>();
+    let mut new_dirs = HashSet::new();
+    // event filters of the entries, keyed by their src paths
+    let filters = watched
+        .iter()
+        .flat_map(|s| {
+            [
+                Some(s.src.clone()),
+                crate::platform::canonicalize(&s.src).ok(),
+            ]
+            .into_iter()
+            .flatten()
+            .map(move |src| (src, &s.filter))
+        })
+        .collect::<Vec<_>>();
 
     let (one_tx, one_rx) = channel::bounded(1024);
 
-    std::thread::spawn(move || {
-        sync_files(
-            sync,
-            one_rx,
-            debounce,
-            &config_path,
-            project.name.as_str(),
-            project.restart,
-        )
-    });
+    let sync_ctx = ctx.clone();
+    let sync_thread =
+        std::thread::spawn(move || sync_files(project, one_rx, control, debounce, &sync_ctx));
 
+    let mut watcher = None;
+    let mut watching_since = Instant::now();
+    let mut degraded = false;
+    let mut backoff = WATCHER_RETRY_MIN;
+    let mut retry_at = Instant::now();
     let mut files = HashSet::new();
     'rx: loop {
+        let now = SystemTime::now();
+        for (src, schedule, due) in scheduled.iter_mut() {
+            if due.is_some_and(|due| due <= now) {
+                info!(?src, "Scheduled sync is due");
+                let _ = one_tx.send(SyncOneRequest {
+                    path: src.clone(),
+                    kind: ChangeKind::Changed,
+                    renamed_from: None,
+                    id: next_change_id(),
+                    received: Instant::now(),
+                });
+                *due = schedule.next(now);
+            }
+        }
+        if watcher.is_none() && Instant::now() >= retry_at {
+            match create_watcher(watcher_kind, poll_interval, &watched, &tx) {
+                Ok(mut w) => {
+                    new_dirs.retain(|d: &PathBuf| d.is_dir());
+                    for dir in new_dirs.iter() {
+                        w.watch_new_dir(dir);
+                    }
+                    info!(backend = ?w.backend, "Watching {} paths", watched.len());
+                    watcher = Some(w);
+                    watching_since = Instant::now();
+                    if degraded {
+                        degraded = false;
+                        info!("Watcher recovered, syncing all paths");
+                        ctx.emit(WatchEvent::WatcherRecovered {
+                            project: name.clone(),
+                        });
+                        // changes may have been missed while the watcher was down
+                        for p in watched.iter() {
+                            let _ = one_tx.send(SyncOneRequest {
+                                path: p.src.clone(),
+                                kind: ChangeKind::Changed,
+                                renamed_from: None,
+                                id: next_change_id(),
+                                received: Instant::now(),
+                            });
+                        }
+                    }
+                }
+                Err(err) => {
+                    report_watcher_error(&name, &err, backoff, &ctx);
+                    degraded = true;
+                    retry_at = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(WATCHER_RETRY_MAX);
+                }
+            }
+        }
+        let timeout = if watcher.is_none() {
+            retry_at.saturating_duration_since(Instant::now())
+        } else {
+            WATCHER_RETRY_MAX
+        };
+        let timeout = scheduled
+            .iter()
+            .filter_map(|(_, _, due)| due.as_ref())
+            .map(|due| due.duration_since(now).unwrap_or_default())
+            .fold(timeout, Duration::min);
         let ev = select! {
             recv(rx) -> ev => ev,
             recv(cancel) -> _msg => break 'rx,
+            default(timeout) => continue 'rx,
         };
-        let Ok(Ok(ev)) = ev else {
-            break 'rx;
+        let ev = match ev {
+            Ok(Ok(ev)) => ev,
+            Ok(Err(err)) => {
+                if watcher.is_none() {
+                    // stale error of the watcher that was already dropped
+                    continue;
+                }
+                if watching_since.elapsed() >= WATCHER_RETRY_MAX {
+                    // the watcher was healthy for a while, this is a new failure
+                    backoff = WATCHER_RETRY_MIN;
+                }
+                report_watcher_error(&name, &err.into(), backoff, &ctx);
+                watcher = None;
+                degraded = true;
+                retry_at = Instant::now() + backoff;
+                backoff = (backoff * 2).min(WATCHER_RETRY_MAX);
+                continue;
+            }
+            Err(_) => break 'rx,
         };
-        match ev.kind {
-            notify::EventKind::Create(_)
-            | notify::EventKind::Modify(_)
-            | notify::EventKind::Remove(_) => {
-                files.extend(ev.paths);
+        if let notify::EventKind::Modify(notify::event::ModifyKind::Name(
+            notify::event::RenameMode::Both,
+        )) = ev.kind
+        {
+            // pairs the From and To events of the rename, which were handled already
+            if let [from, to] = &ev.paths[..] {
+                let passes = |p: &Path, kind| {
+                    filters.iter().any(|(src, filter)| {
+                        p.strip_prefix(src)
+                            .is_ok_and(|rel| filter.matches(src, rel, kind))
+                    })
+                };
+                if passes(from, ChangeKind::Removed) && passes(to, ChangeKind::Changed) {
+                    let id = next_change_id();
+                    debug!(?from, ?to, change_id = id, "received rename");
+                    let _ = one_tx.send(SyncOneRequest {
+                        path: to.clone(),
+                        kind: ChangeKind::Changed,
+                        renamed_from: Some(from.clone()),
+                        id,
+                        received: Instant::now(),
+                    });
+                }
             }
+            continue;
+        }
+        let kind = match ev.kind {
+            notify::EventKind::Remove(_)
+            | notify::EventKind::Modify(notify::event::ModifyKind::Name(
+                notify::event::RenameMode::From,
+            )) => ChangeKind::Removed,
+            notify::EventKind::Create(_) | notify::EventKind::Modify(_) => ChangeKind::Changed,
             _ => continue,
+        };
+        if matches!(kind, ChangeKind::Removed) && ev.paths.iter().any(|p| roots.contains(p)) {
+            // the root was deleted or moved, e.g. by `git checkout` or an atomic replace. Its
+            // watch is gone, so re-register once it reappears and sync everything
+            warn!(paths = ?ev.paths, "Watched source removed, waiting for it to reappear");
+            ctx.emit(WatchEvent::WatcherDegraded {
+                project: name.clone(),
+                error: "watched source removed".to_owned(),
+            });
+            watcher = None;
+            degraded = true;
+            retry_at = Instant::now();
+            continue;
+        }
+        files.extend(ev.paths.into_iter().filter(|p| {
+            filters.iter().any(|(src, filter)| {
+                p.strip_prefix(src)
+                    .is_ok_and(|rel| filter.matches(src, rel, kind))
+            })
+        }));
+        if let (notify::EventKind::Create(_), Some(w)) = (ev.kind, watcher.as_mut()) {
+            // non-recursive entries only watch their src, follow the directories created in it
+            for dir in files
+                .iter()
+                .filter(|p| p.parent().is_some_and(|d| shallow_roots.contains(d)) && p.is_dir())
+            {
+                if new_dirs.insert(dir.clone()) {
+                    w.watch_new_dir(dir);
+                }
+            }
         }
-        debug!(?files, "received file updates");
+        if files.is_empty() {
+            continue;
+        }
+        // the paths of an event share its id
+        let id = next_change_id();
+        let received = Instant::now();
+        debug!(?files, ?kind, change_id = id, "received file updates");
         for f in files.drain() {
             one_tx
-                .send(SyncOneRequest { path: f })
+                .send(SyncOneRequest {
+                    path: f,
+                    kind,
+                    renamed_from: None,
+                    id,
+                    received,
+                })
                 .expect("Failed to send");
         }
     }
     info!("filesystem watcher disconnected");
+    drop(watcher);
+    drop(one_tx);
+    if let Err(err) = sync_thread.join() {
+        error!(?err, "Failed to join sync thread");
+    }
     Ok(())
 }
 
+fn report_watcher_error(project: &str, err: &anyhow::Error, retry: Duration, ctx: &SyncContext) {
+    match watcher_error_hint(err) {
+        Some(hint) => error!(?err, ?retry, "Watcher failed. {hint}"),
+        None => error!(?err, ?retry, "Watcher failed, retrying"),
+    }
+    ctx.emit(WatchEvent::WatcherDegraded {
+        project: project.to_owned(),
+        error: format!("{err:#}"),
+    });
+}
+
 /// Continously watch the config for changes as sync
 pub fn watch(
     config_path: PathBuf,
     config: Config,
     cancel: impl Into<Option<crossbeam::channel::Receiver<()>>>,
-    rsync: Option<PathBuf>,
+    options: WatchOptions,
 ) -> anyhow::Result<()> {
+    // syncs and run commands of a watch that crashed
+    crate::process_group::reap_orphans();
+    // commands of the HTTP API and the socket of `notify-change`
+    let (remote_control_tx, mut remote_control) = channel::unbounded();
+    let events = match config.api_addr.as_deref() {
+        Some(addr) => {
+            let token = config
+                .api_token
+                .as_ref()
+                .map(config::Secret::resolve)
+                .transpose()
+                .context("Failed to read the api_token")?;
+            let api = crate::api::ApiServer::start(
+                addr,
+                config.projects.keys().cloned(),
+                token,
+                remote_control_tx.clone(),
+            )?;
+            let (tx, rx) = channel::unbounded::<WatchEvent>();
+            let forward = options.events;
+            // runs until the sync threads dropped their senders, then stops the server
+            std::thread::spawn(move || {
+                for event in rx {
+                    api.publish(&event);
+                    if let Some(forward) = forward.as_ref() {
+                        let _ = forward.send(event);
+                    }
+                }
+            });
+            Some(tx)
+        }
+        None => options.events,
+    };
+    let ctx = SyncContext {
+        config_path,
+        executable: options
+            .executable
+            .map(|p| p.into_os_string())
+            .unwrap_or_else(current_executable),
+        log_dir: config.log_dir.clone(),
+        events,
+        progress: options.progress,
+        initial_syncs: Arc::new(InitialSyncs::new(config.projects.keys())),
+        lock_groups: Default::default(),
+        pool: (config.execution == config::Execution::InProcess)
+            .then(WorkerPool::with_available_parallelism),
+        rsync: options.rsync.clone(),
+        ssh_multiplexing: config.ssh_multiplexing,
+        notifications: Arc::new(config.notifications.clone()),
+        stats: Default::default(),
+    };
+    #[cfg(unix)]
+    let _socket = {
+        let sources = config
+            .projects
+            .iter()
+            .flat_map(|(name, p)| p.sync.iter().filter(|s| s.enabled).map(move |s| (name, s)))
+            .flat_map(|(name, s)| {
+                let label = sync_label(name, &s.src);
+                [
+                    Some(s.src.clone()),
+                    crate::platform::canonicalize(&s.src).ok(),
+                ]
+                .into_iter()
+                .flatten()
+                .map(move |src| (label.clone(), src))
+            })
+            .collect();
+        crate::control_socket::ControlSocket::start(&ctx.config_path, sources, remote_control_tx)
+            .inspect_err(|err| warn!(?err, "notify-change is not available"))
+            .ok()
+            .flatten()
+    };
+    // hosts of the multiplexed transfers, and of the remote commands
+    let masters = {
+        let hosts = config
+            .projects
+            .values()
+            .flat_map(|p| p.sync.iter())
+            .filter(|s| {
+                s.enabled
+                    && ((config.ssh_multiplexing && s.backend == config::SyncBackend::Rsync)
+                        || s.on_sync.iter().any(config::CommandConfig::is_remote))
+            })
+            .filter_map(|s| {
+                let dst = s.dst.as_deref()?;
+                let (host, _) = config::remote_dst(dst)?;
+                Some((host, s.ssh_args_of(dst)))
+            })
+            .collect::<BTreeSet<_>>();
+        crate::ssh::Masters::start(hosts)
+    };
     let mut project_cancel = Vec::with_capacity(config.projects.len());
+    let mut project_control = HashMap::with_capacity(config.projects.len());
     for (name, project) in config.projects {
         let (tx, rx) = crossbeam::channel::bounded(1);
+        let (control_tx, control_rx) = channel::unbounded();
+        project_control.insert(name.clone(), control_tx);
         let h = std::thread::spawn({
-            let config_path = config_path.clone();
-            let rsync = rsync.clone();
-            move || watch_project(name, project, config.debounce, rx, config_path, rsync)
+            let ctx = ctx.clone();
+            move || {
+                let initial_syncs = ctx.initial_syncs.clone();
+                let debounce = Debounce {
+                    quiet_period: config.debounce,
+                    max_wait: config.max_wait,
+                }
+                .for_project(&project);
+                let res = watch_project(name.clone(), project, debounce, rx, control_rx, ctx);
+                if let Err(err) = res.as_ref() {
+                    error!(?err, project = name, "Failed to watch project");
+                    // don't block the dependent projects
+                    initial_syncs.finish(&name, false);
+                }
+                res
+            }
         });
         project_cancel.push((tx, h));
     }
-    if let Some(cancel) = cancel.into() {
-        let _ = cancel.recv();
-        info!("Stopping watchers");
-        for (tx, _) in &project_cancel {
-            if let Err(err) = tx.send(()) {
-                error!(?err, "Failed to send cancel signal to project thread");
+
+    let cancel = cancel.into().unwrap_or_else(channel::never);
+    let mut control = options.control.unwrap_or_else(channel::never);
+    let dispatch = |ctl: WatchControl| {
+        let (WatchControl::Pause { project }
+        | WatchControl::Resume { project }
+        | WatchControl::Trigger { project }) = &ctl
+        else {
+            // the projects ignore the paths outside of their entries
+            for tx in project_control.values() {
+                let _ = tx.send(ctl.clone());
+            }
+            return;
+        };
+        match project_control.get(project) {
+            Some(tx) => {
+                let _ = tx.send(ctl);
+            }
+            None => warn!(project, "Control command for an unknown project"),
+        }
+    };
+    let mut started = false;
+    loop {
+        if !started && ctx.initial_syncs.all_finished() {
+            started = true;
+            run_hooks(&ctx.config_path, "", "on_start", &config.on_start, &[]);
+            ctx.emit(WatchEvent::Ready);
+        }
+        select! {
+            recv(cancel) -> _msg => {
+                info!("Stopping watchers");
+                for (tx, _) in &project_cancel {
+                    if let Err(err) = tx.send(()) {
+                        error!(?err, "Failed to send cancel signal to project thread");
+                    }
+                }
+                break;
+            }
+            recv(control) -> ctl => match ctl {
+                Ok(ctl) => dispatch(ctl),
+                Err(_) => control = channel::never(),
+            },
+            recv(remote_control) -> ctl => match ctl {
+                Ok(ctl) => dispatch(ctl),
+                Err(_) => remote_control = channel::never(),
+            },
+            default(QUEUE_POLL_INTERVAL) => {
+                if project_cancel.iter().all(|(_, h)| h.is_finished()) {
+                    break;
+                }
             }
         }
     }
@@ -366,52 +3674,902 @@ pub fn watch(
             error!(?err, "Failed to join watch thread");
         }
     }
+    crate::state::flush();
+    run_hooks(&ctx.config_path, "", "on_stop", &config.on_stop, &[]);
+    drop(masters);
+    info!("Stats: {}", ctx.stats.lock().unwrap().summary());
 
     Ok(())
 }
 
-fn sync_project_cmd(project: &str, config_path: &std::path::Path) -> std::process::Command {
-    let mut cmd = std::process::Command::new(
-        std::env::args_os()
-            .next()
-            .expect("Executable name not found"),
-    );
-    cmd.arg("-c")
-        .arg(config_path)
-        .arg("sync-project")
-        .arg("--project")
-        .arg(project);
+/// Run the global hooks, with an empty `project`, or the hooks of `project` in order, with the
+/// extra environment variables `env`. Failures are logged, but don't stop the watch.
+///
+/// Returns false if a command failed that doesn't `continue_on_failure`
+pub(crate) fn run_hooks(
+    config_path: &Path,
+    project: &str,
+    name: &str,
+    cmds: &[CommandConfig],
+    env: &[(&str, &str)],
+) -> bool {
+    if cmds.is_empty() {
+        return true;
+    }
+    info!("Running {name} commands");
+    for cmd in cmds {
+        match condition_met(cmd, env, None) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                error!(
+                    ?err,
+                    command = cmd.command,
+                    "Failed to check the run_if condition"
+                );
+                if !cmd.continue_on_failure {
+                    return false;
+                }
+                continue;
+            }
+        }
+        if let Some(script) = crate::script::parse(&cmd.command) {
+            let run = crate::history::start(project, name, cmd, env);
+            let env = script_env(env, cmd);
+            let res =
+                crate::script::run(config_path, script, cmd.cwd.as_deref(), project, name, &env);
+            run.finish_script(res.is_ok());
+            if let Err(err) = res {
+                error!(command = cmd.command, "{name} script failed: {err:#}");
+                if !cmd.continue_on_failure {
+                    return false;
+                }
+            }
+            continue;
+        }
+        let status = shell_command(cmd).and_then(|mut c| {
+            c.envs(env.iter().copied());
+            Ok(crate::history::run(project, name, cmd, env, c)?)
+        });
+        let ok = match status {
+            Ok(status) if status.success() => true,
+            Ok(status) => {
+                error!(command = cmd.command, %status, "{name} command failed");
+                false
+            }
+            Err(err) => {
+                error!(?err, command = cmd.command, "Failed to run {name} command");
+                false
+            }
+        };
+        if !ok && !cmd.continue_on_failure {
+            return false;
+        }
+    }
+    info!("Running {name} commands done");
+    true
+}
+
+pub(crate) fn current_executable() -> OsString {
+    std::env::args_os()
+        .next()
+        .expect("Executable name not found")
+}
+
+/// `project:name` of a sync, the name is the file name of its src
+pub(crate) fn sync_label(project: &str, src: &Path) -> String {
+    match src.file_name() {
+        Some(name) => format!("{project}:{}", name.to_string_lossy()),
+        None => project.to_owned(),
+    }
+}
+
+pub(crate) fn sync_project_cmd(
+    executable: &OsStr,
+    project: &str,
+    config_path: &std::path::Path,
+    rsync: Option<&Path>,
+) -> std::process::Command {
+    let mut cmd = std::process::Command::new(executable);
+    cmd.arg("-c").arg(config_path);
+    if let Some(rsync) = rsync {
+        cmd.arg("--rsync").arg(rsync);
+    }
+    cmd.arg("sync-project").arg("--project").arg(project);
     cmd
 }
 
+/// Outcome of a single sync of [sync_all_once]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncStatus {
+    Success,
+    Failed(SyncError),
+    /// Not started, because a dependency failed or a sync failed with `fail_fast` set
+    Skipped,
+    /// Killed, because another sync failed with `fail_fast` set
+    Cancelled,
+    /// Not started, because nothing changed since the last successful sync
+    Unchanged,
+}
+
+impl SyncStatus {
+    pub fn is_success(&self) -> bool {
+        matches!(self, SyncStatus::Success | SyncStatus::Unchanged)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncReport {
+    pub project: String,
+    pub src: PathBuf,
+    pub dst: Option<PathBuf>,
+    pub status: SyncStatus,
+    pub duration: Duration,
+    /// CPU time and memory of the sync, if it ran
+    pub usage: Option<ResourceUsage>,
+    /// Collected if [SyncOnceOptions::collect_output] is set and the sync ran
+    pub output: Option<SyncOutput>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SyncOnceOptions {
+    /// Don't run the hook commands
+    pub skip_commands: bool,
+    /// Cancel the in-progress syncs and skip the rest after the first failure
+    pub fail_fast: bool,
+    /// Collect rsync's stats and the hook results into [SyncReport::output].
+    /// The output of the syncs is redirected to stderr
+    pub collect_output: bool,
+    /// Skip the entries that didn't change since their last successful sync, according to the
+    /// persisted state
+    pub skip_unchanged: bool,
+    /// rsync executable of the projects that don't set their own
+    pub rsync: Option<PathBuf>,
+}
+
+/// A `sync-project` process started by [sync_all_once]
+struct RunningSync {
+    /// index in the reports
+    report: usize,
+    proc: process::Child,
+    started: Instant,
+    report_file: Option<tempfile::TempPath>,
+    sync: ParsedSync,
+    /// of the entry before the sync started
+    fingerprint: String,
+}
+
+/// Sync every enabled entry once, respecting the project dependencies.
+///
+/// Returns the result of every sync
 pub fn sync_all_once(
-    skip_commands: bool,
     config_path: PathBuf,
     config: Config,
-) -> anyhow::Result<()> {
-    let mut processes = Vec::with_capacity(config.projects.len());
+    options: SyncOnceOptions,
+) -> anyhow::Result<Vec<SyncReport>> {
+    let SyncOnceOptions {
+        skip_commands,
+        fail_fast,
+        collect_output,
+        skip_unchanged,
+        rsync,
+    } = options;
+    let state = crate::state::SyncState::load();
+    let executable = current_executable();
+    let mut remaining = config.projects;
+    // whether all syncs of the project succeeded
+    let mut finished = HashMap::<String, bool>::new();
+    let mut reports = Vec::new();
+    let mut failed = false;
 
-    for (name, project) in config.projects {
-        for f in project.sync.iter() {
-            let mut cmd = sync_project_cmd(&name, &config_path);
-            if skip_commands {
-                cmd.arg("--no-run-commands");
-            }
-            let proc = cmd
-                .arg("--initialize")
-                .arg("--src")
-                .arg(f.src.as_os_str())
-                .spawn()
-                .context("Failed to spawn sync command")?;
+    while !remaining.is_empty() {
+        // dependencies that aren't synced, e.g. because of a project filter, are ignored
+        let ready = remaining
+            .iter()
+            .filter(|(_, p)| p.depends_on.iter().all(|d| !remaining.contains_key(d)))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        anyhow::ensure!(!ready.is_empty(), "Project dependency cycle");
+
+        let mut processes = Vec::new();
+        for name in ready {
+            let project = remaining
+                .remove(&name)
+                .expect("ready projects are remaining");
+            let failed_dep = project
+                .depends_on
+                .iter()
+                .find(|d| finished.get(*d) == Some(&false));
+            if let Some(dep) = failed_dep {
+                error!(
+                    project = name,
+                    dependency = dep,
+                    "Dependency failed, skipping project"
+                );
+            }
+            let skip = failed_dep.is_some() || (fail_fast && failed);
+            for (i, f) in project.sync.into_iter().enumerate() {
+                if !f.enabled {
+                    continue;
+                }
+                let mut report = SyncReport {
+                    project: name.clone(),
+                    src: f.src.clone(),
+                    dst: f.dst.clone(),
+                    status: SyncStatus::Skipped,
+                    duration: Duration::ZERO,
+                    usage: None,
+                    output: None,
+                };
+                if skip {
+                    reports.push(report);
+                    continue;
+                }
+                let sync = parse_sync(&name, i, f)?;
+                let fingerprint = crate::state::fingerprint(&sync);
+                if skip_unchanged
+                    && state
+                        .get(&config_path, &name, &sync.src)
+                        .is_some_and(|e| e.fingerprint == fingerprint)
+                {
+                    info!(project = name, src = ?sync.src, "Unchanged since the last sync, skipping");
+                    report.status = SyncStatus::Unchanged;
+                    reports.push(report);
+                    continue;
+                }
+                let rsync = project.rsync.as_deref().or(rsync.as_deref());
+                let mut cmd = sync_project_cmd(&executable, &name, &config_path, rsync);
+                if skip_commands {
+                    cmd.arg("--no-run-commands");
+                }
+                let report_file = if collect_output {
+                    // written by the sync, created here so it's private to the user
+                    let file = tempfile::Builder::new()
+                        .prefix("atune-report-")
+                        .tempfile()
+                        .context("Failed to create the sync report file")?
+                        .into_temp_path();
+                    cmd.arg("--report").arg(&*file).stdout(std::io::stderr());
+                    Some(file)
+                } else {
+                    None
+                };
+                let proc = cmd
+                    .arg("--initialize")
+                    .arg("--src")
+                    .arg(sync.src.as_os_str())
+                    .spawn()
+                    .context("Failed to spawn sync command")?;
+                processes.push(RunningSync {
+                    report: reports.len(),
+                    proc,
+                    started: Instant::now(),
+                    report_file,
+                    sync,
+                    fingerprint,
+                });
+                reports.push(report);
+            }
+            finished.insert(name, !skip);
+        }
+        while !processes.is_empty() {
+            processes.retain_mut(|running| {
+                let (result, usage) = match crate::rusage::try_wait(&mut running.proc) {
+                    Ok(None) => return true,
+                    Ok(Some((status, usage))) => (sync_result(status), Some(usage)),
+                    Err(err) => {
+                        error!(?err, "Sync failed");
+                        (Err(SyncError::Failed { exit_code: None }), None)
+                    }
+                };
+                let report = &mut reports[running.report];
+                report.duration = running.started.elapsed();
+                report.usage = usage;
+                report.output = running
+                    .report_file
+                    .take()
+                    .and_then(|f| read_sync_output(&f));
+                report.status = match result {
+                    Ok(()) => {
+                        crate::state::record_success(
+                            &config_path,
+                            &report.project,
+                            &running.sync,
+                            std::mem::take(&mut running.fingerprint),
+                            !skip_commands,
+                        );
+                        SyncStatus::Success
+                    }
+                    Err(err) => {
+                        failed = true;
+                        finished.insert(report.project.clone(), false);
+                        SyncStatus::Failed(err)
+                    }
+                };
+                false
+            });
+            if fail_fast && failed {
+                for running in processes.drain(..) {
+                    kill_process(running.proc);
+                    reports[running.report].status = SyncStatus::Cancelled;
+                    reports[running.report].duration = running.started.elapsed();
+                }
+            }
+            if !processes.is_empty() {
+                std::thread::sleep(QUEUE_POLL_INTERVAL);
+            }
+        }
+    }
+
+    Ok(reports)
+}
+
+/// Render the results of [sync_all_once] as a JSON document
+pub fn reports_json(reports: &[SyncReport]) -> String {
+    use std::borrow::Cow;
+
+    #[derive(serde_derive::Serialize)]
+    struct ReportsJson<'a> {
+        success: bool,
+        syncs: Vec<ReportJson<'a>>,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    struct ReportJson<'a> {
+        project: &'a str,
+        src: Cow<'a, str>,
+        dst: Option<Cow<'a, str>>,
+        status: &'static str,
+        exit_code: Option<i32>,
+        duration_ms: u128,
+        usage: Option<ResourceUsage>,
+        rsync_exit_code: Option<i32>,
+        stats: Option<&'a RsyncStats>,
+        hooks: Vec<HookJson<'a>>,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    struct HookJson<'a> {
+        command: &'a str,
+        success: bool,
+        duration_ms: u128,
+    }
+
+    let syncs = reports
+        .iter()
+        .map(|r| {
+            let (status, exit_code) = match &r.status {
+                SyncStatus::Success => ("success", Some(0)),
+                SyncStatus::Failed(SyncError::HookFailed) => {
+                    ("hook_failed", Some(EXIT_HOOK_FAILED))
+                }
+                SyncStatus::Failed(SyncError::DstConflict) => {
+                    ("dst_conflict", Some(EXIT_DST_CONFLICT))
+                }
+                SyncStatus::Failed(SyncError::Unreachable) => {
+                    ("unreachable", Some(EXIT_UNREACHABLE))
+                }
+                SyncStatus::Failed(SyncError::DeleteRefused) => {
+                    ("delete_refused", Some(EXIT_DELETE_REFUSED))
+                }
+                SyncStatus::Failed(SyncError::DeleteLimit) => {
+                    ("delete_limit", Some(EXIT_DELETE_LIMIT))
+                }
+                SyncStatus::Failed(SyncError::Failed { exit_code }) => ("failed", *exit_code),
+                SyncStatus::Skipped => ("skipped", None),
+                SyncStatus::Cancelled => ("cancelled", None),
+                SyncStatus::Unchanged => ("unchanged", None),
+            };
+            let output = r.output.as_ref();
+            ReportJson {
+                project: &r.project,
+                src: r.src.to_string_lossy(),
+                dst: r.dst.as_ref().map(|d| d.to_string_lossy()),
+                status,
+                exit_code,
+                duration_ms: r.duration.as_millis(),
+                usage: r.usage,
+                rsync_exit_code: output.and_then(|o| o.rsync_exit_code),
+                stats: output.and_then(|o| o.stats.as_ref()),
+                hooks: output
+                    .map(|o| o.hooks.as_slice())
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|h| HookJson {
+                        command: &h.command,
+                        success: h.success,
+                        duration_ms: h.duration.as_millis(),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    let json = ReportsJson {
+        success: reports.iter().all(|r| r.status.is_success()),
+        syncs,
+    };
+    serde_json::to_string(&json).expect("Reports always serialize")
+}
 
-            processes.push(proc);
+/// Read the report a sync wrote, the file is removed once it's dropped
+fn read_sync_output(path: &tempfile::TempPath) -> Option<SyncOutput> {
+    let output = std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| Ok(serde_yaml::from_str(&s)?));
+    match output {
+        Ok(o) => Some(o),
+        Err(err) => {
+            warn!(?err, ?path, "Failed to read sync report");
+            None
         }
     }
-    for mut p in processes {
-        if let Err(err) = p.wait() {
-            error!(?err, "Sync failed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_list_file_is_private() {
+        let list = PathListFile::new("changed", "a\nb").unwrap();
+        assert_eq!(std::fs::read_to_string(&list.0).unwrap(), "a\nb\n");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let mode = std::fs::metadata(&list.0).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
         }
+        let path = list.0.to_path_buf();
+        drop(list);
+        assert!(!path.exists());
     }
 
-    Ok(())
+    #[test]
+    fn test_event_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("templates")).unwrap();
+        let filter = EventFilter {
+            include_extensions: vec!["py".into(), ".html".into()],
+            exclude_extensions: vec![],
+            ignore_patterns: vec!["*.swp".into(), ".#*".into(), "build/**".into()],
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let matches = |p: &str, kind| filter.matches(dir.path(), Path::new(p), kind);
+        assert!(matches("main.py", ChangeKind::Changed));
+        assert!(matches("templates/index.html", ChangeKind::Changed));
+        assert!(matches("templates", ChangeKind::Changed));
+        assert!(matches("removed_dir", ChangeKind::Removed));
+        assert!(!matches("main.rs", ChangeKind::Changed));
+        assert!(!matches(".main.py.swp", ChangeKind::Changed));
+        assert!(!matches(".#main.py", ChangeKind::Changed));
+        assert!(!matches("build/main.py", ChangeKind::Changed));
+        assert!(matches("", ChangeKind::Changed));
+
+        let filter = EventFilter {
+            exclude_extensions: vec!["lock".into()],
+            ..Default::default()
+        };
+        assert!(filter.matches(dir.path(), Path::new("a/b"), ChangeKind::Changed));
+        assert!(!filter.matches(dir.path(), Path::new("Cargo.lock"), ChangeKind::Changed));
+
+        let filter = EventFilter {
+            include: vec!["*.py".into(), "static/**".into()],
+            exclude: vec!["target".into(), "static/cache/".into()],
+            ..Default::default()
+        };
+        let matches = |p: &str, kind| filter.matches(dir.path(), Path::new(p), kind);
+        assert!(matches("app/main.py", ChangeKind::Changed));
+        assert!(matches("static/css/site.css", ChangeKind::Changed));
+        assert!(matches("templates", ChangeKind::Changed));
+        assert!(!matches("README.md", ChangeKind::Changed));
+        assert!(!matches("target/gen.py", ChangeKind::Changed));
+        assert!(!matches("static/cache/a.css", ChangeKind::Removed));
+    }
+
+    #[test]
+    fn test_parse_sync_names_the_entry() {
+        let s = config::FileSync {
+            src: "/work/app".into(),
+            rsync_flags: Some("-a 'unterminated".to_owned()),
+            ..Default::default()
+        };
+        let err = parse_sync("web", 2, s).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Invalid sync 2 (/work/app) in project web: Failed to split rsync flags: missing closing quote"
+        );
+    }
+
+    #[test]
+    fn test_create_watcher_reports_the_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = parse_sync(
+            "web",
+            0,
+            config::FileSync {
+                src: dir.path().to_owned(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let (tx, _rx) = channel::unbounded();
+        let backend = |kind| {
+            create_watcher(kind, Duration::from_secs(1), std::slice::from_ref(&s), &tx)
+                .unwrap()
+                .backend
+        };
+        // the native watcher is available on the test platforms
+        assert_eq!(
+            backend(config::WatcherKind::Auto),
+            config::WatcherKind::Native
+        );
+        assert_eq!(
+            backend(config::WatcherKind::Poll),
+            config::WatcherKind::Poll
+        );
+        assert_eq!(
+            backend(config::WatcherKind::Shallow),
+            config::WatcherKind::Shallow
+        );
+    }
+
+    #[test]
+    fn test_filter_flags() {
+        let include = ["*.py".to_owned(), "/static/**".to_owned()];
+        let exclude = ["target/".to_owned(), "docs/build".to_owned()];
+        assert_eq!(
+            filter_flags(Path::new("/work/app"), &include, &exclude),
+            [
+                "--exclude=target/",
+                "--exclude=/app/docs/build",
+                "--include=*.py",
+                "--include=/app/static/**",
+                "--include=*/",
+                "--exclude=*",
+            ]
+        );
+        assert_eq!(
+            filter_flags(Path::new("/work/app/"), &[], &exclude),
+            ["--exclude=target/", "--exclude=/docs/build"]
+        );
+    }
+
+    #[test]
+    fn test_share_flags() {
+        let copies = [
+            "host:/srv/a/node_modules",
+            "host:/srv/b/node_modules",
+            "other:/srv/c/node_modules",
+            "host:relative/node_modules",
+            "/srv/d/node_modules",
+        ]
+        .map(PathBuf::from);
+        let src = Path::new("/work/a/node_modules");
+        assert_eq!(
+            share_flags(src, Path::new("host:/srv/a"), &copies),
+            ["--copy-dest=/srv/b"]
+        );
+        assert_eq!(
+            share_flags(src, Path::new("/srv/e"), &copies),
+            ["--copy-dest=/srv/d"]
+        );
+        // the contents of src are synced into dst itself
+        assert_eq!(
+            share_flags(
+                Path::new("/work/a/node_modules/"),
+                Path::new("/srv/e"),
+                &copies
+            ),
+            ["--copy-dest=/srv/d/node_modules"]
+        );
+    }
+
+    #[test]
+    fn test_relay_source() {
+        let copies = [
+            "host:/srv/a/node_modules",
+            "/srv/b/node_modules",
+            "other:relative/node_modules",
+            "other:/srv/c/node_modules",
+            "third:/srv/d/node_modules",
+        ]
+        .map(PathBuf::from);
+        let recorded = |c: &Path| (!c.starts_with("third:")).then_some(1);
+        assert_eq!(
+            relay_source(Path::new("host:/srv/a"), &copies, 1, recorded),
+            Some(Path::new("other:/srv/c/node_modules"))
+        );
+        // the content changed since the copies were synced
+        assert_eq!(
+            relay_source(Path::new("host:/srv/a"), &copies, 2, recorded),
+            None
+        );
+        assert_eq!(
+            relay_source(Path::new("/srv/e"), &copies, 1, recorded),
+            None
+        );
+    }
+
+    #[test]
+    fn test_dst_conflicts() {
+        let itemized = "sending incremental file list\n\
+                        *deleting   web/stray.log\n\
+                        *deleting   web/old.html\n\
+                        .d..t...... web/\n\
+                        >f.st...... web/index.html\n\
+                        >f.st...... web/hotfix.css\n\
+                        >f+++++++++ web/new.js\n\
+                        cd+++++++++ web/img/\n";
+        let src = Path::new("/home/me/web");
+        let mut changes = SyncChanges::default();
+        changes.changed.insert(src.join("index.html"));
+        changes.deleted.insert(src.join("old.html"));
+        assert_eq!(
+            dst_conflicts(itemized, src, &changes),
+            vec![PathBuf::from("web/stray.log"), "web/hotfix.css".into()]
+        );
+        // full syncs only guard against deletions
+        assert_eq!(
+            dst_conflicts(itemized, src, &SyncChanges::default()),
+            vec![PathBuf::from("web/stray.log"), "web/old.html".into()]
+        );
+        assert_eq!(
+            dst_conflicts(
+                "*deleting   stray.log\n",
+                Path::new("/home/me/web/"),
+                &changes
+            ),
+            vec![PathBuf::from("stray.log")]
+        );
+    }
+
+    #[test]
+    fn test_drift() {
+        let itemized = "sending incremental file list\n\
+                        *deleting   web/stray.log\n\
+                        .d..t...... web/\n\
+                        >fc........ web/index.html\n\
+                        cd+++++++++ web/img/\n\
+                        .f...p..... web/run.sh\n";
+        assert_eq!(
+            drift(itemized),
+            vec![
+                PathBuf::from("web/stray.log"),
+                "web/index.html".into(),
+                "web/img".into()
+            ]
+        );
+        assert!(drift("sending incremental file list\n").is_empty());
+    }
+
+    #[test]
+    fn test_debounce_max_wait() {
+        let debounce = Debounce {
+            quiet_period: Duration::from_millis(50),
+            max_wait: Duration::from_millis(200),
+        };
+        let (tx, rx) = channel::unbounded();
+        let sender = std::thread::spawn(move || {
+            for i in 0..100 {
+                if tx.send(i).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+        let start = Instant::now();
+        let mut received = Vec::new();
+        debounce.collect(&rx, rx.recv().unwrap(), |i| received.push(i));
+        let elapsed = start.elapsed();
+        drop(rx);
+        sender.join().unwrap();
+        assert!(elapsed < Duration::from_millis(500), "{elapsed:?}");
+        assert!(received.len() > 1 && received.len() < 100, "{received:?}");
+    }
+
+    #[test]
+    fn test_debounce_quiet_period() {
+        let debounce = Debounce {
+            quiet_period: Duration::from_millis(20),
+            max_wait: Duration::from_secs(10),
+        };
+        let (tx, rx) = channel::unbounded();
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        let start = Instant::now();
+        let mut received = Vec::new();
+        debounce.collect(&rx, 0, |i| received.push(i));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(received, [0, 1, 2]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sync_processes_keep_exited_syncs_until_reaped() {
+        let spawn = |cmd: &str| {
+            let proc = process::Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .spawn()
+                .unwrap();
+            (
+                SyncHandle::process(proc),
+                crate::output::OutputTail::default(),
+            )
+        };
+        let mut in_progress = SyncProcesses::default();
+        let mut changes = SyncChanges::default();
+        changes.add("/b/1.txt".into(), ChangeKind::Changed);
+        in_progress.insert("/a".into(), spawn("true"), SyncChanges::default());
+        in_progress.insert("/b".into(), spawn("sleep 10"), changes);
+
+        while in_progress.is_running(Path::new("/a")) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // exited, but its result wasn't collected yet
+        assert!(in_progress.contains(Path::new("/a")));
+
+        let finished = in_progress.reap();
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].key, Path::new("/a"));
+        assert_eq!(finished[0].result, Ok(()));
+        assert!(!in_progress.contains(Path::new("/a")));
+
+        // the other entry is unaffected
+        assert!(in_progress.is_running(Path::new("/b")));
+        let cancelled = in_progress.cancel(Path::new("/b")).unwrap();
+        assert!(cancelled.changed.contains(Path::new("/b/1.txt")));
+        assert!(in_progress.is_empty());
+    }
+
+    #[test]
+    fn test_sync_changes_recreated_path_is_not_deleted() {
+        let mut changes = SyncChanges::default();
+        changes.add("/a".into(), ChangeKind::Removed);
+        changes.add("/b".into(), ChangeKind::Removed);
+        changes.add("/a".into(), ChangeKind::Changed);
+
+        assert_eq!(
+            changes.deleted.into_iter().collect::<Vec<_>>(),
+            vec![PathBuf::from("/b")]
+        );
+        assert_eq!(
+            changes.changed.into_iter().collect::<Vec<_>>(),
+            vec![PathBuf::from("/a")]
+        );
+    }
+
+    #[test]
+    fn test_sync_changes_keep_the_first_id() {
+        let mut older = SyncChanges {
+            id: Some(1),
+            ..Default::default()
+        };
+        older.add("/a".into(), ChangeKind::Changed);
+        let mut changes = SyncChanges {
+            id: Some(2),
+            ..Default::default()
+        };
+        changes.add("/b".into(), ChangeKind::Changed);
+        changes.merge_older(older);
+        assert_eq!(changes.id, Some(1));
+
+        // changes without a filesystem event keep the id of the newer ones
+        let mut changes = SyncChanges {
+            id: Some(3),
+            ..Default::default()
+        };
+        changes.merge_older(SyncChanges::default());
+        assert_eq!(changes.id, Some(3));
+    }
+
+    #[test]
+    fn test_partial_files_relative_to_src_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub/a.txt"), "").unwrap();
+
+        let mut changes = SyncChanges::default();
+        changes.add(src.join("sub/a.txt"), ChangeKind::Changed);
+        let (base, files, delete_missing) = partial_files(&src, &changes).unwrap();
+        assert_eq!(base, dir.path());
+        assert_eq!(files, "src/sub/a.txt");
+        assert!(!delete_missing);
+
+        // the contents of src are synced into dst
+        let contents = PathBuf::from(format!("{}/", src.display()));
+        let (base, files, _) = partial_files(&contents, &changes).unwrap();
+        assert_eq!(base, src);
+        assert_eq!(files, "sub/a.txt");
+
+        changes.add(src.join("sub/b.txt"), ChangeKind::Removed);
+        assert!(partial_files(&src, &changes).is_none());
+
+        let mut changes = SyncChanges::default();
+        changes.add(src.clone(), ChangeKind::Changed);
+        assert!(partial_files(&src, &changes).is_none());
+    }
+
+    #[test]
+    fn test_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub/b.txt"), "hello").unwrap();
+
+        let mut changes = SyncChanges::default();
+        changes.rename(src.join("a.txt"), src.join("sub/b.txt"));
+        let (_, files, delete_missing) = partial_files(&src, &changes).unwrap();
+        assert_eq!(files, "src/sub/b.txt\nsrc/a.txt");
+        assert!(delete_missing);
+
+        let dst = dir.path().join("dst");
+        std::fs::create_dir_all(dst.join("src")).unwrap();
+        std::fs::write(dst.join("src/a.txt"), "hello").unwrap();
+        move_renamed(dir.path(), &dst, &changes);
+        assert!(!dst.join("src/a.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(dst.join("src/sub/b.txt")).unwrap(),
+            "hello"
+        );
+
+        // removing the new path forgets the rename, the old one is a plain deletion then
+        changes.add(src.join("sub/b.txt"), ChangeKind::Removed);
+        assert!(changes.renamed.is_empty());
+        assert!(partial_files(&src, &changes).is_none());
+    }
+
+    #[test]
+    fn test_parse_outputs() {
+        let content =
+            "RELEASE=2024-06-01.3\nNOTES<<EOF\nfirst\nsecond\nEOF\n\nnot a value\nEMPTY=\n";
+        assert_eq!(
+            parse_outputs(content),
+            [
+                ("RELEASE".to_owned(), "2024-06-01.3".to_owned()),
+                ("NOTES".to_owned(), "first\nsecond".to_owned()),
+                ("EMPTY".to_owned(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rsync_stats() {
+        let out = r#"
+Number of files: 1,204 (reg: 1,100, dir: 104)
+Number of created files: 3 (reg: 3)
+Number of deleted files: 1
+Number of regular files transferred: 12
+Total file size: 9.87M bytes
+Total transferred file size: 12,345 bytes
+Literal data: 12,345 bytes
+Total bytes sent: 14,020
+Total bytes received: 310
+"#;
+        assert_eq!(
+            RsyncStats::parse(out),
+            RsyncStats {
+                files_transferred: 12,
+                files_created: 3,
+                files_deleted: 1,
+                transferred_size: 12345,
+                bytes_sent: 14020,
+                bytes_received: 310,
+            }
+        );
+    }
+
+    #[test]
+    fn test_watcher_error_hint() {
+        let err = anyhow::Error::from(notify::Error::new(notify::ErrorKind::MaxFilesWatch))
+            .context("Failed to register watcher");
+        assert!(watcher_error_hint(&err)
+            .unwrap()
+            .contains("max_user_watches"));
+
+        let err = anyhow::Error::from(notify::Error::generic("boom"));
+        assert!(watcher_error_hint(&err).is_none());
+    }
 }
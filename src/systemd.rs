@@ -0,0 +1,229 @@
+//! systemd integration: readiness and status notifications, and structured journal logging
+use std::{collections::BTreeMap, io::Write as _, os::unix::net::UnixDatagram, path::Path};
+
+use crossbeam::channel;
+use tracing::field::{Field, Visit};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::WatchEvent;
+
+/// Send a notification to the service manager, e.g. `READY=1`.
+///
+/// Returns false if atune doesn't run under systemd with `Type=notify`, or the send failed
+pub fn notify(state: &str) -> bool {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+    let Ok(socket) = UnixDatagram::unbound() else {
+        return false;
+    };
+    let path = Path::new(&path);
+    let sent = match path.to_str().and_then(|p| p.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt as _;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        _ => socket.send_to(state.as_bytes(), path),
+    };
+    sent.is_ok()
+}
+
+/// Keep the service manager informed about the watch: `READY=1` once the initial syncs
+/// finished, and a `STATUS=` summary of the projects after every change. Returns once the
+/// events are disconnected
+pub fn notify_watch_events(events: channel::Receiver<WatchEvent>) {
+    let mut projects = BTreeMap::<String, &str>::new();
+    for event in events {
+        let (project, status) = match &event {
+            WatchEvent::Ready => {
+                notify("READY=1");
+                continue;
+            }
+            WatchEvent::SyncStarted { project, .. } => (project, "syncing"),
+            WatchEvent::SyncFinished {
+                project, result, ..
+            } => (project, if result.is_ok() { "ok" } else { "failed" }),
+            WatchEvent::SyncCancelled { .. }
+            | WatchEvent::HookFailed { .. }
+            | WatchEvent::SyncProgress { .. }
+            | WatchEvent::DstDrift { .. } => continue,
+            WatchEvent::WatcherDegraded { project, .. } => (project, "watcher degraded"),
+            WatchEvent::WatcherRecovered { project } | WatchEvent::Resumed { project } => {
+                (project, "ok")
+            }
+            WatchEvent::Paused { project } => (project, "paused"),
+        };
+        projects.insert(project.clone(), status);
+        let summary = projects
+            .iter()
+            .map(|(p, s)| format!("{p}: {s}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        notify(&format!("STATUS={summary}"));
+    }
+}
+
+/// Whether stdout, where the logs go, is connected to the journal, see `JOURNAL_STREAM` in
+/// systemd.exec(5)
+pub fn stdout_is_journal() -> bool {
+    let Some(stream) = std::env::var_os("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Some((dev, ino)) = stream.to_str().and_then(|s| s.split_once(':')) else {
+        return false;
+    };
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    // SAFETY: fstat initializes the buffer on success
+    if unsafe { libc::fstat(libc::STDOUT_FILENO, stat.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    // SAFETY: initialized by the successful fstat
+    let stat = unsafe { stat.assume_init() };
+    dev == stat.st_dev.to_string() && ino == stat.st_ino.to_string()
+}
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Logs to the journal using its native protocol, so the fields of the events and their spans,
+/// e.g. `PROJECT` and `SRC`, can be filtered with `journalctl`
+#[derive(Debug)]
+pub struct JournalLayer {
+    socket: UnixDatagram,
+}
+
+impl JournalLayer {
+    /// Returns None if the journal socket isn't available
+    pub fn new() -> Option<Self> {
+        let socket = UnixDatagram::unbound().ok()?;
+        socket.connect(JOURNAL_SOCKET).ok()?;
+        Some(Self { socket })
+    }
+}
+
+/// Fields recorded in the journal format: `NAME=value` lines
+#[derive(Default)]
+struct JournalFields(Vec<u8>);
+
+impl JournalFields {
+    fn push(&mut self, name: &str, value: &str) {
+        let name = name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' => c.to_ascii_uppercase(),
+                'A'..='Z' | '0'..='9' => c,
+                _ => '_',
+            })
+            .collect::<String>();
+        // fields starting with `_` are trusted fields set by the journal itself
+        let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+        if name.is_empty() {
+            return;
+        }
+        self.0.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            // binary safe encoding: the length as little endian u64 follows the name
+            self.0.push(b'\n');
+            self.0
+                .extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            self.0.push(b'=');
+        }
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(b'\n');
+    }
+}
+
+impl Visit for JournalFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field.name(), value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.push(field.name(), &format!("{value:?}"));
+    }
+}
+
+impl<S> Layer<S> for JournalLayer
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = JournalFields::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(
+        &self,
+        id: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        ctx: Context<'_, S>,
+    ) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<JournalFields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        let priority = match *meta.level() {
+            tracing::Level::ERROR => "3",
+            tracing::Level::WARN => "4",
+            tracing::Level::INFO => "6",
+            _ => "7",
+        };
+        let mut fields = JournalFields::default();
+        fields.push("PRIORITY", priority);
+        fields.push("SYSLOG_IDENTIFIER", "atune");
+        fields.push("TARGET", meta.target());
+        if let Some(file) = meta.file() {
+            fields.push("CODE_FILE", file);
+        }
+        if let Some(line) = meta.line() {
+            fields.push("CODE_LINE", &line.to_string());
+        }
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(f) = span.extensions().get::<JournalFields>() {
+                    fields.0.extend_from_slice(&f.0);
+                }
+            }
+        }
+        event.record(&mut fields);
+        if self.socket.send(&fields.0).is_err() {
+            // e.g. too large for a datagram, don't lose the message
+            let _ = std::io::stderr().write_all(&fields.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_fields() {
+        let mut fields = JournalFields::default();
+        fields.push("message", "synced");
+        fields.push("src.path", "a\nb");
+        fields.push("_PID", "1");
+        let mut expected = b"MESSAGE=synced\nSRC_PATH\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\nPID=1\n");
+        assert_eq!(fields.0, expected);
+    }
+}
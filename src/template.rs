@@ -0,0 +1,126 @@
+//! `{{ name }}` placeholders in config values
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Replace the `{{ name }}` placeholders in `s` using `lookup`.
+///
+/// Only names made of alphanumerics, `_`, `-` and `.`, not starting with a `.`, are placeholders, so
+/// other uses of braces, e.g. Go templates in commands, are kept as-is. Unknown names are an error
+pub fn expand(s: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let name = after
+            .find("}}")
+            .map(|end| (after[..end].trim(), end))
+            .filter(|(name, _)| {
+                !name.is_empty()
+                    && !name.starts_with('.')
+                    && name
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+            });
+        match name {
+            Some((name, end)) => {
+                let value = lookup(name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown variable {name:?} in {s:?}"))?;
+                out.push_str(&value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Current UTC date as `YYYY-MM-DD`
+pub fn date() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Year, month and day of the days since the Unix epoch
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Days since the Unix epoch of the date, the inverse of [civil_from_days]
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Name of this machine
+pub fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: the buffer is valid for its length
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+            let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            return String::from_utf8_lossy(&buf[..len]).into_owned();
+        }
+    }
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let lookup = |name: &str| match name {
+            "host" => Some("example.com".to_owned()),
+            "project" => Some("web".to_owned()),
+            "match.1" => Some("api".to_owned()),
+            _ => None,
+        };
+        assert_eq!(
+            expand("deploy@{{ host }}:/srv/{{project}}/", lookup).unwrap(),
+            "deploy@example.com:/srv/web/"
+        );
+        assert_eq!(
+            expand("docker inspect -f '{{.State}}' {{ project }}", lookup).unwrap(),
+            "docker inspect -f '{{.State}}' web"
+        );
+        assert_eq!(expand("/srv/{{ match.1 }}/", lookup).unwrap(), "/srv/api/");
+        assert_eq!(expand("{{ host", lookup).unwrap(), "{{ host");
+        assert!(expand("{{ user }}", lookup).is_err());
+    }
+
+    #[test]
+    fn test_date_format() {
+        let date = date();
+        assert_eq!(date.len(), 10);
+        assert!(date.starts_with("20"));
+    }
+}
@@ -0,0 +1,468 @@
+//! Full screen dashboard of a running watch, see `atune tui`: the state of every project, the
+//! recent events and logs, and keys to pause, resume and trigger the syncs of a project
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam::{channel, select};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style, Stylize as _},
+    text::Line,
+    widgets::{Block, Borders, List, Paragraph, Row, Table, TableState},
+    Frame,
+};
+
+use crate::{output::Progress, SyncError, WatchControl, WatchEvent, Watcher};
+
+/// Number of events and log lines kept for display
+const HISTORY: usize = 200;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Up,
+    Down,
+    TogglePause,
+    Trigger,
+    Quit,
+}
+
+impl Key {
+    /// The key of a terminal event, other input is ignored
+    fn from_event(event: &Event) -> Option<Self> {
+        let Event::Key(key) = event else {
+            return None;
+        };
+        if key.kind == KeyEventKind::Release {
+            return None;
+        }
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => Some(Key::Up),
+            KeyCode::Down | KeyCode::Char('j') => Some(Key::Down),
+            // ctrl-c, the terminal doesn't turn it into a signal in raw mode
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Some(Key::Quit),
+            KeyCode::Char('p') => Some(Key::TogglePause),
+            KeyCode::Char('s') => Some(Key::Trigger),
+            KeyCode::Char('q') | KeyCode::Esc => Some(Key::Quit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProjectState {
+    paused: bool,
+    /// running syncs by src, with their last reported progress
+    running: BTreeMap<PathBuf, Option<Progress>>,
+    /// time, duration and success of the last finished sync
+    last_sync: Option<(SystemTime, Duration, bool)>,
+    last_error: Option<String>,
+    watcher_error: Option<String>,
+}
+
+impl ProjectState {
+    fn status(&self) -> String {
+        if self.watcher_error.is_some() {
+            return "watcher degraded".to_owned();
+        }
+        if self.paused {
+            return "paused".to_owned();
+        }
+        if self.running.is_empty() {
+            return "idle".to_owned();
+        }
+        match self.running.values().flatten().map(|p| p.percent).min() {
+            Some(percent) => format!("syncing {percent}%"),
+            None => "syncing".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Dashboard {
+    projects: BTreeMap<String, ProjectState>,
+    selected: usize,
+    ready: bool,
+    events: VecDeque<String>,
+    logs: VecDeque<String>,
+}
+
+fn push_line(lines: &mut VecDeque<String>, line: String) {
+    if lines.len() == HISTORY {
+        lines.pop_front();
+    }
+    lines.push_back(line);
+}
+
+impl Dashboard {
+    fn new(projects: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            projects: projects
+                .into_iter()
+                .map(|p| (p, ProjectState::default()))
+                .collect(),
+            selected: 0,
+            ready: false,
+            events: VecDeque::new(),
+            logs: VecDeque::new(),
+        }
+    }
+
+    fn selected_project(&self) -> Option<&String> {
+        self.projects.keys().nth(self.selected)
+    }
+
+    fn event(&mut self, line: String) {
+        push_line(
+            &mut self.events,
+            format!("{} {line}", clock(SystemTime::now())),
+        );
+    }
+
+    fn apply(&mut self, event: WatchEvent) {
+        let line = match event {
+            WatchEvent::Ready => {
+                self.ready = true;
+                "initial syncs finished".to_owned()
+            }
+            WatchEvent::SyncStarted {
+                project,
+                src,
+                initialize,
+            } => {
+                let label = crate::sync::sync_label(&project, &src);
+                self.project(&project).running.insert(src, None);
+                match initialize {
+                    true => format!("{label} initial sync started"),
+                    false => format!("{label} sync started"),
+                }
+            }
+            WatchEvent::SyncProgress {
+                project,
+                src,
+                progress,
+            } => {
+                if let Some(p) = self.project(&project).running.get_mut(&src) {
+                    *p = Some(progress);
+                }
+                return;
+            }
+            WatchEvent::SyncFinished {
+                project,
+                src,
+                result,
+                duration,
+                ..
+            } => {
+                let label = crate::sync::sync_label(&project, &src);
+                let state = self.project(&project);
+                state.running.remove(&src);
+                state.last_sync = Some((SystemTime::now(), duration, result.is_ok()));
+                match result {
+                    Ok(()) => format!("{label} synced in {duration:.2?}"),
+                    Err(err) => {
+                        let err = describe_error(&err);
+                        state.last_error = Some(format!("{label}: {err}"));
+                        format!("{label} {err}")
+                    }
+                }
+            }
+            WatchEvent::SyncCancelled { project, src } => {
+                self.project(&project).running.remove(&src);
+                format!(
+                    "{} cancelled by newer changes",
+                    crate::sync::sync_label(&project, &src)
+                )
+            }
+            // followed by the failed SyncFinished
+            WatchEvent::HookFailed { .. } => return,
+            WatchEvent::WatcherDegraded { project, error } => {
+                self.project(&project).watcher_error = Some(error.clone());
+                format!("{project} watcher failed: {error}")
+            }
+            WatchEvent::WatcherRecovered { project } => {
+                self.project(&project).watcher_error = None;
+                format!("{project} watcher recovered")
+            }
+            WatchEvent::Paused { project } => {
+                self.project(&project).paused = true;
+                format!("{project} paused")
+            }
+            WatchEvent::Resumed { project } => {
+                self.project(&project).paused = false;
+                format!("{project} resumed")
+            }
+            WatchEvent::DstDrift {
+                project,
+                src,
+                paths,
+            } => format!(
+                "{} dst changed outside of atune: {} path(s)",
+                crate::sync::sync_label(&project, &src),
+                paths.len()
+            ),
+        };
+        self.event(line);
+    }
+
+    fn project(&mut self, name: &str) -> &mut ProjectState {
+        self.projects.entry(name.to_owned()).or_default()
+    }
+
+    /// Handle a key other than [Key::Quit]
+    fn key(&mut self, key: Key, control: &channel::Sender<WatchControl>) {
+        let Some(project) = self.selected_project().cloned() else {
+            return;
+        };
+        match key {
+            Key::Up => self.selected = self.selected.saturating_sub(1),
+            Key::Down => self.selected = (self.selected + 1).min(self.projects.len() - 1),
+            Key::TogglePause => {
+                let ctl = match self.projects[&project].paused {
+                    true => WatchControl::Resume { project },
+                    false => WatchControl::Pause { project },
+                };
+                let _ = control.send(ctl);
+            }
+            Key::Trigger => {
+                self.event(format!("{project} sync requested"));
+                let _ = control.send(WatchControl::Trigger { project });
+            }
+            Key::Quit => {}
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let state = if self.ready { "watching" } else { "starting" };
+        // the projects get a row each, the rest is split between the events and the logs
+        let [header, projects, events, logs, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(self.projects.len() as u16 + 1),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        frame.render_widget(
+            Paragraph::new(format!("atune {state} {} projects", self.projects.len())).reversed(),
+            header,
+        );
+
+        let rows = self.projects.iter().map(|(name, p)| {
+            let last_sync = match p.last_sync {
+                Some((at, duration, ok)) => format!(
+                    "{} {} {duration:.1?}",
+                    clock(at),
+                    if ok { "ok" } else { "failed" }
+                ),
+                None => "-".to_owned(),
+            };
+            let error = p
+                .watcher_error
+                .as_deref()
+                .or(p.last_error.as_deref())
+                .unwrap_or("");
+            Row::new([name.clone(), p.status(), last_sync, error.to_owned()])
+        });
+        let name_width = self
+            .projects
+            .keys()
+            .map(|p| p.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max(7);
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(name_width as u16),
+                Constraint::Length(16),
+                Constraint::Length(22),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(Row::new(["PROJECT", "STATUS", "LAST SYNC", "LAST ERROR"]))
+        .row_highlight_style(Style::new().add_modifier(Modifier::BOLD))
+        .highlight_symbol("> ");
+        let mut selected = TableState::new().with_selected(Some(self.selected));
+        frame.render_stateful_widget(table, projects, &mut selected);
+
+        for (title, history, area) in [("Events", &self.events, events), ("Log", &self.logs, logs)]
+        {
+            let block = Block::new().borders(Borders::TOP).title(title.bold());
+            let skip = history
+                .len()
+                .saturating_sub(block.inner(area).height as usize);
+            let list = List::new(history.iter().skip(skip).map(String::as_str)).block(block);
+            frame.render_widget(list, area);
+        }
+        frame.render_widget(
+            Line::from("up/down select   p pause/resume   s sync now   q quit"),
+            help,
+        );
+    }
+}
+
+fn describe_error(err: &SyncError) -> String {
+    match err {
+        SyncError::HookFailed => "hook failed".to_owned(),
+        SyncError::DstConflict => "refused, dst changed".to_owned(),
+        SyncError::DeleteRefused => "refused, deletions not confirmed".to_owned(),
+        SyncError::DeleteLimit => "aborted, too many deletions".to_owned(),
+        SyncError::Unreachable => "dst unreachable".to_owned(),
+        SyncError::Failed {
+            exit_code: Some(code),
+        } => format!("failed ({code})"),
+        SyncError::Failed { exit_code: None } => "failed".to_owned(),
+    }
+}
+
+/// Local time of day, e.g. `12:00:01`
+fn clock(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as libc::time_t;
+    let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+    // SAFETY: localtime_r initializes tm on success
+    if unsafe { libc::localtime_r(&secs, tm.as_mut_ptr()) }.is_null() {
+        return "--:--:--".to_owned();
+    }
+    // SAFETY: checked above
+    let tm = unsafe { tm.assume_init() };
+    format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+/// Show the dashboard of `watcher` until `q` is pressed, `stop` is set or the watch exits.
+///
+/// `projects` are the watched projects. While the dashboard is shown, the logs are displayed
+/// in it instead of being printed
+pub fn run(
+    watcher: &Watcher,
+    projects: impl IntoIterator<Item = String>,
+    stop: &AtomicBool,
+) -> anyhow::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let (log_tx, log_rx) = channel::unbounded();
+    crate::output::divert(Some(log_tx));
+    let (key_tx, key_rx) = channel::unbounded();
+    // blocks on the terminal until the process exits
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if let Some(key) = Key::from_event(&event) {
+                if key_tx.send(key).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut dashboard = Dashboard::new(projects);
+    let res = loop {
+        if stop.load(Ordering::Relaxed) {
+            break Ok(());
+        }
+        if let Err(err) = terminal.draw(|frame| dashboard.draw(frame)) {
+            break Err(err.into());
+        }
+        select! {
+            recv(watcher.events()) -> event => match event {
+                Ok(event) => dashboard.apply(event),
+                Err(_) => break Ok(()),
+            },
+            recv(key_rx) -> key => match key {
+                Ok(Key::Quit) | Err(_) => break Ok(()),
+                Ok(key) => dashboard.key(key, watcher.control()),
+            },
+            recv(log_rx) -> line => {
+                if let Ok(line) = line {
+                    push_line(&mut dashboard.logs, line);
+                }
+            },
+            default(REDRAW_INTERVAL) => {}
+        }
+    };
+    crate::output::divert(None);
+    ratatui::restore();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        use ratatui::crossterm::event::KeyEvent;
+
+        let key = |code, modifiers| Key::from_event(&Event::Key(KeyEvent::new(code, modifiers)));
+        assert_eq!(key(KeyCode::Up, KeyModifiers::NONE), Some(Key::Up));
+        assert_eq!(key(KeyCode::Char('j'), KeyModifiers::NONE), Some(Key::Down));
+        assert_eq!(
+            key(KeyCode::Char('p'), KeyModifiers::NONE),
+            Some(Key::TogglePause)
+        );
+        assert_eq!(
+            key(KeyCode::Char('s'), KeyModifiers::NONE),
+            Some(Key::Trigger)
+        );
+        assert_eq!(
+            key(KeyCode::Char('c'), KeyModifiers::CONTROL),
+            Some(Key::Quit)
+        );
+        assert_eq!(key(KeyCode::Char('x'), KeyModifiers::NONE), None);
+        assert_eq!(Key::from_event(&Event::FocusGained), None);
+    }
+
+    #[test]
+    fn test_dashboard_controls_selected_project() {
+        let mut dashboard = Dashboard::new(["api".to_owned(), "web".to_owned()]);
+        let (tx, rx) = channel::unbounded();
+        dashboard.key(Key::Down, &tx);
+        dashboard.key(Key::TogglePause, &tx);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            WatchControl::Pause {
+                project: "web".to_owned()
+            }
+        );
+        dashboard.apply(WatchEvent::Paused {
+            project: "web".to_owned(),
+        });
+        dashboard.key(Key::TogglePause, &tx);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            WatchControl::Resume {
+                project: "web".to_owned()
+            }
+        );
+
+        dashboard.apply(WatchEvent::SyncStarted {
+            project: "api".to_owned(),
+            src: "/src/api".into(),
+            initialize: false,
+        });
+        assert_eq!(dashboard.projects["api"].status(), "syncing");
+        dashboard.apply(WatchEvent::SyncFinished {
+            project: "api".to_owned(),
+            src: "/src/api".into(),
+            result: Err(SyncError::Failed {
+                exit_code: Some(23),
+            }),
+            duration: Duration::from_secs(1),
+            usage: Default::default(),
+        });
+        assert_eq!(dashboard.projects["api"].status(), "idle");
+        let mut terminal =
+            ratatui::Terminal::new(ratatui::backend::TestBackend::new(100, 20)).unwrap();
+        terminal.draw(|frame| dashboard.draw(frame)).unwrap();
+        let screen = terminal.backend().buffer().content();
+        let screen = screen
+            .chunks(100)
+            .map(|row| row.iter().map(|c| c.symbol()).collect::<String>())
+            .collect::<Vec<_>>();
+        assert!(screen.iter().any(|l| l.contains("api:api: failed (23)")));
+        assert!(screen.iter().any(|l| l.starts_with("> web")));
+    }
+}
@@ -0,0 +1,84 @@
+//! Verbosity of the logs, see `-v` and `-q`, passed on to the child processes
+use std::env;
+
+/// Environment variable passing the verbosity on to the syncs and hooks, from -2 (`-qq`) to 2
+/// (`-vv`)
+pub const ENV: &str = "ATUNE_VERBOSITY";
+
+/// The verbosity inherited from the atune running this one, 0 if there is none
+pub fn from_env() -> i8 {
+    env::var(ENV)
+        .ok()
+        .and_then(|v| v.trim().parse::<i8>().ok())
+        .unwrap_or(0)
+        .clamp(-2, 2)
+}
+
+/// Tracing filter directives of the verbosity, followed by the `extra` ones, which override
+/// them per module
+pub fn filter(verbosity: i8, extra: Option<&str>) -> String {
+    let base = match verbosity {
+        ..=-2 => "error",
+        -1 => "warn",
+        0 => "info",
+        1 => "info,atune=debug",
+        2.. => "debug,atune=trace",
+    };
+    match extra.map(str::trim).filter(|e| !e.is_empty()) {
+        Some(extra) => format!("{base},{extra}"),
+        None => base.to_owned(),
+    }
+}
+
+/// Remove the flags making rsync print every file or its progress, for quiet syncs
+pub fn quiet_rsync_flags(flags: &mut Vec<String>) {
+    let mut quiet = Vec::with_capacity(flags.len());
+    for flag in flags.drain(..) {
+        match flag.as_str() {
+            "-v" | "--verbose" | "--progress" | "--info=progress2" => {}
+            "-P" => quiet.push("--partial".to_owned()),
+            // combined short flags, e.g. `-avzP`
+            f if f.len() > 1
+                && f.starts_with('-')
+                && !f.starts_with("--")
+                && f[1..].bytes().all(|b| b.is_ascii_alphabetic()) =>
+            {
+                let rest = f[1..].replace(['v', 'P'], "");
+                if f.contains('P') {
+                    quiet.push("--partial".to_owned());
+                }
+                if !rest.is_empty() {
+                    quiet.push(format!("-{rest}"));
+                }
+            }
+            _ => quiet.push(flag),
+        }
+    }
+    *flags = quiet;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(filter(0, None), "info");
+        assert_eq!(filter(-1, None), "warn");
+        assert_eq!(filter(-5, None), "error");
+        assert_eq!(
+            filter(1, Some("notify=debug")),
+            "info,atune=debug,notify=debug"
+        );
+        assert_eq!(filter(2, Some(" ")), "debug,atune=trace");
+    }
+
+    #[test]
+    fn test_quiet_rsync_flags() {
+        let mut flags = ["-avzP", "--progress", "-e", "ssh -v", "--delete", "-v"]
+            .map(String::from)
+            .to_vec();
+        quiet_rsync_flags(&mut flags);
+        assert_eq!(flags, ["--partial", "-az", "-e", "ssh -v", "--delete"]);
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use crossbeam::channel;
+
+use crate::{
+    config::Config,
+    sync::{self, WatchControl, WatchEvent, WatchOptions},
+};
+
+/// Handle of a watch running in the background
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let config: atune::config::Config = serde_yaml::from_str("projects: {}")?;
+/// let watcher = atune::Watcher::start("atune.yaml".into(), config, Default::default());
+/// let event = watcher.events().recv()?;
+/// println!("{event:?}");
+/// watcher.stop()
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Watcher {
+    cancel: channel::Sender<()>,
+    events: channel::Receiver<WatchEvent>,
+    control: channel::Sender<WatchControl>,
+    thread: std::thread::JoinHandle<anyhow::Result<()>>,
+}
+
+impl Watcher {
+    /// Start watching the projects of the config.
+    ///
+    /// `options.events` and `options.control` are replaced by the channels returned by
+    /// [Watcher::events] and [Watcher::control]
+    pub fn start(config_path: PathBuf, config: Config, options: WatchOptions) -> Self {
+        let (cancel_tx, cancel_rx) = channel::bounded(1);
+        let (events_tx, events_rx) = channel::unbounded();
+        let (control_tx, control_rx) = channel::unbounded();
+        let options = WatchOptions {
+            events: Some(events_tx),
+            control: Some(control_rx),
+            ..options
+        };
+        let thread =
+            std::thread::spawn(move || sync::watch(config_path, config, cancel_rx, options));
+        Self {
+            cancel: cancel_tx,
+            events: events_rx,
+            control: control_tx,
+            thread,
+        }
+    }
+
+    /// Events of the watch. The channel is disconnected once the watch stops
+    pub fn events(&self) -> &channel::Receiver<WatchEvent> {
+        &self.events
+    }
+
+    /// Pause, resume or trigger the syncs of projects
+    pub fn control(&self) -> &channel::Sender<WatchControl> {
+        &self.control
+    }
+
+    /// Stop the watch and wait for it to exit
+    pub fn stop(self) -> anyhow::Result<()> {
+        // the watch may have exited on its own
+        let _ = self.cancel.send(());
+        self.thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("Watch thread panicked"))?
+            .context("Watch error")
+    }
+}
@@ -1,4 +1,12 @@
-use std::{ffi::OsStr, io::Write, path::Path, process::Stdio, time::Duration};
+use std::{
+    ffi::OsStr,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use crossbeam::channel::Receiver;
 
 struct TestAtune(pub std::process::Child);
 
@@ -24,6 +32,28 @@ fn atune(config_file_path: &OsStr, command: &str) -> TestAtune {
     TestAtune(proc)
 }
 
+/// Start watching with the atune executable under test running the syncs
+fn start_watcher(
+    config_file_path: PathBuf,
+    config: atune::config::Config,
+) -> (atune::Watcher, Receiver<atune::WatchEvent>) {
+    let watcher = atune::Watcher::start(
+        config_file_path,
+        config,
+        atune::WatchOptions {
+            executable: Some(std::env!("CARGO_BIN_EXE_atune").into()),
+            ..Default::default()
+        },
+    );
+    let events = watcher.events().clone();
+    (watcher, events)
+}
+
+/// Wait for the initial syncs to finish
+fn wait_ready(events: &Receiver<atune::WatchEvent>) {
+    while events.recv_timeout(Duration::from_secs(5)).unwrap() != atune::WatchEvent::Ready {}
+}
+
 fn setup(root: &Path) {
     let test_1 = root.join("test_1");
     let test_2 = root.join("test_2");
@@ -53,11 +83,11 @@ projects:
         -
             src: {}
             dst: {}
-            rsync_flags: -av --rsync-path "mkdir -p {} && rsync"
+            rsync_flags: -av
+            create_dst: true
     "#,
         dir.path().join("test_1").display(),
         out.display(),
-        out.display()
     );
 
     let config_file_path = dir.path().join("config.yaml");
@@ -98,11 +128,11 @@ projects:
         -
             src: {}
             dst: {}
-            rsync_flags: -av --rsync-path "mkdir -p {} && rsync"
+            rsync_flags: -av
+            create_dst: true
     "#,
         dir.path().join("test_1").display(),
         out.display(),
-        out.display()
     );
 
     let config_file_path = dir.path().join("config.yaml");
@@ -145,11 +175,11 @@ projects:
         -
             src: {}
             dst: {}
-            rsync_flags: -av --rsync-path "mkdir -p {} && rsync"
+            rsync_flags: -av
+            create_dst: true
     "#,
         dir.path().join("test_1").display(),
         out.display(),
-        out.display()
     );
 
     let config_file_path = dir.path().join("test_1/config.yaml");
@@ -204,3 +234,2271 @@ projects:
     let fout = out.join("test.txt");
     assert!(!fout.exists());
 }
+
+#[test]
+fn test_watcher_events() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        -
+            src: {}
+            on_sync:
+                - exit 1
+    "#,
+        dir.path().join("test_1").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    assert!(matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncStarted {
+            initialize: true,
+            ..
+        }
+    ));
+    assert!(matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::HookFailed { .. }
+    ));
+    assert!(matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncFinished {
+            result: Err(atune::SyncError::HookFailed),
+            ..
+        }
+    ));
+
+    watcher.stop().unwrap();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_watch_events() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        -
+            src: {}
+            on_sync:
+                - exit 1
+    "#,
+        dir.path().join("test_1").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let events = async {
+        let mut watcher = atune::async_watch(
+            config_file_path,
+            config,
+            atune::WatchOptions {
+                executable: Some(std::env!("CARGO_BIN_EXE_atune").into()),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            watcher.events().recv().await.unwrap(),
+            atune::WatchEvent::SyncStarted {
+                initialize: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            watcher.events().recv().await.unwrap(),
+            atune::WatchEvent::HookFailed { .. }
+        ));
+        assert!(matches!(
+            watcher.events().recv().await.unwrap(),
+            atune::WatchEvent::SyncFinished {
+                result: Err(atune::SyncError::HookFailed),
+                ..
+            }
+        ));
+        assert!(matches!(
+            watcher.events().recv().await.unwrap(),
+            atune::WatchEvent::Ready
+        ));
+
+        // a change of src is synced again
+        std::fs::write(dir.path().join("test_1/changed.txt"), "x").unwrap();
+        assert!(matches!(
+            watcher.events().recv().await.unwrap(),
+            atune::WatchEvent::SyncStarted {
+                initialize: false,
+                ..
+            }
+        ));
+        watcher.stop().await.unwrap();
+    };
+    runtime
+        .block_on(async { tokio::time::timeout(Duration::from_secs(10), events).await })
+        .expect("Timed out waiting for the events");
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_watch_refuses_unsupported() {
+    let config: atune::config::Config = serde_yaml::from_str(
+        r#"
+projects:
+    test_1:
+      run:
+        - sleep 60
+      sync:
+        - src: /tmp
+    "#,
+    )
+    .unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mut watcher = atune::async_watch("atune.yaml".into(), config, Default::default());
+        assert!(watcher.events().recv().await.is_none());
+        let err = watcher.stop().await.unwrap_err();
+        assert!(format!("{err:#}").contains("run of project test_1"));
+    });
+}
+
+#[test]
+fn test_sync_once_dependency_order() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let marker = dir.path().join("marker");
+    let result = dir.path().join("result");
+    let config = format!(
+        r#"
+projects:
+    app:
+      depends_on: [lib]
+      sync:
+        -
+            src: {}
+            on_sync:
+                - test -f {} && touch {}
+    lib:
+      sync:
+        -
+            src: {}
+            on_sync:
+                - sleep 0.2 && touch {}
+    "#,
+        dir.path().join("test_2").display(),
+        marker.display(),
+        result.display(),
+        dir.path().join("test_1").display(),
+        marker.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+
+    assert!(result.exists());
+}
+
+#[test]
+fn test_sync_once_tags() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let marker = |name: &str| dir.path().join(name);
+    let config = format!(
+        r#"
+projects:
+    web:
+      tags: [frontend]
+      sync:
+        -
+            src: {}
+            on_sync:
+                - touch {}
+    api:
+      sync:
+        -
+            src: {}
+            tags: [deploy]
+            on_sync:
+                - touch {}
+        -
+            src: {}
+            on_sync:
+                - touch {}
+    "#,
+        dir.path().join("test_1").display(),
+        marker("web").display(),
+        dir.path().join("test_2").display(),
+        marker("api_deploy").display(),
+        dir.path().join("test_1").display(),
+        marker("api_other").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let sync_once = |tags: &str| {
+        std::process::Command::new(std::env!("CARGO_BIN_EXE_atune"))
+            .arg("-c")
+            .arg(&config_file_path)
+            .arg("sync-once")
+            .arg("--tags")
+            .arg(tags)
+            .env("XDG_STATE_HOME", dir.path().join("state"))
+            .status()
+            .unwrap()
+    };
+    assert!(sync_once("frontend,deploy").success());
+    assert!(marker("web").exists());
+    assert!(marker("api_deploy").exists());
+    assert!(!marker("api_other").exists());
+
+    // a typo selects nothing
+    assert!(!sync_once("backend").success());
+}
+
+#[test]
+fn test_sync_once_exit_code() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let config = format!(
+        r#"
+projects:
+    ok:
+      sync:
+        -
+            src: {}
+            on_sync:
+                - "true"
+    fails:
+      sync:
+        -
+            src: {}
+            on_sync:
+                - "false"
+    "#,
+        dir.path().join("test_1").display(),
+        dir.path().join("test_2").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(!proc.0.wait().unwrap().success());
+}
+
+#[test]
+fn test_command_conditions() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let out = dir.path().join("out");
+    std::fs::create_dir(&out).unwrap();
+    let config = format!(
+        r#"
+projects:
+    test_1:
+      sync:
+        -
+            src: {}
+            on_sync:
+                - command: touch init_only
+                  cwd: {out}
+                  only_on: init
+                - command: touch change_only
+                  cwd: {out}
+                  only_on: change
+                - command: touch skipped
+                  cwd: {out}
+                  run_if: test -f missing
+                - command: touch checked
+                  cwd: {out}
+                  run_if: test -d .
+    "#,
+        dir.path().join("test_1").display(),
+        out = out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    // sync-once runs the initial syncs
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+
+    assert!(out.join("init_only").exists());
+    assert!(!out.join("change_only").exists());
+    assert!(!out.join("skipped").exists());
+    assert!(out.join("checked").exists());
+}
+
+#[test]
+fn test_copy_contents() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let contents = dir.path().join("contents-out");
+    let into = dir.path().join("into-out");
+    std::fs::create_dir(&contents).unwrap();
+    std::fs::create_dir(&into).unwrap();
+
+    let config = format!(
+        r#"
+projects:
+    test_1:
+      sync:
+        - src: {0}
+          dst: {1}
+          rsync_flags: -a
+          copy_contents: true
+    test_2:
+      sync:
+        - src: {0}/
+          dst: {2}
+          rsync_flags: -a
+          copy_contents: false
+    "#,
+        dir.path().join("test_1").display(),
+        contents.display(),
+        into.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+
+    assert!(contents.join("0.txt").is_file());
+    assert!(!contents.join("test_1").exists());
+    assert!(into.join("test_1/0.txt").is_file());
+}
+
+#[test]
+fn test_atomic() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("atomic-out");
+
+    let config = format!(
+        r#"
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+          atomic: true
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+    assert!(out.join("test_1/0.txt").is_file());
+
+    // the staging copy starts empty, so files removed from src are gone too
+    std::fs::remove_file(dir.path().join("test_1/0.txt")).unwrap();
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+    assert!(!out.join("test_1/0.txt").exists());
+    assert!(out.join("test_1/new.txt").is_file());
+    let names = std::fs::read_dir(&out)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["test_1"]);
+}
+
+#[test]
+fn test_hook_outputs() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let result = dir.path().join("release");
+    let config = format!(
+        r#"
+projects:
+    test_1:
+      sync:
+        -
+            src: {}
+            on_sync:
+                - echo RELEASE=42 >> "$ATUNE_OUTPUT"
+                - echo "release $RELEASE" > {}
+    "#,
+        dir.path().join("test_1").display(),
+        result.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+
+    assert_eq!(std::fs::read_to_string(result).unwrap(), "release 42\n");
+}
+
+#[test]
+fn test_daemon() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("daemon-out");
+    std::fs::create_dir(&out).unwrap();
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let cli = std::env!("CARGO_BIN_EXE_atune");
+    let run = |args: &[&str]| {
+        let out = std::process::Command::new(cli)
+            .arg("-c")
+            .arg(&config_file_path)
+            .args(args)
+            .env("XDG_STATE_HOME", dir.path().join("state"))
+            .env("XDG_RUNTIME_DIR", dir.path())
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "{out:?}");
+        String::from_utf8(out.stdout).unwrap()
+    };
+    let started = run(&["watch", "--daemon"]);
+    assert!(started.contains("in the background"), "{started}");
+
+    // the second watch of the config fails to start
+    let second = std::process::Command::new(cli)
+        .arg("-c")
+        .arg(&config_file_path)
+        .args(["watch", "--daemon"])
+        .env("XDG_STATE_HOME", dir.path().join("state"))
+        .env("XDG_RUNTIME_DIR", dir.path())
+        .output()
+        .unwrap();
+    assert!(!second.status.success());
+    assert!(String::from_utf8_lossy(&second.stderr).contains("already watching"));
+
+    std::thread::sleep(TIMEOUT);
+    assert!(out.join("test_1/0.txt").is_file());
+    let logs = std::fs::read_dir(dir.path().join("state/atune"))
+        .unwrap()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "log"))
+        .collect::<Vec<_>>();
+    assert_eq!(logs.len(), 1);
+    let log = std::fs::read_to_string(&logs[0]).unwrap();
+    assert!(log.contains("[test_1:test_1]"), "{log}");
+
+    let stopped = run(&["stop"]);
+    assert!(stopped.contains("Stopped atune"), "{stopped}");
+    let stopped = run(&["stop"]);
+    assert!(stopped.contains("isn't watching"), "{stopped}");
+}
+
+#[test]
+fn test_hook_history() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let config = format!(
+        r#"
+projects:
+    fails:
+      sync:
+        -
+            src: {}
+            on_sync:
+                - "true"
+                - echo deploy broke && false
+    "#,
+        dir.path().join("test_1").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let state = dir.path().join("state");
+    let cli = std::env!("CARGO_BIN_EXE_atune");
+    let run = |command: &str| {
+        std::process::Command::new(cli)
+            .arg("-c")
+            .arg(&config_file_path)
+            .arg(command)
+            .env("XDG_STATE_HOME", &state)
+            .output()
+            .unwrap()
+    };
+    assert!(!run("sync-once").status.success());
+
+    let history = run("history");
+    assert!(history.status.success());
+    let history = String::from_utf8(history.stdout).unwrap();
+    assert!(history.contains("on_sync"), "{history}");
+    assert!(history.contains("failed (1)"), "{history}");
+    assert!(history.contains("  | deploy broke"), "{history}");
+}
+
+#[test]
+fn test_poll_watcher() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      watcher: Poll
+      poll_interval: 50ms
+      sync:
+        -
+            src: {}
+            on_sync:
+                - "true"
+    "#,
+        dir.path().join("test_1").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    // initial sync
+    wait_ready(&events);
+
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    assert!(matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncStarted {
+            initialize: false,
+            ..
+        }
+    ));
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_shallow_watcher() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let deep = dir.path().join("test_1/a/b");
+    std::fs::create_dir_all(&deep).unwrap();
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      watcher: Shallow
+      poll_interval: 50ms
+      sync:
+        -
+            src: {}
+            on_sync:
+                - "true"
+    "#,
+        dir.path().join("test_1").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    // initial sync
+    wait_ready(&events);
+
+    // below the natively watched levels
+    std::fs::write(deep.join("new.txt"), "hello").unwrap();
+    assert!(matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncStarted {
+            initialize: false,
+            ..
+        }
+    ));
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_rewatch_replaced_root() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let src = dir.path().join("test_1");
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      watcher: Native
+      sync:
+        -
+            src: {}
+            on_sync:
+                - "true"
+    "#,
+        src.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    // initial sync
+    events.recv_timeout(timeout).unwrap();
+    events.recv_timeout(timeout).unwrap();
+
+    std::fs::remove_dir_all(&src).unwrap();
+    // the removal of the files inside may trigger syncs first
+    let wait_for = |f: fn(&atune::WatchEvent) -> bool| loop {
+        if f(&events.recv_timeout(timeout).unwrap()) {
+            break;
+        }
+    };
+    wait_for(|ev| matches!(ev, atune::WatchEvent::WatcherDegraded { .. }));
+    std::fs::create_dir_all(&src).unwrap();
+    wait_for(|ev| matches!(ev, atune::WatchEvent::WatcherRecovered { .. }));
+    wait_for(|ev| matches!(ev, atune::WatchEvent::SyncStarted { .. }));
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_watch_new_dir_of_non_recursive_sync() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let src = dir.path().join("test_1");
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      watcher: Native
+      sync:
+        -
+            src: {}
+            recursive: false
+            on_sync:
+                - "true"
+    "#,
+        src.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    // initial sync
+    wait_ready(&events);
+    let wait_for = |f: fn(&atune::WatchEvent) -> bool| loop {
+        if f(&events.recv_timeout(timeout).unwrap()) {
+            break;
+        }
+    };
+
+    std::fs::create_dir(src.join("new")).unwrap();
+    wait_for(|ev| matches!(ev, atune::WatchEvent::SyncFinished { .. }));
+    std::fs::write(src.join("new/new.txt"), "hello").unwrap();
+    wait_for(|ev| matches!(ev, atune::WatchEvent::SyncStarted { .. }));
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_watch_dst() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let warn_dst = dir.path().join("warn-out");
+    let resync_dst = dir.path().join("resync-out");
+    let marker = dir.path().join("drifted");
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    warn:
+      sync:
+        -
+            src: {}
+            dst: {}
+            watch_dst: Warn
+            on_drift:
+                - "sh -c 'echo \"$ATUNE_DRIFTED_PATHS\" > {}'"
+    resync:
+      sync:
+        -
+            src: {}
+            dst: {}
+            watch_dst: Resync
+    "#,
+        dir.path().join("test_1").display(),
+        warn_dst.display(),
+        marker.display(),
+        dir.path().join("test_2").display(),
+        resync_dst.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    // initial sync
+    wait_ready(&events);
+    // past the grace period of the initial syncs
+    std::thread::sleep(Duration::from_millis(1500));
+
+    std::fs::write(warn_dst.join("test_1/foreign.txt"), "hello").unwrap();
+    std::fs::remove_file(resync_dst.join("test_2/0.txt")).unwrap();
+
+    let mut drifted = Vec::new();
+    let deadline = std::time::Instant::now() + timeout;
+    while drifted.len() < 2 && std::time::Instant::now() < deadline {
+        if let Ok(atune::WatchEvent::DstDrift { project, paths, .. }) = events.recv_timeout(timeout)
+        {
+            drifted.push((project, paths));
+        }
+    }
+    drifted.sort();
+    assert_eq!(drifted.len(), 2, "{drifted:?}");
+    assert_eq!(drifted[0].0, "resync");
+    assert_eq!(drifted[1].0, "warn");
+    assert!(drifted[1].1.iter().any(|p| p.ends_with("foreign.txt")));
+
+    // the resync restores the mirror
+    while !matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncFinished { ref project, .. } if project == "resync"
+    ) {}
+    assert!(resync_dst.join("test_2/0.txt").exists());
+    // warn only reports it
+    assert!(warn_dst.join("test_1/foreign.txt").exists());
+    let marker = std::fs::read_to_string(&marker).unwrap();
+    assert!(marker.contains("foreign.txt"), "{marker}");
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_min_interval() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("out");
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        -
+            src: {}
+            dst: {}
+            min_interval: 1s
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (_watcher, events) = start_watcher(config_file_path, config);
+    wait_ready(&events);
+
+    // a writer that never pauses long enough for the debounce
+    let log = dir.path().join("test_1/app.log");
+    let start = std::time::Instant::now();
+    let mut n = 0;
+    while start.elapsed() < Duration::from_millis(1500) {
+        n += 1;
+        std::fs::write(&log, n.to_string()).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let mut started = 0;
+    let deadline = std::time::Instant::now() + Duration::from_secs(3);
+    while std::time::Instant::now() < deadline {
+        if let Ok(atune::WatchEvent::SyncStarted { .. }) =
+            events.recv_timeout(Duration::from_millis(100))
+        {
+            started += 1;
+        }
+    }
+    // the initial sync ran right before the writes, so at most two more fit into them
+    assert!((1..=2).contains(&started), "{started} syncs");
+    // the last changes are synced once the interval passed
+    assert_eq!(
+        std::fs::read_to_string(out.join("test_1/app.log")).unwrap(),
+        n.to_string()
+    );
+}
+
+#[test]
+fn test_stats() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("stats-out");
+    std::fs::create_dir(&out).unwrap();
+    let state = dir.path().join("state");
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: {}
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let atune = |cmd: &str| {
+        let mut c = std::process::Command::new(std::env!("CARGO_BIN_EXE_atune"));
+        c.arg("-c")
+            .arg(&config_file_path)
+            .arg(cmd)
+            .env("XDG_STATE_HOME", &state);
+        c
+    };
+
+    let proc = TestAtune(atune("watch").spawn().unwrap());
+    let stats = state.join("atune/stats.json");
+    let wait_for_syncs = |n: usize| {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !std::fs::read_to_string(&stats)
+            .is_ok_and(|s| s.contains(&format!(r#""syncs":{n},"#)))
+        {
+            assert!(std::time::Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    };
+    // the initial sync
+    wait_for_syncs(1);
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    wait_for_syncs(2);
+    drop(proc);
+
+    let output = atune("stats").output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert!(lines[0].starts_with("PROJECT"), "{stdout}");
+    let row = lines[1].split_whitespace().collect::<Vec<_>>();
+    assert_eq!(row[0], "test_1", "{stdout}");
+    assert_eq!(&row[2..4], ["2", "0"], "{stdout}");
+    assert!(lines[2].starts_with("TOTAL"), "{stdout}");
+}
+
+#[test]
+fn test_change_id_passed_to_hooks() {
+    for execution in ["Subprocess", "InProcess"] {
+        change_id_passed_to_hooks(execution);
+    }
+}
+
+fn change_id_passed_to_hooks(execution: &str) {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let marker = dir.path().join("change-id");
+
+    let config = format!(
+        r#"
+debounce: 0s
+execution: {}
+projects:
+    test_1:
+      sync:
+        -
+            src: {}
+            dst: {}
+            initial_sync: Never
+            on_sync:
+                - "sh -c 'echo $ATUNE_CHANGE_ID > {}'"
+    "#,
+        execution,
+        dir.path().join("test_1").display(),
+        dir.path().join("out").display(),
+        marker.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (_watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    wait_ready(&events);
+    // without initial syncs Ready may precede the registration of the watcher
+    std::thread::sleep(Duration::from_millis(300));
+
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    while !matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncFinished { .. }
+    ) {}
+    let id = std::fs::read_to_string(&marker).unwrap();
+    assert!(id.trim().parse::<u64>().is_ok(), "{execution}: {id:?}");
+}
+
+#[test]
+fn test_resource_usage_of_syncs() {
+    for execution in ["Subprocess", "InProcess"] {
+        resource_usage_of_syncs(execution);
+    }
+}
+
+fn resource_usage_of_syncs(execution: &str) {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let config = format!(
+        r#"
+execution: {}
+projects:
+    test_1:
+      sync:
+        -
+            src: {}
+            dst: {}
+            on_sync:
+                - "sh -c 'i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done'"
+    "#,
+        execution,
+        dir.path().join("test_1").display(),
+        dir.path().join("out").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (_watcher, events) = start_watcher(config_file_path, config);
+    let usage = loop {
+        if let atune::WatchEvent::SyncFinished { result, usage, .. } =
+            events.recv_timeout(Duration::from_secs(5)).unwrap()
+        {
+            assert_eq!(result, Ok(()), "{execution}");
+            break usage;
+        }
+    };
+    // the hook alone takes some CPU time
+    assert!(usage.cpu() > Duration::ZERO, "{execution}: {usage:?}");
+    assert!(usage.max_rss > 0, "{execution}: {usage:?}");
+}
+
+#[test]
+fn test_pause_resume_and_trigger() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        -
+            src: {}
+            on_sync:
+                - "true"
+    "#,
+        dir.path().join("test_1").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let control = watcher.control();
+    let project = || "test_1".to_owned();
+    let timeout = Duration::from_secs(5);
+    wait_ready(&events);
+
+    control
+        .send(atune::WatchControl::Pause { project: project() })
+        .unwrap();
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::Paused { project: project() }
+    );
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    // the change is queued, but not synced
+    assert!(events.recv_timeout(Duration::from_millis(500)).is_err());
+
+    control
+        .send(atune::WatchControl::Resume { project: project() })
+        .unwrap();
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::Resumed { project: project() }
+    );
+    assert!(matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncStarted { .. }
+    ));
+    assert!(matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncFinished { result: Ok(()), .. }
+    ));
+
+    control
+        .send(atune::WatchControl::Trigger { project: project() })
+        .unwrap();
+    assert!(matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncStarted { .. }
+    ));
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_project_on_sync_runs_once_per_batch() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let log = dir.path().join("hooks.log");
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      on_sync:
+        - echo synced >> {log}
+        - command: echo init >> {log}
+          on: Init
+      sync:
+        - src: {}
+        - src: {}
+    "#,
+        dir.path().join("test_1").display(),
+        dir.path().join("test_2").display(),
+        log = log.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    let wait_for_log = |expected: &str| {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let content = std::fs::read_to_string(&log).unwrap_or_default();
+            if content == expected || std::time::Instant::now() > deadline {
+                return content;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    };
+    wait_ready(&events);
+    assert_eq!(wait_for_log("init\nsynced\n"), "init\nsynced\n");
+
+    // both entries sync, the project hooks run once
+    watcher
+        .control()
+        .send(atune::WatchControl::Trigger {
+            project: "test_1".to_owned(),
+        })
+        .unwrap();
+    let mut finished = 0;
+    while finished < 2 {
+        if let atune::WatchEvent::SyncFinished { result, .. } =
+            events.recv_timeout(timeout).unwrap()
+        {
+            assert_eq!(result, Ok(()));
+            finished += 1;
+        }
+    }
+    assert_eq!(
+        wait_for_log("init\nsynced\nsynced\n"),
+        "init\nsynced\nsynced\n"
+    );
+    std::thread::sleep(TIMEOUT);
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "init\nsynced\nsynced\n"
+    );
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_watch_only_entry_runs_its_commands() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let log = dir.path().join("changed.log");
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        - src: {}
+          initial_sync: Never
+          on_sync:
+            - echo "$ATUNE_CHANGED_FILES" >> {log}
+    "#,
+        dir.path().join("test_1").display(),
+        log = log.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    wait_ready(&events);
+
+    let changed = dir.path().join("test_1").join("3.txt");
+    std::fs::write(&changed, "new content").unwrap();
+    loop {
+        if let atune::WatchEvent::SyncFinished { result, .. } =
+            events.recv_timeout(timeout).unwrap()
+        {
+            assert_eq!(result, Ok(()));
+            break;
+        }
+    }
+    let content = std::fs::read_to_string(&log).unwrap();
+    assert!(content.contains("3.txt"), "{content}");
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_scheduled_entry() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let log = dir.path().join("ticks.log");
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        - src: {}
+          initial_sync: Never
+          schedule: 500ms
+          on_sync:
+            - echo tick >> {log}
+    "#,
+        dir.path().join("test_1").display(),
+        log = log.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    let mut finished = 0;
+    while finished < 2 {
+        if let atune::WatchEvent::SyncFinished { result, .. } =
+            events.recv_timeout(timeout).unwrap()
+        {
+            assert_eq!(result, Ok(()));
+            finished += 1;
+        }
+    }
+    let content = std::fs::read_to_string(&log).unwrap();
+    assert!(content.starts_with("tick\ntick\n"), "{content}");
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_fan_out_to_hosts() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("fan-out");
+    std::fs::create_dir(&out).unwrap();
+    // rsync can't create its destination below a file
+    std::fs::write(out.join("blocked"), "").unwrap();
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: "{}/{{{{ host }}}}"
+          hosts: [a, b, blocked]
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    loop {
+        if let atune::WatchEvent::SyncFinished { result, .. } =
+            events.recv_timeout(timeout).unwrap()
+        {
+            assert!(result.is_err());
+            break;
+        }
+    }
+    for host in ["a", "b"] {
+        assert!(
+            out.join(host).join("test_1").join("3.txt").exists(),
+            "{host}"
+        );
+    }
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_watch_in_process() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("in-process-out");
+    std::fs::create_dir(&out).unwrap();
+
+    let config = format!(
+        r#"
+debounce: 0s
+execution: InProcess
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+          on_sync:
+            - command: exit 1
+              on: Delete
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    // the syncs don't run the atune executable
+    let watcher = atune::Watcher::start(
+        config_file_path,
+        config,
+        atune::WatchOptions {
+            executable: Some("/nonexistent/atune".into()),
+            ..Default::default()
+        },
+    );
+    let events = watcher.events();
+    let timeout = Duration::from_secs(5);
+    let finished = || loop {
+        if let atune::WatchEvent::SyncFinished { result, .. } =
+            events.recv_timeout(timeout).unwrap()
+        {
+            return result;
+        }
+    };
+    assert_eq!(finished(), Ok(()));
+    assert!(out.join("test_1/0.txt").is_file());
+
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    assert_eq!(finished(), Ok(()));
+    assert!(out.join("test_1/new.txt").is_file());
+
+    std::fs::remove_file(dir.path().join("test_1/new.txt")).unwrap();
+    assert_eq!(finished(), Err(atune::SyncError::HookFailed));
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_custom_backend() {
+    /// Records the steps of the transfers
+    struct Recording(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl atune::backend::SyncBackend for Recording {
+        fn prepare(&self, ctx: &atune::backend::TransferContext<'_>) -> anyhow::Result<()> {
+            let step = format!("prepare {}", ctx.dst.display());
+            self.0.lock().unwrap().push(step);
+            Ok(())
+        }
+
+        fn transfer(
+            &self,
+            ctx: &atune::backend::TransferContext<'_>,
+            _: Option<&mut atune::sync::SyncOutput>,
+        ) -> anyhow::Result<()> {
+            let step = format!("transfer {}", ctx.changes.changed.len());
+            self.0.lock().unwrap().push(step);
+            Ok(())
+        }
+
+        fn finalize(&self, _: &atune::backend::TransferContext<'_>) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push("finalize".to_owned());
+            Ok(())
+        }
+    }
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let steps = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    atune::backend::register("recording", Recording(steps.clone()));
+
+    let config = format!(
+        r#"
+debounce: 0s
+execution: InProcess
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: remote:/out
+          backend: recording
+    "#,
+        dir.path().join("test_1").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+    config.validate().unwrap();
+
+    let watcher = atune::Watcher::start(
+        config_file_path,
+        config,
+        atune::WatchOptions {
+            executable: Some("/nonexistent/atune".into()),
+            ..Default::default()
+        },
+    );
+    let events = watcher.events();
+    let timeout = Duration::from_secs(5);
+    let finished = || loop {
+        if let atune::WatchEvent::SyncFinished { result, .. } =
+            events.recv_timeout(timeout).unwrap()
+        {
+            return result;
+        }
+    };
+    assert_eq!(finished(), Ok(()));
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    assert_eq!(finished(), Ok(()));
+    watcher.stop().unwrap();
+
+    assert_eq!(
+        *steps.lock().unwrap(),
+        [
+            "prepare remote:/out",
+            "transfer 0",
+            "finalize",
+            "prepare remote:/out",
+            "transfer 1",
+            "finalize"
+        ]
+    );
+}
+
+#[test]
+fn test_lock_group() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("shared-out");
+    std::fs::create_dir(&out).unwrap();
+
+    let entry = |src: &str| {
+        format!(
+            r#"
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+          lock_group: venv
+          on_sync:
+            - sleep 0.5
+"#,
+            dir.path().join(src).display(),
+            out.display(),
+        )
+    };
+    let config = format!(
+        "projects:\n    test_1:{}    test_2:{}",
+        entry("test_1"),
+        entry("test_2")
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let mut order = Vec::new();
+    while order.len() < 4 {
+        match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+            atune::WatchEvent::SyncStarted { .. } => order.push("started"),
+            atune::WatchEvent::SyncFinished { result, .. } => {
+                assert_eq!(result, Ok(()));
+                order.push("finished");
+            }
+            _ => {}
+        }
+    }
+    // the syncs of both projects ran one after the other
+    assert_eq!(order, ["started", "finished", "started", "finished"]);
+    assert!(out.join("test_1/0.txt").is_file());
+    assert!(out.join("test_2/0.txt").is_file());
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_healthcheck() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("health-out");
+    std::fs::create_dir(&out).unwrap();
+    let up = dir.path().join("up");
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      healthcheck:
+        - test -e {}
+      healthcheck_interval: 100ms
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+    "#,
+        up.display(),
+        dir.path().join("test_1").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let (watcher, events) = start_watcher(config_file_path, config);
+    let timeout = Duration::from_secs(5);
+    let started = || loop {
+        if let atune::WatchEvent::SyncStarted { .. } = events.recv_timeout(timeout).unwrap() {
+            break;
+        }
+    };
+
+    // the initial sync waits for the target
+    assert!(events.recv_timeout(Duration::from_millis(500)).is_err());
+    std::fs::write(&up, "").unwrap();
+    started();
+    wait_ready(&events);
+
+    // changes are queued while it's down, and synced once it's back
+    std::fs::remove_file(&up).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    assert!(events.recv_timeout(Duration::from_millis(500)).is_err());
+    std::fs::write(&up, "").unwrap();
+    started();
+    assert!(matches!(
+        events.recv_timeout(timeout).unwrap(),
+        atune::WatchEvent::SyncFinished { result: Ok(()), .. }
+    ));
+    assert!(out.join("test_1/new.txt").is_file());
+
+    watcher.stop().unwrap();
+}
+
+#[test]
+fn test_initial_sync_if_needed() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("if-needed-out");
+    std::fs::create_dir(&out).unwrap();
+    let log = dir.path().join("init.log");
+    let state = dir.path().join("state");
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+          initial_sync: IfNeeded
+          on_sync:
+            - command: echo init >> {}
+              on: Init
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+        log.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+
+    let watch = || {
+        let cli = std::env!("CARGO_BIN_EXE_atune");
+        let proc = std::process::Command::new(cli)
+            .arg("-c")
+            .arg(&config_file_path)
+            .arg("watch")
+            .env("XDG_STATE_HOME", &state)
+            .spawn()
+            .expect("Failed to spawn atune");
+        TestAtune(proc)
+    };
+    let wait_for = |f: &dyn Fn() -> bool| {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !f() {
+            assert!(std::time::Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    };
+    let initialized = || {
+        std::fs::read_to_string(state.join("atune/state.json"))
+            .is_ok_and(|s| s.contains(r#""initialized":true"#))
+    };
+
+    let proc = watch();
+    wait_for(&initialized);
+    drop(proc);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "init\n");
+
+    // unchanged, the initial sync is skipped
+    std::fs::remove_file(out.join("test_1/0.txt")).unwrap();
+    let proc = watch();
+    std::thread::sleep(Duration::from_secs(1));
+    drop(proc);
+    assert!(!out.join("test_1/0.txt").exists());
+
+    // changed while atune wasn't running, synced without the init commands
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    let proc = watch();
+    wait_for(&|| out.join("test_1/new.txt").exists());
+    drop(proc);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "init\n");
+}
+
+#[test]
+fn test_on_init_when() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("init-when-out");
+    let log = dir.path().join("init.log");
+    let state = dir.path().join("state");
+
+    let entry = |src: &str, when: &str| {
+        format!(
+            r#"
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+          on_init_when: {when}
+          on_sync:
+            - command: echo {when} >> {}
+              on: Init
+"#,
+            dir.path().join(src).display(),
+            out.display(),
+            log.display(),
+        )
+    };
+    let config = format!(
+        "projects:\n    test_1:{}    test_2:{}",
+        entry("test_1", "dst-missing"),
+        entry("test_2", "state-missing")
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+
+    let sync_once = || {
+        let status = std::process::Command::new(std::env!("CARGO_BIN_EXE_atune"))
+            .arg("-c")
+            .arg(&config_file_path)
+            .arg("sync-once")
+            .env("XDG_STATE_HOME", &state)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut lines = std::fs::read_to_string(&log)
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        lines.sort();
+        lines
+    };
+
+    assert_eq!(sync_once(), ["dst-missing", "state-missing"]);
+    assert_eq!(sync_once(), ["dst-missing", "state-missing"]);
+    std::fs::remove_dir_all(out.join("test_1")).unwrap();
+    assert_eq!(sync_once(), ["dst-missing", "dst-missing", "state-missing"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch_uses_the_given_rsync() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("rsync-out");
+    std::fs::create_dir(&out).unwrap();
+    // records its invocation, then runs the real rsync
+    let wrapper = |name: &str| {
+        let path = dir.path().join(name);
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\ntouch {}\nexec rsync \"$@\"\n",
+                dir.path().join(format!("{name}.called")).display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    };
+    let cli_rsync = wrapper("cli-rsync");
+    let project_rsync = wrapper("project-rsync");
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+    test_2:
+      rsync: {}
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+        project_rsync.display(),
+        dir.path().join("test_2").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let config: atune::config::Config = serde_yaml::from_str(&config).unwrap();
+
+    let watcher = atune::Watcher::start(
+        config_file_path,
+        config,
+        atune::WatchOptions {
+            executable: Some(std::env!("CARGO_BIN_EXE_atune").into()),
+            rsync: Some(cli_rsync),
+            ..Default::default()
+        },
+    );
+    let events = watcher.events();
+    wait_ready(events);
+    watcher.stop().unwrap();
+
+    assert!(dir.path().join("cli-rsync.called").exists());
+    assert!(dir.path().join("project-rsync.called").exists());
+    assert!(out.join("test_1/0.txt").is_file());
+    assert!(out.join("test_2/0.txt").is_file());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_offline_queue_survives_restart() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("offline-out");
+    std::fs::create_dir(&out).unwrap();
+    let state = dir.path().join("state");
+    let down = dir.path().join("down");
+    // fails like ssh when the host can't be reached
+    let rsync = dir.path().join("flaky-rsync");
+    std::fs::write(
+        &rsync,
+        format!(
+            "#!/bin/sh\n[ -e {} ] && exit 255\nexec rsync \"$@\"\n",
+            down.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&rsync, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config = format!(
+        r#"
+debounce: 0s
+projects:
+    test_1:
+      rsync: {}
+      healthcheck_interval: 200ms
+      sync:
+        - src: {}
+          dst: {}
+          rsync_flags: -a
+          initial_sync: Never
+    "#,
+        rsync.display(),
+        dir.path().join("test_1").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+
+    let watch = || {
+        let proc = std::process::Command::new(std::env!("CARGO_BIN_EXE_atune"))
+            .arg("-c")
+            .arg(&config_file_path)
+            .arg("watch")
+            .env("XDG_STATE_HOME", &state)
+            .spawn()
+            .expect("Failed to spawn atune");
+        TestAtune(proc)
+    };
+    let wait_for = |f: &dyn Fn() -> bool| {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !f() {
+            assert!(std::time::Instant::now() < deadline, "timed out");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    };
+    let queued = || {
+        std::fs::read_dir(state.join("atune/offline"))
+            .into_iter()
+            .flatten()
+            .filter_map(|e| std::fs::read_to_string(e.ok()?.path()).ok())
+            .collect::<String>()
+    };
+
+    std::fs::write(&down, "").unwrap();
+    let proc = watch();
+    std::thread::sleep(Duration::from_millis(500));
+    std::fs::write(dir.path().join("test_1/new.txt"), "hello").unwrap();
+    wait_for(&|| queued().contains("new.txt"));
+    drop(proc);
+    assert!(!out.join("test_1/new.txt").exists());
+
+    // replayed once dst is reachable, even though the initial sync is skipped
+    std::fs::remove_file(&down).unwrap();
+    let proc = watch();
+    wait_for(&|| out.join("test_1/new.txt").exists());
+    wait_for(&|| queued().is_empty());
+    drop(proc);
+}
+
+#[test]
+fn test_watch_config_dir() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let configs = dir.path().join("configs");
+    std::fs::create_dir(&configs).unwrap();
+
+    let config = |name: &str| {
+        let out = dir.path().join(format!("{name}-out"));
+        let config = format!(
+            r#"
+debounce: 0s
+projects:
+    {name}:
+      sync:
+        -
+            src: {}
+            dst: {}
+    "#,
+            dir.path().join(name).display(),
+            out.display(),
+        );
+        std::fs::write(configs.join(format!("{name}.yaml")), config).unwrap();
+        out
+    };
+    let out_1 = config("test_1");
+
+    let _proc = atune(configs.as_os_str(), "watch");
+    std::thread::sleep(TIMEOUT);
+    assert!(out_1.join("test_1/0.txt").is_file());
+
+    // picked up without a restart, once the reload debounce passed
+    let out_2 = config("test_2");
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while !out_2.join("test_2/0.txt").is_file() {
+        assert!(std::time::Instant::now() < deadline);
+        std::thread::sleep(TIMEOUT);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_confirm_delete() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let synced = dir.path().join("synced");
+    // its dry run deletes 8 of the 18 files in dst
+    let rsync = dir.path().join("deleting-rsync");
+    std::fs::write(
+        &rsync,
+        format!(
+            r#"#!/bin/sh
+case " $* " in
+  *" --dry-run "*)
+    for i in 1 2 3 4 5 6 7 8; do echo "*deleting   stale/$i.txt"; done
+    echo "Number of files: 10 (reg: 10)" ;;
+  *) touch {} ;;
+esac
+"#,
+            synced.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&rsync, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let write_config = |threshold: &str| {
+        let config = format!(
+            r#"
+projects:
+    test_1:
+      rsync: {}
+      sync:
+        - src: {}
+          dst: {}
+          confirm_delete: true
+          confirm_delete_threshold: {threshold}
+    "#,
+            rsync.display(),
+            dir.path().join("test_1").display(),
+            dir.path().join("out").display(),
+        );
+        let config_file_path = dir.path().join("config.yaml");
+        std::fs::write(&config_file_path, &config).unwrap();
+        config_file_path
+    };
+    let sync_once = |config: &Path, args: &[&str]| {
+        std::process::Command::new(std::env!("CARGO_BIN_EXE_atune"))
+            .arg("-c")
+            .arg(config)
+            .arg("sync-once")
+            .args(args)
+            .env("XDG_STATE_HOME", dir.path().join("state"))
+            .stdin(Stdio::null())
+            .status()
+            .unwrap()
+    };
+
+    // 44% of dst, without a terminal to confirm it
+    let config = write_config("20%");
+    assert!(!sync_once(&config, &[]).success());
+    assert!(!synced.exists());
+
+    assert!(sync_once(&config, &["--yes"]).success());
+    assert!(synced.exists());
+    std::fs::remove_file(&synced).unwrap();
+
+    let config = write_config("10");
+    assert!(sync_once(&config, &[]).success());
+    assert!(synced.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_max_delete() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let args = dir.path().join("args");
+    // its dry run deletes 8 files in dst
+    let rsync = dir.path().join("deleting-rsync");
+    std::fs::write(
+        &rsync,
+        format!(
+            r#"#!/bin/sh
+case " $* " in
+  *" --dry-run "*)
+    for i in 1 2 3 4 5 6 7 8; do echo "*deleting   stale/$i.txt"; done
+    echo "Number of files: 10 (reg: 10)" ;;
+  *) echo "$@" > {} ;;
+esac
+"#,
+            args.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&rsync, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let write_config = |max: u64| {
+        let config = format!(
+            r#"
+projects:
+    test_1:
+      rsync: {}
+      sync:
+        - src: {}
+          dst: {}
+          max_delete: {max}
+    "#,
+            rsync.display(),
+            dir.path().join("test_1").display(),
+            dir.path().join("out").display(),
+        );
+        let config_file_path = dir.path().join("config.yaml");
+        std::fs::write(&config_file_path, &config).unwrap();
+        config_file_path
+    };
+    let sync_once = |config: &Path| {
+        std::process::Command::new(std::env!("CARGO_BIN_EXE_atune"))
+            .arg("-c")
+            .arg(config)
+            .arg("sync-once")
+            .arg("--yes")
+            .env("XDG_STATE_HOME", dir.path().join("state"))
+            .stdin(Stdio::null())
+            .status()
+            .unwrap()
+    };
+
+    assert!(!sync_once(&write_config(5)).success());
+    assert!(!args.exists());
+
+    assert!(sync_once(&write_config(10)).success());
+    let args = std::fs::read_to_string(&args).unwrap();
+    assert!(args.contains("--max-delete=10"), "{args}");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_plugin_command() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let bin = dir.path().join("bin");
+    std::fs::create_dir(&bin).unwrap();
+    let recorded = dir.path().join("recorded");
+    let plugin = bin.join("atune-record");
+    std::fs::write(
+        &plugin,
+        format!(
+            "#!/bin/sh\necho \"$@\" > {0}\ncat >> {0}\n",
+            recorded.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config = format!(
+        r#"
+projects:
+    test_1:
+      sync:
+        - src: {}
+          on_sync:
+            - plugin:record --channel dev
+    "#,
+        dir.path().join("test_1").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let path = std::env::join_paths(
+        std::iter::once(bin).chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+    )
+    .unwrap();
+    let status = std::process::Command::new(std::env!("CARGO_BIN_EXE_atune"))
+        .arg("-c")
+        .arg(&config_file_path)
+        .arg("sync-once")
+        .env("PATH", path)
+        .env("XDG_STATE_HOME", dir.path().join("state"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let recorded = std::fs::read_to_string(&recorded).unwrap();
+    let (args, payload) = recorded.split_once('\n').unwrap();
+    assert_eq!(args, "--channel dev");
+    assert!(
+        payload.starts_with(r#"{"event":"on_sync","project":"test_1","env":{"#),
+        "{payload}"
+    );
+    assert!(payload.contains(r#""ATUNE_SYNC_SRC":"#), "{payload}");
+}
+
+#[test]
+fn test_agent_backend() {
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("out");
+    std::fs::create_dir_all(out.join("test_1/stale")).unwrap();
+    std::fs::write(out.join("test_1/stale/old.txt"), "old").unwrap();
+    std::fs::write(out.join("test_1/0.txt"), "outdated").unwrap();
+
+    let config = format!(
+        r#"
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: {}
+          backend: Agent
+          agent: "'{}'"
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+        std::env!("CARGO_BIN_EXE_atune"),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+
+    for i in 0..10 {
+        let content = std::fs::read_to_string(out.join(format!("test_1/{i}.txt"))).unwrap();
+        assert_eq!(content, "some content");
+    }
+    assert!(!out.join("test_1/stale").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_remote_command() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let bin = dir.path().join("bin");
+    std::fs::create_dir(&bin).unwrap();
+    let args = dir.path().join("args");
+    // runs the remote command locally
+    let ssh = bin.join("ssh");
+    std::fs::write(
+        &ssh,
+        format!(
+            "#!/bin/sh\necho \"$@\" >> {}\nfor a; do last=$a; done\nsh -c \"$last\"\n",
+            args.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&ssh, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let rsync = bin.join("noop-rsync");
+    std::fs::write(&rsync, "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(&rsync, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let restarted = dir.path().join("restarted");
+
+    let config = format!(
+        r#"
+projects:
+    test_1:
+      rsync: {}
+      sync:
+        - src: {}
+          dst: deploy@devbox:/srv/app
+          on_sync:
+            - command: echo "$MODE $ATUNE_SYNC_DST" > {}
+              where: Remote
+              env:
+                MODE: dev build
+    "#,
+        rsync.display(),
+        dir.path().join("test_1").display(),
+        restarted.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+    let path = std::env::join_paths(
+        std::iter::once(bin).chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+    )
+    .unwrap();
+    let status = std::process::Command::new(std::env!("CARGO_BIN_EXE_atune"))
+        .arg("-c")
+        .arg(&config_file_path)
+        .arg("sync-once")
+        .env("PATH", path)
+        .env("XDG_STATE_HOME", dir.path().join("state"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert_eq!(
+        std::fs::read_to_string(&restarted).unwrap(),
+        "dev build /srv/app\n"
+    );
+    let args = std::fs::read_to_string(&args).unwrap();
+    assert!(args.contains("ControlMaster=auto"), "{args}");
+    assert!(args.contains("deploy@devbox"), "{args}");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_ssh_opts() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let rsh = dir.path().join("rsh");
+    let rsync = dir.path().join("rsh-rsync");
+    std::fs::write(
+        &rsync,
+        format!("#!/bin/sh\necho \"$RSYNC_RSH\" > {}\n", rsh.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&rsync, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config = format!(
+        r#"
+projects:
+    test_1:
+      rsync: {}
+      ssh_opts:
+        proxy_jump: admin@bastion
+        port: 2222
+      sync:
+        - src: {}
+          dst: deploy@devbox:/srv/app
+    "#,
+        rsync.display(),
+        dir.path().join("test_1").display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, &config).unwrap();
+
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+    assert_eq!(
+        std::fs::read_to_string(&rsh).unwrap(),
+        "ssh -J admin@bastion -p 2222\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_create_dst() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    // like rsync, fails if the parent of dst is missing
+    let rsync = dir.path().join("strict-rsync");
+    std::fs::write(
+        &rsync,
+        "#!/bin/sh\nfor a; do last=$a; done\n[ -d \"$last\" ]\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&rsync, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let dst = dir.path().join("out/nested/dst");
+
+    let write_config = |create_dst: bool| {
+        let config = format!(
+            r#"
+projects:
+    test_1:
+      rsync: {}
+      sync:
+        - src: {}
+          dst: {}
+          create_dst: {create_dst}
+    "#,
+            rsync.display(),
+            dir.path().join("test_1").display(),
+            dst.display(),
+        );
+        let config_file_path = dir.path().join("config.yaml");
+        std::fs::write(&config_file_path, config).unwrap();
+        config_file_path
+    };
+
+    let mut proc = atune(write_config(false).as_os_str(), "sync-once");
+    assert!(!proc.0.wait().unwrap().success());
+    assert!(!dst.exists());
+
+    let mut proc = atune(write_config(true).as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+    assert!(dst.is_dir());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_dst_mode() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = tempfile::Builder::new().prefix("atune_").tempdir().unwrap();
+    setup(dir.path());
+    let out = dir.path().join("out");
+
+    let config = format!(
+        r#"
+projects:
+    test_1:
+      sync:
+        - src: {}
+          dst: {}
+          backend: Copy
+          dst_mode: D750,F600
+    "#,
+        dir.path().join("test_1").display(),
+        out.display(),
+    );
+    let config_file_path = dir.path().join("config.yaml");
+    std::fs::write(&config_file_path, config).unwrap();
+
+    let mut proc = atune(config_file_path.as_os_str(), "sync-once");
+    assert!(proc.0.wait().unwrap().success());
+
+    let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode(&out.join("test_1")), 0o750);
+    assert_eq!(mode(&out.join("test_1/0.txt")), 0o600);
+}